@@ -1,10 +1,12 @@
+use crate::gossip::topic::SharedSubscriptions;
 use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
 use crate::repo::repo::Repo;
 use crate::storage::repo_model;
 use anyhow::Result;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::interval;
 use tracing::{debug, info, warn};
 
@@ -12,13 +14,31 @@ use super::BundleService;
 
 const SYNC_INTERVAL: Duration = Duration::from_secs(30);
 
-/// 后台任务：定时检查和同步 external repos 的 bundle
-pub async fn start_bundle_sync_task(bundle_service: Arc<Mutex<BundleService>>) {
+/// 后台任务：定时检查和同步关注的 external repos 的 bundle，节点关闭后不再发起新的请求
+///
+/// `requests` 收到仓库 ID 时立即同步该仓库，不必等待下一轮检查；只见过而未关注的仓库不下载 bundle
+pub async fn start_bundle_sync_task(
+    bundle_service: Arc<Mutex<BundleService>>,
+    subscriptions: SharedSubscriptions,
+    shutdown: Shutdown,
+    mut requests: mpsc::UnboundedReceiver<String>,
+) {
     tokio::spawn(async move {
         let mut tick = interval(SYNC_INTERVAL);
 
         loop {
-            tick.tick().await;
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tick.tick() => {}
+                Some(repo_id) = requests.recv() => {
+                    match repo_model::load_repo_from_db(&repo_id).await {
+                        Ok(Some(repo)) => sync_repo(&bundle_service, &subscriptions, &repo).await,
+                        Ok(None) => {}
+                        Err(e) => warn!("Failed to load repo {} for sync: {}", repo_id, e),
+                    }
+                    continue;
+                }
+            }
 
             debug!("Starting bundle sync check for external repos");
 
@@ -26,30 +46,7 @@ pub async fn start_bundle_sync_task(bundle_service: Arc<Mutex<BundleService>>) {
             match repo_model::list_repos().await {
                 Ok(repos) => {
                     for repo in repos {
-                        if repo.is_external && repo.bundle.as_os_str().is_empty() {
-                            debug!(
-                                "Found external repo without bundle: {} (creator: {})",
-                                repo.repo_id, repo.p2p_description.creator
-                            );
-
-                            // 从creator节点请求bundle
-                            if let Err(e) = request_bundle_from_owner(
-                                &bundle_service,
-                                &repo,
-                                &repo.p2p_description.creator,
-                            )
-                            .await
-                            {
-                                warn!("Failed to request bundle for repo {}: {}", repo.repo_id, e);
-                            }
-                        } else if repo.is_external && !repo.bundle.as_os_str().is_empty() {
-                            // Bundle 已存在，确保数据库已更新
-                            debug!(
-                                "External repo {} already has bundle: {}",
-                                repo.repo_id,
-                                repo.bundle.display()
-                            );
-                        }
+                        sync_repo(&bundle_service, &subscriptions, &repo).await;
                     }
                 }
                 Err(e) => {
@@ -60,6 +57,37 @@ pub async fn start_bundle_sync_task(bundle_service: Arc<Mutex<BundleService>>) {
     });
 }
 
+/// 关注的外部仓库没有 bundle 时向所有者请求
+async fn sync_repo(
+    bundle_service: &Arc<Mutex<BundleService>>,
+    subscriptions: &SharedSubscriptions,
+    repo: &Repo,
+) {
+    if repo.is_external && !subscriptions.lock().await.follows(repo) {
+        return;
+    }
+    if repo.is_external && repo.bundle.as_os_str().is_empty() {
+        debug!(
+            "Found external repo without bundle: {} (creator: {})",
+            repo.repo_id, repo.p2p_description.creator
+        );
+
+        // 从creator节点请求bundle
+        if let Err(e) =
+            request_bundle_from_owner(bundle_service, repo, &repo.p2p_description.creator).await
+        {
+            warn!("Failed to request bundle for repo {}: {}", repo.repo_id, e);
+        }
+    } else if repo.is_external && !repo.bundle.as_os_str().is_empty() {
+        // Bundle 已存在，确保数据库已更新
+        debug!(
+            "External repo {} already has bundle: {}",
+            repo.repo_id,
+            repo.bundle.display()
+        );
+    }
+}
+
 /// 从仓库所有者请求 bundle
 async fn request_bundle_from_owner(
     bundle_service: &Arc<Mutex<BundleService>>,
@@ -74,10 +102,10 @@ async fn request_bundle_from_owner(
     // 解析所有者的 NodeId
     let owner_node_id = NodeId::from_string(owner_node_id_str)?;
 
-    // 通过 BundleService 的 request_bundle 发送 Request 消息
+    // 通过路由表在已连接的提供者中选择得分最高的节点请求
     let service = bundle_service.lock().await;
     service
-        .request_bundle(&owner_node_id, &repo.repo_id)
+        .request_bundle_from_providers(&[owner_node_id], &repo.repo_id)
         .await?;
 
     Ok(())
@@ -1,18 +1,24 @@
-use crate::bundle::transfer::BundleMessageType;
 use crate::bundle::transfer::BundleTransferManager;
+use crate::bundle::transfer::{BundleOffer, BundleRequestParams, RequestBundle};
 use crate::node::node_id::NodeId;
-use crate::transport::quic::ConnectionManager;
+use crate::node::shutdown::Shutdown;
+use crate::transport::frame::Channel;
+use crate::transport::protocol::Capabilities;
+use crate::transport::transport::Transport;
 use anyhow::Result;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
+
+/// bundle 请求超时时间，包含所有者打包仓库的耗时
+const BUNDLE_REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
 
 /// Bundle 传输服务
 ///
 /// 负责处理 bundle 文件的接收和发送，
 pub struct BundleService {
-    connection_manager: Arc<Mutex<ConnectionManager>>,
+    transport: Arc<dyn Transport>,
     bundle_manager: Arc<BundleTransferManager>,
 }
 pub struct BundleRequest {
@@ -22,27 +28,44 @@ pub struct BundleRequest {
 
 impl BundleService {
     /// 创建新的 BundleService
-    pub fn new(connection_manager: Arc<Mutex<ConnectionManager>>, storage_dir: PathBuf) -> Self {
-        let bundle_manager = Arc::new(BundleTransferManager::new(
-            connection_manager.clone(),
-            storage_dir,
-        ));
+    pub fn new(transport: Arc<dyn Transport>, storage_dir: PathBuf) -> Self {
+        let bundle_manager = Arc::new(BundleTransferManager::new(transport.clone(), storage_dir));
 
         Self {
-            connection_manager,
+            transport,
             bundle_manager,
         }
     }
 
-    /// 启动 Bundle 服务：注册 data_sender 并处理接收的 bundle 消息
+    /// 节点关闭时拒绝新的 bundle 请求，已开始的发送在关闭前完成，
+    /// 中断的接收只留下 `.part` 临时文件
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        // manager 只在 start 之后才被共享，构建时总能取得可变引用
+        Arc::get_mut(&mut self.bundle_manager)
+            .expect("bundle manager is shared before start")
+            .set_shutdown(shutdown);
+        self
+    }
+
+    /// 启动 Bundle 服务：注册 Data 通道和 bundle 请求 RPC，并处理接收的 bundle 消息
     pub async fn start(self: Arc<Self>) -> Result<()> {
         // 注册数据传输接收器
         let (data_tx, mut data_rx) = mpsc::channel::<(NodeId, Vec<u8>)>(256);
 
-        {
-            let mgr = self.connection_manager.lock().await;
-            mgr.register_data_sender(data_tx).await;
-        }
+        self.transport
+            .register_channel(Channel::Data, data_tx)
+            .await;
+
+        let bundle_manager = Arc::clone(&self.bundle_manager);
+        self.transport
+            .register_rpc::<RequestBundle, _, _>(move |from, params| {
+                let bundle_manager = Arc::clone(&bundle_manager);
+                async move {
+                    bundle_manager
+                        .handle_bundle_request(&from, &params.repo_id)
+                        .await
+                }
+            });
 
         // Bundle 数据处理任务
         let s = Arc::clone(&self);
@@ -74,27 +97,88 @@ impl BundleService {
         self.bundle_manager.get_bundle_path(from, repo_id)
     }
 
-    /// 向指定节点请求 bundle（发送 Request 消息）
-    pub async fn request_bundle(&self, target_node_id: &NodeId, repo_id: &str) -> Result<()> {
-        // 构造 Request 消息
-        let start_msg = BundleMessageType::Request {
-            repo_id: repo_id.to_string(),
-        };
-
-        let payload = serde_json::to_vec(&start_msg)?;
+    /// 向指定节点请求 bundle
+    ///
+    /// 返回所有者的应答，bundle 文件随后通过 Data 通道到达；
+    /// 所有者没有该仓库或打包失败时返回远程错误，对端不支持 bundle 请求 RPC 时直接返回错误
+    pub async fn request_bundle(
+        &self,
+        target_node_id: &NodeId,
+        repo_id: &str,
+    ) -> Result<BundleOffer> {
+        let mgr = &self.transport;
+        if let Some(protocol) = mgr.peer_protocol(target_node_id).await {
+            if !protocol.supports(Capabilities::BUNDLE_V2) {
+                return Err(anyhow::anyhow!(
+                    "node[{}] does not support bundle requests (capabilities: {})",
+                    target_node_id,
+                    protocol.capabilities
+                ));
+            }
+        }
+        let result = mgr
+            .call_with_timeout::<RequestBundle>(
+                target_node_id.clone(),
+                BundleRequestParams {
+                    repo_id: repo_id.to_string(),
+                },
+                BUNDLE_REQUEST_TIMEOUT,
+            )
+            .await;
 
-        let mgr = self.connection_manager.lock().await;
-        let peers = mgr.list_peers().await;
-        tracing::info!("Sent bundle peers: {:?}", peers);
-        mgr.send_data_message(target_node_id.clone(), payload)
-            .await?;
+        let offer = match result {
+            Ok(offer) => offer,
+            Err(e) => {
+                mgr.routing().lock().await.record_failure(target_node_id);
+                return Err(e);
+            }
+        };
 
         tracing::info!(
-            "Bundle request sent to {} for repo {}",
+            "Bundle request for repo {} accepted by {}: {} ({} bytes)",
+            repo_id,
             target_node_id,
-            repo_id
+            offer.file_name,
+            offer.total_size
         );
 
-        Ok(())
+        Ok(offer)
+    }
+
+    /// 从候选提供者中按路由得分依次请求 bundle，返回第一个接受请求的节点及其应答
+    ///
+    /// 只考虑当前已连接、且支持 bundle 请求 RPC 的候选节点
+    pub async fn request_bundle_from_providers(
+        &self,
+        providers: &[NodeId],
+        repo_id: &str,
+    ) -> Result<(NodeId, BundleOffer)> {
+        let connected = self.transport.peers_with(Capabilities::BUNDLE_V2).await;
+        let routing = self.transport.routing();
+        let candidates: Vec<NodeId> = providers
+            .iter()
+            .filter(|p| connected.contains(p))
+            .cloned()
+            .collect();
+        let ranked = routing.lock().await.rank(&candidates);
+
+        let mut last_err = None;
+        for provider in ranked {
+            match self.request_bundle(&provider, repo_id).await {
+                Ok(offer) => return Ok((provider, offer)),
+                Err(e) => {
+                    tracing::warn!(
+                        "Provider {} could not serve bundle for repo {}: {}",
+                        provider,
+                        repo_id,
+                        e
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| anyhow::anyhow!("no connected provider for repo {}", repo_id)))
     }
 }
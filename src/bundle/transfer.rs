@@ -1,10 +1,16 @@
 use crate::node::node_id::NodeId;
+use crate::node::reputation::{self, Offence};
+use crate::node::shutdown::Shutdown;
 use crate::storage::repo_model;
-use crate::transport::quic::ConnectionManager;
+use crate::transport::codec;
+use crate::transport::frame::Channel;
+use crate::transport::rpc::RpcMethod;
+use crate::transport::transport::Transport;
 use crate::util::get_node_id_last_part;
 use crate::util::get_repo_id_last_part;
 use anyhow::Context;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -15,12 +21,34 @@ use tracing::{debug, info, warn};
 
 const TRANSFER_CHUNK_SIZE: usize = 64 * 1024; // 64KB per chunk
 
+/// RPC：向仓库所有者请求 bundle
+///
+/// 所有者生成 bundle 后返回 [`BundleOffer`]，随后通过 Data 通道推送文件；
+/// 仓库不存在或无法提供时返回远程错误
+pub struct RequestBundle;
+
+impl RpcMethod for RequestBundle {
+    const NAME: &'static str = "bundle.request";
+    type Request = BundleRequestParams;
+    type Response = BundleOffer;
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BundleRequestParams {
+    pub repo_id: String,
+}
+
+/// 所有者对 bundle 请求的应答
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BundleOffer {
+    pub repo_id: String,
+    pub file_name: String,
+    pub total_size: u64,
+}
+
 /// Bundle 消息类型（用于多帧传输）
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum BundleMessageType {
-    Request {
-        repo_id: String,
-    },
     /// 开始传输：包含文件元数据
     Start {
         repo_id: String,
@@ -31,29 +59,60 @@ pub enum BundleMessageType {
     Chunk {
         repo_id: String,
         chunk_idx: u32,
+        #[serde(with = "serde_bytes")]
         data: Vec<u8>,
     },
     /// 传输完成
-    Done {
-        repo_id: String,
-    },
+    Done { repo_id: String },
 }
 
 /// Bundle 文件传输管理器
+///
+/// 接收中的 bundle 先写入 `<name>.bundle.part`，收到 DONE 后才改名为 `<name>.bundle`，
+/// 节点在传输中途退出时不会留下看似完整的文件。收到的大小与 START 声明的不符时丢弃文件，
+/// 并记为发送方的违规
 pub struct BundleTransferManager {
-    connection_manager: Arc<Mutex<ConnectionManager>>,
+    transport: Arc<dyn Transport>,
     storage_dir: PathBuf,
+    shutdown: Shutdown,
+    /// 接收中的临时文件及其在 START 中声明的大小
+    expected_sizes: Mutex<HashMap<PathBuf, u64>>,
 }
 
 impl BundleTransferManager {
     /// 创建新的 BundleTransferManager
-    pub fn new(connection_manager: Arc<Mutex<ConnectionManager>>, storage_dir: PathBuf) -> Self {
+    pub fn new(transport: Arc<dyn Transport>, storage_dir: PathBuf) -> Self {
         Self {
-            connection_manager,
+            transport,
             storage_dir,
+            shutdown: Shutdown::new(),
+            expected_sizes: Mutex::new(HashMap::new()),
         }
     }
 
+    /// 节点关闭时拒绝新的 bundle 请求，并在退出前等待已开始的发送完成
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.set_shutdown(shutdown);
+        self
+    }
+
+    /// 与 [`Self::with_shutdown`] 相同，用于已创建的 manager
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    /// bundle 存储目录
+    pub fn storage_dir(&self) -> &Path {
+        &self.storage_dir
+    }
+
+    /// 接收中的 bundle 的临时文件路径
+    fn partial_path(file_path: &Path) -> PathBuf {
+        let mut name = file_path.as_os_str().to_owned();
+        name.push(".part");
+        PathBuf::from(name)
+    }
+
     /// 发送 bundle 文件到指定节点
     ///
     /// # Arguments
@@ -88,7 +147,7 @@ impl BundleTransferManager {
             file_name, total_size, target_node_id
         );
 
-        let mgr = self.connection_manager.lock().await;
+        let mgr = &self.transport;
 
         // 1. 发送 START 消息
         let start_msg = BundleMessageType::Start {
@@ -96,8 +155,7 @@ impl BundleTransferManager {
             file_name: file_name.clone(),
             total_size,
         };
-        let start_payload = serde_json::to_vec(&start_msg).context("Failed to serialize START")?;
-        mgr.send_data_message(target_node_id.clone(), start_payload)
+        mgr.send_message(target_node_id.clone(), Channel::Data, &start_msg)
             .await
             .context("Failed to send START message")?;
 
@@ -108,10 +166,7 @@ impl BundleTransferManager {
                 chunk_idx: chunk_idx as u32,
                 data: chunk.to_vec(),
             };
-            let chunk_payload =
-                serde_json::to_vec(&chunk_msg).context("Failed to serialize CHUNK")?;
-
-            mgr.send_data_message(target_node_id.clone(), chunk_payload)
+            mgr.send_message(target_node_id.clone(), Channel::Data, &chunk_msg)
                 .await
                 .context("Failed to send CHUNK message")?;
 
@@ -127,8 +182,7 @@ impl BundleTransferManager {
         let done_msg = BundleMessageType::Done {
             repo_id: repo_id.clone(),
         };
-        let done_payload = serde_json::to_vec(&done_msg).context("Failed to serialize DONE")?;
-        mgr.send_data_message(target_node_id.clone(), done_payload)
+        mgr.send_message(target_node_id.clone(), Channel::Data, &done_msg)
             .await
             .context("Failed to send DONE message")?;
 
@@ -144,15 +198,17 @@ impl BundleTransferManager {
 
     /// 处理接收的 bundle 消息流
     ///
-    /// 这个方法应该由接收 data_sender 的处理器调用
+    /// 这个方法应该由Data 通道的处理器调用
     pub async fn handle_bundle_message(&self, from: NodeId, data: Vec<u8>) -> Result<()> {
         // 反序列化消息
-        let msg: BundleMessageType =
-            serde_json::from_slice(&data).context("Failed to deserialize bundle message")?;
-        match msg {
-            BundleMessageType::Request { repo_id } => {
-                self.handle_bundle_request(&from, &repo_id).await
+        let msg: BundleMessageType = match codec::decode(&data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                reputation::report(self.transport.as_ref(), &from, Offence::MalformedPayload).await;
+                return Err(e.context("Failed to deserialize bundle message"));
             }
+        };
+        match msg {
             BundleMessageType::Start {
                 repo_id,
                 file_name,
@@ -179,75 +235,86 @@ impl BundleTransferManager {
         get_node_id_last_part(&id_str)
     }
 
-    /// 处理 Request 消息：检查本地 repo 是否存在，如果存在则生成 bundle 并发送
-    async fn handle_bundle_request(&self, from: &NodeId, repo_id: &str) -> Result<()> {
+    /// 处理 bundle 请求：检查本地 repo 是否存在，如果存在则生成 bundle，
+    /// 返回 offer 并在后台通过 Data 通道发送给请求者
+    pub async fn handle_bundle_request(
+        self: &Arc<Self>,
+        from: &NodeId,
+        repo_id: &str,
+    ) -> Result<BundleOffer> {
         info!("Received bundle request from {} for repo {}", from, repo_id);
+        if self.shutdown.is_triggered() {
+            return Err(anyhow!("node is shutting down"));
+        }
 
         // 检查本地是否有该 repo
-        match crate::storage::repo_model::load_repo_from_db(repo_id).await {
-            Ok(Some(repo)) => {
-                // repo 存在，检查是否是本地 repo（不是 external）
-                if repo.is_external {
-                    warn!(
-                        "Cannot send bundle for external repo {} to {}",
-                        repo_id, from
-                    );
-                    return Ok(());
-                }
-
-                let repo_path = repo.path.to_string_lossy().to_string();
-                let bundle_dir = self.storage_dir.clone();
-                let bundle_file_name = format!("{}.bundle", get_repo_id_last_part(repo_id));
-                let bundle_path = bundle_dir.join(&bundle_file_name);
-
-                info!(
-                    "Found local repo {} at {}, generating bundle for request from {}",
-                    repo_id, repo_path, from
-                );
-
-                // 生成 bundle 文件（同步操作，需要在线程中运行）
-                let repo_path_clone = repo_path.clone();
-                let bundle_path_clone = bundle_path.clone();
-
-                tokio::task::spawn_blocking(move || {
-                    crate::git::pack::pack_repo_bundle(
-                        &repo_path_clone,
-                        bundle_path_clone.to_str().unwrap_or(""),
-                    )
-                })
-                .await
-                .context("Failed to spawn bundle packing task")??;
+        let repo = repo_model::load_repo_from_db(repo_id)
+            .await
+            .context("Failed to load repo")?
+            .ok_or_else(|| anyhow!("repo {} not found", repo_id))?;
+
+        // 只提供本地 repo（不是 external）
+        if repo.is_external {
+            warn!(
+                "Cannot send bundle for external repo {} to {}",
+                repo_id, from
+            );
+            return Err(anyhow!("repo {} is not hosted by this node", repo_id));
+        }
+
+        let repo_path = repo.path.to_string_lossy().to_string();
+        let bundle_file_name = format!("{}.bundle", get_repo_id_last_part(repo_id));
+        let bundle_path = self.storage_dir.join(&bundle_file_name);
+
+        info!(
+            "Found local repo {} at {}, generating bundle for request from {}",
+            repo_id, repo_path, from
+        );
 
-                info!("Bundle generated successfully for repo {}", repo_id);
+        // 生成 bundle 文件（同步操作，需要在线程中运行）
+        let bundle_path_clone = bundle_path.clone();
+        tokio::task::spawn_blocking(move || {
+            crate::git::pack::pack_repo_bundle(&repo_path, bundle_path_clone.to_str().unwrap_or(""))
+        })
+        .await
+        .context("Failed to spawn bundle packing task")?
+        .context("Failed to generate bundle")?;
 
-                // 发送 bundle 给请求者
-                self.send_bundle(
-                    from.clone(),
-                    repo_id.to_string(),
+        let total_size = fs::metadata(&bundle_path)
+            .await
+            .context("Failed to stat bundle file")?
+            .len();
+        info!("Bundle generated successfully for repo {}", repo_id);
+
+        // 应答返回后再发送 bundle 给请求者；节点关闭时等待发送完成后再断开连接
+        let manager = Arc::clone(self);
+        let target = from.clone();
+        let repo_id_owned = repo_id.to_string();
+        self.shutdown.spawn_tracked(async move {
+            match manager
+                .send_bundle(
+                    target.clone(),
+                    repo_id_owned.clone(),
                     bundle_path.to_str().unwrap_or(""),
                 )
                 .await
-                .context("Failed to send bundle in response to request")?;
-
-                info!("Bundle for repo {} sent successfully to {}", repo_id, from);
-
-                Ok(())
-            }
-            Ok(None) => {
-                warn!(
-                    "Received bundle request for non-existent repo {} from {}",
-                    repo_id, from
-                );
-                Ok(())
-            }
-            Err(e) => {
-                warn!(
-                    "Error checking repo {} for bundle request from {}: {}",
-                    repo_id, from, e
-                );
-                Ok(())
+            {
+                Ok(()) => info!(
+                    "Bundle for repo {} sent successfully to {}",
+                    repo_id_owned, target
+                ),
+                Err(e) => warn!(
+                    "Failed to send bundle for repo {} to {}: {}",
+                    repo_id_owned, target, e
+                ),
             }
-        }
+        });
+
+        Ok(BundleOffer {
+            repo_id: repo_id.to_string(),
+            file_name: bundle_file_name,
+            total_size,
+        })
     }
 
     /// 处理 START 消息
@@ -268,9 +335,14 @@ impl BundleTransferManager {
         let encoded_repo_id = get_repo_id_last_part(repo_id);
         let file_path = dir.join(format!("{}.bundle", encoded_repo_id));
 
-        let _ = fs::File::create(&file_path)
+        let part_path = Self::partial_path(&file_path);
+        let _ = fs::File::create(&part_path)
             .await
             .context("Failed to create/truncate bundle file")?;
+        self.expected_sizes
+            .lock()
+            .await
+            .insert(part_path, total_size);
 
         info!(
             "Bundle transfer START from {}: repo={}, file={}, size={} bytes",
@@ -291,7 +363,7 @@ impl BundleTransferManager {
         let encoded_id = Self::encode_node_id(from);
         let dir = self.storage_dir.join(&encoded_id);
         let encoded_repo_id = get_repo_id_last_part(repo_id);
-        let file_path = dir.join(format!("{}.bundle", encoded_repo_id));
+        let file_path = Self::partial_path(&dir.join(format!("{}.bundle", encoded_repo_id)));
 
         // 如果文件不存在（可能是 Start 消息丢失），先创建
         if !file_path.exists() {
@@ -337,13 +409,35 @@ impl BundleTransferManager {
         let encoded_repo_id = get_repo_id_last_part(repo_id);
         let file_path = dir.join(format!("{}.bundle", encoded_repo_id));
 
-        if file_path.exists() {
+        let part_path = Self::partial_path(&file_path);
+        let expected = self.expected_sizes.lock().await.remove(&part_path);
+        if part_path.exists() {
+            let received = fs::metadata(&part_path)
+                .await
+                .context("Failed to get bundle file metadata")?
+                .len();
+            if let Some(expected) = expected.filter(|&size| size != received) {
+                let _ = fs::remove_file(&part_path).await;
+                reputation::report(self.transport.as_ref(), from, Offence::FailedTransfer).await;
+                return Err(anyhow!(
+                    "bundle for repo {} from {} has {} bytes, expected {}",
+                    repo_id,
+                    from,
+                    received,
+                    expected
+                ));
+            }
+            fs::rename(&part_path, &file_path)
+                .await
+                .context("Failed to finalize bundle file")?;
             let metadata = fs::metadata(&file_path)
                 .await
                 .context("Failed to get bundle file metadata")?;
             // 标记 bundle 已接收
             let bundle_path = file_path.to_string_lossy().to_string();
             repo_model::update_repo_bundle(repo_id, &bundle_path).await?;
+            let routing = self.transport.routing();
+            routing.lock().await.record_success(from);
             info!(
                 "Bundle transfer completed from {}: repo={}, file_size={} bytes",
                 from,
@@ -382,7 +476,7 @@ mod tests {
         };
 
         let serialized = serde_json::to_vec(&msg).unwrap();
-        let deserialized: BundleMessageType = serde_json::from_slice(&serialized).unwrap();
+        let deserialized: BundleMessageType = codec::decode(&serialized).unwrap();
 
         match deserialized {
             BundleMessageType::Start {
@@ -397,4 +491,117 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
+    #[tokio::test]
+    async fn test_interrupted_transfer_leaves_partial_file() {
+        use crate::identity::keypair::KeyPair;
+        use crate::transport::memory::MemoryNetwork;
+
+        let local = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let from = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let storage_dir = std::env::temp_dir().join(format!("bundle-{}", uuid::Uuid::new_v4()));
+        let shutdown = Shutdown::new();
+        let manager = Arc::new(
+            BundleTransferManager::new(MemoryNetwork::new().add_node(local), storage_dir.clone())
+                .with_shutdown(shutdown.clone()),
+        );
+
+        for msg in [
+            BundleMessageType::Start {
+                repo_id: "repo1".to_string(),
+                file_name: "repo1.bundle".to_string(),
+                total_size: 8,
+            },
+            BundleMessageType::Chunk {
+                repo_id: "repo1".to_string(),
+                chunk_idx: 0,
+                data: vec![1, 2, 3, 4],
+            },
+        ] {
+            // 新旧版本节点发来的消息混合到达
+            let encoding = match msg {
+                BundleMessageType::Chunk { .. } => codec::Encoding::Binary,
+                _ => codec::Encoding::Json,
+            };
+            let data = codec::encode(&msg, encoding).unwrap();
+            manager
+                .handle_bundle_message(from.clone(), data)
+                .await
+                .unwrap();
+        }
+
+        // 尚未收到 DONE，只有临时文件存在
+        let dir = storage_dir.join(BundleTransferManager::encode_node_id(&from));
+        assert!(!dir.join("repo1.bundle").exists());
+        assert_eq!(
+            fs::read(dir.join("repo1.bundle.part")).await.unwrap(),
+            vec![1, 2, 3, 4]
+        );
+
+        // 节点关闭后拒绝新的 bundle 请求
+        shutdown.trigger();
+        let err = manager
+            .handle_bundle_request(&from, "repo1")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("shutting down"));
+
+        let _ = fs::remove_dir_all(&storage_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_truncated_transfer_is_discarded_and_penalized() {
+        use crate::identity::keypair::KeyPair;
+        use crate::transport::memory::MemoryNetwork;
+
+        let local = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let from = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let storage_dir = std::env::temp_dir().join(format!("bundle-{}", uuid::Uuid::new_v4()));
+        let transport: Arc<dyn Transport> = MemoryNetwork::new().add_node(local);
+        let manager = BundleTransferManager::new(Arc::clone(&transport), storage_dir.clone());
+
+        for msg in [
+            BundleMessageType::Start {
+                repo_id: "repo1".to_string(),
+                file_name: "repo1.bundle".to_string(),
+                total_size: 8,
+            },
+            BundleMessageType::Chunk {
+                repo_id: "repo1".to_string(),
+                chunk_idx: 0,
+                data: vec![1, 2, 3, 4],
+            },
+        ] {
+            let data = codec::encode(&msg, codec::Encoding::Binary).unwrap();
+            manager
+                .handle_bundle_message(from.clone(), data)
+                .await
+                .unwrap();
+        }
+        let done = BundleMessageType::Done {
+            repo_id: "repo1".to_string(),
+        };
+        let data = codec::encode(&done, codec::Encoding::Binary).unwrap();
+        assert!(manager
+            .handle_bundle_message(from.clone(), data)
+            .await
+            .is_err());
+
+        // 不完整的文件被丢弃，发送方被扣分
+        let dir = storage_dir.join(BundleTransferManager::encode_node_id(&from));
+        assert!(!dir.join("repo1.bundle").exists());
+        assert!(!dir.join("repo1.bundle.part").exists());
+        let now = crate::util::timestamp_now();
+        let score = transport.reputation().lock().await.score(&from, now);
+        assert_eq!(score, -Offence::FailedTransfer.penalty());
+
+        // 无法解析的消息同样扣分
+        assert!(manager
+            .handle_bundle_message(from.clone(), b"not a bundle".to_vec())
+            .await
+            .is_err());
+        let score = transport.reputation().lock().await.score(&from, now);
+        assert!(score < -Offence::FailedTransfer.penalty());
+
+        let _ = fs::remove_dir_all(&storage_dir).await;
+    }
 }
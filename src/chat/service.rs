@@ -3,33 +3,41 @@ use crate::gossip::message::{
 };
 use crate::node::node::Node;
 use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
 use crate::storage::chat_message::MessageStatus;
-use crate::transport::quic::ConnectionManager;
+use crate::transport::frame::Channel;
+use crate::transport::protocol::Capabilities;
+use crate::transport::transport::Transport;
 use crate::util::timestamp_now;
 use anyhow::{anyhow, Result};
 use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use uuid::Uuid;
 
 const TTL: u8 = 16;
+/// 接收方未直连时，转发给路由表中得分最高的若干个节点
+const NEXT_HOPS: usize = 3;
+/// 聊天消息及其确认只发给声明了聊天能力的节点
+const CHAT_CAPABILITIES: Capabilities = Capabilities::GOSSIP.union(Capabilities::CHAT);
 
+/// Periodically sends pending messages until the node shuts down.
 pub async fn start_chat_sender_task(
-    manager: Arc<Mutex<ConnectionManager>>,
+    transport: Arc<dyn Transport>,
     my_node: Node,
+    shutdown: Shutdown,
 ) -> Result<()> {
     loop {
-        if let Err(e) = process_pending_messages(manager.clone(), my_node.clone()).await {
+        if let Err(e) = process_pending_messages(transport.clone(), my_node.clone()).await {
             tracing::error!("Failed to process pending messages: {}", e);
         }
-        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(tokio::time::Duration::from_millis(1000)) => {}
+        }
     }
 }
 
-async fn process_pending_messages(
-    manager: Arc<Mutex<ConnectionManager>>,
-    my_node: Node,
-) -> Result<()> {
+async fn process_pending_messages(transport: Arc<dyn Transport>, my_node: Node) -> Result<()> {
     // 1. Find all messages with status 'Sending'
     let db = crate::storage::get_db_conn().await?;
     let pending_msgs = crate::storage::chat_message::Entity::find()
@@ -51,7 +59,7 @@ async fn process_pending_messages(
         };
 
         match try_send_pending_msg(
-            manager.clone(),
+            transport.clone(),
             my_node.clone(),
             receiver_node_id,
             msg.content.clone(),
@@ -75,7 +83,7 @@ async fn process_pending_messages(
 }
 
 async fn try_send_pending_msg(
-    manager: Arc<Mutex<ConnectionManager>>,
+    transport: Arc<dyn Transport>,
     my_node: Node,
     receiver_node_id: NodeId,
     content: String,
@@ -106,38 +114,36 @@ async fn try_send_pending_msg(
         node_id: my_node.node_id().clone(),
         message,
         timestamp: timestamp_now(),
+        sequence: 0,
         signature: "".to_string(),
+        canonical_signature: None,
     };
-    let self_hash = signed_msg.self_hash();
-    let sign = my_node.sign_message(self_hash.as_slice())?;
-    signed_msg.signature = hex::encode(sign);
+    signed_msg.sign(&my_node)?;
 
     let envelope = Envelope {
         payload: signed_msg,
         ttl: TTL,
     };
-    let data = serde_json::to_vec(&envelope)?;
 
-    // Try to find if we are connected or have a known route?
-    // Gossip usually just floods peers if not knowing better.
-    // If we have direct connection or routing table, use it.
-    // For now: broadcast to all connected peers.
+    // Direct connection if we have one, otherwise hand the message to the
+    // best-scored peers from the routing table and let gossip forward it.
 
     // Obtain the current peer list while holding the mutex only briefly.
-    let peers = {
-        let mgr = manager.lock().await;
-        mgr.list_peers().await
+    // Only peers that declared chat support can parse or forward the message.
+    let (peers, routing) = {
+        let mgr = &transport;
+        (mgr.peers_with(CHAT_CAPABILITIES).await, mgr.routing())
     };
 
     if peers.is_empty() {
-        return Err(anyhow!("No peers connected to send message"));
+        return Err(anyhow!("No chat-capable peers connected to send message"));
     }
 
     if peers.contains(&receiver_node_id) {
         // Direct send to receiver; propagate any error to the caller.
         let send_result = {
-            let mgr = manager.lock().await;
-            mgr.send_gossip_message(receiver_node_id.clone(), data.clone())
+            let mgr = &transport;
+            mgr.send_message(receiver_node_id.clone(), Channel::Gossip, &envelope)
                 .await
         };
 
@@ -149,21 +155,28 @@ async fn try_send_pending_msg(
             )
         })?;
     } else {
-        // Broadcast to all peers; require at least one successful send.
+        // Send to the best next hops; require at least one successful send.
+        let next_hops: Vec<NodeId> = routing
+            .lock()
+            .await
+            .rank(&peers)
+            .into_iter()
+            .take(NEXT_HOPS)
+            .collect();
         let mut at_least_one_success = false;
         let mut last_err: Option<anyhow::Error> = None;
 
-        for peer in peers {
-            let send_result = {
-                let mgr = manager.lock().await;
-                mgr.send_gossip_message(peer.clone(), data.clone()).await
-            };
-
+        // Hand the message to all next hops in parallel.
+        let results = transport
+            .send_many(next_hops, Channel::Gossip, &envelope)
+            .await;
+        for (peer, send_result) in results {
             match send_result {
                 Ok(()) => {
                     at_least_one_success = true;
                 }
                 Err(e) => {
+                    routing.lock().await.record_failure(&peer);
                     last_err = Some(anyhow!(
                         "Failed to send gossip message to peer {}: {}",
                         peer,
@@ -189,7 +202,7 @@ async fn try_send_pending_msg(
 }
 
 pub async fn send_chat_message(
-    _manager: Arc<Mutex<ConnectionManager>>,
+    _transport: Arc<dyn Transport>,
     my_node: Node,
     receiver_node_id: NodeId,
     content: String,
@@ -217,7 +230,7 @@ pub async fn send_chat_message(
 
 pub async fn process_incoming_chat(
     msg: EncryptedChatMessage,
-    manager: Arc<Mutex<ConnectionManager>>,
+    transport: Arc<dyn Transport>,
     my_node: Node,
 ) -> Result<()> {
     // 1. Check if it's for me
@@ -270,30 +283,27 @@ pub async fn process_incoming_chat(
         node_id: my_node.node_id().clone(),
         message: gossip_msg,
         timestamp: timestamp_now(),
+        sequence: 0,
         signature: "".to_string(),
+        canonical_signature: None,
     };
-    let self_hash = signed_ack.self_hash();
-    let sign = my_node.sign_message(self_hash.as_slice())?;
-    signed_ack.signature = hex::encode(sign);
+    signed_ack.sign(&my_node)?;
 
     let envelope = Envelope {
         payload: signed_ack,
         ttl: TTL,
     };
-    let data = serde_json::to_vec(&envelope)?;
 
-    let mgr = manager.lock().await;
-    let peers = mgr.list_peers().await;
-    for peer in peers {
-        let _ = mgr.send_gossip_message(peer.clone(), data.clone()).await;
-    }
+    let mgr = &transport;
+    let peers = mgr.peers_with(CHAT_CAPABILITIES).await;
+    mgr.send_many(peers, Channel::Gossip, &envelope).await;
 
     Ok(())
 }
 
 pub async fn process_ack(
     ack: ChatAckMessage,
-    _manager: Arc<Mutex<ConnectionManager>>,
+    _transport: Arc<dyn Transport>,
     my_node: Node,
 ) -> Result<()> {
     // 1. Check if it's for me
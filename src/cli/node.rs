@@ -1,27 +1,49 @@
 use anyhow::Result;
+use megaengine::gossip::dedup::DedupConfig;
+use megaengine::gossip::membership::MembershipConfig;
+use megaengine::gossip::replay::FreshnessConfig;
+use megaengine::gossip::topic::Topic;
 use megaengine::mcp::start_sse_server;
+use megaengine::node::metrics;
+use megaengine::node::node::NodeType;
+use megaengine::node::node_id::NodeId;
+use megaengine::node::peer_manager::{self, PeerManager};
+use megaengine::node::reputation::Ban;
+use megaengine::node::shutdown::{self, Shutdown};
+use megaengine::repo::anti_entropy::{AntiEntropy, DbCatalog};
+use megaengine::transport::events::Direction;
+use megaengine::transport::limits::ConnectionLimits;
+use megaengine::transport::relay::RelayLimits;
 use megaengine::{
     bundle::BundleService, node::node_addr::NodeAddr, storage, transport::config::QuicConfig,
 };
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
+/// 关闭时等待进行中的传输完成的最长时间
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// 重新读取 `repo follow` / `repo unfollow` 修改的订阅的间隔
+const SUBSCRIPTION_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_node_start(
     root_path: &str,
     alias: String,
-    addr: String,
-    cert_path: String,
+    bind_addrs: Vec<SocketAddr>,
+    announce: Vec<SocketAddr>,
+    allow_local_addrs: bool,
     bootstrap_node: Option<String>,
+    target_peers: usize,
+    relay: bool,
+    limits: ConnectionLimits,
+    freshness: FreshnessConfig,
     enable_mcp: bool,
     mcp_sse_port: Option<u16>,
+    metrics_port: Option<u16>,
 ) -> Result<()> {
     tracing::info!("Starting node...");
-    let cert_dir = format!("{}/{}", root_path, cert_path);
-    megaengine::transport::cert::ensure_certificates(
-        &format!("{}/cert.pem", cert_dir),
-        &format!("{}/key.pem", cert_dir),
-        &format!("{}/ca-cert.pem", cert_dir),
-    )?;
 
     let kp = match storage::load_keypair() {
         Ok(k) => k,
@@ -32,73 +54,173 @@ pub async fn handle_node_start(
         }
     };
 
-    let addrs: Vec<std::net::SocketAddr> = vec![addr.parse()?];
+    // 未指定公告地址时公告监听地址，无法拨号的地址在广播前过滤
+    let addrs = if announce.is_empty() {
+        bind_addrs.clone()
+    } else {
+        announce
+    };
 
-    let mut node = megaengine::node::node::Node::from_keypair(
-        &kp,
-        &alias,
-        addrs.clone(),
-        megaengine::node::node::NodeType::Normal,
-    );
+    let node_type = if relay {
+        NodeType::Relay
+    } else {
+        NodeType::Normal
+    };
+    let mut node =
+        megaengine::node::node::Node::from_keypair(&kp, &alias, addrs.clone(), node_type);
     tracing::info!(
         "Node initialized: alias={} id={}",
         node.alias(),
         node.node_id().0
     );
 
-    let quic_config = QuicConfig::new(
-        addr.parse()?,
-        format!("{}/cert.pem", cert_dir),
-        format!("{}/key.pem", cert_dir),
-        format!("{}/ca-cert.pem", cert_dir),
-    );
+    // TLS 证书由节点身份密钥派生，无需预先生成证书文件
+    let mut quic_config = QuicConfig::new(bind_addrs[0], kp.clone())
+        .with_bind_addrs(bind_addrs.clone())
+        .with_local_addrs(allow_local_addrs)
+        .with_limits(limits);
+    if relay {
+        quic_config = quic_config.with_relay(RelayLimits::default());
+    }
 
-    tracing::info!("Starting QUIC server on {}...", addr);
+    tracing::info!("Starting QUIC server on {:?}...", bind_addrs);
     node.start_quic_server(quic_config).await?;
 
-    if let Some(conn_mgr) = &node.connection_manager {
-        // 启动 Gossip 服务
-        let gossip = Arc::new(megaengine::gossip::GossipService::new(
-            Arc::clone(conn_mgr),
-            node.clone(),
-            None,
-        ));
-        tokio::spawn(gossip.start());
+    // 所有后台服务共享同一个关闭协调器，收到 SIGINT/SIGTERM 时统一停止
+    let shutdown = Shutdown::new();
+    let mut peers = None;
+    let mut dedup = None;
+    if let Some(transport) = &node.transport {
+        // 启动 Gossip 服务，active view 的大小即目标连接数；
+        // 收到 RefUpdate 后通知 bundle 同步任务立即同步受影响的仓库
+        let (sync_tx, sync_rx) = tokio::sync::mpsc::unbounded_channel();
+        let topics = storage::subscription_model::load_topics()
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to load subscriptions: {}", e);
+                Topic::defaults()
+            });
+        let gossip = Arc::new(
+            megaengine::gossip::GossipService::new(Arc::clone(transport), node.clone(), None)
+                .with_membership_config(MembershipConfig::default().with_active_size(target_peers))
+                .with_freshness(freshness)
+                // 重启后恢复最近收到的消息 ID，避免重新转发引起广播风暴
+                .with_dedup(DedupConfig::default().with_persistence(true))
+                .with_subscriptions(topics)
+                .with_sync_requests(sync_tx)
+                .with_shutdown(shutdown.clone()),
+        );
+        let membership = gossip.membership();
+        let subscriptions = gossip.subscriptions();
+        dedup = Some(gossip.dedup());
+        tokio::spawn(Arc::clone(&gossip).start());
         tracing::info!("Gossip protocol started");
 
+        // 定期读取订阅，`repo follow` / `repo unfollow` 的修改无需重启节点即可生效
+        let reload_gossip = Arc::clone(&gossip);
+        let reload_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = reload_shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(SUBSCRIPTION_RELOAD_INTERVAL) => {}
+                }
+                match storage::subscription_model::load_topics().await {
+                    Ok(topics) => reload_gossip.set_subscriptions(topics).await,
+                    Err(e) => tracing::warn!("Failed to reload subscriptions: {}", e),
+                }
+            }
+        });
+
         // 启动 Bundle 传输服务
         let bundles_dir = PathBuf::from(format!("{}/bundles", root_path));
         let bundle_storage = bundles_dir.clone();
-        let bundle_service = Arc::new(BundleService::new(Arc::clone(conn_mgr), bundle_storage));
+        let bundle_service = Arc::new(
+            BundleService::new(Arc::clone(transport), bundle_storage)
+                .with_shutdown(shutdown.clone()),
+        );
         tokio::spawn(bundle_service.clone().start());
         tracing::info!("Bundle transfer service started");
 
         // 启动 Bundle 同步后台任务
         let bundle_service_for_sync = Arc::new(tokio::sync::Mutex::new(BundleService::new(
-            Arc::clone(conn_mgr),
+            Arc::clone(transport),
             bundles_dir,
         )));
-        megaengine::bundle::start_bundle_sync_task(bundle_service_for_sync).await;
+        megaengine::bundle::start_bundle_sync_task(
+            bundle_service_for_sync,
+            Arc::clone(&subscriptions),
+            shutdown.clone(),
+            sync_rx,
+        )
+        .await;
         tracing::info!("Bundle sync task started");
 
-        // 启动 Repo 同步后台任务
-        megaengine::repo::start_repo_sync_task().await;
+        // 启动 Repo 同步后台任务，本地仓库的 ref 变化立即作为 RefUpdate 广播
+        let (changes_tx, mut changes_rx) = tokio::sync::mpsc::unbounded_channel();
+        megaengine::repo::start_repo_sync_task(shutdown.clone(), changes_tx).await;
+        tokio::spawn(async move {
+            while let Some(changes) = changes_rx.recv().await {
+                gossip.announce_ref_changes(changes).await;
+            }
+        });
+        megaengine::repo::repo_sync::register_ref_rpc(transport.as_ref());
         tracing::info!("Repo sync task started");
 
+        // 定期与随机邻居交换仓库目录摘要，补上错过的 RepoAnnouncement
+        Arc::new(
+            AntiEntropy::new(
+                Arc::clone(transport),
+                Arc::new(DbCatalog::new(subscriptions)),
+            )
+            .with_shutdown(shutdown.clone()),
+        )
+        .start();
+        tracing::info!("Repo anti-entropy started");
+
         // Start Chat Sender Task
         let chat_node = node.clone();
-        let chat_mgr = Arc::clone(conn_mgr);
+        let chat_transport = Arc::clone(transport);
+        let chat_shutdown = shutdown.clone();
         tokio::spawn(async move {
-            let _ = megaengine::chat::service::start_chat_sender_task(chat_mgr, chat_node).await;
+            let _ = megaengine::chat::service::start_chat_sender_task(
+                chat_transport,
+                chat_node,
+                chat_shutdown,
+            )
+            .await;
         });
         tracing::info!("Chat sender task started");
+
+        // 路由表：定期淘汰过期条目并写入数据库供 `node routes` 查询
+        megaengine::node::routing::start_routing_task(transport.routing(), shutdown.clone());
+        // 封禁记录：定期读取 `node ban` / `node unban` 的修改并断开被封禁的节点
+        megaengine::node::reputation::start_reputation_task(
+            Arc::clone(transport),
+            shutdown.clone(),
+        );
+        // 各节点的连接和流量统计：定期写入数据库供 `node peers` 查询
+        if let Some(manager) = &node.connection_manager {
+            megaengine::transport::stats::start_stats_task(manager.clone(), shutdown.clone());
+        }
+
+        peers = Some(Arc::new(
+            PeerManager::new(Arc::clone(transport), node.node_id().clone(), target_peers)
+                .with_membership(membership),
+        ));
     } else {
-        tracing::warn!("No connection manager found, services not started");
+        tracing::warn!("No transport found, services not started");
     }
 
-    // 连接到 bootstrap node
-    if let Some(bootstrap_addr_str) = bootstrap_node {
-        connect_to_bootstrap_node(&node, bootstrap_addr_str).await;
+    if let Some(peers) = peers {
+        // 连接到 bootstrap node
+        if let Some(bootstrap_addr_str) = bootstrap_node {
+            connect_to_bootstrap_node(&peers, bootstrap_addr_str).await;
+        }
+
+        // 维护与已知节点的连接（包括重启前记录的 bootstrap 节点）
+        peers.start(shutdown.clone());
+        tracing::info!("Peer manager started (target {} peers)", target_peers);
     }
 
     println!(
@@ -106,9 +228,18 @@ pub async fn handle_node_start(
         node.node_id().0,
         node.alias()
     );
-    println!("Listening on: {}", addr);
+    let local_addrs = node
+        .connection_manager
+        .as_ref()
+        .map(|m| m.local_addrs())
+        .unwrap_or(bind_addrs);
+    let listening: Vec<String> = local_addrs.iter().map(|a| a.to_string()).collect();
+    println!("Listening on: {}", listening.join(", "));
 
-    let node_addr = NodeAddr::new(node.node_id().clone(), addr.parse()?);
+    let node_addr = NodeAddr::new(
+        node.node_id().clone(),
+        node.addresses().first().copied().unwrap_or(local_addrs[0]),
+    );
     println!("Node address: {}", node_addr);
     println!("Press Ctrl+C to stop");
 
@@ -124,63 +255,97 @@ pub async fn handle_node_start(
     if let Some(port) = mcp_sse_port {
         tracing::info!("MCP SSE server enabled on port {}", port);
         println!("MCP SSE server enabled on port {}", port);
+        let sse_shutdown = shutdown.clone();
         tokio::spawn(async move {
             let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
-            if let Err(e) = start_sse_server(addr).await {
+            if let Err(e) = start_sse_server(addr, sse_shutdown).await {
                 tracing::error!("MCP SSE server error: {}", e);
             }
         });
     }
 
-    loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    if let (Some(port), Some(manager)) = (metrics_port, node.connection_manager.clone()) {
+        println!("Metrics available at http://127.0.0.1:{}/metrics", port);
+        let metrics_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            if let Err(e) =
+                metrics::start_metrics_server(addr, manager, dedup, metrics_shutdown).await
+            {
+                tracing::error!("Metrics server error: {}", e);
+            }
+        });
     }
-}
 
-async fn connect_to_bootstrap_node(
-    node: &megaengine::node::node::Node,
-    bootstrap_addr_str: String,
-) {
-    if let Some(conn_mgr) = &node.connection_manager {
-        tracing::info!(
-            "Attempting to connect to bootstrap node: {}",
-            bootstrap_addr_str
+    shutdown::wait_for_signal().await?;
+    println!("Shutting down...");
+    tracing::info!("Shutdown requested, stopping services");
+    shutdown.trigger();
+
+    // 等待进行中的 bundle 发送完成，再断开连接
+    let pending = shutdown.pending_tasks();
+    if pending > 0 {
+        tracing::info!("Waiting for {} in-flight transfers", pending);
+    }
+    if !shutdown.drain(DRAIN_TIMEOUT).await {
+        tracing::warn!(
+            "{} transfers did not finish within {:?}",
+            shutdown.pending_tasks(),
+            DRAIN_TIMEOUT
         );
+    }
+    if let Some(manager) = &node.connection_manager {
+        manager.shutdown().await;
+    }
 
-        match NodeAddr::parse(&bootstrap_addr_str) {
-            Ok(bootstrap_info) => {
-                match conn_mgr
-                    .lock()
-                    .await
-                    .connect(
-                        node.node_id().clone(),
-                        bootstrap_info.peer_id.clone(),
-                        vec![bootstrap_info.address],
-                    )
-                    .await
-                {
-                    Ok(_) => {
-                        tracing::info!(
-                            "Successfully connected to bootstrap node {} at {}",
-                            bootstrap_info.peer_id,
-                            bootstrap_info.address
-                        );
-                        println!(
-                            "Connected to bootstrap node: {} at {}",
-                            bootstrap_info.peer_id, bootstrap_info.address
-                        );
-                    }
-                    Err(e) => {
-                        tracing::warn!("Failed to connect to bootstrap node: {}", e);
-                        eprintln!("Warning: Failed to connect to bootstrap node: {}", e);
-                    }
-                }
+    tracing::info!("Node stopped");
+    println!("Node stopped");
+    Ok(())
+}
+
+async fn connect_to_bootstrap_node(peers: &PeerManager, bootstrap_addr_str: String) {
+    tracing::info!(
+        "Attempting to connect to bootstrap node: {}",
+        bootstrap_addr_str
+    );
+
+    match NodeAddr::parse(&bootstrap_addr_str) {
+        Ok(bootstrap_info) => {
+            // 记录到 nodes 表，断线或重启后由 PeerManager 重新拨号
+            if let Err(e) = peer_manager::remember_bootstrap_node(
+                &bootstrap_info.peer_id,
+                bootstrap_info.address,
+            )
+            .await
+            {
+                tracing::warn!("Failed to persist bootstrap node: {}", e);
             }
-            Err(e) => {
-                tracing::error!("Failed to parse bootstrap node address: {}", e);
-                eprintln!("Error: {}", e);
+
+            match peers
+                .dial(bootstrap_info.peer_id.clone(), vec![bootstrap_info.address])
+                .await
+            {
+                Ok(_) => {
+                    tracing::info!(
+                        "Successfully connected to bootstrap node {} at {}",
+                        bootstrap_info.peer_id,
+                        bootstrap_info.address
+                    );
+                    println!(
+                        "Connected to bootstrap node: {} at {}",
+                        bootstrap_info.peer_id, bootstrap_info.address
+                    );
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to connect to bootstrap node: {}", e);
+                    eprintln!("Warning: Failed to connect to bootstrap node: {}", e);
+                }
             }
         }
+        Err(e) => {
+            tracing::error!("Failed to parse bootstrap node address: {}", e);
+            eprintln!("Error: {}", e);
+        }
     }
 }
 
@@ -199,27 +364,206 @@ pub async fn handle_node_id() -> Result<()> {
     Ok(())
 }
 
+pub async fn handle_node_routes() -> Result<()> {
+    let routes = storage::routing_model::list_routes().await?;
+    if routes.is_empty() {
+        println!("Routing table is empty (is the node running?)");
+        return Ok(());
+    }
+
+    println!(
+        "{:<60} {:>9} {:>8} {:>5} {:>5} {:>5} {:>6}  Addresses",
+        "NodeId", "Connected", "RTT(ms)", "OK", "Fail", "Bad", "Score"
+    );
+    for route in routes {
+        let addresses: Vec<String> = serde_json::from_str(&route.addresses).unwrap_or_default();
+        println!(
+            "{:<60} {:>9} {:>8} {:>5} {:>5} {:>5} {:>6.3}  {}",
+            route.node_id,
+            if route.connected { "yes" } else { "no" },
+            route
+                .latency_ms
+                .map(|l| l.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            route.successes,
+            route.failures,
+            route.misbehaviour,
+            route.score,
+            addresses.join(",")
+        );
+    }
+    Ok(())
+}
+
+pub async fn handle_node_peers() -> Result<()> {
+    let peers = storage::peer_stats_model::list_peer_stats().await?;
+    if peers.is_empty() {
+        println!("No peer statistics (is the node running?)");
+        return Ok(());
+    }
+
+    println!(
+        "{:<60} {:>4} {:>8} {:>7} {:>10} {:>10} {:>12} {:>12} {:>5} {:>5}  Address",
+        "NodeId",
+        "Dir",
+        "RTT(ms)",
+        "Age(s)",
+        "Gossip tx",
+        "Gossip rx",
+        "Data tx(B)",
+        "Data rx(B)",
+        "SFail",
+        "Fail"
+    );
+    for peer in peers {
+        let direction = match peer.direction {
+            Some(Direction::Inbound) => "in",
+            Some(Direction::Outbound) => "out",
+            None => "-",
+        };
+        let address = match (&peer.relay, peer.addr) {
+            (Some(relay), _) => format!("via {}", relay),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => "-".to_string(),
+        };
+        println!(
+            "{:<60} {:>4} {:>8} {:>7} {:>10} {:>10} {:>12} {:>12} {:>5} {:>5}  {}",
+            peer.node_id,
+            direction,
+            peer.rtt_ms
+                .map(|r| format!("{:.1}", r))
+                .unwrap_or_else(|| "-".to_string()),
+            peer.connected_secs
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            peer.gossip.messages_sent,
+            peer.gossip.messages_received,
+            peer.data.bytes_sent,
+            peer.data.bytes_received,
+            peer.send_failures,
+            peer.failures,
+            address
+        );
+    }
+    Ok(())
+}
+
+pub async fn handle_node_bans() -> Result<()> {
+    let now = megaengine::util::timestamp_now();
+    let bans: Vec<Ban> = storage::ban_model::list_bans()
+        .await?
+        .into_iter()
+        .filter(|ban| ban.is_active(now))
+        .collect();
+    if bans.is_empty() {
+        println!("No banned peers");
+        return Ok(());
+    }
+
+    println!("{:<60} {:>12}  Reason", "NodeId", "Expires in");
+    for ban in bans {
+        let expires = match ban.expires_at {
+            Some(expires_at) => format!("{}s", expires_at - now),
+            None => "never".to_string(),
+        };
+        println!("{:<60} {:>12}  {}", ban.node_id, expires, ban.reason);
+    }
+    Ok(())
+}
+
+/// 封禁节点，运行中的节点在几秒内断开它并拒绝其连接
+pub async fn handle_node_ban(node_id: String, duration: Option<u64>, reason: String) -> Result<()> {
+    let node_id = match NodeId::from_string(&node_id) {
+        Ok(node_id) => node_id,
+        Err(e) => {
+            eprintln!("❌ Invalid node ID {}: {}", node_id, e);
+            return Ok(());
+        }
+    };
+    let now = megaengine::util::timestamp_now();
+    let ban = Ban {
+        node_id,
+        reason,
+        banned_at: now,
+        expires_at: duration.map(|d| now + d as i64),
+    };
+    storage::ban_model::save_ban(&ban).await?;
+    match duration {
+        Some(d) => println!("✅ Banned {} for {}s", ban.node_id, d),
+        None => println!("✅ Banned {} permanently", ban.node_id),
+    }
+    Ok(())
+}
+
+/// 解除封禁，同时清除运行中的节点记录的违规
+pub async fn handle_node_unban(node_id: String) -> Result<()> {
+    let node_id = match NodeId::from_string(&node_id) {
+        Ok(node_id) => node_id,
+        Err(e) => {
+            eprintln!("❌ Invalid node ID {}: {}", node_id, e);
+            return Ok(());
+        }
+    };
+    if storage::ban_model::delete_ban(&node_id).await? {
+        println!("✅ Unbanned {}", node_id);
+    } else {
+        println!("{} is not banned", node_id);
+    }
+    Ok(())
+}
+
 pub async fn handle_node(root_path: String, action: crate::NodeAction) -> Result<()> {
     match action {
         crate::NodeAction::Start {
             alias,
             addr,
-            cert_path,
+            announce,
+            allow_local_addrs,
             bootstrap_node,
+            target_peers,
+            max_clock_skew,
+            relay,
+            max_connections,
+            max_connections_per_ip,
             mcp,
             mcp_sse_port,
+            metrics_port,
         } => {
+            let mut limits = ConnectionLimits::default();
+            if let Some(max) = max_connections {
+                limits.max_connections = max;
+            }
+            if let Some(max) = max_connections_per_ip {
+                limits.max_connections_per_ip = max;
+            }
+            let freshness =
+                FreshnessConfig::default().with_max_clock_skew(Duration::from_secs(max_clock_skew));
             handle_node_start(
                 &root_path,
                 alias,
                 addr,
-                cert_path,
+                announce,
+                allow_local_addrs,
                 bootstrap_node,
+                target_peers,
+                relay,
+                limits,
+                freshness,
                 mcp,
                 mcp_sse_port,
+                metrics_port,
             )
             .await
         }
         crate::NodeAction::Id => handle_node_id().await,
+        crate::NodeAction::Routes => handle_node_routes().await,
+        crate::NodeAction::Peers => handle_node_peers().await,
+        crate::NodeAction::Bans => handle_node_bans().await,
+        crate::NodeAction::Ban {
+            node_id,
+            duration,
+            reason,
+        } => handle_node_ban(node_id, duration, reason).await,
+        crate::NodeAction::Unban { node_id } => handle_node_unban(node_id).await,
     }
 }
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use megaengine::{
     git::pack::{pull_repo_from_bundle, restore_repo_from_bundle},
+    gossip::topic::{Subscriptions, Topic},
     node::node_id::NodeId,
     repo::{self, repo::Repo, repo_id::RepoId},
     storage,
@@ -232,16 +233,32 @@ fn format_bytes(bytes: u64) -> String {
     }
 }
 
-pub async fn handle_repo_list() -> Result<()> {
+pub async fn handle_repo_list(followed_only: bool) -> Result<()> {
+    let subscriptions = match storage::subscription_model::load_topics().await {
+        Ok(topics) => Subscriptions::new(topics),
+        Err(e) => {
+            tracing::error!("Failed to load subscriptions: {}", e);
+            eprintln!("❌ Failed to load subscriptions: {}", e);
+            return Ok(());
+        }
+    };
     match storage::repo_model::list_repos().await {
         Ok(repos) => {
+            let repos: Vec<(Repo, bool)> = repos
+                .into_iter()
+                .map(|repo| {
+                    let followed = !repo.is_external || subscriptions.follows(&repo);
+                    (repo, followed)
+                })
+                .filter(|(_, followed)| *followed || !followed_only)
+                .collect();
             if repos.is_empty() {
                 println!("No repositories found.");
             } else {
                 println!("Found {} repositories:", repos.len());
                 println!("{}", "─".repeat(60));
-                for repo in repos {
-                    print_repo_info(&repo).await;
+                for (repo, followed) in repos {
+                    print_repo_info(&repo, followed).await;
                 }
             }
         }
@@ -253,10 +270,17 @@ pub async fn handle_repo_list() -> Result<()> {
     Ok(())
 }
 
-async fn print_repo_info(repo: &Repo) {
+async fn print_repo_info(repo: &Repo, followed: bool) {
     println!("📦 Repo: {}", repo.p2p_description.name);
     println!("   ID:          {}", repo.repo_id);
     println!("   Creator:     {}", repo.p2p_description.creator);
+    if !repo.is_external {
+        println!("   Following:   (local repository)");
+    } else if followed {
+        println!("   Following:   ✅ Yes");
+    } else {
+        println!("   Following:   👀 No (seen only, run `repo follow` to sync it)");
+    }
     println!("   Language:    {}", repo.p2p_description.language);
     if repo.p2p_description.latest_commit_at > 0 {
         if let Some(dt) = chrono::DateTime::from_timestamp(repo.p2p_description.latest_commit_at, 0)
@@ -477,10 +501,44 @@ pub async fn handle_repo_clone(output: String, repo_id: String) -> Result<()> {
     Ok(())
 }
 
+/// 订阅或取消订阅主题，运行中的节点在几秒内读取新的订阅
+pub async fn handle_repo_follow(topic: crate::TopicArgs, follow: bool) -> Result<()> {
+    let topic = match (topic.repo_id, topic.creator) {
+        (Some(repo_id), _) => Topic::Repo(repo_id),
+        (None, Some(creator)) => match NodeId::from_string(&creator) {
+            Ok(node_id) => Topic::Creator(node_id),
+            Err(e) => {
+                eprintln!("❌ Invalid creator node ID {}: {}", creator, e);
+                return Ok(());
+            }
+        },
+        (None, None) => Topic::NewRepos,
+    };
+
+    if let Err(e) = storage::subscription_model::set_subscribed(&topic, follow).await {
+        tracing::error!("Failed to update subscription {}: {}", topic, e);
+        eprintln!("❌ Failed to update subscription: {}", e);
+        return Ok(());
+    }
+    if follow {
+        println!("✅ Following {}", topic);
+    } else {
+        println!("✅ Unfollowed {}", topic);
+    }
+    if let (true, Topic::Repo(repo_id)) = (follow, &topic) {
+        if let Ok(None) = storage::repo_model::load_repo_from_db(repo_id).await {
+            println!("   Repository not seen yet, it will be saved when it is announced.");
+        }
+    }
+    Ok(())
+}
+
 pub async fn handle_repo(action: crate::RepoAction) -> Result<()> {
     match action {
         crate::RepoAction::Add { path, description } => handle_repo_add(path, description).await,
-        crate::RepoAction::List => handle_repo_list().await,
+        crate::RepoAction::List { followed } => handle_repo_list(followed).await,
+        crate::RepoAction::Follow { topic } => handle_repo_follow(topic, true).await,
+        crate::RepoAction::Unfollow { topic } => handle_repo_follow(topic, false).await,
         crate::RepoAction::Pull { repo_id } => handle_repo_pull(repo_id).await,
         crate::RepoAction::Clone { output, repo_id } => handle_repo_clone(output, repo_id).await,
     }
@@ -0,0 +1,307 @@
+//! gossip 消息去重
+//!
+//! 按到达顺序记录最近收到的消息 ID。ID 以 32 字节的 SHA-256 保存，而不是 64 个字符的十六进制字符串。
+//! 记录超过保留时间或总数超过容量时从最早的开始淘汰，内存占用有上限。
+//!
+//! 开启持久化时，新记录的 ID 定期写入数据库，节点重启后恢复仍在保留时间内的记录，
+//! 不会重新处理和转发重启前刚收到的消息。
+use anyhow::Result;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::node::node_id::NodeId;
+use crate::storage::seen_model;
+
+pub type SharedDedup = Arc<Mutex<DedupCache>>;
+
+/// 消息 ID：[`SignedMessage::self_hash`](crate::gossip::message::SignedMessage::self_hash) 的原始字节
+pub type MessageId = [u8; 32];
+
+/// 解析十六进制的消息 ID，例如 IHAVE / GRAFT 中的 ID
+pub fn parse_id(id: &str) -> Option<MessageId> {
+    hex::decode(id).ok()?.try_into().ok()
+}
+
+/// 去重记录的容量、保留时间和持久化
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupConfig {
+    /// 最多记录的消息 ID 数
+    pub capacity: usize,
+    /// 记录的保留时间；实际使用的保留时间不短于消息时间戳的接受窗口，否则窗口内的消息可以重放
+    pub retention: Duration,
+    /// 是否把记录写入数据库，重启后恢复
+    pub persist: bool,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 100_000,
+            retention: Duration::from_secs(360),
+            persist: false,
+        }
+    }
+}
+
+impl DedupConfig {
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn with_persistence(mut self, persist: bool) -> Self {
+        self.persist = persist;
+        self
+    }
+}
+
+/// [`DedupCache`] 的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// 已记录过的消息
+    pub hits: u64,
+    /// 首次收到并通过校验的消息
+    pub misses: u64,
+    /// 因超过容量在保留时间内被淘汰的记录
+    pub evicted: u64,
+    /// 当前的记录数
+    pub len: usize,
+}
+
+impl DedupStats {
+    /// 收到的消息中重复消息的比例
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// 有容量上限的去重记录，时间均为 Unix 时间（秒）
+#[derive(Debug)]
+pub struct DedupCache {
+    config: DedupConfig,
+    ids: HashSet<MessageId>,
+    /// 按记录时间排列的 ID
+    order: VecDeque<(MessageId, i64)>,
+    /// `order` 末尾尚未写入数据库的记录数
+    unsaved: usize,
+    hits: u64,
+    misses: u64,
+    evicted: u64,
+}
+
+impl DedupCache {
+    pub fn new(config: DedupConfig) -> Self {
+        Self {
+            config,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            unsaved: 0,
+            hits: 0,
+            misses: 0,
+            evicted: 0,
+        }
+    }
+
+    pub fn shared(self) -> SharedDedup {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn config(&self) -> DedupConfig {
+        self.config
+    }
+
+    /// 保留时间至少为 `retention`
+    pub fn set_min_retention(&mut self, retention: Duration) {
+        self.config.retention = self.config.retention.max(retention);
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// 收到的消息是否已记录过，已记录时计入命中
+    ///
+    /// 只查询不记录：消息 ID 不包含签名，未通过校验就记录的话，先到达的伪造副本会使真实消息被当作重复消息丢弃
+    pub fn is_duplicate(&mut self, id: &MessageId) -> bool {
+        let duplicate = self.ids.contains(id);
+        if duplicate {
+            self.hits += 1;
+        }
+        duplicate
+    }
+
+    /// 记录通过校验的消息，计入未命中
+    pub fn accept(&mut self, id: MessageId, now: i64) {
+        self.misses += 1;
+        self.insert(id, now);
+    }
+
+    /// 记录本节点发出的消息，不计入命中率
+    pub fn insert(&mut self, id: MessageId, now: i64) {
+        if !self.ids.insert(id) {
+            return;
+        }
+        while self.order.len() >= self.config.capacity.max(1) {
+            self.evict_front();
+            self.evicted += 1;
+        }
+        self.order.push_back((id, now));
+        self.unsaved += 1;
+    }
+
+    fn evict_front(&mut self) {
+        if let Some((id, _)) = self.order.pop_front() {
+            self.ids.remove(&id);
+        }
+        self.unsaved = self.unsaved.min(self.order.len());
+    }
+
+    /// 淘汰超过保留时间的记录，返回淘汰的数量
+    pub fn expire(&mut self, now: i64) -> usize {
+        let cutoff = now - self.config.retention.as_secs() as i64;
+        let mut expired = 0;
+        while self.order.front().is_some_and(|&(_, at)| at <= cutoff) {
+            self.evict_front();
+            expired += 1;
+        }
+        expired
+    }
+
+    /// 取出上次调用后新记录的 ID，供写入数据库
+    pub fn take_unsaved(&mut self) -> Vec<(MessageId, i64)> {
+        let start = self.order.len() - self.unsaved;
+        self.unsaved = 0;
+        self.order.range(start..).copied().collect()
+    }
+
+    /// 恢复数据库中的记录，跳过超过保留时间的记录
+    pub fn restore(&mut self, mut entries: Vec<(MessageId, i64)>, now: i64) {
+        let cutoff = now - self.config.retention.as_secs() as i64;
+        entries.sort_by_key(|&(_, at)| at);
+        for (id, at) in entries {
+            if at > cutoff {
+                self.insert(id, at);
+            }
+        }
+        self.unsaved = 0;
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        DedupStats {
+            hits: self.hits,
+            misses: self.misses,
+            evicted: self.evicted,
+            len: self.order.len(),
+        }
+    }
+}
+
+/// 把新记录的消息 ID 写入数据库，并删除超过保留时间的记录
+pub async fn save_changes(node_id: &NodeId, dedup: &SharedDedup, now: i64) -> Result<()> {
+    let (entries, retention) = {
+        let mut dedup = dedup.lock().await;
+        (dedup.take_unsaved(), dedup.config().retention)
+    };
+    seen_model::save_seen(node_id, &entries).await?;
+    seen_model::delete_seen_before(node_id, now - retention.as_secs() as i64).await?;
+    Ok(())
+}
+
+/// 恢复数据库中仍在保留时间内的记录，返回恢复的数量
+pub async fn restore(node_id: &NodeId, dedup: &SharedDedup, now: i64) -> Result<usize> {
+    let retention = dedup.lock().await.config().retention;
+    let entries = seen_model::load_seen(node_id, now - retention.as_secs() as i64).await?;
+    let mut dedup = dedup.lock().await;
+    let before = dedup.len();
+    dedup.restore(entries, now);
+    Ok(dedup.len() - before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> MessageId {
+        [n; 32]
+    }
+
+    #[test]
+    fn test_capacity_and_retention() {
+        let config = DedupConfig::default()
+            .with_capacity(3)
+            .with_retention(Duration::from_secs(10));
+        let mut cache = DedupCache::new(config);
+
+        assert!(!cache.is_duplicate(&id(1)));
+        cache.accept(id(1), 0);
+        assert!(cache.is_duplicate(&id(1)));
+        for n in 2..=4 {
+            assert!(!cache.is_duplicate(&id(n)));
+            cache.accept(id(n), n as i64);
+        }
+        // 超过容量时淘汰最早的记录
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.contains(&id(1)));
+        assert_eq!(
+            cache.stats(),
+            DedupStats {
+                hits: 1,
+                misses: 4,
+                evicted: 1,
+                len: 3
+            }
+        );
+        assert_eq!(cache.stats().hit_rate(), 0.2);
+
+        // 超过保留时间的记录被淘汰，不计入 evicted
+        assert_eq!(cache.expire(13), 2);
+        assert!(cache.contains(&id(4)));
+        assert_eq!(cache.stats().evicted, 1);
+    }
+
+    #[test]
+    fn test_take_unsaved_and_restore() {
+        let config = DedupConfig::default().with_retention(Duration::from_secs(10));
+        let mut cache = DedupCache::new(config);
+        cache.insert(id(1), 1);
+        cache.insert(id(2), 2);
+        assert_eq!(cache.take_unsaved(), vec![(id(1), 1), (id(2), 2)]);
+        cache.insert(id(3), 3);
+        assert_eq!(cache.take_unsaved(), vec![(id(3), 3)]);
+        assert!(cache.take_unsaved().is_empty());
+
+        let mut restarted = DedupCache::new(config);
+        restarted.restore(vec![(id(3), 3), (id(1), 1), (id(2), 2)], 11);
+        assert!(!restarted.contains(&id(1)));
+        assert!(restarted.contains(&id(2)) && restarted.contains(&id(3)));
+        assert!(restarted.take_unsaved().is_empty());
+        assert!(restarted.is_duplicate(&id(3)));
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id(&hex::encode(id(7))), Some(id(7)));
+        assert_eq!(parse_id("abcd"), None);
+        assert_eq!(parse_id("not hex"), None);
+    }
+}
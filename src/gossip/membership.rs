@@ -0,0 +1,799 @@
+//! HyParView 部分成员视图
+//!
+//! 每个节点维护一个小的 active view 和一个较大的 passive view：
+//!
+//! - active view 中的节点互为邻居，gossip 只在这些邻居之间传播（见 [`super::plumtree`]）；
+//! - passive view 是备用节点，邻居断开后由 `PeerManager` 从中挑选节点拨号，补足 active view；
+//! - 新节点向联系节点发送 JOIN，联系节点把它加入 active view，并沿 FORWARDJOIN 随机游走
+//!   把它介绍给更远的节点；
+//! - 节点定期沿随机游走发送 SHUFFLE，与远处的节点交换部分视图，保持 passive view 的随机性。
+//!
+//! 视图变化定期写入 `nodes` 表，节点重启后从中恢复 passive view。
+use crate::gossip::message::{MembershipMessage, PeerEntry};
+use crate::node::node_id::NodeId;
+use crate::storage::node_model;
+use crate::transport::frame::Channel;
+use crate::transport::transport::Transport;
+use anyhow::{anyhow, Result};
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// FORWARDJOIN / SHUFFLE 需要连接新节点时，单次拨号的超时时间
+const DIAL_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub type SharedMembership = Arc<Mutex<HyParView>>;
+
+#[derive(Debug, Clone)]
+pub struct MembershipConfig {
+    pub active_size: usize,
+    pub passive_size: usize,
+    /// FORWARDJOIN 和 SHUFFLE 随机游走的长度（ARWL）
+    pub active_walk_length: u8,
+    /// FORWARDJOIN 游走到剩余长度为此值时，经过的节点把新节点加入 passive view（PRWL）
+    pub passive_walk_length: u8,
+    /// SHUFFLE 携带的 active view 节点数
+    pub shuffle_active: usize,
+    /// SHUFFLE 携带的 passive view 节点数
+    pub shuffle_passive: usize,
+    pub shuffle_interval: Duration,
+}
+
+impl Default for MembershipConfig {
+    fn default() -> Self {
+        Self {
+            active_size: 5,
+            passive_size: 30,
+            active_walk_length: 6,
+            passive_walk_length: 3,
+            shuffle_active: 3,
+            shuffle_passive: 4,
+            shuffle_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl MembershipConfig {
+    /// 指定 active view 的大小，passive view 取其 6 倍
+    pub fn with_active_size(mut self, active_size: usize) -> Self {
+        self.active_size = active_size.max(1);
+        self.passive_size = self.active_size * 6;
+        self
+    }
+
+    pub fn with_shuffle_interval(mut self, interval: Duration) -> Self {
+        self.shuffle_interval = interval;
+        self
+    }
+}
+
+/// 节点所在的视图，写入 `nodes.membership`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    Active,
+    Passive,
+}
+
+impl View {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            View::Active => "active",
+            View::Passive => "passive",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "active" => Some(View::Active),
+            "passive" => Some(View::Passive),
+            _ => None,
+        }
+    }
+}
+
+/// 处理消息后需要执行的动作
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// 向已连接的节点发送
+    Send(NodeId, MembershipMessage),
+    /// 与节点建立连接后发送
+    Dial(PeerEntry, MembershipMessage),
+}
+
+#[derive(Debug)]
+pub struct HyParView {
+    local: NodeId,
+    /// 本节点公告的地址，随 JOIN / NEIGHBOR / SHUFFLE 发送
+    addresses: Vec<SocketAddr>,
+    config: MembershipConfig,
+    active: HashMap<NodeId, Vec<SocketAddr>>,
+    passive: HashMap<NodeId, Vec<SocketAddr>>,
+    /// 上一次 SHUFFLE 发出的节点，收到回复时优先替换它们
+    last_shuffle: Vec<NodeId>,
+    /// 尚未写入数据库的视图变化，`None` 表示已移出两个视图
+    changes: HashMap<NodeId, (Vec<SocketAddr>, Option<View>)>,
+    rng: u64,
+}
+
+impl HyParView {
+    pub fn new(local: NodeId, config: MembershipConfig) -> Self {
+        Self::with_seed(local, config, OsRng.next_u64())
+    }
+
+    /// 指定随机数种子，得到可重复的随机选择
+    pub fn with_seed(local: NodeId, config: MembershipConfig, seed: u64) -> Self {
+        Self {
+            local,
+            addresses: Vec::new(),
+            config,
+            active: HashMap::new(),
+            passive: HashMap::new(),
+            last_shuffle: Vec::new(),
+            changes: HashMap::new(),
+            // xorshift 的状态不能为 0
+            rng: seed.max(1),
+        }
+    }
+
+    pub fn shared(self) -> SharedMembership {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn config(&self) -> &MembershipConfig {
+        &self.config
+    }
+
+    pub fn set_addresses(&mut self, addresses: Vec<SocketAddr>) {
+        self.addresses = addresses;
+    }
+
+    fn local_entry(&self) -> PeerEntry {
+        PeerEntry {
+            node_id: self.local.clone(),
+            addresses: self.addresses.clone(),
+        }
+    }
+
+    pub fn active(&self) -> Vec<NodeId> {
+        sorted_ids(&self.active)
+    }
+
+    pub fn passive(&self) -> Vec<PeerEntry> {
+        sorted_ids(&self.passive)
+            .into_iter()
+            .map(|node_id| PeerEntry {
+                addresses: self.passive[&node_id].clone(),
+                node_id,
+            })
+            .collect()
+    }
+
+    pub fn is_active(&self, node_id: &NodeId) -> bool {
+        self.active.contains_key(node_id)
+    }
+
+    /// active view 还差多少个邻居
+    pub fn needed(&self) -> usize {
+        self.config.active_size.saturating_sub(self.active.len())
+    }
+
+    /// 把上次运行时记录的节点放入 passive view，重新连接后再进入 active view
+    pub fn restore(&mut self, entries: Vec<PeerEntry>) {
+        for entry in entries {
+            self.add_passive(entry.node_id, entry.addresses);
+        }
+    }
+
+    /// 向新连接的节点发送的请求
+    ///
+    /// 两个视图都为空时发送 JOIN，并把对方作为 active view 的第一个邻居；
+    /// 否则发送 NEIGHBOR，active view 为空时使用高优先级
+    pub fn join_or_neighbor(&mut self, peer: &PeerEntry) -> MembershipMessage {
+        if self.active.is_empty() && self.passive.is_empty() {
+            self.add_active(
+                peer.node_id.clone(),
+                peer.addresses.clone(),
+                &mut Vec::new(),
+            );
+            return MembershipMessage::Join {
+                addresses: self.addresses.clone(),
+            };
+        }
+        // 对方拒绝时留在 passive view 中
+        self.add_passive(peer.node_id.clone(), peer.addresses.clone());
+        MembershipMessage::Neighbor {
+            high_priority: self.active.is_empty(),
+            addresses: self.addresses.clone(),
+        }
+    }
+
+    /// 处理来自直连节点 `from` 的消息，返回需要发送的消息
+    pub fn handle(&mut self, from: &NodeId, message: MembershipMessage) -> Vec<Action> {
+        let mut actions = Vec::new();
+        match message {
+            MembershipMessage::Join { addresses } => {
+                self.add_active(from.clone(), addresses.clone(), &mut actions);
+                let node = PeerEntry {
+                    node_id: from.clone(),
+                    addresses,
+                };
+                for peer in sorted_ids(&self.active) {
+                    if &peer != from {
+                        actions.push(Action::Send(
+                            peer,
+                            MembershipMessage::ForwardJoin {
+                                node: node.clone(),
+                                ttl: self.config.active_walk_length,
+                            },
+                        ));
+                    }
+                }
+            }
+            MembershipMessage::ForwardJoin { node, ttl } => {
+                self.forward_join(from, node, ttl, &mut actions)
+            }
+            MembershipMessage::Neighbor {
+                high_priority,
+                addresses,
+            } => {
+                let accepted = high_priority
+                    || self.active.contains_key(from)
+                    || self.active.len() < self.config.active_size;
+                if accepted {
+                    self.add_active(from.clone(), addresses, &mut actions);
+                } else {
+                    self.add_passive(from.clone(), addresses);
+                }
+                actions.push(Action::Send(
+                    from.clone(),
+                    MembershipMessage::NeighborReply { accepted },
+                ));
+            }
+            MembershipMessage::NeighborReply { accepted: true } => {
+                if self.needed() > 0 || self.active.contains_key(from) {
+                    let addresses = self.passive.get(from).cloned().unwrap_or_default();
+                    self.add_active(from.clone(), addresses, &mut actions);
+                } else {
+                    // 等待回复期间 active view 已满
+                    actions.push(Action::Send(from.clone(), MembershipMessage::Disconnect));
+                }
+            }
+            // 被拒绝的节点留在 passive view 中，之后尝试其他节点
+            MembershipMessage::NeighborReply { accepted: false } => {}
+            MembershipMessage::Disconnect => {
+                self.peer_down(from);
+            }
+            MembershipMessage::Shuffle { origin, nodes, ttl } => {
+                self.shuffle_received(from, origin, nodes, ttl, &mut actions)
+            }
+            MembershipMessage::ShuffleReply { nodes } => {
+                let sent = std::mem::take(&mut self.last_shuffle);
+                self.integrate(nodes, sent);
+            }
+        }
+        actions
+    }
+
+    fn forward_join(&mut self, from: &NodeId, node: PeerEntry, ttl: u8, actions: &mut Vec<Action>) {
+        if node.node_id == self.local || self.active.contains_key(&node.node_id) {
+            return;
+        }
+        if ttl > 0 && self.active.len() > 1 {
+            if ttl == self.config.passive_walk_length {
+                self.add_passive(node.node_id.clone(), node.addresses.clone());
+            }
+            if let Some(next) = self.random_active_except(&[from, &node.node_id]) {
+                actions.push(Action::Send(
+                    next,
+                    MembershipMessage::ForwardJoin { node, ttl: ttl - 1 },
+                ));
+                return;
+            }
+        }
+        // 游走结束：与新节点建立连接并成为邻居
+        self.add_active(node.node_id.clone(), node.addresses.clone(), actions);
+        actions.push(Action::Dial(
+            node,
+            MembershipMessage::Neighbor {
+                high_priority: true,
+                addresses: self.addresses.clone(),
+            },
+        ));
+    }
+
+    fn shuffle_received(
+        &mut self,
+        from: &NodeId,
+        origin: PeerEntry,
+        nodes: Vec<PeerEntry>,
+        ttl: u8,
+        actions: &mut Vec<Action>,
+    ) {
+        if origin.node_id == self.local {
+            return;
+        }
+        if ttl > 1 {
+            if let Some(next) = self.random_active_except(&[from, &origin.node_id]) {
+                actions.push(Action::Send(
+                    next,
+                    MembershipMessage::Shuffle {
+                        origin,
+                        nodes,
+                        ttl: ttl - 1,
+                    },
+                ));
+                return;
+            }
+        }
+
+        // 用同样数量的 passive view 节点回复
+        let candidates: Vec<PeerEntry> = self
+            .passive()
+            .into_iter()
+            .filter(|entry| entry.node_id != origin.node_id)
+            .collect();
+        let reply = self.sample(candidates, nodes.len());
+        let sent = reply.iter().map(|entry| entry.node_id.clone()).collect();
+        let message = MembershipMessage::ShuffleReply { nodes: reply };
+        if self.active.contains_key(&origin.node_id) {
+            actions.push(Action::Send(origin.node_id, message));
+        } else {
+            actions.push(Action::Dial(origin, message));
+        }
+        self.integrate(nodes, sent);
+    }
+
+    /// 把交换得到的节点加入 passive view，已满时优先替换本节点发出的节点
+    fn integrate(&mut self, nodes: Vec<PeerEntry>, sent: Vec<NodeId>) {
+        let mut replaceable: Vec<NodeId> = sent
+            .into_iter()
+            .filter(|id| self.passive.contains_key(id))
+            .collect();
+        for entry in nodes {
+            if entry.node_id == self.local
+                || self.active.contains_key(&entry.node_id)
+                || self.passive.contains_key(&entry.node_id)
+            {
+                continue;
+            }
+            if self.passive.len() >= self.config.passive_size {
+                if let Some(victim) = replaceable.pop() {
+                    self.remove_passive(&victim);
+                }
+            }
+            self.add_passive(entry.node_id, entry.addresses);
+        }
+    }
+
+    /// 发起一次 SHUFFLE：向随机邻居发送本节点和部分视图
+    pub fn shuffle(&mut self) -> Option<Action> {
+        let target = self.random_active_except(&[])?;
+        let active: Vec<PeerEntry> = sorted_ids(&self.active)
+            .into_iter()
+            .filter(|id| *id != target)
+            .map(|node_id| PeerEntry {
+                addresses: self.active[&node_id].clone(),
+                node_id,
+            })
+            .collect();
+        let passive = self.passive();
+
+        let mut nodes = vec![self.local_entry()];
+        nodes.extend(self.sample(active, self.config.shuffle_active));
+        nodes.extend(self.sample(passive, self.config.shuffle_passive));
+        self.last_shuffle = nodes.iter().map(|entry| entry.node_id.clone()).collect();
+        Some(Action::Send(
+            target,
+            MembershipMessage::Shuffle {
+                origin: self.local_entry(),
+                nodes,
+                ttl: self.config.active_walk_length,
+            },
+        ))
+    }
+
+    /// 邻居断开或发来 DISCONNECT，移入 passive view
+    pub fn peer_down(&mut self, peer: &NodeId) -> bool {
+        match self.active.remove(peer) {
+            Some(addresses) => {
+                self.add_passive(peer.clone(), addresses);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 无法连接的节点移出 passive view
+    pub fn remove_passive(&mut self, peer: &NodeId) {
+        if let Some(addresses) = self.passive.remove(peer) {
+            self.changes.insert(peer.clone(), (addresses, None));
+        }
+    }
+
+    /// 取出尚未写入数据库的视图变化
+    pub fn take_changes(&mut self) -> Vec<(PeerEntry, Option<View>)> {
+        self.changes
+            .drain()
+            .map(|(node_id, (addresses, view))| (PeerEntry { node_id, addresses }, view))
+            .collect()
+    }
+
+    fn add_active(
+        &mut self,
+        node_id: NodeId,
+        addresses: Vec<SocketAddr>,
+        actions: &mut Vec<Action>,
+    ) {
+        if node_id == self.local {
+            return;
+        }
+        if let Some(existing) = self.active.get_mut(&node_id) {
+            if !addresses.is_empty() {
+                *existing = addresses;
+            }
+            return;
+        }
+        if self.active.len() >= self.config.active_size {
+            if let Some(victim) = self.random_active_except(&[]) {
+                let victim_addresses = self.active.remove(&victim).unwrap_or_default();
+                actions.push(Action::Send(victim.clone(), MembershipMessage::Disconnect));
+                self.add_passive(victim, victim_addresses);
+            }
+        }
+
+        let known = self.passive.remove(&node_id).unwrap_or_default();
+        let addresses = if addresses.is_empty() {
+            known
+        } else {
+            addresses
+        };
+        self.changes
+            .insert(node_id.clone(), (addresses.clone(), Some(View::Active)));
+        self.active.insert(node_id, addresses);
+    }
+
+    fn add_passive(&mut self, node_id: NodeId, addresses: Vec<SocketAddr>) {
+        if node_id == self.local || self.active.contains_key(&node_id) {
+            return;
+        }
+        if let Some(existing) = self.passive.get_mut(&node_id) {
+            if !addresses.is_empty() {
+                *existing = addresses;
+            }
+            return;
+        }
+        if self.passive.len() >= self.config.passive_size {
+            let candidates = sorted_ids(&self.passive);
+            if let Some(victim) = self.pick(candidates) {
+                self.remove_passive(&victim);
+            }
+        }
+        self.changes
+            .insert(node_id.clone(), (addresses.clone(), Some(View::Passive)));
+        self.passive.insert(node_id, addresses);
+    }
+
+    /// xorshift64
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+
+    fn pick(&mut self, mut items: Vec<NodeId>) -> Option<NodeId> {
+        if items.is_empty() {
+            return None;
+        }
+        let index = (self.next_random() % items.len() as u64) as usize;
+        Some(items.swap_remove(index))
+    }
+
+    fn random_active_except(&mut self, exclude: &[&NodeId]) -> Option<NodeId> {
+        let candidates = sorted_ids(&self.active)
+            .into_iter()
+            .filter(|id| !exclude.contains(&id))
+            .collect();
+        self.pick(candidates)
+    }
+
+    /// 随机选出至多 `n` 个元素
+    fn sample<T>(&mut self, mut items: Vec<T>, n: usize) -> Vec<T> {
+        let n = n.min(items.len());
+        for i in 0..n {
+            let j = i + (self.next_random() % (items.len() - i) as u64) as usize;
+            items.swap(i, j);
+        }
+        items.truncate(n);
+        items
+    }
+}
+
+/// 按 NodeId 排序，使随机选择只取决于随机数种子
+fn sorted_ids(view: &HashMap<NodeId, Vec<SocketAddr>>) -> Vec<NodeId> {
+    let mut ids: Vec<NodeId> = view.keys().cloned().collect();
+    ids.sort_by(|a, b| a.0.cmp(&b.0));
+    ids
+}
+
+/// 发送成员协议消息
+pub async fn send(
+    transport: &dyn Transport,
+    to: NodeId,
+    message: &MembershipMessage,
+) -> Result<()> {
+    transport.send_message(to, Channel::Gossip, message).await
+}
+
+/// 请求与已连接的节点成为邻居
+pub async fn request_neighbor(
+    transport: &dyn Transport,
+    membership: &SharedMembership,
+    peer: PeerEntry,
+) -> Result<()> {
+    let message = membership.lock().await.join_or_neighbor(&peer);
+    send(transport, peer.node_id, &message).await
+}
+
+/// 执行 [`HyParView::handle`] 返回的动作，无法连接的节点移出视图
+pub async fn run_actions(
+    transport: &Arc<dyn Transport>,
+    membership: &SharedMembership,
+    actions: Vec<Action>,
+) {
+    for action in actions {
+        match action {
+            Action::Send(to, message) => {
+                if let Err(e) = send(transport.as_ref(), to.clone(), &message).await {
+                    debug!("Failed to send membership message to {}: {}", to, e);
+                }
+            }
+            Action::Dial(entry, message) => {
+                let transport = Arc::clone(transport);
+                let membership = Arc::clone(membership);
+                tokio::spawn(async move {
+                    let node_id = entry.node_id.clone();
+                    let result = tokio::time::timeout(
+                        DIAL_TIMEOUT,
+                        transport.connect(node_id.clone(), entry.addresses),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("dial to node[{}] timed out", node_id)));
+                    let result = match result {
+                        Ok(()) => send(transport.as_ref(), node_id.clone(), &message).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        debug!("Failed to reach {} for membership: {}", node_id, e);
+                        let mut membership = membership.lock().await;
+                        membership.peer_down(&node_id);
+                        membership.remove_passive(&node_id);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// 把视图变化写入 `nodes` 表
+pub async fn save_changes(membership: &SharedMembership) -> Result<()> {
+    let changes = membership.lock().await.take_changes();
+    for (entry, view) in changes {
+        node_model::save_membership(&entry.node_id, &entry.addresses, view).await?;
+    }
+    Ok(())
+}
+
+/// 从 `nodes` 表恢复上次运行时记录的视图
+pub async fn restore(membership: &SharedMembership) -> Result<usize> {
+    let entries: Vec<PeerEntry> = node_model::list_membership()
+        .await?
+        .into_iter()
+        .map(|(info, _)| PeerEntry {
+            node_id: info.node_id,
+            addresses: info.addresses,
+        })
+        .collect();
+    let count = entries.len();
+    membership.lock().await.restore(entries);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    fn entry(node_id: &NodeId) -> PeerEntry {
+        PeerEntry {
+            node_id: node_id.clone(),
+            addresses: vec!["127.0.0.1:9000".parse().unwrap()],
+        }
+    }
+
+    fn view(active_size: usize) -> HyParView {
+        HyParView::with_seed(
+            node_id(),
+            MembershipConfig::default().with_active_size(active_size),
+            7,
+        )
+    }
+
+    #[test]
+    fn test_join_and_forward_join() {
+        let mut contact = view(3);
+        let (a, b, joiner) = (node_id(), node_id(), node_id());
+
+        // 第一个连接的节点收到 JOIN，对方直接成为邻居
+        let mut first = view(3);
+        assert!(matches!(
+            first.join_or_neighbor(&entry(&contact.local)),
+            MembershipMessage::Join { .. }
+        ));
+        assert_eq!(first.active(), vec![contact.local.clone()]);
+        // 之后的连接发送 NEIGHBOR
+        assert!(matches!(
+            first.join_or_neighbor(&entry(&a)),
+            MembershipMessage::Neighbor {
+                high_priority: false,
+                ..
+            }
+        ));
+
+        // 联系节点接受 JOIN，并向其他邻居发起 FORWARDJOIN
+        for peer in [&a, &b] {
+            contact.handle(peer, MembershipMessage::Join { addresses: vec![] });
+        }
+        let actions = contact.handle(
+            &joiner,
+            MembershipMessage::Join {
+                addresses: entry(&joiner).addresses,
+            },
+        );
+        assert!(contact.is_active(&joiner));
+        let forwarded: Vec<&NodeId> = actions
+            .iter()
+            .map(|action| match action {
+                Action::Send(to, MembershipMessage::ForwardJoin { node, ttl: 6 })
+                    if node.node_id == joiner =>
+                {
+                    to
+                }
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(forwarded.len(), 2);
+        assert!(forwarded.contains(&&a) && forwarded.contains(&&b));
+
+        // 游走结束的节点连接新节点；剩余长度为 PRWL 的节点只记入 passive view
+        let mut end = view(3);
+        end.handle(&a, MembershipMessage::Join { addresses: vec![] });
+        let actions = end.handle(
+            &a,
+            MembershipMessage::ForwardJoin {
+                node: entry(&joiner),
+                ttl: 0,
+            },
+        );
+        assert!(end.is_active(&joiner));
+        assert!(matches!(
+            &actions[..],
+            [Action::Dial(e, MembershipMessage::Neighbor { high_priority: true, .. })] if e.node_id == joiner
+        ));
+
+        let mut middle = view(3);
+        middle.handle(&a, MembershipMessage::Join { addresses: vec![] });
+        middle.handle(&b, MembershipMessage::Join { addresses: vec![] });
+        let actions = middle.handle(
+            &a,
+            MembershipMessage::ForwardJoin {
+                node: entry(&joiner),
+                ttl: 3,
+            },
+        );
+        assert!(!middle.is_active(&joiner));
+        assert_eq!(middle.passive(), vec![entry(&joiner)]);
+        assert!(matches!(
+            &actions[..],
+            [Action::Send(to, MembershipMessage::ForwardJoin { ttl: 2, .. })] if *to == b
+        ));
+    }
+
+    #[test]
+    fn test_neighbor_priority_and_eviction() {
+        let mut node = view(2);
+        let (a, b, c, d) = (node_id(), node_id(), node_id(), node_id());
+        let neighbor = |high_priority| MembershipMessage::Neighbor {
+            high_priority,
+            addresses: vec![],
+        };
+
+        for peer in [&a, &b] {
+            let actions = node.handle(peer, neighbor(false));
+            assert_eq!(
+                actions,
+                vec![Action::Send(
+                    peer.clone(),
+                    MembershipMessage::NeighborReply { accepted: true }
+                )]
+            );
+        }
+
+        // active view 已满：拒绝低优先级请求，对方进入 passive view
+        let actions = node.handle(&c, neighbor(false));
+        assert_eq!(
+            actions,
+            vec![Action::Send(
+                c.clone(),
+                MembershipMessage::NeighborReply { accepted: false }
+            )]
+        );
+        assert_eq!(node.passive()[0].node_id, c);
+
+        // 高优先级请求必须接受，随机移出一个邻居并通知它
+        let actions = node.handle(&d, neighbor(true));
+        assert!(node.is_active(&d));
+        assert_eq!(node.active().len(), 2);
+        let evicted = match &actions[0] {
+            Action::Send(to, MembershipMessage::Disconnect) => to.clone(),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(evicted == a || evicted == b);
+        assert!(node.passive().iter().any(|e| e.node_id == evicted));
+
+        // 邻居断开后移入 passive view，需要补充一个邻居
+        assert!(node.peer_down(&d));
+        assert_eq!(node.needed(), 1);
+        node.remove_passive(&d);
+        assert!(!node.passive().iter().any(|e| e.node_id == d));
+
+        let changes = node.take_changes();
+        assert!(changes.contains(&(
+            PeerEntry {
+                node_id: d.clone(),
+                addresses: vec![]
+            },
+            None
+        )));
+        assert!(node.take_changes().is_empty());
+    }
+
+    #[test]
+    fn test_shuffle_exchanges_passive_views() {
+        let mut origin = view(3);
+        let mut target = view(3);
+        let (p1, p2, q1) = (node_id(), node_id(), node_id());
+        origin.set_addresses(entry(&origin.local).addresses);
+        origin.handle(&target.local, MembershipMessage::Join { addresses: vec![] });
+        origin.restore(vec![entry(&p1), entry(&p2)]);
+        target.restore(vec![entry(&q1)]);
+
+        // 唯一的邻居就是游走终点
+        let (to, message) = match origin.shuffle() {
+            Some(Action::Send(to, message)) => (to, message),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(to, target.local);
+        let actions = target.handle(&origin.local, message);
+        assert!(target.passive().iter().any(|e| e.node_id == origin.local));
+        assert!(target.passive().iter().any(|e| e.node_id == p1));
+
+        // 未连接的 origin 需要先建立连接再回复
+        let reply = match &actions[..] {
+            [Action::Dial(to, reply)] if to.node_id == origin.local => reply.clone(),
+            other => panic!("unexpected {:?}", other),
+        };
+        origin.handle(&target.local, reply);
+        assert!(origin.passive().iter().any(|e| e.node_id == q1));
+    }
+}
@@ -1,17 +1,24 @@
 use anyhow::Result;
+use ed25519_dalek::Signature;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::net::SocketAddr;
 
 use crate::{
+    gossip::topic::Topic,
+    identity::keypair::KeyPair,
     node::{
         node::{Node, NodeType},
         node_id::NodeId,
     },
-    repo::repo::Repo,
+    repo::{repo::Repo, repo_sync::RefChange},
+    transport::{codec, protocol::Capabilities},
     util::timestamp_now,
 };
 
+/// 规范签名的域分隔前缀，避免签名被挪用到其他协议消息上
+const SIGNING_CONTEXT: &str = "megaengine/signed-message/1";
+
 /// Gossip 消息类型
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum GossipMessage {
@@ -19,6 +26,8 @@ pub enum GossipMessage {
     NodeAnnouncement(NodeAnnouncement),
     /// 仓库公告 (库存公告)
     RepoAnnouncement(RepoAnnouncement),
+    /// 本地仓库单个 ref 的变化
+    RefUpdate(RefUpdate),
     /// P2P 聊天消息
     Chat(EncryptedChatMessage),
     /// 聊天消息送达确认
@@ -35,6 +44,7 @@ pub struct EncryptedChatMessage {
     /// 消息 ID (用于去重)
     pub msg_id: String,
     /// 密文数据 (包含 ephemeral public key)
+    #[serde(with = "serde_bytes")]
     pub ciphertext: Vec<u8>,
 }
 
@@ -64,6 +74,66 @@ pub struct Envelope {
     pub ttl: u8,
 }
 
+/// Plumtree 控制消息，与 [`Envelope`] 一样经 Gossip 通道发送，只发给声明了 `plumtree` 能力的节点
+///
+/// 消息 ID 为 [`SignedMessage::self_hash`] 的十六进制编码
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum GossipControl {
+    /// 发送方已收到这些消息，接收方缺少时可以用 GRAFT 取回
+    IHave(Vec<String>),
+    /// 请求发送方完整发送这些消息，并恢复对接收方的急切推送
+    Graft(Vec<String>),
+    /// 请求发送方之后只通过 IHAVE 告知新消息
+    Prune,
+}
+
+/// 成员视图中的一个节点及其可拨号地址
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PeerEntry {
+    pub node_id: NodeId,
+    pub addresses: Vec<SocketAddr>,
+}
+
+/// HyParView 成员协议消息，经 Gossip 通道发送，只发给声明了 `membership` 能力的节点
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MembershipMessage {
+    /// 新节点请求加入，接收方将其加入 active view 并发起 FORWARDJOIN 随机游走
+    Join {
+        addresses: Vec<SocketAddr>,
+    },
+    /// 沿随机游走传播新节点，`ttl` 归零的节点将其加入 active view
+    ForwardJoin {
+        node: PeerEntry,
+        ttl: u8,
+    },
+    /// 请求成为邻居；高优先级请求（发送方 active view 为空）必须接受
+    Neighbor {
+        high_priority: bool,
+        addresses: Vec<SocketAddr>,
+    },
+    NeighborReply {
+        accepted: bool,
+    },
+    /// 发送方已把接收方移出 active view
+    Disconnect,
+    /// 沿随机游走交换部分视图，`ttl` 归零的节点向 `origin` 回复 SHUFFLEREPLY
+    Shuffle {
+        origin: PeerEntry,
+        nodes: Vec<PeerEntry>,
+        ttl: u8,
+    },
+    ShuffleReply {
+        nodes: Vec<PeerEntry>,
+    },
+}
+
+/// 主题订阅消息，经 Gossip 通道发送，只发给声明了 `topics` 能力的节点
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TopicMessage {
+    /// 发送方订阅的全部主题，替换之前的声明
+    Subscriptions(Vec<Topic>),
+}
+
 impl From<Node> for NodeAnnouncement {
     fn from(node: Node) -> Self {
         Self {
@@ -81,6 +151,36 @@ impl From<Node> for NodeAnnouncement {
 pub struct RepoAnnouncement {
     pub node_id: NodeId,
     pub repos: Vec<Repo>,
+    /// 完整库存分多页发送时的页码，只有一页时省略，与旧版本节点的消息格式相同
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<InventoryPage>,
+}
+
+/// 一条库存公告最多包含的仓库数
+///
+/// 完整库存按页发送，每页远小于该值；旧版本节点不分页，只有仓库极多时才会超过
+pub const MAX_INVENTORY_REPOS: usize = 1000;
+
+/// 完整库存公告的分页信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InventoryPage {
+    /// 从 0 开始的页码
+    pub index: u32,
+    pub count: u32,
+}
+
+/// 仓库所有者在检测到 ref 变化后立即广播的增量更新
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefUpdate {
+    pub node_id: NodeId,
+    pub repo_id: String,
+    pub ref_name: String,
+    /// 更新前指向的提交，新建的 ref 为 None
+    pub old_oid: Option<String>,
+    /// 更新后指向的提交，删除的 ref 为 None
+    pub new_oid: Option<String>,
+    /// 所有者为该仓库发出的更新序号，单调递增，接收方忽略不大于已应用序号的更新
+    pub sequence: u64,
 }
 
 /// 带签名的消息包装
@@ -90,26 +190,43 @@ pub struct SignedMessage {
     pub node_id: NodeId,
     pub message: GossipMessage,
     pub timestamp: i64,
+    /// 发送方单调递增的序号，0 表示旧版本节点发出的无序号消息
+    #[serde(default)]
+    pub sequence: u64,
+    /// 对 [`Self::legacy_hash`] 的签名，供旧版本节点校验，只再保留一个版本
     pub signature: String,
+    /// 对 [`Self::signing_bytes`] 的签名，旧版本节点发出或转发的消息没有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canonical_signature: Option<String>,
 }
 
 impl SignedMessage {
-    pub fn new_node_sign_message(node: Node) -> Result<Self> {
+    pub fn new_node_sign_message(node: Node, sequence: u64) -> Result<Self> {
         let message = GossipMessage::NodeAnnouncement(node.clone().into());
 
         let mut sign_message = SignedMessage {
             node_id: node.node_id().clone(),
             message,
             timestamp: timestamp_now(),
+            sequence,
             signature: "".to_string(),
+            canonical_signature: None,
         };
-        let self_hash = sign_message.self_hash();
-        let sign = node.sign_message(self_hash.as_slice())?;
-        sign_message.signature = hex::encode(sign);
+        sign_message.sign(&node)?;
         Ok(sign_message)
     }
 
-    pub fn new_repo_sign_message(repos: Vec<Repo>, node: Node) -> Result<Self> {
+    pub fn new_repo_sign_message(repos: Vec<Repo>, node: Node, sequence: u64) -> Result<Self> {
+        Self::new_inventory_sign_message(repos, None, node, sequence)
+    }
+
+    /// 完整库存的一页，`page` 为 None 时与 [`Self::new_repo_sign_message`] 相同
+    pub fn new_inventory_sign_message(
+        repos: Vec<Repo>,
+        page: Option<InventoryPage>,
+        node: Node,
+        sequence: u64,
+    ) -> Result<Self> {
         // 转换 repos，清空 path
         let repos_with_empty_path = repos
             .into_iter()
@@ -123,20 +240,89 @@ impl SignedMessage {
         let message = GossipMessage::RepoAnnouncement(RepoAnnouncement {
             node_id: node.node_id().clone(),
             repos: repos_with_empty_path,
+            page,
+        });
+
+        let mut sign_message = SignedMessage {
+            node_id: node.node_id().clone(),
+            message,
+            timestamp: timestamp_now(),
+            sequence,
+            signature: "".to_string(),
+            canonical_signature: None,
+        };
+        sign_message.sign(&node)?;
+        Ok(sign_message)
+    }
+
+    /// 本地仓库 ref 变化的增量公告，`sequence` 同时作为消息序号和该更新的序号
+    pub fn new_ref_update_sign_message(
+        change: RefChange,
+        node: Node,
+        sequence: u64,
+    ) -> Result<Self> {
+        let message = GossipMessage::RefUpdate(RefUpdate {
+            node_id: node.node_id().clone(),
+            repo_id: change.repo_id,
+            ref_name: change.ref_name,
+            old_oid: change.old_oid,
+            new_oid: change.new_oid,
+            sequence,
         });
 
         let mut sign_message = SignedMessage {
             node_id: node.node_id().clone(),
             message,
             timestamp: timestamp_now(),
+            sequence,
             signature: "".to_string(),
+            canonical_signature: None,
         };
-        let self_hash = sign_message.self_hash();
-        let sign = node.sign_message(self_hash.as_slice())?;
-        sign_message.signature = hex::encode(sign);
+        sign_message.sign(&node)?;
         Ok(sign_message)
     }
 
+    /// 用节点私钥签名，同时生成规范签名和供旧版本节点校验的签名
+    pub fn sign(&mut self, node: &Node) -> Result<()> {
+        let canonical = node.sign_message(&self.signing_bytes()?)?;
+        let legacy = node.sign_message(&self.legacy_hash())?;
+        self.canonical_signature = Some(hex::encode(canonical));
+        self.signature = hex::encode(legacy);
+        Ok(())
+    }
+
+    /// 校验签名：有规范签名时只校验规范签名，否则按旧版本的方式校验
+    pub fn verify(&self, key: &KeyPair) -> bool {
+        let (signature, message) = match &self.canonical_signature {
+            Some(signature) => match self.signing_bytes() {
+                Ok(bytes) => (signature, bytes),
+                Err(_) => return false,
+            },
+            None => (&self.signature, self.legacy_hash()),
+        };
+        let bytes = hex::decode(signature).unwrap_or_default();
+        match <[u8; 64]>::try_from(bytes.as_slice()) {
+            Ok(arr) => key.verify(&message, &Signature::from_bytes(&arr)),
+            Err(_) => false,
+        }
+    }
+
+    /// 规范签名覆盖的字节：发送方、消息、时间戳和序号的确定性编码
+    pub fn signing_bytes(&self) -> Result<Vec<u8>> {
+        codec::canonical(&(
+            SIGNING_CONTEXT,
+            &self.node_id,
+            &self.message,
+            self.timestamp,
+            self.sequence,
+        ))
+    }
+
+    /// 消息 ID：[`Self::signing_bytes`] 的 SHA-256，不依赖消息到达时的编码
+    pub fn self_hash(&self) -> [u8; 32] {
+        Sha256::digest(self.signing_bytes().unwrap_or_default()).into()
+    }
+
     fn canonicalize_value(value: serde_json::Value) -> serde_json::Value {
         match value {
             serde_json::Value::Object(map) => {
@@ -157,20 +343,34 @@ impl SignedMessage {
         }
     }
 
-    pub fn self_hash(&self) -> Vec<u8> {
+    /// 旧版本节点签名和校验的哈希：重新规范化 JSON 后计算，只再保留一个版本
+    pub fn legacy_hash(&self) -> Vec<u8> {
         let mut hasher = Sha256::new();
         // Canonicalize JSON by recursively sorting object keys before serialization.
-        let message_value =
-            serde_json::to_value(&self.message).unwrap_or(serde_json::Value::Null);
+        let message_value = serde_json::to_value(&self.message).unwrap_or(serde_json::Value::Null);
         let canonical_value = Self::canonicalize_value(message_value);
         let message_bytes = serde_json::to_vec(&canonical_value).unwrap_or_default();
 
         hasher.update(self.node_id.0.as_bytes());
         hasher.update(&message_bytes);
         hasher.update(self.timestamp.to_le_bytes());
+        // 无序号消息的哈希与旧版本保持一致
+        if self.sequence > 0 {
+            hasher.update(self.sequence.to_le_bytes());
+        }
         hasher.finalize().to_vec()
     }
 
+    /// 接收方必须声明的能力；带序号的消息还要求 `sequence` 能力
+    pub fn required_capabilities(&self) -> Capabilities {
+        let required = self.message.required_capabilities();
+        if self.sequence > 0 {
+            required | Capabilities::SEQUENCE
+        } else {
+            required
+        }
+    }
+
     /// 获取消息的时间戳
     pub fn timestamp(&self) -> i64 {
         self.timestamp
@@ -188,6 +388,7 @@ impl GossipMessage {
         match self {
             GossipMessage::NodeAnnouncement(_) => "node_announcement",
             GossipMessage::RepoAnnouncement(_) => "inventory_announcement",
+            GossipMessage::RefUpdate(_) => "ref_update",
             GossipMessage::Chat(_) => "chat",
             GossipMessage::ChatAck(_ack) => "chat_ack",
         }
@@ -198,10 +399,65 @@ impl GossipMessage {
         match self {
             GossipMessage::NodeAnnouncement(na) => &na.node_id,
             GossipMessage::RepoAnnouncement(ra) => &ra.node_id,
+            GossipMessage::RefUpdate(update) => &update.node_id,
             GossipMessage::Chat(c) => &c.sender_id,
             GossipMessage::ChatAck(ack) => &ack.sender_id,
         }
     }
+
+    /// 同一节点的这类消息必须严格按序号接受，返回用于记录最新序号的键；无需排序的消息返回 None
+    ///
+    /// 公告会覆盖接收方保存的状态，重放旧公告会回滚地址和 refs。完整库存的各页相互独立，分别排序
+    pub fn ordering_key(&self) -> Option<String> {
+        match self {
+            GossipMessage::NodeAnnouncement(_) => Some(self.message_type().to_string()),
+            GossipMessage::RepoAnnouncement(ra) => Some(match ra.page {
+                Some(page) => format!("{}/{}", self.message_type(), page.index),
+                None => self.message_type().to_string(),
+            }),
+            // ref 更新按 ref 排序，由 ref 表中记录的序号保证
+            GossipMessage::RefUpdate(_) | GossipMessage::Chat(_) | GossipMessage::ChatAck(_) => {
+                None
+            }
+        }
+    }
+
+    /// 消息所属的主题，只转发给订阅了其中任一主题的邻居；返回空列表的消息发给所有邻居
+    pub fn topics(&self) -> Vec<Topic> {
+        match self {
+            GossipMessage::RepoAnnouncement(ra) => {
+                let mut topics = vec![Topic::NewRepos, Topic::Creator(ra.node_id.clone())];
+                for topic in ra.repos.iter().flat_map(Topic::of_repo) {
+                    if !topics.contains(&topic) {
+                        topics.push(topic);
+                    }
+                }
+                topics
+            }
+            GossipMessage::RefUpdate(update) => vec![
+                Topic::Repo(update.repo_id.clone()),
+                Topic::Creator(update.node_id.clone()),
+            ],
+            GossipMessage::NodeAnnouncement(_)
+            | GossipMessage::Chat(_)
+            | GossipMessage::ChatAck(_) => Vec::new(),
+        }
+    }
+
+    /// 接收方必须声明的能力，只向具备这些能力的节点发送或转发该消息
+    pub fn required_capabilities(&self) -> Capabilities {
+        match self {
+            GossipMessage::NodeAnnouncement(_) => Capabilities::GOSSIP,
+            GossipMessage::RepoAnnouncement(ra) if ra.page.is_some() => {
+                Capabilities::GOSSIP | Capabilities::REF_UPDATE
+            }
+            GossipMessage::RepoAnnouncement(_) => Capabilities::GOSSIP,
+            GossipMessage::RefUpdate(_) => Capabilities::GOSSIP | Capabilities::REF_UPDATE,
+            GossipMessage::Chat(_) | GossipMessage::ChatAck(_) => {
+                Capabilities::GOSSIP | Capabilities::CHAT
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -224,10 +480,12 @@ mod tests {
     #[test]
     fn test_new_node_sign_message() {
         let node = make_node();
-        let signed = SignedMessage::new_node_sign_message(node.clone()).expect("sign node message");
+        let signed =
+            SignedMessage::new_node_sign_message(node.clone(), 0).expect("sign node message");
 
         assert_eq!(signed.message_type(), "node_announcement");
         assert!(signed.timestamp() > 0);
+        assert_eq!(signed.required_capabilities(), Capabilities::GOSSIP);
 
         // signature should be a hex string that decodes to 64 bytes (ed25519)
         let sig = hex::decode(&signed.signature).expect("decode hex");
@@ -236,6 +494,57 @@ mod tests {
         // self_hash is 32 bytes
         let h = signed.self_hash();
         assert_eq!(h.len(), 32);
+        assert!(signed.verify(&node.keypair));
+    }
+
+    #[test]
+    fn test_legacy_signature_is_still_accepted() {
+        let node = make_node();
+        let signed = SignedMessage::new_node_sign_message(node.clone(), 0).unwrap();
+
+        // 旧版本节点发出或转发的消息只有 signature 字段
+        let mut legacy = signed.clone();
+        legacy.canonical_signature = None;
+        let value = serde_json::to_value(&legacy).unwrap();
+        assert!(value.get("canonical_signature").is_none());
+        assert!(legacy.verify(&node.keypair));
+
+        // 规范签名与消息不符时不会退回校验旧签名
+        let mut forged = signed.clone();
+        forged.canonical_signature = Some(hex::encode([0u8; 64]));
+        assert!(!forged.verify(&node.keypair));
+
+        // 两种编码解码后得到相同的消息 ID
+        for encoding in [codec::Encoding::Json, codec::Encoding::Binary] {
+            let bytes = codec::encode(&signed, encoding).unwrap();
+            let decoded: SignedMessage = codec::decode(&bytes).unwrap();
+            assert_eq!(decoded.self_hash(), signed.self_hash());
+            assert!(decoded.verify(&node.keypair));
+        }
+    }
+
+    #[test]
+    fn test_sequence_is_signed() {
+        let node = make_node();
+        let legacy = SignedMessage::new_node_sign_message(node.clone(), 0).unwrap();
+        let mut sequenced = legacy.clone();
+        sequenced.sequence = 5;
+
+        // 序号参与哈希，改动序号会使签名失效
+        assert_ne!(legacy.self_hash(), sequenced.self_hash());
+        assert_eq!(
+            sequenced.required_capabilities(),
+            Capabilities::GOSSIP | Capabilities::SEQUENCE
+        );
+
+        // 旧版本节点发出的消息没有 sequence 字段
+        let mut value = serde_json::to_value(&legacy).unwrap();
+        value.as_object_mut().unwrap().remove("sequence");
+        let decoded: SignedMessage = serde_json::from_value(value).unwrap();
+        assert_eq!(decoded.sequence, 0);
+        assert_eq!(decoded.self_hash(), legacy.self_hash());
+        assert_eq!(decoded.legacy_hash(), legacy.legacy_hash());
+        assert_ne!(legacy.legacy_hash(), sequenced.legacy_hash());
     }
 
     #[test]
@@ -270,10 +579,14 @@ mod tests {
             std::path::PathBuf::from("/tmp/test-repo"),
         );
 
-        let signed = SignedMessage::new_repo_sign_message(vec![repo.clone()], node.clone())
+        let signed = SignedMessage::new_repo_sign_message(vec![repo.clone()], node.clone(), 0)
             .expect("sign repo message");
 
         assert_eq!(signed.message_type(), "inventory_announcement");
+        let topics = signed.message.topics();
+        assert!(topics.contains(&Topic::NewRepos));
+        assert!(topics.contains(&Topic::Creator(node.node_id().clone())));
+        assert!(topics.contains(&Topic::Repo(repo_id.to_string())));
         let sig = hex::decode(&signed.signature).expect("decode hex");
         assert_eq!(sig.len(), 64);
 
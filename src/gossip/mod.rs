@@ -1,5 +1,10 @@
+pub mod dedup;
+pub mod membership;
 pub mod message;
+pub mod plumtree;
+pub mod replay;
 mod service;
+pub mod topic;
 
 pub use message::SignedMessage;
-pub use service::GossipService;
+pub use service::{GossipService, GossipStats};
@@ -0,0 +1,359 @@
+//! Plumtree 广播树（Epidemic Broadcast Trees）
+//!
+//! 每个节点把邻居分为急切推送（eager）和惰性推送（lazy）两类：新消息完整地发给 eager 邻居，
+//! 只通过 IHAVE 把消息 ID 告知 lazy 邻居。收到重复消息的节点把发送方降为 lazy 并回复 PRUNE，
+//! 冗余链路逐渐被剪掉，eager 链路收敛为一棵生成树。树断开时，节点在收到 IHAVE 后
+//! [`GRAFT_TIMEOUT`] 内仍未收到消息，就向宣告者发送 GRAFT 取回消息并把这条链路恢复为 eager。
+//!
+//! 等待中的消息数有上限，每个宣告者和全部宣告者超出上限时都淘汰最早的条目，
+//! 邻居无法用不存在的消息 ID 撑大内存或引发大量 GRAFT。
+//!
+//! 新连接的邻居默认为 eager；未声明 `plumtree` 能力的旧版本节点不理解控制消息，始终急切推送。
+use crate::gossip::message::Envelope;
+use crate::node::node_id::NodeId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// 收到 IHAVE 后等待 eager 推送的时间，超时后向宣告者发送 GRAFT
+pub const GRAFT_TIMEOUT: Duration = Duration::from_millis(500);
+/// GRAFT 仍未取回消息时，向下一个宣告者重试的间隔
+pub const GRAFT_RETRY: Duration = Duration::from_millis(250);
+/// 已收到消息的缓存时间，用于响应 GRAFT
+pub const CACHE_TTL: Duration = Duration::from_secs(60);
+/// 批量发送 IHAVE、检查 GRAFT 超时的间隔
+pub const LAZY_INTERVAL: Duration = Duration::from_millis(100);
+/// 只收到 IHAVE、等待推送的消息数上限
+pub const MAX_MISSING: usize = 4096;
+/// 一个宣告者最多占用的等待条目数
+pub const MAX_MISSING_PER_PEER: usize = 256;
+
+/// 一条只收到 IHAVE 的消息
+#[derive(Debug)]
+struct Missing {
+    /// 尚未尝试 GRAFT 的宣告者，按 IHAVE 到达顺序
+    announcers: VecDeque<NodeId>,
+    deadline: Instant,
+}
+
+/// 一条新消息的推送对象
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Push {
+    /// 完整发送消息
+    pub eager: Vec<NodeId>,
+    /// 只发送 IHAVE
+    pub lazy: Vec<NodeId>,
+}
+
+#[derive(Debug, Default)]
+pub struct Plumtree {
+    /// 被 PRUNE 或发来重复消息的邻居，其余邻居均为 eager
+    lazy: HashSet<NodeId>,
+    cache: HashMap<String, (Instant, Envelope)>,
+    missing: HashMap<String, Missing>,
+    /// `missing` 中的 ID，按首次收到 IHAVE 的顺序，可能包含已移除的 ID
+    missing_order: VecDeque<String>,
+    /// 各宣告者宣告过的等待中的 ID，按到达顺序，可能包含已移除的 ID
+    announced: HashMap<NodeId, VecDeque<String>>,
+    /// 等待下一次批量发送的 IHAVE
+    pending_ihave: HashMap<NodeId, Vec<String>>,
+}
+
+impl Plumtree {
+    /// 把候选节点分为 eager 和 lazy，`plumtree_peers` 是其中声明了 `plumtree` 能力的节点
+    ///
+    /// `flood` 为 true 时全部急切推送，用于修复可能断开的广播树
+    pub fn split(&self, peers: Vec<NodeId>, plumtree_peers: &HashSet<NodeId>, flood: bool) -> Push {
+        let mut push = Push::default();
+        for peer in peers {
+            if !flood && plumtree_peers.contains(&peer) && self.lazy.contains(&peer) {
+                push.lazy.push(peer);
+            } else {
+                push.eager.push(peer);
+            }
+        }
+        push
+    }
+
+    /// 记录一条新消息：缓存以响应 GRAFT、取消等待中的 GRAFT，发送方恢复为 eager
+    pub fn delivered(&mut self, id: &str, envelope: Envelope, from: Option<&NodeId>) {
+        let now = Instant::now();
+        self.cache.insert(id.to_string(), (now, envelope));
+        self.missing.remove(id);
+        if let Some(from) = from {
+            self.lazy.remove(from);
+        }
+    }
+
+    /// 为 lazy 邻居排队 IHAVE，由 [`Plumtree::take_ihaves`] 批量取出
+    pub fn queue_ihave(&mut self, peers: &[NodeId], id: &str) {
+        for peer in peers {
+            self.pending_ihave
+                .entry(peer.clone())
+                .or_default()
+                .push(id.to_string());
+        }
+    }
+
+    /// 收到重复消息，发送方降为 lazy，调用方随后回复 PRUNE
+    pub fn duplicate(&mut self, from: &NodeId) {
+        self.lazy.insert(from.clone());
+    }
+
+    /// 收到尚未见过的消息的 IHAVE，开始等待 eager 推送
+    ///
+    /// 宣告者或全部等待条目超过上限时，先淘汰最早的条目
+    pub fn ihave(&mut self, from: &NodeId, id: String) {
+        if self
+            .missing
+            .get(&id)
+            .is_some_and(|missing| missing.announcers.contains(from))
+        {
+            return;
+        }
+        self.limit_announcer(from);
+        if !self.missing.contains_key(&id) {
+            while self.missing.len() >= MAX_MISSING {
+                let Some(oldest) = self.missing_order.pop_front() else {
+                    break;
+                };
+                self.missing.remove(&oldest);
+            }
+            self.missing_order.push_back(id.clone());
+        }
+        self.missing
+            .entry(id.clone())
+            .or_insert_with(|| Missing {
+                announcers: VecDeque::new(),
+                deadline: Instant::now() + GRAFT_TIMEOUT,
+            })
+            .announcers
+            .push_back(from.clone());
+        self.announced
+            .entry(from.clone())
+            .or_default()
+            .push_back(id);
+    }
+
+    /// 为 `from` 的新 IHAVE 腾出位置：淘汰它最早宣告的条目，没有其他宣告者的条目整条移除
+    fn limit_announcer(&mut self, from: &NodeId) {
+        let Some(ids) = self.announced.get_mut(from) else {
+            return;
+        };
+        let missing = &mut self.missing;
+        ids.retain(|id| missing.get(id).is_some_and(|m| m.announcers.contains(from)));
+        while ids.len() >= MAX_MISSING_PER_PEER {
+            let Some(oldest) = ids.pop_front() else {
+                break;
+            };
+            if let Some(entry) = missing.get_mut(&oldest) {
+                entry.announcers.retain(|a| a != from);
+                if entry.announcers.is_empty() {
+                    missing.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// 收到 GRAFT：恢复对发送方的急切推送，返回缓存中被请求的消息
+    pub fn graft(&mut self, from: &NodeId, ids: &[String]) -> Vec<Envelope> {
+        self.lazy.remove(from);
+        ids.iter()
+            .filter_map(|id| self.cache.get(id).map(|(_, env)| env.clone()))
+            .collect()
+    }
+
+    /// 收到 PRUNE：之后只向发送方发送 IHAVE
+    pub fn prune(&mut self, from: &NodeId) {
+        self.lazy.insert(from.clone());
+    }
+
+    /// 邻居断开，重连后重新作为 eager 邻居
+    pub fn remove_peer(&mut self, peer: &NodeId) {
+        self.lazy.remove(peer);
+        self.pending_ihave.remove(peer);
+        self.announced.remove(peer);
+        for missing in self.missing.values_mut() {
+            missing.announcers.retain(|a| a != peer);
+        }
+    }
+
+    /// 等待超时的消息，返回需要发送的 GRAFT，被 GRAFT 的宣告者恢复为 eager
+    pub fn due_grafts(&mut self, now: Instant) -> Vec<(NodeId, String)> {
+        let mut grafts = Vec::new();
+        self.missing.retain(|id, missing| {
+            if missing.deadline > now {
+                return true;
+            }
+            match missing.announcers.pop_front() {
+                Some(peer) => {
+                    self.lazy.remove(&peer);
+                    grafts.push((peer, id.clone()));
+                    missing.deadline = now + GRAFT_RETRY;
+                    true
+                }
+                None => false,
+            }
+        });
+
+        // 去掉索引中已移除的 ID
+        let missing = &self.missing;
+        self.missing_order.retain(|id| missing.contains_key(id));
+        self.announced.retain(|peer, ids| {
+            ids.retain(|id| missing.get(id).is_some_and(|m| m.announcers.contains(peer)));
+            !ids.is_empty()
+        });
+        grafts
+    }
+
+    /// 取出排队的 IHAVE
+    pub fn take_ihaves(&mut self) -> Vec<(NodeId, Vec<String>)> {
+        self.pending_ihave.drain().collect()
+    }
+
+    /// 清理过期的消息缓存
+    pub fn evict(&mut self, now: Instant) {
+        self.cache.retain(|_, (at, _)| *at + CACHE_TTL > now);
+    }
+
+    pub fn is_lazy(&self, peer: &NodeId) -> bool {
+        self.lazy.contains(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::message::SignedMessage;
+    use crate::identity::keypair::KeyPair;
+    use crate::node::node::{Node, NodeType};
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    fn envelope() -> Envelope {
+        let kp = KeyPair::generate().unwrap();
+        let node = Node::from_keypair(&kp, "n", vec![], NodeType::Normal);
+        Envelope {
+            payload: SignedMessage::new_node_sign_message(node, 0).unwrap(),
+            ttl: 3,
+        }
+    }
+
+    #[test]
+    fn test_prune_and_graft() {
+        let mut tree = Plumtree::default();
+        let (a, b, legacy) = (node_id(), node_id(), node_id());
+        let plumtree_peers: HashSet<NodeId> = [a.clone(), b.clone()].into_iter().collect();
+        let peers = vec![a.clone(), b.clone(), legacy.clone()];
+
+        // 新邻居默认 eager
+        let push = tree.split(peers.clone(), &plumtree_peers, false);
+        assert_eq!(push.eager.len(), 3);
+
+        // b 发来重复消息后降为 lazy；旧版本节点始终 eager
+        tree.duplicate(&b);
+        tree.duplicate(&legacy);
+        let push = tree.split(peers.clone(), &plumtree_peers, false);
+        assert_eq!(push.eager, vec![a.clone(), legacy.clone()]);
+        assert_eq!(push.lazy, vec![b.clone()]);
+        assert_eq!(tree.split(peers, &plumtree_peers, true).eager.len(), 3);
+
+        // a 发来 PRUNE，之后只收 IHAVE
+        tree.prune(&a);
+        assert!(tree.is_lazy(&a));
+        tree.queue_ihave(&[a.clone(), b.clone()], "m1");
+        tree.queue_ihave(std::slice::from_ref(&a), "m2");
+        let mut ihaves = tree.take_ihaves();
+        ihaves.sort_by_key(|(_, ids)| ids.len());
+        assert_eq!(ihaves[0], (b.clone(), vec!["m1".to_string()]));
+        assert_eq!(ihaves[1].1, vec!["m1".to_string(), "m2".to_string()]);
+        assert!(tree.take_ihaves().is_empty());
+
+        // a 请求缓存的消息，恢复为 eager
+        let env = envelope();
+        tree.delivered("m1", env.clone(), None);
+        let served = tree.graft(&a, &["m1".to_string(), "unknown".to_string()]);
+        assert_eq!(served.len(), 1);
+        assert_eq!(served[0].payload.signature, env.payload.signature);
+        assert!(!tree.is_lazy(&a));
+
+        tree.evict(Instant::now() + CACHE_TTL);
+        assert!(tree.graft(&a, &["m1".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn test_missing_message_grafts_announcers_in_order() {
+        let mut tree = Plumtree::default();
+        let (a, b) = (node_id(), node_id());
+        tree.prune(&a);
+        tree.prune(&b);
+        tree.ihave(&a, "m".to_string());
+        tree.ihave(&b, "m".to_string());
+        tree.ihave(&a, "m".to_string());
+
+        let start = Instant::now();
+        assert!(tree.due_grafts(start).is_empty());
+
+        // 超时后先 GRAFT 第一个宣告者，再向下一个重试
+        let now = start + GRAFT_TIMEOUT;
+        assert_eq!(tree.due_grafts(now), vec![(a.clone(), "m".to_string())]);
+        assert!(!tree.is_lazy(&a));
+        assert!(tree.due_grafts(now).is_empty());
+        let now = now + GRAFT_RETRY;
+        assert_eq!(tree.due_grafts(now), vec![(b.clone(), "m".to_string())]);
+        assert!(tree.due_grafts(now + GRAFT_RETRY).is_empty());
+        assert!(tree.missing.is_empty());
+
+        // 消息经 eager 推送到达后不再 GRAFT
+        tree.prune(&a);
+        tree.ihave(&a, "m2".to_string());
+        tree.delivered("m2", envelope(), Some(&b));
+        assert!(tree.due_grafts(now + GRAFT_TIMEOUT * 2).is_empty());
+
+        // 宣告者断开后不再向其 GRAFT
+        tree.ihave(&a, "m3".to_string());
+        tree.remove_peer(&a);
+        assert!(!tree.is_lazy(&a));
+        assert!(tree.due_grafts(now + GRAFT_TIMEOUT * 4).is_empty());
+    }
+
+    #[test]
+    fn test_ihave_flood_stays_bounded() {
+        let mut tree = Plumtree::default();
+        let flooder = node_id();
+
+        // 一个邻居宣告大量不存在的消息，只保留它最新的条目
+        for n in 0..MAX_MISSING_PER_PEER * 10 {
+            tree.ihave(&flooder, format!("fake-{}", n));
+        }
+        assert_eq!(tree.missing.len(), MAX_MISSING_PER_PEER);
+        assert!(tree.announced[&flooder].len() <= MAX_MISSING_PER_PEER);
+        assert!(!tree.missing.contains_key("fake-0"));
+        let newest = format!("fake-{}", MAX_MISSING_PER_PEER * 10 - 1);
+        assert!(tree.missing.contains_key(&newest));
+
+        // 其他邻居宣告的消息不受影响，多个邻居合计也不超过总上限
+        let honest = node_id();
+        tree.ihave(&honest, "real".to_string());
+        tree.ihave(&flooder, "real".to_string());
+        for _ in 0..MAX_MISSING / MAX_MISSING_PER_PEER * 2 {
+            let peer = node_id();
+            for n in 0..MAX_MISSING_PER_PEER {
+                tree.ihave(&peer, format!("{}-{}", peer, n));
+            }
+            assert!(tree.missing.len() <= MAX_MISSING);
+        }
+        assert_eq!(tree.missing.len(), MAX_MISSING);
+        assert!(!tree.missing.contains_key("real"));
+
+        // 超时后的 GRAFT 数同样有上限，索引随条目一起清理
+        let grafts = tree.due_grafts(Instant::now() + GRAFT_TIMEOUT);
+        assert_eq!(grafts.len(), MAX_MISSING);
+        for _ in 0..=tree.missing.len() {
+            tree.due_grafts(Instant::now() + GRAFT_TIMEOUT * 100);
+        }
+        assert!(tree.missing.is_empty());
+        assert!(tree.missing_order.is_empty() && tree.announced.is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::gossip::message::SignedMessage;
+use crate::node::node_id::NodeId;
+
+/// 签名消息时间戳的接受窗口
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreshnessConfig {
+    /// 允许消息时间戳领先本地时钟的最大值
+    pub max_clock_skew: Duration,
+    /// 消息的最大存活时间，超过后视为过期
+    pub max_age: Duration,
+}
+
+impl Default for FreshnessConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_skew: Duration::from_secs(60),
+            max_age: Duration::from_secs(300),
+        }
+    }
+}
+
+impl FreshnessConfig {
+    pub fn with_max_clock_skew(mut self, max_clock_skew: Duration) -> Self {
+        self.max_clock_skew = max_clock_skew;
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// 消息 ID 的去重记录至少保留到消息离开接受窗口，否则过期前可以重放
+    pub fn retention(&self) -> Duration {
+        self.max_age + self.max_clock_skew
+    }
+}
+
+/// 消息被拒绝的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// 时间戳领先本地时钟超过允许的偏差
+    Future { ahead: i64 },
+    /// 消息已超过最大存活时间
+    Expired { age: i64 },
+    /// 不比已接受的同类公告更新
+    Stale {
+        sequence: u64,
+        timestamp: i64,
+        latest: Latest,
+    },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Future { ahead } => write!(f, "timestamp is {}s in the future", ahead),
+            Rejection::Expired { age } => write!(f, "message expired {}s ago", age),
+            Rejection::Stale {
+                sequence,
+                timestamp,
+                latest,
+            } => write!(
+                f,
+                "sequence {} (timestamp {}) is not newer than accepted sequence {} (timestamp {})",
+                sequence, timestamp, latest.sequence, latest.timestamp
+            ),
+        }
+    }
+}
+
+/// 某个来源节点最新接受的公告
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latest {
+    pub sequence: u64,
+    pub timestamp: i64,
+}
+
+/// 重放保护：检查签名消息的时间戳窗口，并要求同一节点的同类公告严格递增
+///
+/// 按来源节点和 [`GossipMessage::ordering_key`](crate::gossip::message::GossipMessage::ordering_key)
+/// 记录最新接受的序号；旧版本节点发出的无序号公告按时间戳比较。没有排序键的消息（聊天、回执、ref 更新）只检查时间戳窗口。
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    config: FreshnessConfig,
+    latest: HashMap<(NodeId, String), Latest>,
+}
+
+impl ReplayGuard {
+    pub fn new(config: FreshnessConfig) -> Self {
+        Self {
+            config,
+            latest: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> FreshnessConfig {
+        self.config
+    }
+
+    /// 恢复持久化的最新接受记录
+    pub fn restore(&mut self, node_id: NodeId, kind: String, latest: Latest) {
+        let entry = self.latest.entry((node_id, kind)).or_default();
+        *entry = newest(*entry, latest);
+    }
+
+    /// 检查消息能否接受，`now` 为本地 Unix 时间（秒）
+    pub fn check(&self, signed: &SignedMessage, now: i64) -> Result<(), Rejection> {
+        let ahead = signed.timestamp - now;
+        if ahead > self.config.max_clock_skew.as_secs() as i64 {
+            return Err(Rejection::Future { ahead });
+        }
+        let age = now - signed.timestamp;
+        if age > self.config.max_age.as_secs() as i64 {
+            return Err(Rejection::Expired { age });
+        }
+
+        let Some(latest) = self.latest_for(signed) else {
+            return Ok(());
+        };
+        let newer = if signed.sequence > 0 && latest.sequence > 0 {
+            signed.sequence > latest.sequence
+        } else {
+            signed.timestamp >= latest.timestamp
+        };
+        if newer {
+            Ok(())
+        } else {
+            Err(Rejection::Stale {
+                sequence: signed.sequence,
+                timestamp: signed.timestamp,
+                latest: *latest,
+            })
+        }
+    }
+
+    /// 记录已接受的公告，返回需要持久化的最新记录；其他消息返回 `None`
+    pub fn accept(&mut self, signed: &SignedMessage) -> Option<(String, Latest)> {
+        let key = signed.message.ordering_key()?;
+        let entry = self
+            .latest
+            .entry((signed.node_id.clone(), key.clone()))
+            .or_default();
+        *entry = newest(
+            *entry,
+            Latest {
+                sequence: signed.sequence,
+                timestamp: signed.timestamp,
+            },
+        );
+        Some((key, *entry))
+    }
+
+    fn latest_for(&self, signed: &SignedMessage) -> Option<&Latest> {
+        let key = signed.message.ordering_key()?;
+        self.latest.get(&(signed.node_id.clone(), key))
+    }
+}
+
+fn newest(a: Latest, b: Latest) -> Latest {
+    Latest {
+        sequence: a.sequence.max(b.sequence),
+        timestamp: a.timestamp.max(b.timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+    use crate::node::node::{Node, NodeType};
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn node() -> Node {
+        let keypair = KeyPair::generate().unwrap();
+        Node::from_keypair(&keypair, "n", vec![], NodeType::Normal)
+    }
+
+    fn announcement(node: &Node, sequence: u64, timestamp: i64) -> SignedMessage {
+        let mut signed = SignedMessage::new_node_sign_message(node.clone(), sequence).unwrap();
+        signed.timestamp = timestamp;
+        signed
+    }
+
+    #[test]
+    fn test_timestamp_window() {
+        let guard = ReplayGuard::new(
+            FreshnessConfig::default().with_max_clock_skew(Duration::from_secs(10)),
+        );
+        let node = node();
+
+        assert!(guard.check(&announcement(&node, 1, NOW), NOW).is_ok());
+        assert!(guard.check(&announcement(&node, 1, NOW + 10), NOW).is_ok());
+        assert_eq!(
+            guard.check(&announcement(&node, 1, NOW + 11), NOW),
+            Err(Rejection::Future { ahead: 11 })
+        );
+        assert!(guard.check(&announcement(&node, 1, NOW - 300), NOW).is_ok());
+        assert_eq!(
+            guard.check(&announcement(&node, 1, NOW - 301), NOW),
+            Err(Rejection::Expired { age: 301 })
+        );
+    }
+
+    #[test]
+    fn test_rejects_older_announcements() {
+        let mut guard = ReplayGuard::default();
+        let node = node();
+
+        let second = announcement(&node, 2, NOW);
+        assert!(guard.check(&second, NOW).is_ok());
+        assert_eq!(
+            guard.accept(&second),
+            Some((
+                "node_announcement".to_string(),
+                Latest {
+                    sequence: 2,
+                    timestamp: NOW
+                }
+            ))
+        );
+
+        // 重放和较旧的公告都被拒绝，即使时间戳更新
+        assert!(matches!(
+            guard.check(&second, NOW),
+            Err(Rejection::Stale { .. })
+        ));
+        assert!(guard.check(&announcement(&node, 1, NOW + 5), NOW).is_err());
+        assert!(guard.check(&announcement(&node, 3, NOW), NOW).is_ok());
+
+        // 其他节点互不影响
+        assert!(guard
+            .check(&announcement(&self::node(), 1, NOW), NOW)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_unsequenced_announcements_compare_timestamps() {
+        let mut guard = ReplayGuard::default();
+        let node = node();
+        guard.restore(
+            node.node_id().clone(),
+            "node_announcement".to_string(),
+            Latest {
+                sequence: 0,
+                timestamp: NOW - 30,
+            },
+        );
+
+        assert!(guard.check(&announcement(&node, 0, NOW - 31), NOW).is_err());
+        assert!(guard.check(&announcement(&node, 0, NOW - 30), NOW).is_ok());
+        assert!(guard.check(&announcement(&node, 7, NOW - 10), NOW).is_ok());
+        guard.accept(&announcement(&node, 7, NOW - 10));
+
+        // 升级后的节点发出的旧无序号公告也不能回滚状态
+        assert!(guard.check(&announcement(&node, 0, NOW - 20), NOW).is_err());
+    }
+}
@@ -1,160 +1,795 @@
-use crate::gossip::message::{Envelope, GossipMessage, SignedMessage};
-use crate::node::node::{Node, NodeInfo};
+use crate::gossip::dedup::{self, DedupCache, DedupConfig, DedupStats, SharedDedup};
+use crate::gossip::membership::{self, HyParView, MembershipConfig, SharedMembership};
+use crate::gossip::message::{
+    Envelope, GossipControl, GossipMessage, InventoryPage, MembershipMessage, PeerEntry,
+    SignedMessage, TopicMessage, MAX_INVENTORY_REPOS,
+};
+use crate::gossip::plumtree::{Plumtree, LAZY_INTERVAL};
+use crate::gossip::replay::{FreshnessConfig, Latest, Rejection, ReplayGuard};
+use crate::gossip::topic::{SharedSubscriptions, Subscriptions, Topic};
+use crate::node::node::{Node, NodeInfo, NodeType};
 use crate::node::node_id::NodeId;
+use crate::node::reputation::{self, Offence};
+use crate::node::shutdown::Shutdown;
+use crate::repo::repo::Repo;
 use crate::repo::repo_manager::RepoManager;
-use crate::storage::node_model;
-use crate::transport::quic::ConnectionManager;
+use crate::repo::repo_sync::{apply_ref_update, merge_subscribed_repo, RefChange};
+use crate::storage::{node_model, sequence_model, signed_refs_model};
+use crate::transport::codec;
+use crate::transport::events::{ConnectionEvent, Direction};
+use crate::transport::frame::Channel;
+use crate::transport::protocol::Capabilities;
+use crate::transport::transport::Transport;
+use crate::util::timestamp_now;
 use anyhow::Result;
-use ed25519_dalek::Signature;
 use hex;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex};
 
 const DEFAULT_TTL: u8 = 16;
+/// 周期公告的间隔
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// 每隔多少轮周期公告向所有邻居洪泛一次，修复可能断开的广播树
+const FLOOD_EVERY: u64 = 10;
+/// 每隔多少轮周期公告发送一次完整库存，平时由 RefUpdate 增量传播仓库变化
+const INVENTORY_EVERY: u64 = 20;
+/// 完整库存每页包含的仓库数
+const INVENTORY_PAGE_SIZE: usize = 50;
+/// 成员视图变化写入数据库的间隔
+const MEMBERSHIP_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+/// 开启持久化时新的去重记录写入数据库的间隔
+const DEDUP_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// 淘汰过期的去重记录和广播树缓存的间隔
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
 
-/// 简单的 gossip 服务：接收来自 QUIC 的 Gossip 控制消息，去重、验签、处理并转发给邻居
+/// gossip 消息计数
+#[derive(Debug, Default)]
+struct GossipCounters {
+    delivered: AtomicU64,
+    duplicates: AtomicU64,
+    ihave_sent: AtomicU64,
+    grafts_sent: AtomicU64,
+    prunes_sent: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// [`GossipCounters`] 的快照
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GossipStats {
+    /// 首次收到的消息
+    pub delivered: u64,
+    /// 重复收到的完整消息
+    pub duplicates: u64,
+    pub ihave_sent: u64,
+    pub grafts_sent: u64,
+    pub prunes_sent: u64,
+    /// 未通过时间戳窗口、序号或大小检查的消息
+    pub rejected: u64,
+}
+
+/// gossip 服务：接收来自 QUIC 的 Gossip 消息，去重、验签、处理，并沿 Plumtree 广播树转发给
+/// HyParView active view 中订阅了消息主题的邻居
 #[allow(dead_code)]
 pub struct GossipService {
-    manager: Arc<Mutex<ConnectionManager>>,
+    transport: Arc<dyn Transport>,
     node: Node,
     repo_manager: Option<Arc<Mutex<RepoManager>>>,
-    seen: Arc<Mutex<HashMap<String, Instant>>>,
+    dedup: SharedDedup,
+    tree: Arc<Mutex<Plumtree>>,
+    membership: SharedMembership,
+    subscriptions: SharedSubscriptions,
+    replay: Mutex<ReplayGuard>,
+    /// 本节点上次使用的发送序号，首次使用时从数据库加载
+    sequence: Mutex<Option<u64>>,
+    /// 收到 RefUpdate 后请求立即同步受影响仓库的 bundle
+    sync_requests: Option<mpsc::UnboundedSender<String>>,
+    counters: GossipCounters,
+    shutdown: Shutdown,
 }
 
 impl GossipService {
     pub fn new(
-        manager: Arc<Mutex<ConnectionManager>>,
+        transport: Arc<dyn Transport>,
         node: Node,
         repo_manager: Option<Arc<Mutex<RepoManager>>>,
     ) -> Self {
+        let membership =
+            HyParView::new(node.node_id().clone(), MembershipConfig::default()).shared();
         Self {
-            manager,
+            transport,
             node,
             repo_manager,
-            seen: Arc::new(Mutex::new(HashMap::new())),
+            dedup: DedupCache::new(DedupConfig::default()).shared(),
+            tree: Arc::new(Mutex::new(Plumtree::default())),
+            membership,
+            subscriptions: Subscriptions::default().shared(),
+            replay: Mutex::new(ReplayGuard::default()),
+            sequence: Mutex::new(None),
+            sync_requests: None,
+            counters: GossipCounters::default(),
+            shutdown: Shutdown::new(),
         }
     }
 
+    /// 节点关闭时停止处理消息和周期广播
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 设置成员视图的大小和 SHUFFLE 间隔
+    pub fn with_membership_config(mut self, config: MembershipConfig) -> Self {
+        self.membership = HyParView::new(self.node.node_id().clone(), config).shared();
+        self
+    }
+
+    /// 设置签名消息时间戳的接受窗口
+    pub fn with_freshness(mut self, config: FreshnessConfig) -> Self {
+        self.replay = Mutex::new(ReplayGuard::new(config));
+        self
+    }
+
+    /// 设置去重记录的容量、保留时间和持久化
+    pub fn with_dedup(mut self, config: DedupConfig) -> Self {
+        self.dedup = DedupCache::new(config).shared();
+        self
+    }
+
+    /// ref 更新改变外部仓库后，把仓库 ID 发送给 bundle 同步任务
+    pub fn with_sync_requests(mut self, sync_requests: mpsc::UnboundedSender<String>) -> Self {
+        self.sync_requests = Some(sync_requests);
+        self
+    }
+
+    /// 设置本节点订阅的主题，默认只订阅新仓库
+    pub fn with_subscriptions(mut self, topics: impl IntoIterator<Item = Topic>) -> Self {
+        self.subscriptions = Subscriptions::new(topics).shared();
+        self
+    }
+
+    /// 成员视图，供 `PeerManager` 补足 active view
+    pub fn membership(&self) -> SharedMembership {
+        Arc::clone(&self.membership)
+    }
+
+    /// 最近收到的消息 ID，供指标导出统计命中率
+    pub fn dedup(&self) -> SharedDedup {
+        Arc::clone(&self.dedup)
+    }
+
+    /// 本节点和各邻居的订阅，供反熵和 bundle 同步按订阅过滤仓库
+    pub fn subscriptions(&self) -> SharedSubscriptions {
+        Arc::clone(&self.subscriptions)
+    }
+
+    /// 替换本节点订阅的主题，订阅变化时通知所有邻居
+    pub async fn set_subscriptions(&self, topics: impl IntoIterator<Item = Topic>) {
+        if !self.subscriptions.lock().await.set_local(topics) {
+            return;
+        }
+        for peer in self.transport.peers_with(Capabilities::TOPICS).await {
+            self.send_subscriptions(peer).await;
+        }
+    }
+
+    /// 当前 active view 中的邻居
+    pub async fn active_view(&self) -> Vec<NodeId> {
+        self.membership.lock().await.active()
+    }
+
+    /// 分配本节点下一条消息的序号并持久化
+    ///
+    /// 序号不小于当前 Unix 毫秒时间，数据库丢失后重启的节点发出的序号仍然大于之前的序号
+    pub async fn next_sequence(&self) -> u64 {
+        let node_id = self.node.node_id();
+        let mut last = self.sequence.lock().await;
+        let previous = match *last {
+            Some(sequence) => sequence,
+            None => {
+                match sequence_model::load_sequence(node_id, sequence_model::LOCAL_KIND).await {
+                    Ok(stored) => stored.map(|(sequence, _)| sequence).unwrap_or(0),
+                    Err(e) => {
+                        tracing::warn!("Failed to load gossip sequence: {}", e);
+                        0
+                    }
+                }
+            }
+        };
+        let now_ms = chrono::Utc::now().timestamp_millis().max(0) as u64;
+        let next = (previous + 1).max(now_ms);
+        *last = Some(next);
+        if let Err(e) = sequence_model::save_sequence(
+            node_id,
+            sequence_model::LOCAL_KIND,
+            next,
+            timestamp_now(),
+        )
+        .await
+        {
+            tracing::warn!("Failed to save gossip sequence: {}", e);
+        }
+        next
+    }
+
     /// Start the gossip service: register gossip channel and spawn handler + periodic broadcaster
     pub async fn start(self: Arc<Self>) -> Result<()> {
         // 注册 Gossip 控制消息接收器
         let (gossip_tx, mut gossip_rx) = mpsc::channel::<(NodeId, Vec<u8>)>(256);
 
-        {
-            let mgr = self.manager.lock().await;
-            mgr.register_gossip_sender(gossip_tx).await;
+        self.transport
+            .register_channel(Channel::Gossip, gossip_tx)
+            .await;
+
+        // 恢复各来源节点最新接受的公告序号，防止重启后接受重放的旧公告
+        match sequence_model::list_sequences().await {
+            Ok(rows) => {
+                let mut replay = self.replay.lock().await;
+                for (node_id, kind, sequence, timestamp) in rows {
+                    replay.restore(
+                        node_id,
+                        kind,
+                        Latest {
+                            sequence,
+                            timestamp,
+                        },
+                    );
+                }
+            }
+            Err(e) => tracing::warn!("Failed to restore gossip sequences: {}", e),
+        }
+
+        // 去重记录至少保留到消息离开时间戳接受窗口；开启持久化时恢复上次运行时收到的消息 ID，
+        // 重启后不会重新处理和转发这些消息
+        let retention = self.replay.lock().await.config().retention();
+        let persist = {
+            let mut dedup = self.dedup.lock().await;
+            dedup.set_min_retention(retention);
+            dedup.config().persist
+        };
+        if persist {
+            match dedup::restore(self.node.node_id(), &self.dedup, timestamp_now()).await {
+                Ok(n) if n > 0 => tracing::info!("Restored {} recently seen gossip messages", n),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Failed to restore seen gossip messages: {}", e),
+            }
+        }
+
+        // 恢复上次运行时的成员视图，并向已建立的连接请求成为邻居
+        match membership::restore(&self.membership).await {
+            Ok(n) if n > 0 => tracing::info!("Restored {} nodes into passive view", n),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Failed to restore membership view: {}", e),
+        }
+        let addresses = self
+            .transport
+            .announce_addresses(self.node.addresses().to_vec())
+            .await;
+        self.membership.lock().await.set_addresses(addresses);
+        for peer in self.transport.peers_with(Capabilities::MEMBERSHIP).await {
+            self.request_neighbor(peer, None).await;
+        }
+        for peer in self.transport.peers_with(Capabilities::TOPICS).await {
+            self.send_subscriptions(peer).await;
         }
 
         // Gossip 消息处理任务
         let s = Arc::clone(&self);
         tokio::spawn(async move {
-            while let Some((from, data)) = gossip_rx.recv().await {
+            loop {
+                let (from, data) = tokio::select! {
+                    _ = s.shutdown.cancelled() => break,
+                    msg = gossip_rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                };
                 let _ = s.handle_incoming(from, data).await;
             }
         });
 
         // periodic broadcaster: node announcement (and repo announcement if available)
         let s2 = Arc::clone(&self);
+        tokio::spawn(async move {
+            for round in 0u64.. {
+                // 平时沿广播树发送，定期洪泛一次
+                let flood = round % FLOOD_EVERY == 0;
+
+                // 1. 发送 NodeAnnouncement，去掉 0.0.0.0 之类无法拨号的地址并补充对端观察到的外部地址
+                let mut node = s2.node.clone();
+                node.info.addresses = s2
+                    .transport
+                    .announce_addresses(node.addresses().to_vec())
+                    .await;
+                let sequence = s2.next_sequence().await;
+                if let Ok(signed) = SignedMessage::new_node_sign_message(node, sequence) {
+                    tracing::debug!("Broadcasting NodeAnnouncement: {:?}", signed);
+                    s2.publish(signed, flood).await;
+                }
+
+                // 2. 定期分页发送完整库存（从本地 storage 加载 repo 列表），作为 RefUpdate 的补充
+                if round % INVENTORY_EVERY == 0 {
+                    s2.announce_inventory(flood).await;
+                }
+
+                tokio::select! {
+                    _ = s2.shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(ANNOUNCE_INTERVAL) => {}
+                }
+            }
+        });
+
+        // 批量发送 IHAVE，对等待超时的消息发送 GRAFT
+        let s3 = Arc::clone(&self);
         tokio::spawn(async move {
             loop {
-                // 1. 发送 NodeAnnouncement
-                if let Ok(signed) = SignedMessage::new_node_sign_message(s2.node.clone()) {
-                    let env = Envelope {
-                        payload: signed,
-                        ttl: DEFAULT_TTL,
-                    };
-                    tracing::debug!("Broadcasting NodeAnnouncement: {:?}", env);
-                    let data = serde_json::to_vec(&env).unwrap_or_default();
-                    let mgr = s2.manager.lock().await;
-                    let peers = mgr.list_peers().await;
-                    tracing::debug!("Send NodeAnnouncement to {} peers", peers.len());
-                    for peer in peers {
-                        let _ = mgr.send_gossip_message(peer.clone(), data.clone()).await;
-                    }
+                tokio::select! {
+                    _ = s3.shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(LAZY_INTERVAL) => {}
+                }
+                let (ihaves, grafts) = {
+                    let mut tree = s3.tree.lock().await;
+                    (tree.take_ihaves(), tree.due_grafts(Instant::now()))
+                };
+                for (peer, ids) in ihaves {
+                    s3.counters.ihave_sent.fetch_add(1, Ordering::Relaxed);
+                    s3.send_control(peer, GossipControl::IHave(ids)).await;
+                }
+                for (peer, id) in grafts {
+                    tracing::debug!("Grafting {} to fetch missing message {}", peer, id);
+                    s3.counters.grafts_sent.fetch_add(1, Ordering::Relaxed);
+                    s3.send_control(peer, GossipControl::Graft(vec![id])).await;
                 }
+            }
+        });
 
-                // 2. 发送 RepoAnnouncement（从本地 storage 加载 repo 列表）
-                if let Ok(repos) = crate::storage::repo_model::list_repos().await {
-                    if !repos.is_empty() {
-                        if let Ok(signed) =
-                            SignedMessage::new_repo_sign_message(repos, s2.node.clone())
-                        {
-                            let env = Envelope {
-                                payload: signed,
-                                ttl: DEFAULT_TTL,
-                            };
-                            tracing::debug!("Broadcasting RepoAnnouncement: {:?}", env);
-                            let data = serde_json::to_vec(&env).unwrap_or_default();
-                            let mgr = s2.manager.lock().await;
-                            let peers = mgr.list_peers().await;
-                            for peer in peers {
-                                let _ = mgr.send_gossip_message(peer.clone(), data.clone()).await;
-                            }
+        // 邻居断开后从广播树中移除，重连后重新作为 eager 邻居；新连接建立后声明本节点的订阅
+        let s4 = Arc::clone(&self);
+        let mut events = self.transport.subscribe_events();
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    _ = s4.shutdown.cancelled() => break,
+                    event = events.recv() => event,
+                };
+                match event {
+                    Ok(ConnectionEvent::Disconnected { node_id }) => {
+                        s4.tree.lock().await.remove_peer(&node_id);
+                        s4.membership.lock().await.peer_down(&node_id);
+                        s4.subscriptions.lock().await.remove_peer(&node_id);
+                    }
+                    Ok(ConnectionEvent::Connected {
+                        node_id,
+                        direction,
+                        addr,
+                        ..
+                    }) => {
+                        s4.send_subscriptions(node_id.clone()).await;
+                        // 由拨号方请求成为邻居，避免双方同时请求
+                        let wanted = direction == Direction::Outbound && {
+                            let membership = s4.membership.lock().await;
+                            !membership.is_active(&node_id) && membership.needed() > 0
+                        };
+                        if wanted {
+                            s4.request_neighbor(node_id, addr).await;
                         }
                     }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
                 }
+            }
+        });
 
-                tokio::time::sleep(Duration::from_secs(30)).await;
+        // 定期与随机邻居交换视图，并把视图变化写入数据库
+        let s5 = Arc::clone(&self);
+        tokio::spawn(async move {
+            let interval = s5.membership.lock().await.config().shuffle_interval;
+            let mut shuffle =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            let mut save = tokio::time::interval(MEMBERSHIP_SAVE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = s5.shutdown.cancelled() => break,
+                    _ = shuffle.tick() => {
+                        let action = s5.membership.lock().await.shuffle();
+                        membership::run_actions(
+                            &s5.transport,
+                            &s5.membership,
+                            action.into_iter().collect(),
+                        )
+                        .await;
+                    }
+                    _ = save.tick() => {
+                        if let Err(e) = membership::save_changes(&s5.membership).await {
+                            tracing::warn!("Failed to save membership view: {}", e);
+                        }
+                    }
+                }
             }
+            let _ = membership::save_changes(&s5.membership).await;
         });
 
-        // spawn a cleanup task for seen map
-        let seen = Arc::clone(&self.seen);
+        // 定期淘汰过期的去重记录和广播树缓存；开启持久化时把新的去重记录写入数据库
+        let s6 = Arc::clone(&self);
         tokio::spawn(async move {
+            let mut cleanup = tokio::time::interval_at(
+                tokio::time::Instant::now() + CLEANUP_INTERVAL,
+                CLEANUP_INTERVAL,
+            );
+            let mut save = tokio::time::interval(DEDUP_SAVE_INTERVAL);
             loop {
-                tokio::time::sleep(Duration::from_secs(30)).await;
-                let mut guard = seen.lock().await;
-                let now = Instant::now();
-                guard.retain(|_, &mut v| v + Duration::from_secs(300) > now);
+                tokio::select! {
+                    _ = s6.shutdown.cancelled() => break,
+                    _ = cleanup.tick() => {
+                        s6.dedup.lock().await.expire(timestamp_now());
+                        s6.tree.lock().await.evict(Instant::now());
+                    }
+                    _ = save.tick(), if persist => s6.save_dedup().await,
+                }
+            }
+            if persist {
+                s6.save_dedup().await;
             }
         });
 
         Ok(())
     }
 
+    async fn save_dedup(&self) {
+        if let Err(e) = dedup::save_changes(self.node.node_id(), &self.dedup, timestamp_now()).await
+        {
+            tracing::warn!("Failed to save seen gossip messages: {}", e);
+        }
+    }
+
+    pub fn stats(&self) -> GossipStats {
+        let c = &self.counters;
+        GossipStats {
+            delivered: c.delivered.load(Ordering::Relaxed),
+            duplicates: c.duplicates.load(Ordering::Relaxed),
+            ihave_sent: c.ihave_sent.load(Ordering::Relaxed),
+            grafts_sent: c.grafts_sent.load(Ordering::Relaxed),
+            prunes_sent: c.prunes_sent.load(Ordering::Relaxed),
+            rejected: c.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 去重记录的命中率和大小
+    pub async fn dedup_stats(&self) -> DedupStats {
+        self.dedup.lock().await.stats()
+    }
+
+    /// 立即广播本地仓库的 ref 变化，每个 ref 一条 RefUpdate，然后以更大的序号重新签名变化后的 refs
+    pub async fn announce_ref_changes(&self, changes: Vec<RefChange>) {
+        let mut repo_ids: Vec<String> = changes.iter().map(|c| c.repo_id.clone()).collect();
+        repo_ids.sort();
+        repo_ids.dedup();
+        for change in changes {
+            let sequence = self.next_sequence().await;
+            match SignedMessage::new_ref_update_sign_message(change, self.node.clone(), sequence) {
+                Ok(signed) => {
+                    tracing::debug!("Broadcasting RefUpdate: {:?}", signed);
+                    self.publish(signed, false).await;
+                }
+                Err(e) => tracing::warn!("Failed to sign ref update: {}", e),
+            }
+        }
+        for repo_id in repo_ids {
+            match crate::storage::repo_model::load_repo_from_db(&repo_id).await {
+                Ok(Some(mut repo)) => {
+                    if let Err(e) = self.sign_local_refs(&mut repo).await {
+                        tracing::warn!("Failed to sign refs of repo {}: {}", repo_id, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to load repo {}: {}", repo_id, e),
+            }
+        }
+    }
+
+    /// 本节点创建的仓库的 refs 与上次签名时不同时，以新的序号重新签名并保存，
+    /// 其他节点转发仓库记录时带上这份签名
+    async fn sign_local_refs(&self, repo: &mut Repo) -> Result<()> {
+        if repo.is_external
+            || repo.p2p_description.creator != self.node.node_id().to_string()
+            || repo
+                .signed_refs
+                .as_ref()
+                .is_some_and(|signed| signed.refs == repo.refs)
+        {
+            return Ok(());
+        }
+        let sequence = self.next_sequence().await;
+        repo.sign_refs(&self.node.keypair, sequence)?;
+        if let Some(signed) = &repo.signed_refs {
+            signed_refs_model::save_signed_refs(&repo.repo_id, signed).await?;
+        }
+        Ok(())
+    }
+
+    /// 分页广播完整的仓库列表，只有一页时不带分页信息，旧版本节点也能处理
+    async fn announce_inventory(&self, flood: bool) {
+        let mut repos = match crate::storage::repo_model::list_repos().await {
+            Ok(repos) => repos,
+            Err(e) => {
+                tracing::warn!("Failed to list repos for inventory: {}", e);
+                return;
+            }
+        };
+        for repo in &mut repos {
+            if let Err(e) = self.sign_local_refs(repo).await {
+                tracing::warn!("Failed to sign refs of repo {}: {}", repo.repo_id, e);
+            }
+        }
+        let pages: Vec<&[Repo]> = repos.chunks(INVENTORY_PAGE_SIZE).collect();
+        let count = pages.len() as u32;
+        for (index, repos) in pages.into_iter().enumerate() {
+            let page = (count > 1).then_some(InventoryPage {
+                index: index as u32,
+                count,
+            });
+            let sequence = self.next_sequence().await;
+            if let Ok(signed) = SignedMessage::new_inventory_sign_message(
+                repos.to_vec(),
+                page,
+                self.node.clone(),
+                sequence,
+            ) {
+                tracing::debug!("Broadcasting RepoAnnouncement: {:?}", signed);
+                self.publish(signed, flood).await;
+            }
+        }
+    }
+
+    /// 广播本节点签名的消息
+    pub async fn broadcast(&self, signed: SignedMessage) {
+        self.publish(signed, false).await;
+    }
+
+    /// 广播本节点签名的消息，`flood` 为 true 时完整发送给所有邻居
+    async fn publish(&self, signed: SignedMessage, flood: bool) {
+        let hash = signed.self_hash();
+        // 消息经其他节点绕回时按重复消息处理
+        self.dedup.lock().await.insert(hash, timestamp_now());
+        let id = hex::encode(hash);
+        self.push(&id, signed, DEFAULT_TTL, None, flood).await;
+    }
+
+    /// 沿广播树推送消息：完整发送给 eager 邻居，向 lazy 邻居排队 IHAVE；
+    /// 属于某些主题的消息只发给订阅了其中任一主题的邻居
+    async fn push(
+        &self,
+        id: &str,
+        signed: SignedMessage,
+        ttl: u8,
+        from: Option<&NodeId>,
+        flood: bool,
+    ) {
+        let mgr = &self.transport;
+        // 不理解该消息的旧版本节点会将其视为无效消息，不向其发送
+        let required = signed.required_capabilities();
+        let peers: Vec<NodeId> = mgr
+            .peers_with(required)
+            .await
+            .into_iter()
+            .filter(|peer| Some(peer) != from)
+            .collect();
+        let peers = if flood {
+            peers
+        } else {
+            self.neighbors(peers).await
+        };
+        let topics = signed.message.topics();
+        let peers: Vec<NodeId> = {
+            let subscriptions = self.subscriptions.lock().await;
+            peers
+                .into_iter()
+                .filter(|peer| subscriptions.forwards_to(peer, &topics))
+                .collect()
+        };
+        let plumtree_peers: HashSet<NodeId> = mgr
+            .peers_with(required | Capabilities::PLUMTREE)
+            .await
+            .into_iter()
+            .collect();
+
+        let env = Envelope {
+            payload: signed,
+            ttl,
+        };
+        let push = {
+            let mut tree = self.tree.lock().await;
+            tree.delivered(id, env.clone(), from);
+            let push = tree.split(peers, &plumtree_peers, flood);
+            tree.queue_ihave(&push.lazy, id);
+            push
+        };
+        tracing::debug!(
+            "Push message {} to {} eager peers, announce to {} lazy peers",
+            id,
+            push.eager.len(),
+            push.lazy.len()
+        );
+        mgr.send_many(push.eager, Channel::Gossip, &env).await;
+    }
+
+    /// 从候选节点中选出 active view 中的邻居，以及不参与成员协议的旧版本节点
+    ///
+    /// active view 为空时（例如刚启动、尚未完成邻居请求）发给所有候选节点
+    async fn neighbors(&self, peers: Vec<NodeId>) -> Vec<NodeId> {
+        let members: HashSet<NodeId> = self
+            .transport
+            .peers_with(Capabilities::MEMBERSHIP)
+            .await
+            .into_iter()
+            .collect();
+        let membership = self.membership.lock().await;
+        if membership.active().is_empty() {
+            return peers;
+        }
+        peers
+            .into_iter()
+            .filter(|peer| membership.is_active(peer) || !members.contains(peer))
+            .collect()
+    }
+
+    /// 请求与已连接的节点成为邻居，地址取自路由表，没有记录时使用连接地址
+    async fn request_neighbor(&self, node_id: NodeId, addr: Option<SocketAddr>) {
+        let mut addresses = self
+            .transport
+            .routing()
+            .lock()
+            .await
+            .get(&node_id)
+            .map(|route| route.addresses.clone())
+            .unwrap_or_default();
+        if addresses.is_empty() {
+            addresses.extend(addr);
+        }
+        let peer = PeerEntry {
+            node_id: node_id.clone(),
+            addresses,
+        };
+        if let Err(e) =
+            membership::request_neighbor(self.transport.as_ref(), &self.membership, peer).await
+        {
+            tracing::debug!("Failed to request neighbor {}: {}", node_id, e);
+        }
+    }
+
+    async fn handle_membership(&self, from: NodeId, message: MembershipMessage) {
+        let actions = self.membership.lock().await.handle(&from, message);
+        membership::run_actions(&self.transport, &self.membership, actions).await;
+    }
+
+    /// 向声明了 `topics` 能力的邻居发送本节点订阅的主题
+    async fn send_subscriptions(&self, peer: NodeId) {
+        let supported = self
+            .transport
+            .peer_protocol(&peer)
+            .await
+            .is_some_and(|p| p.supports(Capabilities::TOPICS));
+        if !supported {
+            return;
+        }
+        let topics = self.subscriptions.lock().await.local();
+        let message = TopicMessage::Subscriptions(topics);
+        if let Err(e) = self
+            .transport
+            .send_message(peer, Channel::Gossip, &message)
+            .await
+        {
+            tracing::debug!("Failed to send subscriptions: {}", e);
+        }
+    }
+
+    async fn send_control(&self, peer: NodeId, control: GossipControl) {
+        if let Err(e) = self
+            .transport
+            .send_message(peer, Channel::Gossip, &control)
+            .await
+        {
+            tracing::debug!("Failed to send gossip control message: {}", e);
+        }
+    }
+
+    async fn supports_plumtree(&self, peer: &NodeId) -> bool {
+        self.transport
+            .peer_protocol(peer)
+            .await
+            .is_some_and(|p| p.supports(Capabilities::PLUMTREE))
+    }
+
+    async fn handle_control(&self, from: NodeId, control: GossipControl) {
+        match control {
+            GossipControl::IHave(ids) => {
+                let dedup = self.dedup.lock().await;
+                let mut tree = self.tree.lock().await;
+                for id in ids {
+                    if dedup::parse_id(&id).is_some_and(|hash| !dedup.contains(&hash)) {
+                        tree.ihave(&from, id);
+                    }
+                }
+            }
+            GossipControl::Graft(ids) => {
+                let envelopes = self.tree.lock().await.graft(&from, &ids);
+                for env in envelopes {
+                    if let Err(e) = self
+                        .transport
+                        .send_message(from.clone(), Channel::Gossip, &env)
+                        .await
+                    {
+                        tracing::debug!("Failed to answer graft from {}: {}", from, e);
+                    }
+                }
+            }
+            GossipControl::Prune => self.tree.lock().await.prune(&from),
+        }
+    }
+
+    /// 转发无效消息的直连节点记为违规，降低其路由得分和信誉，信誉过低时封禁
+    async fn record_misbehaviour(&self, from: &NodeId, offence: Offence) {
+        reputation::report(self.transport.as_ref(), from, offence).await;
+    }
+
     async fn handle_incoming(&self, from: NodeId, data: Vec<u8>) -> Result<()> {
         // Try parse as Envelope (with ttl). If not, fall back to raw SignedMessage.
+        // 二进制编码和 JSON 由 codec 自动识别
 
-        let (signed, mut ttl) = if let Ok(env) = serde_json::from_slice::<Envelope>(&data) {
+        let (signed, ttl) = if let Ok(env) = codec::decode::<Envelope>(&data) {
             (env.payload, env.ttl)
-        } else if let Ok(s) = serde_json::from_slice::<SignedMessage>(&data) {
+        } else if let Ok(s) = codec::decode::<SignedMessage>(&data) {
             (s, DEFAULT_TTL)
+        } else if let Ok(control) = codec::decode::<GossipControl>(&data) {
+            self.handle_control(from, control).await;
+            return Ok(());
+        } else if let Ok(message) = codec::decode::<MembershipMessage>(&data) {
+            self.handle_membership(from, message).await;
+            return Ok(());
+        } else if let Ok(TopicMessage::Subscriptions(topics)) = codec::decode(&data) {
+            tracing::debug!("Peer {} subscribed to {} topics", from, topics.len());
+            self.subscriptions.lock().await.set_peer(from, topics);
+            return Ok(());
         } else {
+            self.record_misbehaviour(&from, Offence::MalformedPayload)
+                .await;
             return Ok(());
         };
 
-        let id = hex::encode(signed.self_hash());
+        let hash = signed.self_hash();
+        let id = hex::encode(hash);
 
-        // dedup
+        // dedup：通过下面的校验后才记录消息 ID
         {
-            let mut seen = self.seen.lock().await;
-            if seen.contains_key(&id) {
+            let duplicate = self.dedup.lock().await.is_duplicate(&hash);
+            if duplicate {
+                self.counters.duplicates.fetch_add(1, Ordering::Relaxed);
+                // 重复消息说明存在冗余链路，请求对方之后只发送 IHAVE
+                if self.supports_plumtree(&from).await {
+                    self.tree.lock().await.duplicate(&from);
+                    self.counters.prunes_sent.fetch_add(1, Ordering::Relaxed);
+                    self.send_control(from, GossipControl::Prune).await;
+                }
                 return Ok(());
             }
-            seen.insert(id.clone(), Instant::now());
         }
 
-        // verify signature using sender's NodeId -> verifying key
-        if let Ok(kp) = signed.node_id.to_keypair() {
-            let sig_bytes = hex::decode(&signed.signature).unwrap_or_default();
-            let arr: [u8; 64] = match sig_bytes.as_slice().try_into() {
-                Ok(a) => a,
-                Err(e) => {
-                    tracing::error!("Failed to convert signature bytes: {}", e);
-                    return Ok(());
-                }
-            };
-            let sig = Signature::from_bytes(&arr);
-            if !kp.verify(&signed.self_hash(), &sig) {
-                tracing::error!(
-                    "signature verification failed for message from {}",
-                    signed.node_id
-                );
-                return Ok(());
-            }
+        // verify signature using sender's NodeId -> verifying key；无法解析出公钥的签名者同样视为无效签名
+        let verified = signed
+            .node_id
+            .to_keypair()
+            .is_ok_and(|kp| signed.verify(&kp));
+        if !verified {
+            tracing::error!(
+                "signature verification failed for message from {}",
+                signed.node_id
+            );
+            self.record_misbehaviour(&from, Offence::InvalidSignature)
+                .await;
+            return Ok(());
         }
 
         // Ensure outer signer identity matches the embedded payload sender identity.
@@ -165,9 +800,83 @@ impl GossipService {
                 signed.node_id,
                 signed.message.sender()
             );
+            self.record_misbehaviour(&from, Offence::InvalidSignature)
+                .await;
             return Ok(());
         }
 
+        // 超大的库存公告不处理也不转发，分页发送的库存每页远小于上限
+        if let GossipMessage::RepoAnnouncement(ra) = &signed.message {
+            if ra.repos.len() > MAX_INVENTORY_REPOS {
+                tracing::warn!(
+                    "Rejected RepoAnnouncement from {} via {} with {} repos",
+                    signed.node_id,
+                    from,
+                    ra.repos.len()
+                );
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                self.record_misbehaviour(&from, Offence::OversizedInventory)
+                    .await;
+                return Ok(());
+            }
+        }
+
+        // 过期、来自未来或不比已接受公告更新的消息不处理也不转发；
+        // 诚实节点也会转发延迟到达或经其他路径先到的旧公告，这些消息直接丢弃，不计为违规；
+        // 签名者本人直连发来旧公告时，只能是重放，记为轻微违规
+        let checked = {
+            let mut replay = self.replay.lock().await;
+            replay
+                .check(&signed, timestamp_now())
+                .map(|()| replay.accept(&signed))
+        };
+        let latest = match checked {
+            Ok(latest) => latest,
+            Err(reason) => {
+                tracing::debug!(
+                    "Rejected {} from {} via {}: {}",
+                    signed.message_type(),
+                    signed.node_id,
+                    from,
+                    reason
+                );
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                if matches!(reason, Rejection::Stale { .. }) && from == signed.node_id {
+                    self.record_misbehaviour(&from, Offence::Replay).await;
+                }
+                return Ok(());
+            }
+        };
+        if let Some((kind, latest)) = latest {
+            if let Err(e) = sequence_model::save_sequence(
+                &signed.node_id,
+                &kind,
+                latest.sequence,
+                latest.timestamp,
+            )
+            .await
+            {
+                tracing::warn!("Failed to save gossip sequence: {}", e);
+            }
+        }
+        self.dedup.lock().await.accept(hash, timestamp_now());
+        self.counters.delivered.fetch_add(1, Ordering::Relaxed);
+
+        // forward if ttl > 0
+        if ttl > 0 {
+            self.push(&id, signed.clone(), ttl - 1, Some(&from), false)
+                .await;
+        } else {
+            self.tree.lock().await.delivered(
+                &id,
+                Envelope {
+                    payload: signed.clone(),
+                    ttl,
+                },
+                Some(&from),
+            );
+        }
+
         // process message (borrow the inner message to avoid moving)
         match &signed.message {
             GossipMessage::NodeAnnouncement(na) => {
@@ -191,6 +900,17 @@ impl GossipService {
                 if let Err(e) = node_model::save_node_info_to_db(&node_info).await {
                     tracing::warn!("Failed to save node info to db: {}", e);
                 }
+
+                self.transport
+                    .routing()
+                    .lock()
+                    .await
+                    .observe(&na.node_id, &na.addresses);
+
+                // 中继节点可用于连接无法直连的节点，并为本节点预约入站电路
+                if na.node_type == NodeType::Relay {
+                    self.transport.add_relay(na.node_id.clone()).await;
+                }
             }
             GossipMessage::RepoAnnouncement(ra) => {
                 tracing::info!(
@@ -199,188 +919,51 @@ impl GossipService {
                     ra.repos.len(),
                     ra.repos.iter().map(|r| &r.repo_id).collect::<Vec<_>>()
                 );
-                // 将每个 repo 保存到数据库
+                // 将订阅了主题的 repo 保存到数据库；refs 以所有者的签名为准，
+                // 旧版本的所有者直接公告时没有签名，以消息序号合并
                 for repo in &ra.repos {
-                    // 检查仓库是否已存在
-                    match crate::storage::repo_model::load_repo_from_db(&repo.repo_id).await {
-                        Ok(Some(local_repo)) => {
-                            // 如果是本地仓库，不更新
-                            if !local_repo.is_external {
-                                tracing::debug!(
-                                    "Repo {} is a local repository, skipping update",
-                                    &repo.repo_id
-                                );
-                                continue;
-                            }
-
-                            // Repo 已存在，检查是否需要更新
-                            tracing::debug!(
-                                "Repo {} already exists, checking if update needed",
-                                &repo.repo_id
-                            );
-
-                            // 比较 refs：从 bundle 中提取本地 refs
-                            let local_refs = if !local_repo.bundle.as_os_str().is_empty() {
-                                // Bundle 存在，从 bundle 中提取 refs
-                                let bundle_path = local_repo.bundle.to_string_lossy().to_string();
-                                match crate::git::pack::extract_bundle_refs(&bundle_path) {
-                                    Ok(refs) => {
-                                        tracing::debug!(
-                                            "Extracted {} refs from bundle for repo {}",
-                                            refs.len(),
-                                            &repo.repo_id
-                                        );
-                                        refs
-                                    }
-                                    Err(e) => {
-                                        tracing::warn!(
-                                            "Failed to extract refs from bundle for repo {}: {}",
-                                            &repo.repo_id,
-                                            e
-                                        );
-                                        // 如果 bundle 提取失败，从数据库读取 refs 作为备份
-                                        match crate::storage::ref_model::load_refs_for_repo(
-                                            &repo.repo_id,
-                                        )
-                                        .await
-                                        {
-                                            Ok(refs) => refs,
-                                            Err(e2) => {
-                                                tracing::warn!(
-                                                    "Failed to load local refs for repo {}: {}",
-                                                    &repo.repo_id,
-                                                    e2
-                                                );
-                                                continue;
-                                            }
-                                        }
-                                    }
-                                }
-                            } else {
-                                // Bundle 不存在，从数据库读取 refs
-                                match crate::storage::ref_model::load_refs_for_repo(&repo.repo_id)
-                                    .await
-                                {
-                                    Ok(refs) => refs,
-                                    Err(e) => {
-                                        tracing::warn!(
-                                            "Failed to load local refs for repo {}: {}",
-                                            &repo.repo_id,
-                                            e
-                                        );
-                                        continue;
-                                    }
-                                }
-                            };
-
-                            // 检查 2：如果远端 refs 与本地相同，不更新
-                            if local_refs == repo.refs {
-                                tracing::debug!("Repo {} refs are up-to-date", &repo.repo_id);
-                                continue;
-                            }
-
-                            // 有新的 refs 更新，清空 bundle 等待重新同步
-                            tracing::info!(
-                                "Detected ref updates for repo {} from node {}. local refs: {:?}, remote refs: {:?}",
-                                &repo.repo_id,
-                                ra.node_id,
-                                local_refs,
-                                repo.refs
-                            );
-
-                            // 删除旧的 bundle 文件
-                            if !local_repo.bundle.as_os_str().is_empty() {
-                                let bundle_path = local_repo.bundle.to_string_lossy().to_string();
-                                match tokio::fs::remove_file(&bundle_path).await {
-                                    Ok(_) => {
-                                        tracing::info!(
-                                            "Deleted outdated bundle for repo {}",
-                                            &repo.repo_id
-                                        );
-                                    }
-                                    Err(e) => {
-                                        tracing::warn!(
-                                            "Failed to delete bundle file {}: {}",
-                                            bundle_path,
-                                            e
-                                        );
-                                    }
-                                }
-                            }
-
-                            // 清空旧的 refs 并添加最新的 refs
-                            if let Err(e) =
-                                crate::storage::ref_model::delete_refs_for_repo(&repo.repo_id).await
-                            {
-                                tracing::warn!(
-                                    "Failed to delete refs for repo {}: {}",
-                                    &repo.repo_id,
-                                    e
-                                );
-                            }
-
-                            // 添加最新的 refs
-                            if let Err(e) = crate::storage::ref_model::batch_save_refs(
-                                &repo.repo_id,
-                                &repo.refs,
-                            )
+                    let sequence = if repo.p2p_description.creator == ra.node_id.to_string() {
+                        signed.sequence
+                    } else {
+                        0
+                    };
+                    if let Err(e) =
+                        merge_subscribed_repo(repo, &ra.node_id, sequence, &self.subscriptions)
                             .await
-                            {
-                                tracing::warn!(
-                                    "Failed to save new refs for repo {}: {}",
-                                    &repo.repo_id,
-                                    e
-                                );
-                            } else {
-                                tracing::info!(
-                                    "Updated refs for repo {} with {} new refs",
-                                    &repo.repo_id,
-                                    repo.refs.len()
-                                );
-                            }
-
-                            // 更新 repo 表：清空 bundle 字段
-                            if let Err(e) =
-                                crate::storage::repo_model::update_repo_bundle(&repo.repo_id, "")
-                                    .await
-                            {
-                                tracing::warn!(
-                                    "Failed to clear bundle for repo {}: {}",
-                                    &repo.repo_id,
-                                    e
-                                );
-                            }
-
-                            tracing::info!(
-                                "Cleared bundle and refs for repo {}, waiting for automatic sync",
-                                &repo.repo_id
-                            );
-                        }
-                        Ok(None) => {
-                            // Repo 不存在，插入为 external repo
-                            tracing::debug!("Repo {} is new, adding as external", &repo.repo_id);
-                            let mut new_repo = repo.clone();
-                            new_repo.is_external = true;
-                            if let Err(e) =
-                                crate::storage::repo_model::save_repo_to_db(&new_repo).await
-                            {
-                                tracing::warn!(
-                                    "Failed to save remote repo {} to db: {}",
-                                    &repo.repo_id,
-                                    e
-                                );
-                            }
-                        }
-                        Err(e) => {
-                            tracing::warn!("Failed to load repo {}: {}", &repo.repo_id, e);
+                    {
+                        tracing::warn!("Failed to merge repo {}: {}", &repo.repo_id, e);
+                    }
+                }
+            }
+            GossipMessage::RefUpdate(update) => {
+                tracing::info!(
+                    "Gossip: RefUpdate from {} for repo {}: {} {:?} -> {:?}",
+                    update.node_id,
+                    update.repo_id,
+                    update.ref_name,
+                    update.old_oid,
+                    update.new_oid
+                );
+                match apply_ref_update(update).await {
+                    Ok(true) => {
+                        if let Some(sync_requests) = &self.sync_requests {
+                            let _ = sync_requests.send(update.repo_id.clone());
                         }
                     }
+                    Ok(false) => {}
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to apply ref update for repo {}: {}",
+                            update.repo_id,
+                            e
+                        );
+                    }
                 }
             }
             GossipMessage::Chat(c) => {
                 if let Err(e) = crate::chat::service::process_incoming_chat(
                     c.clone(),
-                    self.manager.clone(),
+                    self.transport.clone(),
                     self.node.clone(),
                 )
                 .await
@@ -391,7 +974,7 @@ impl GossipService {
             GossipMessage::ChatAck(ack) => {
                 if let Err(e) = crate::chat::service::process_ack(
                     ack.clone(),
-                    self.manager.clone(),
+                    self.transport.clone(),
                     self.node.clone(),
                 )
                 .await
@@ -401,24 +984,6 @@ impl GossipService {
             }
         }
 
-        // forward if ttl > 0
-        if ttl > 0 {
-            ttl -= 1;
-            let fwd = Envelope {
-                payload: signed.clone(),
-                ttl,
-            };
-            let data = serde_json::to_vec(&fwd).unwrap_or_default();
-            let mgr = self.manager.lock().await;
-            let peers = mgr.list_peers().await;
-            for peer in peers {
-                if peer == from {
-                    continue;
-                }
-                let _ = mgr.send_gossip_message(peer.clone(), data.clone()).await;
-            }
-        }
-
         Ok(())
     }
 }
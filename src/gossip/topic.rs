@@ -0,0 +1,234 @@
+//! 主题订阅
+//!
+//! 节点订阅自己关心的主题（某个仓库、某个节点创建的所有仓库、新出现的仓库），
+//! 并把订阅的主题告诉邻居：
+//!
+//! - 仓库相关的消息（库存公告、RefUpdate）只转发给订阅了其中任一主题的邻居；
+//! - 没有声明订阅的邻居（旧版本节点、尚未收到其订阅）照常接收所有消息；
+//! - 节点公告和聊天消息不属于任何主题，发给所有邻居。
+//!
+//! 本节点的订阅保存在 `subscriptions` 表，由 `repo follow` / `repo unfollow` 修改。
+use crate::node::node_id::NodeId;
+use crate::repo::repo::Repo;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub type SharedSubscriptions = Arc<Mutex<Subscriptions>>;
+
+/// gossip 主题
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Topic {
+    /// 单个仓库的 ref 更新和库存条目
+    Repo(String),
+    /// 某个节点创建的所有仓库
+    Creator(NodeId),
+    /// 网络中新出现的仓库，订阅后保存所有公告的仓库
+    NewRepos,
+}
+
+impl Topic {
+    /// 仓库的库存条目所属的主题
+    pub fn of_repo(repo: &Repo) -> Vec<Topic> {
+        vec![
+            Topic::Repo(repo.repo_id.clone()),
+            Topic::Creator(NodeId(repo.p2p_description.creator.clone())),
+            Topic::NewRepos,
+        ]
+    }
+
+    /// 节点未修改过订阅时的默认订阅：只发现新仓库，不关注任何仓库
+    pub fn defaults() -> HashSet<Topic> {
+        HashSet::from([Topic::NewRepos])
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Topic::Repo(repo_id) => write!(f, "repo:{}", repo_id),
+            Topic::Creator(node_id) => write!(f, "creator:{}", node_id),
+            Topic::NewRepos => write!(f, "new-repos"),
+        }
+    }
+}
+
+impl FromStr for Topic {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "new-repos" {
+            return Ok(Topic::NewRepos);
+        }
+        match s.split_once(':') {
+            Some(("repo", repo_id)) if !repo_id.is_empty() => Ok(Topic::Repo(repo_id.to_string())),
+            Some(("creator", node_id)) => Ok(Topic::Creator(NodeId::from_string(node_id)?)),
+            _ => Err(anyhow!("invalid topic: {}", s)),
+        }
+    }
+}
+
+/// 本节点和各邻居订阅的主题
+#[derive(Debug, Clone)]
+pub struct Subscriptions {
+    local: HashSet<Topic>,
+    peers: HashMap<NodeId, HashSet<Topic>>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self::new(Topic::defaults())
+    }
+}
+
+impl Subscriptions {
+    pub fn new(local: impl IntoIterator<Item = Topic>) -> Self {
+        Self {
+            local: local.into_iter().collect(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn shared(self) -> SharedSubscriptions {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// 本节点订阅的主题，按名称排序
+    pub fn local(&self) -> Vec<Topic> {
+        let mut topics: Vec<Topic> = self.local.iter().cloned().collect();
+        topics.sort_by_key(|topic| topic.to_string());
+        topics
+    }
+
+    /// 替换本节点的订阅，返回订阅是否变化
+    pub fn set_local(&mut self, topics: impl IntoIterator<Item = Topic>) -> bool {
+        let topics: HashSet<Topic> = topics.into_iter().collect();
+        if topics == self.local {
+            return false;
+        }
+        self.local = topics;
+        true
+    }
+
+    /// 本节点是否订阅了其中任一主题
+    pub fn wants(&self, topics: &[Topic]) -> bool {
+        topics.iter().any(|topic| self.local.contains(topic))
+    }
+
+    /// 本节点是否关注该仓库：直接关注，或关注了它的创建者
+    pub fn follows(&self, repo: &Repo) -> bool {
+        Topic::of_repo(repo)
+            .iter()
+            .filter(|topic| **topic != Topic::NewRepos)
+            .any(|topic| self.local.contains(topic))
+    }
+
+    /// 记录邻居声明的订阅，替换之前的声明
+    pub fn set_peer(&mut self, peer: NodeId, topics: Vec<Topic>) {
+        self.peers.insert(peer, topics.into_iter().collect());
+    }
+
+    pub fn remove_peer(&mut self, peer: &NodeId) {
+        self.peers.remove(peer);
+    }
+
+    /// 邻居声明的订阅，未声明时返回 None
+    pub fn peer(&self, peer: &NodeId) -> Option<&HashSet<Topic>> {
+        self.peers.get(peer)
+    }
+
+    /// 属于 `topics` 的消息是否应发给 `peer`：不属于任何主题的消息和未声明订阅的邻居总是发送
+    pub fn forwards_to(&self, peer: &NodeId, topics: &[Topic]) -> bool {
+        if topics.is_empty() {
+            return true;
+        }
+        match self.peers.get(peer) {
+            Some(subscribed) => topics.iter().any(|topic| subscribed.contains(topic)),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+    use crate::repo::repo::P2PDescription;
+    use std::path::PathBuf;
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    fn repo(repo_id: &str, creator: &NodeId) -> Repo {
+        Repo::new(
+            repo_id.to_string(),
+            P2PDescription {
+                creator: creator.to_string(),
+                name: repo_id.to_string(),
+                description: String::new(),
+                language: String::new(),
+                latest_commit_at: 0,
+                size: 0,
+            },
+            PathBuf::new(),
+        )
+    }
+
+    #[test]
+    fn test_topic_round_trip() {
+        let creator = node_id();
+        for topic in [
+            Topic::Repo("did:repo:abc".to_string()),
+            Topic::Creator(creator),
+            Topic::NewRepos,
+        ] {
+            assert_eq!(topic.to_string().parse::<Topic>().unwrap(), topic);
+        }
+        assert!("repo:".parse::<Topic>().is_err());
+        assert!("creator:not-a-node".parse::<Topic>().is_err());
+        assert!("everything".parse::<Topic>().is_err());
+    }
+
+    #[test]
+    fn test_follows_repo_or_creator() {
+        let alice = node_id();
+        let bob = node_id();
+        let subscriptions = Subscriptions::new([
+            Topic::Repo("did:repo:a".to_string()),
+            Topic::Creator(bob.clone()),
+            Topic::NewRepos,
+        ]);
+
+        assert!(subscriptions.follows(&repo("did:repo:a", &alice)));
+        assert!(subscriptions.follows(&repo("did:repo:b", &bob)));
+        // 只订阅新仓库不算关注
+        assert!(!subscriptions.follows(&repo("did:repo:c", &alice)));
+        assert!(subscriptions.wants(&Topic::of_repo(&repo("did:repo:c", &alice))));
+    }
+
+    #[test]
+    fn test_forwards_only_to_interested_peers() {
+        let creator = node_id();
+        let (follower, other, legacy) = (node_id(), node_id(), node_id());
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.set_peer(follower.clone(), vec![Topic::Creator(creator.clone())]);
+        subscriptions.set_peer(other.clone(), vec![]);
+
+        let topics = vec![
+            Topic::Repo("did:repo:a".to_string()),
+            Topic::Creator(creator),
+        ];
+        assert!(subscriptions.forwards_to(&follower, &topics));
+        assert!(!subscriptions.forwards_to(&other, &topics));
+        assert!(subscriptions.forwards_to(&legacy, &topics));
+        assert!(subscriptions.forwards_to(&other, &[]));
+
+        subscriptions.remove_peer(&other);
+        assert!(subscriptions.forwards_to(&other, &topics));
+    }
+}
@@ -3,7 +3,7 @@ use chacha20poly1305::{
     aead::{Aead, KeyInit},
     ChaCha20Poly1305, Nonce,
 };
-use curve25519_dalek::{edwards::CompressedEdwardsY, montgomery::MontgomeryPoint, scalar::Scalar};
+use curve25519_dalek::{edwards::CompressedEdwardsY, montgomery::MontgomeryPoint};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use rand_core::OsRng;
 use rand_core::RngCore;
@@ -76,14 +76,13 @@ impl KeyPair {
         scalar_bytes[31] &= 127;
         scalar_bytes[31] |= 64;
 
-        let ephemeral_scalar = Scalar::from_bits(scalar_bytes);
-        let ephemeral_point = MontgomeryPoint::mul_base(&ephemeral_scalar);
+        let ephemeral_point = MontgomeryPoint::mul_base_clamped(scalar_bytes);
 
         // 3. Keep Ephemeral Public Key
         let ephemeral_pk_bytes = ephemeral_point.to_bytes();
 
         // 4. Calculate Shared Secret: ephemeral_secret * recipient_public
-        let shared_secret_point = ephemeral_scalar * recipient_mont_point;
+        let shared_secret_point = recipient_mont_point.mul_clamped(scalar_bytes);
         let shared_secret_bytes = shared_secret_point.to_bytes();
 
         // 5. Derive Encryption Key (Hash)
@@ -93,21 +92,21 @@ impl KeyPair {
         hasher.update(recipient_mont_point.to_bytes());
         let key_hash = hasher.finalize();
 
-        let key = chacha20poly1305::Key::from_slice(&key_hash);
-        let cipher = ChaCha20Poly1305::new(key);
+        let key: [u8; 32] = key_hash.into();
+        let cipher = ChaCha20Poly1305::new(&key.into());
 
         // 6. Encrypt
         let mut nonce_bytes = [0u8; 12];
         OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = chacha20poly1305::Nonce::from_slice(&nonce_bytes);
+        let nonce = Nonce::from(nonce_bytes);
         let ciphertext = cipher
-            .encrypt(nonce, message)
+            .encrypt(&nonce, message)
             .map_err(|e| anyhow!("Encryption failed: {}", e))?;
 
         // 7. Pack: EphemeralPK (32) + Nonce (12) + Ciphertext
         let mut result = Vec::with_capacity(32 + 12 + ciphertext.len());
         result.extend_from_slice(&ephemeral_pk_bytes);
-        result.extend_from_slice(nonce);
+        result.extend_from_slice(&nonce);
         result.extend_from_slice(&ciphertext);
 
         Ok(result)
@@ -135,8 +134,6 @@ impl KeyPair {
         clamped[31] &= 127;
         clamped[31] |= 64;
 
-        let my_scalar = Scalar::from_bits(clamped);
-
         // 2. Parse Payload
         let ephemeral_pk_bytes = &payload[0..32];
         let nonce_bytes = &payload[32..44];
@@ -145,7 +142,7 @@ impl KeyPair {
         let ephemeral_point = MontgomeryPoint(ephemeral_pk_bytes.try_into()?);
 
         // 3. Calculate Shared Secret: my_secret * ephemeral_public
-        let shared_secret_point = my_scalar * ephemeral_point;
+        let shared_secret_point = ephemeral_point.mul_clamped(clamped);
         let shared_secret_bytes = shared_secret_point.to_bytes();
 
         // 4. Derive Key
@@ -161,13 +158,14 @@ impl KeyPair {
         hasher.update(my_mont_point.to_bytes());
         let key_hash = hasher.finalize();
 
-        let key = chacha20poly1305::Key::from_slice(&key_hash);
-        let cipher = ChaCha20Poly1305::new(key);
-        let nonce = Nonce::from_slice(nonce_bytes);
+        let key: [u8; 32] = key_hash.into();
+        let cipher = ChaCha20Poly1305::new(&key.into());
+        let nonce_bytes: [u8; 12] = nonce_bytes.try_into()?;
+        let nonce = Nonce::from(nonce_bytes);
 
         // 5. Decrypt
         let plaintext = cipher
-            .decrypt(nonce, ciphertext)
+            .decrypt(&nonce, ciphertext)
             .map_err(|e| anyhow!("Decryption failed: {}", e))?;
 
         Ok(plaintext)
@@ -1,5 +1,6 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
 
 mod cli;
 use cli::{handle_auth, handle_node, handle_repo};
@@ -56,17 +57,42 @@ enum NodeAction {
         /// node alias
         #[arg(long, default_value = "mega-node")]
         alias: String,
-        /// one or more listen/announce addresses, e.g. 0.0.0.0:9000
-        #[arg(short, long, default_value = "0.0.0.0:9000")]
-        addr: String,
+        /// one or more listen addresses, comma separated, e.g. 0.0.0.0:9000,[::]:9000
+        #[arg(short, long, value_delimiter = ',', default_value = "0.0.0.0:9000")]
+        addr: Vec<SocketAddr>,
 
-        #[arg(short, long, default_value = "cert")]
-        cert_path: String,
+        /// addresses announced to other nodes, comma separated (defaults to the listen addresses)
+        #[arg(long, value_delimiter = ',')]
+        announce: Vec<SocketAddr>,
+
+        /// Announce loopback addresses, for test networks on a single machine
+        #[arg(long, default_value = "false")]
+        allow_local_addrs: bool,
 
         /// Bootstrap node address to connect to on startup (e.g., 127.0.0.1:9000)
         #[arg(long)]
         bootstrap_node: Option<String>,
 
+        /// Size of the gossip active view; missing neighbors are dialed from the passive view and known nodes
+        #[arg(long, default_value_t = megaengine::node::peer_manager::DEFAULT_TARGET_PEERS)]
+        target_peers: usize,
+
+        /// Maximum number of seconds a gossip message timestamp may be ahead of the local clock
+        #[arg(long, default_value_t = 60)]
+        max_clock_skew: u64,
+
+        /// Run as a relay node, forwarding traffic for peers that cannot connect directly
+        #[arg(long, default_value = "false")]
+        relay: bool,
+
+        /// Maximum number of simultaneous inbound connections
+        #[arg(long)]
+        max_connections: Option<usize>,
+
+        /// Maximum number of simultaneous inbound connections from one IP address
+        #[arg(long)]
+        max_connections_per_ip: Option<usize>,
+
         /// Deprecated for node start: stdio MCP must run as a separate process via `megaengine mcp`
         #[arg(long, default_value = "false")]
         mcp: bool,
@@ -74,9 +100,39 @@ enum NodeAction {
         /// Start MCP SSE server on the specified port (e.g., 3001)
         #[arg(long)]
         mcp_sse_port: Option<u16>,
+
+        /// Serve Prometheus metrics on 127.0.0.1:<port>/metrics
+        #[arg(long)]
+        metrics_port: Option<u16>,
     },
     /// Print node id using stored keypair
     Id,
+    /// Show the routing table of the running node
+    Routes,
+    /// Show per-peer connection and traffic statistics of the running node
+    Peers,
+    /// List banned peers
+    Bans,
+    /// Ban a peer; the running node disconnects it and refuses its connections
+    Ban {
+        /// Node ID of the peer
+        #[arg(long)]
+        node_id: String,
+
+        /// Ban duration in seconds (permanent if omitted)
+        #[arg(long)]
+        duration: Option<u64>,
+
+        /// Reason recorded with the ban
+        #[arg(long, default_value = "banned by operator")]
+        reason: String,
+    },
+    /// Lift the ban on a peer
+    Unban {
+        /// Node ID of the peer
+        #[arg(long)]
+        node_id: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -92,7 +148,21 @@ enum RepoAction {
         description: String,
     },
     /// List all repositories
-    List,
+    List {
+        /// Only list local and followed repositories, hiding ones that were merely seen
+        #[arg(long, default_value = "false")]
+        followed: bool,
+    },
+    /// Follow a repository, a creator or new repositories, so the node receives their updates
+    Follow {
+        #[command(flatten)]
+        topic: TopicArgs,
+    },
+    /// Stop following a repository, a creator or new repositories
+    Unfollow {
+        #[command(flatten)]
+        topic: TopicArgs,
+    },
     /// Update repository from bundle (like git pull)
     Pull {
         /// Repository ID
@@ -108,6 +178,23 @@ enum RepoAction {
     },
 }
 
+/// The topic selected by `repo follow` / `repo unfollow`
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct TopicArgs {
+    /// Repository ID
+    #[arg(long)]
+    repo_id: Option<String>,
+
+    /// Node ID of a creator, to follow all of its repositories
+    #[arg(long)]
+    creator: Option<String>,
+
+    /// Repositories announced for the first time (followed by default)
+    #[arg(long, default_value = "false")]
+    new_repos: bool,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let _ = rustls::crypto::ring::default_provider().install_default();
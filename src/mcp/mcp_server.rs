@@ -1,4 +1,4 @@
-use crate::{git::pack, storage};
+use crate::{git::pack, gossip::topic::Subscriptions, storage};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -39,7 +39,7 @@ impl RepoMcpServer {
         vec![
             json!({
                 "name": "list_repos",
-                "description": "List all repositories with their details and refs",
+                "description": "List all repositories with their details, refs and whether this node follows them",
                 "inputSchema": {
                     "type": "object",
                     "properties": {},
@@ -78,6 +78,24 @@ impl RepoMcpServer {
                     "required": ["repo_id", "output_path"]
                 }
             }),
+            json!({
+                "name": "list_routes",
+                "description": "List the node's routing table: known peers, addresses, latency and scores",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {},
+                    "required": []
+                }
+            }),
+            json!({
+                "name": "list_peer_stats",
+                "description": "Per-peer connection and traffic statistics: RTT, connection age, messages and bytes per channel, failures",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {},
+                    "required": []
+                }
+            }),
         ]
     }
 
@@ -102,11 +120,14 @@ impl RepoMcpServer {
                     .ok_or_else(|| anyhow::anyhow!("Missing output_path parameter"))?;
                 Self::clone_repo(repo_id, output_path).await
             }
+            "list_routes" => Self::list_routes().await,
+            "list_peer_stats" => Self::list_peer_stats().await,
             _ => Err(anyhow::anyhow!("Unknown tool: {}", name)),
         }
     }
 
     async fn list_repos() -> Result<Value> {
+        let subscriptions = Subscriptions::new(storage::subscription_model::load_topics().await?);
         match storage::repo_model::list_repos().await {
             Ok(repos) => {
                 let repo_list: Vec<Value> = repos
@@ -122,6 +143,8 @@ impl RepoMcpServer {
                             "path": repo.path.display().to_string(),
                             "bundle": repo.bundle.display().to_string(),
                             "latest_commit_at": repo.p2p_description.latest_commit_at,
+                            // 外部仓库只有关注后才同步 bundle
+                            "followed": !repo.is_external || subscriptions.follows(repo),
                         });
 
                         // 恢复 refs 处理逻辑
@@ -256,6 +279,43 @@ impl RepoMcpServer {
             Err(e) => Err(e),
         }
     }
+
+    async fn list_routes() -> Result<Value> {
+        let routes = storage::routing_model::list_routes().await?;
+        let route_list: Vec<Value> = routes
+            .iter()
+            .map(|route| {
+                json!({
+                    "node_id": route.node_id,
+                    "addresses": serde_json::from_str::<Value>(&route.addresses)
+                        .unwrap_or(Value::Null),
+                    "connected": route.connected,
+                    "latency_ms": route.latency_ms,
+                    "successes": route.successes,
+                    "failures": route.failures,
+                    "misbehaviour": route.misbehaviour,
+                    "score": route.score,
+                    "last_seen": route.last_seen,
+                })
+            })
+            .collect();
+        Ok(json!({
+           "content": [{
+               "type": "text",
+               "text": serde_json::to_string(&route_list)?
+           }]
+        }))
+    }
+
+    async fn list_peer_stats() -> Result<Value> {
+        let peers = storage::peer_stats_model::list_peer_stats().await?;
+        Ok(json!({
+           "content": [{
+               "type": "text",
+               "text": serde_json::to_string(&peers)?
+           }]
+        }))
+    }
 }
 
 pub async fn start_mcp_server() -> Result<()> {
@@ -1,12 +1,13 @@
 use crate::mcp::mcp_server::RepoMcpServer;
+use crate::node::shutdown::Shutdown;
 use axum::{
     extract::{Query, State},
+    http::Method,
     response::{
         sse::{Event, Sse},
         IntoResponse,
     },
     routing::{get, post},
-    http::Method,
     Json, Router,
 };
 use futures::stream::Stream;
@@ -52,7 +53,8 @@ impl Drop for SessionCleanup {
     }
 }
 
-pub async fn start_sse_server(addr: SocketAddr) -> anyhow::Result<()> {
+/// Serves MCP over SSE until the node shuts down.
+pub async fn start_sse_server(addr: SocketAddr, shutdown: Shutdown) -> anyhow::Result<()> {
     let state = Arc::new(AppState {
         sessions: RwLock::new(HashMap::new()),
     });
@@ -70,7 +72,9 @@ pub async fn start_sse_server(addr: SocketAddr) -> anyhow::Result<()> {
 
     tracing::info!("MCP SSE Server listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.token().cancelled_owned())
+        .await?;
 
     Ok(())
 }
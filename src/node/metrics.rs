@@ -0,0 +1,336 @@
+//! Prometheus 格式的指标导出
+//!
+//! `node start --metrics-port <port>` 在 `127.0.0.1:<port>/metrics` 上提供各节点的连接和流量统计
+//! （见 [`PeerStats`]）、连接限制的触发次数（见 [`LimitStats`]）以及 gossip 去重记录的命中情况
+//! （见 [`DedupStats`]），每次请求时实时采集。
+use crate::gossip::dedup::{DedupStats, SharedDedup};
+use crate::node::shutdown::Shutdown;
+use crate::transport::limits::LimitStats;
+use crate::transport::quic::ConnectionManager;
+use crate::transport::stats::{ChannelStats, PeerStats};
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+/// 一个指标族：名称、说明、类型和带标签的样本
+struct Family<'a> {
+    out: &'a mut String,
+    name: &'static str,
+}
+
+impl<'a> Family<'a> {
+    fn new(out: &'a mut String, name: &'static str, kind: &str, help: &str) -> Self {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        Self { out, name }
+    }
+
+    fn sample(&mut self, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v))
+            .collect();
+        if labels.is_empty() {
+            let _ = writeln!(self.out, "{} {}", self.name, value);
+        } else {
+            let _ = writeln!(self.out, "{}{{{}}} {}", self.name, labels.join(","), value);
+        }
+    }
+}
+
+/// 从一个通道的统计中取出某个计数
+type ChannelValue = fn(&ChannelStats) -> u64;
+
+/// 以 Prometheus 文本格式输出指标，未运行 gossip 服务时 `dedup` 为 `None`
+pub fn render_prometheus(
+    peers: &[PeerStats],
+    limits: &LimitStats,
+    dedup: Option<&DedupStats>,
+) -> String {
+    let mut out = String::new();
+    let ids: Vec<String> = peers.iter().map(|p| p.node_id.to_string()).collect();
+    let connected = peers.iter().filter(|p| p.connected).count();
+
+    Family::new(
+        &mut out,
+        "megaengine_peers_connected",
+        "gauge",
+        "Number of connected peers",
+    )
+    .sample(&[], connected);
+
+    let mut family = Family::new(
+        &mut out,
+        "megaengine_peer_connected",
+        "gauge",
+        "Whether the peer is currently connected",
+    );
+    for (peer, id) in peers.iter().zip(&ids) {
+        family.sample(&[("peer", id)], u8::from(peer.connected));
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "megaengine_peer_rtt_seconds",
+        "gauge",
+        "Smoothed round-trip time of the current connection",
+    );
+    for (peer, id) in peers.iter().zip(&ids) {
+        if let Some(rtt) = peer.rtt_ms {
+            family.sample(&[("peer", id)], rtt / 1000.0);
+        }
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "megaengine_peer_connection_age_seconds",
+        "gauge",
+        "Time since the current connection was established",
+    );
+    for (peer, id) in peers.iter().zip(&ids) {
+        if let Some(age) = peer.connected_secs {
+            family.sample(&[("peer", id)], age);
+        }
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "megaengine_peer_lost_packets",
+        "gauge",
+        "Packets lost on the current connection",
+    );
+    for (peer, id) in peers.iter().zip(&ids) {
+        if peer.connected {
+            family.sample(&[("peer", id)], peer.lost_packets);
+        }
+    }
+
+    let channel_counters: [(_, _, ChannelValue); 4] = [
+        (
+            "megaengine_peer_messages_sent_total",
+            "Messages sent to the peer",
+            |c| c.messages_sent,
+        ),
+        (
+            "megaengine_peer_bytes_sent_total",
+            "Payload bytes sent to the peer",
+            |c| c.bytes_sent,
+        ),
+        (
+            "megaengine_peer_messages_received_total",
+            "Messages received from the peer",
+            |c| c.messages_received,
+        ),
+        (
+            "megaengine_peer_bytes_received_total",
+            "Payload bytes received from the peer",
+            |c| c.bytes_received,
+        ),
+    ];
+    for (name, help, value) in channel_counters {
+        let mut family = Family::new(&mut out, name, "counter", help);
+        for (peer, id) in peers.iter().zip(&ids) {
+            family.sample(&[("peer", id), ("channel", "gossip")], value(&peer.gossip));
+            family.sample(&[("peer", id), ("channel", "data")], value(&peer.data));
+        }
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "megaengine_peer_send_failures_total",
+        "counter",
+        "Messages that could not be sent to the peer",
+    );
+    for (peer, id) in peers.iter().zip(&ids) {
+        family.sample(&[("peer", id)], peer.send_failures);
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "megaengine_peer_failures_total",
+        "counter",
+        "Failed dials and requests recorded in the routing table",
+    );
+    for (peer, id) in peers.iter().zip(&ids) {
+        family.sample(&[("peer", id)], peer.failures);
+    }
+
+    let mut family = Family::new(
+        &mut out,
+        "megaengine_connections_refused_total",
+        "counter",
+        "Inbound connections refused by connection limits",
+    );
+    family.sample(&[("reason", "total")], limits.connections_refused_total);
+    family.sample(&[("reason", "per_ip")], limits.connections_refused_per_ip);
+
+    Family::new(
+        &mut out,
+        "megaengine_rate_limited_total",
+        "counter",
+        "Times reading from a peer was paused by rate limits",
+    )
+    .sample(&[], limits.rate_limited);
+    Family::new(
+        &mut out,
+        "megaengine_messages_dropped_total",
+        "counter",
+        "Inbound messages dropped because a receive queue was full",
+    )
+    .sample(&[], limits.messages_dropped);
+    Family::new(
+        &mut out,
+        "megaengine_connections_closed_overflow_total",
+        "counter",
+        "Connections closed because a receive queue overflowed",
+    )
+    .sample(&[], limits.connections_closed_overflow);
+
+    if let Some(dedup) = dedup {
+        let mut family = Family::new(
+            &mut out,
+            "megaengine_gossip_dedup_lookups_total",
+            "counter",
+            "Received gossip messages checked against the dedup cache",
+        );
+        family.sample(&[("result", "hit")], dedup.hits);
+        family.sample(&[("result", "miss")], dedup.misses);
+        Family::new(
+            &mut out,
+            "megaengine_gossip_dedup_hit_ratio",
+            "gauge",
+            "Fraction of received gossip messages that were duplicates",
+        )
+        .sample(&[], dedup.hit_rate());
+        Family::new(
+            &mut out,
+            "megaengine_gossip_dedup_entries",
+            "gauge",
+            "Message ids currently held by the dedup cache",
+        )
+        .sample(&[], dedup.len);
+        Family::new(
+            &mut out,
+            "megaengine_gossip_dedup_evicted_total",
+            "counter",
+            "Message ids evicted before their retention because the cache was full",
+        )
+        .sample(&[], dedup.evicted);
+    }
+
+    out
+}
+
+#[derive(Clone)]
+struct MetricsState {
+    manager: ConnectionManager,
+    dedup: Option<SharedDedup>,
+}
+
+async fn metrics_handler(State(state): State<MetricsState>) -> impl IntoResponse {
+    let dedup = match &state.dedup {
+        Some(dedup) => Some(dedup.lock().await.stats()),
+        None => None,
+    };
+    let manager = &state.manager;
+    let body = render_prometheus(
+        &manager.peer_stats().await,
+        &manager.limit_stats(),
+        dedup.as_ref(),
+    );
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    )
+}
+
+/// 在 `addr` 上提供 `/metrics`，直到节点关闭
+pub async fn start_metrics_server(
+    addr: SocketAddr,
+    manager: ConnectionManager,
+    dedup: Option<SharedDedup>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(MetricsState { manager, dedup });
+
+    tracing::info!("Metrics server listening on {}", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.token().cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+    use crate::node::node_id::NodeId;
+    use crate::transport::events::Direction;
+
+    #[test]
+    fn test_render_prometheus() {
+        let node_id = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let peer = PeerStats {
+            node_id: node_id.clone(),
+            connected: true,
+            direction: Some(Direction::Outbound),
+            addr: Some("127.0.0.1:9000".parse().unwrap()),
+            relay: None,
+            rtt_ms: Some(12.5),
+            lost_packets: 3,
+            connected_secs: Some(60),
+            gossip: ChannelStats {
+                messages_sent: 4,
+                bytes_sent: 400,
+                ..Default::default()
+            },
+            data: ChannelStats {
+                bytes_received: 1 << 20,
+                ..Default::default()
+            },
+            send_failures: 1,
+            failures: 2,
+        };
+        let limits = LimitStats {
+            messages_dropped: 7,
+            ..Default::default()
+        };
+
+        let dedup = DedupStats {
+            hits: 1,
+            misses: 3,
+            evicted: 0,
+            len: 3,
+        };
+
+        let text = render_prometheus(&[peer], &limits, Some(&dedup));
+        let id = node_id.to_string();
+        for line in [
+            "# TYPE megaengine_peers_connected gauge".to_string(),
+            "megaengine_peers_connected 1".to_string(),
+            format!("megaengine_peer_rtt_seconds{{peer=\"{}\"}} 0.0125", id),
+            format!(
+                "megaengine_peer_bytes_sent_total{{peer=\"{}\",channel=\"gossip\"}} 400",
+                id
+            ),
+            format!(
+                "megaengine_peer_bytes_received_total{{peer=\"{}\",channel=\"data\"}} 1048576",
+                id
+            ),
+            format!("megaengine_peer_failures_total{{peer=\"{}\"}} 2", id),
+            "megaengine_connections_refused_total{reason=\"per_ip\"} 0".to_string(),
+            "megaengine_messages_dropped_total 7".to_string(),
+            "megaengine_gossip_dedup_lookups_total{result=\"hit\"} 1".to_string(),
+            "megaengine_gossip_dedup_hit_ratio 0.25".to_string(),
+            "megaengine_gossip_dedup_entries 3".to_string(),
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+}
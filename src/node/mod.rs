@@ -1,4 +1,9 @@
 #![allow(clippy::module_inception)]
+pub mod metrics;
 pub mod node;
 pub mod node_addr;
 pub mod node_id;
+pub mod peer_manager;
+pub mod reputation;
+pub mod routing;
+pub mod shutdown;
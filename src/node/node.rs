@@ -1,13 +1,14 @@
 use crate::identity::keypair::KeyPair;
 use crate::node::node_id::NodeId;
 use crate::transport::config::QuicConfig;
+use crate::transport::protocol::PROTOCOL_VERSION;
 use crate::transport::quic::ConnectionManager;
+use crate::transport::transport::Transport;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use tokio::sync::Mutex;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum NodeType {
@@ -29,7 +30,10 @@ pub struct NodeInfo {
 #[derive(Clone)]
 pub struct Node {
     pub info: NodeInfo,
-    pub connection_manager: Option<Arc<Mutex<ConnectionManager>>>,
+    /// QUIC 连接管理器，内部并发安全，克隆只复制句柄
+    pub connection_manager: Option<ConnectionManager>,
+    /// 上层服务使用的传输，启动 QUIC 服务端后指向同一个 `ConnectionManager`
+    pub transport: Option<Arc<dyn Transport>>,
     pub keypair: KeyPair,
 }
 
@@ -38,6 +42,7 @@ impl std::fmt::Debug for Node {
         f.debug_struct("Node")
             .field("info", &self.info)
             .field("connection_manager", &"<ConnectionManager>")
+            .field("transport", &self.transport.as_ref().map(|t| t.local_id()))
             .finish()
     }
 }
@@ -55,11 +60,12 @@ impl Node {
             alias: alias.into(),
             addresses,
             node_type,
-            version: 1,
+            version: PROTOCOL_VERSION,
         };
         Self {
             info,
             connection_manager: None,
+            transport: None,
             keypair,
         }
     }
@@ -81,7 +87,8 @@ impl Node {
     /// 启动 QUIC 服务端
     pub async fn start_quic_server(&mut self, config: QuicConfig) -> Result<()> {
         let manager = ConnectionManager::run_server(config).await?;
-        self.connection_manager = Some(std::sync::Arc::new(tokio::sync::Mutex::new(manager)));
+        self.transport = Some(Arc::new(manager.clone()));
+        self.connection_manager = Some(manager);
         Ok(())
     }
 
@@ -111,13 +118,28 @@ impl Node {
     }
 }
 
+/// 计算延迟得分的参考值：RTT 等于该值时延迟因子为 0.5
+const LATENCY_REFERENCE_MS: f32 = 200.0;
+
+/// 路由表中的一个节点条目
 #[derive(Clone, Debug)]
 pub struct NodeRouting {
     pub node_id: NodeId,
     pub addresses: Vec<SocketAddr>,
     pub last_seen: SystemTime,
     pub ttl: Duration,
+    /// 综合得分，范围 (0, 1]，越高越优先
     pub score: f32,
+    /// 当前是否有直连
+    pub connected: bool,
+    /// 最近一次测得的 RTT
+    pub latency: Option<Duration>,
+    /// 成功交互次数（传输完成、RPC 成功等）
+    pub successes: u32,
+    /// 失败次数（拨号失败、请求失败等）
+    pub failures: u32,
+    /// 违规次数（签名错误、畸形消息等）
+    pub misbehaviour: u32,
 }
 
 impl NodeRouting {
@@ -128,9 +150,30 @@ impl NodeRouting {
             last_seen: SystemTime::now(),
             ttl: Duration::from_secs(60 * 60 * 24),
             score: 1.0,
+            connected: false,
+            latency: None,
+            successes: 0,
+            failures: 0,
+            misbehaviour: 0,
         }
     }
 
+    /// 根据可靠性、延迟和违规记录重新计算得分
+    ///
+    /// - 可靠性：(1 + 成功) / (1 + 成功 + 失败)
+    /// - 延迟：1 / (1 + RTT / 200ms)，未测量时为 1
+    /// - 每次违规得分减半
+    pub fn update_score(&mut self) {
+        let successes = self.successes as f32;
+        let reliability = (1.0 + successes) / (1.0 + successes + self.failures as f32);
+        let latency = self
+            .latency
+            .map(|l| 1.0 / (1.0 + l.as_secs_f32() * 1000.0 / LATENCY_REFERENCE_MS))
+            .unwrap_or(1.0);
+        let penalty = 0.5f32.powi(self.misbehaviour.min(32) as i32);
+        self.score = reliability * latency * penalty;
+    }
+
     pub fn refresh(&mut self) {
         self.last_seen = SystemTime::now();
     }
@@ -235,6 +278,31 @@ mod tests {
         assert!(node_routing.expired()); // Should be expired now
     }
 
+    // Test `NodeRouting::update_score`
+    #[test]
+    fn test_node_routing_score() {
+        let node_id = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let mut node_routing = NodeRouting::new(node_id, vec![]);
+
+        node_routing.update_score();
+        assert_eq!(node_routing.score, 1.0);
+
+        // RTT equal to the reference latency halves the score
+        node_routing.latency = Some(Duration::from_millis(200));
+        node_routing.update_score();
+        assert!((node_routing.score - 0.5).abs() < 1e-6);
+
+        let fast = node_routing.score;
+        node_routing.failures = 1;
+        node_routing.update_score();
+        assert!(node_routing.score < fast);
+
+        let before_misbehaviour = node_routing.score;
+        node_routing.misbehaviour = 1;
+        node_routing.update_score();
+        assert!((node_routing.score - before_misbehaviour / 2.0).abs() < 1e-6);
+    }
+
     // Test the `NodeType` enum
     #[test]
     fn test_node_type_enum() {
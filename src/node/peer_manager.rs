@@ -0,0 +1,427 @@
+//! 对端连接维护
+//!
+//! 定期检查当前连接数，不足目标值时从 `nodes` 表中挑选已知节点拨号。
+//! 拨号失败的节点按指数退避推迟下一次尝试。收到连接断开事件时立即执行一轮维护，
+//! 不必等到下一个周期。
+//!
+//! 启用 HyParView 成员视图后，维护目标变为 active view 的大小：优先从 passive view 中
+//! 挑选节点拨号并请求成为邻居，邻居离开后 overlay 由此自愈。
+use crate::gossip::membership::{self, SharedMembership};
+use crate::gossip::message::PeerEntry;
+use crate::node::node::{NodeInfo, NodeType};
+use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
+use crate::storage::node_model;
+use crate::transport::events::ConnectionEvent;
+use crate::transport::protocol::MIN_PROTOCOL_VERSION;
+use crate::transport::transport::Transport;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// 默认目标连接数
+pub const DEFAULT_TARGET_PEERS: usize = 8;
+const MAINTAIN_INTERVAL: Duration = Duration::from_secs(10);
+/// 单次拨号（含身份握手）的超时时间，避免不可达地址阻塞整轮维护
+const DIAL_TIMEOUT: Duration = Duration::from_secs(20);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// 单个节点的拨号退避状态
+#[derive(Debug, Clone)]
+struct Backoff {
+    failures: u32,
+    next_attempt: Instant,
+}
+
+/// 第 `failures` 次连续失败后需要等待的时间：INITIAL_BACKOFF * 2^(failures-1)，不超过 MAX_BACKOFF
+fn backoff_delay(failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(16);
+    INITIAL_BACKOFF.saturating_mul(1u32 << exp).min(MAX_BACKOFF)
+}
+
+/// 对端连接管理器
+pub struct PeerManager {
+    transport: Arc<dyn Transport>,
+    local_id: NodeId,
+    target_peers: usize,
+    backoff: Mutex<HashMap<NodeId, Backoff>>,
+    membership: Option<SharedMembership>,
+}
+
+impl PeerManager {
+    pub fn new(transport: Arc<dyn Transport>, local_id: NodeId, target_peers: usize) -> Self {
+        Self {
+            transport,
+            local_id,
+            target_peers,
+            backoff: Mutex::new(HashMap::new()),
+            membership: None,
+        }
+    }
+
+    /// 按成员视图维护连接：补足 active view，而不是连接数
+    pub fn with_membership(mut self, membership: SharedMembership) -> Self {
+        self.membership = Some(membership);
+        self
+    }
+
+    /// 启动后台维护任务，节点关闭后不再拨号
+    pub fn start(self: Arc<Self>, shutdown: Shutdown) {
+        let mut events = self.transport.subscribe_events();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(MAINTAIN_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tick.tick() => {}
+                    event = events.recv() => match event {
+                        Ok(ConnectionEvent::Disconnected { node_id }) => {
+                            debug!("Peer {} disconnected, refilling connections", node_id);
+                            if let Some(membership) = &self.membership {
+                                membership.lock().await.peer_down(&node_id);
+                            }
+                        }
+                        Ok(ConnectionEvent::Connected { .. }) => continue,
+                        // 错过了部分事件，维护一轮以确认连接数
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    },
+                }
+                if let Err(e) = self.maintain().await {
+                    warn!("Peer maintenance failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// 执行一轮维护：连接数不足时拨号已知节点，返回新建立的连接数
+    pub async fn maintain(&self) -> Result<usize> {
+        if let Some(membership) = &self.membership {
+            return self.maintain_membership(membership).await;
+        }
+
+        let connected: HashSet<NodeId> = self.transport.list_peers().await.into_iter().collect();
+        if connected.len() >= self.target_peers {
+            return Ok(0);
+        }
+
+        let known = node_model::list_nodes().await?;
+        let candidates = {
+            let backoff = self.backoff.lock().await;
+            select_candidates(
+                known,
+                &connected,
+                &self.local_id,
+                &backoff,
+                Instant::now(),
+                self.target_peers - connected.len(),
+            )
+        };
+        if candidates.is_empty() {
+            return Ok(0);
+        }
+
+        debug!(
+            "Connected to {} peers (target {}), dialing {} known nodes",
+            connected.len(),
+            self.target_peers,
+            candidates.len()
+        );
+
+        let results = futures::future::join_all(
+            candidates
+                .into_iter()
+                .map(|info| async move { self.dial(info.node_id, info.addresses).await.is_ok() }),
+        )
+        .await;
+        Ok(results.into_iter().filter(|ok| *ok).count())
+    }
+
+    /// active view 不足时从 passive view（为空时从 `nodes` 表）挑选节点，请求成为邻居
+    ///
+    /// 已连接的节点直接发送请求；新建立的连接由 gossip 服务在连接事件中发送请求
+    async fn maintain_membership(&self, membership: &SharedMembership) -> Result<usize> {
+        // 拨号失败的节点移出 passive view 后继续尝试其余节点，直到补足 active view 或没有候选
+        let mut tried = HashSet::new();
+        let mut added = 0;
+        loop {
+            let (attempted, succeeded) = self.refill_membership(membership, &mut tried).await?;
+            added += succeeded;
+            if succeeded == attempted {
+                return Ok(added);
+            }
+        }
+    }
+
+    /// 尝试一批候选节点，返回 (尝试数, 成功数)；尝试过的节点记入 `tried`，本轮维护不再重复尝试
+    async fn refill_membership(
+        &self,
+        membership: &SharedMembership,
+        tried: &mut HashSet<NodeId>,
+    ) -> Result<(usize, usize)> {
+        let (needed, active, passive) = {
+            let membership = membership.lock().await;
+            (
+                membership.needed(),
+                membership.active(),
+                membership.passive(),
+            )
+        };
+        if needed == 0 {
+            return Ok((0, 0));
+        }
+
+        let known = node_model::list_nodes().await?;
+        let candidates = if passive.is_empty() {
+            known
+        } else {
+            // 交换视图得到的节点可能还没有公告过，按可互通处理
+            let mut known: HashMap<NodeId, NodeInfo> = known
+                .into_iter()
+                .map(|info| (info.node_id.clone(), info))
+                .collect();
+            passive
+                .into_iter()
+                .map(|entry| {
+                    let mut info = known.remove(&entry.node_id).unwrap_or(NodeInfo {
+                        node_id: entry.node_id,
+                        alias: String::new(),
+                        addresses: Vec::new(),
+                        node_type: NodeType::Normal,
+                        version: MIN_PROTOCOL_VERSION,
+                    });
+                    if !entry.addresses.is_empty() {
+                        info.addresses = entry.addresses;
+                    }
+                    info
+                })
+                .collect()
+        };
+        let mut exclude: HashSet<NodeId> = active.into_iter().collect();
+        exclude.extend(tried.iter().cloned());
+        let candidates = {
+            let backoff = self.backoff.lock().await;
+            select_candidates(
+                candidates,
+                &exclude,
+                &self.local_id,
+                &backoff,
+                Instant::now(),
+                needed,
+            )
+        };
+        if candidates.is_empty() {
+            return Ok((0, 0));
+        }
+        tried.extend(candidates.iter().map(|info| info.node_id.clone()));
+
+        debug!(
+            "Active view needs {} more neighbors, trying {} nodes",
+            needed,
+            candidates.len()
+        );
+
+        let connected: HashSet<NodeId> = self.transport.list_peers().await.into_iter().collect();
+        let results = futures::future::join_all(candidates.into_iter().map(|info| {
+            let connected = connected.contains(&info.node_id);
+            async move {
+                if connected {
+                    let peer = PeerEntry {
+                        node_id: info.node_id,
+                        addresses: info.addresses,
+                    };
+                    return membership::request_neighbor(self.transport.as_ref(), membership, peer)
+                        .await
+                        .is_ok();
+                }
+                let node_id = info.node_id.clone();
+                match self.dial(info.node_id, info.addresses).await {
+                    Ok(()) => true,
+                    Err(_) => {
+                        membership.lock().await.remove_passive(&node_id);
+                        false
+                    }
+                }
+            }
+        }))
+        .await;
+        let succeeded = results.iter().filter(|ok| **ok).count();
+        Ok((results.len(), succeeded))
+    }
+
+    /// 拨号指定节点，并根据结果更新退避状态
+    pub async fn dial(&self, node_id: NodeId, addresses: Vec<SocketAddr>) -> Result<()> {
+        let result = tokio::time::timeout(
+            DIAL_TIMEOUT,
+            self.transport.connect(node_id.clone(), addresses),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("dial to node[{}] timed out", node_id)));
+
+        let mut backoff = self.backoff.lock().await;
+        match &result {
+            Ok(()) => {
+                backoff.remove(&node_id);
+                info!("Connected to peer {}", node_id);
+            }
+            Err(e) => {
+                let failures = backoff.get(&node_id).map(|b| b.failures).unwrap_or(0) + 1;
+                let delay = backoff_delay(failures);
+                debug!(
+                    "Failed to dial peer {} ({} consecutive failures), retry in {:?}: {}",
+                    node_id, failures, delay, e
+                );
+                backoff.insert(
+                    node_id,
+                    Backoff {
+                        failures,
+                        next_attempt: Instant::now() + delay,
+                    },
+                );
+            }
+        }
+        result
+    }
+}
+
+/// 从已知节点中挑选本轮要拨号的节点
+///
+/// 跳过自身、已连接、没有地址以及仍在退避期内的节点
+fn select_candidates(
+    known: Vec<NodeInfo>,
+    connected: &HashSet<NodeId>,
+    local_id: &NodeId,
+    backoff: &HashMap<NodeId, Backoff>,
+    now: Instant,
+    limit: usize,
+) -> Vec<NodeInfo> {
+    let mut candidates: Vec<(u32, NodeInfo)> = known
+        .into_iter()
+        .filter(|info| &info.node_id != local_id)
+        .filter(|info| !connected.contains(&info.node_id))
+        .filter(|info| !info.addresses.is_empty())
+        // 公告的协议版本过旧的节点会在握手时被拒绝，不再拨号
+        .filter(|info| info.version >= MIN_PROTOCOL_VERSION)
+        .filter_map(|info| match backoff.get(&info.node_id) {
+            Some(b) if b.next_attempt > now => None,
+            Some(b) => Some((b.failures, info)),
+            None => Some((0, info)),
+        })
+        .collect();
+
+    // 优先尝试失败次数少的节点
+    candidates.sort_by_key(|(failures, _)| *failures);
+    candidates
+        .into_iter()
+        .take(limit)
+        .map(|(_, info)| info)
+        .collect()
+}
+
+/// 记录 bootstrap 节点，使其在重启后仍可被重新拨号
+///
+/// 节点已存在时保留其通过 NodeAnnouncement 学到的信息，只补充地址
+pub async fn remember_bootstrap_node(node_id: &NodeId, address: SocketAddr) -> Result<()> {
+    let info = match node_model::load_node_info_from_db(&node_id.to_string()).await? {
+        Some(mut info) => {
+            if info.addresses.contains(&address) {
+                return Ok(());
+            }
+            info.addresses.push(address);
+            info
+        }
+        None => NodeInfo {
+            node_id: node_id.clone(),
+            alias: "bootstrap".to_string(),
+            addresses: vec![address],
+            node_type: NodeType::Normal,
+            // 版本未知，按可互通处理，握手时再确认
+            version: MIN_PROTOCOL_VERSION,
+        },
+    };
+    node_model::save_node_info_to_db(&info).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    fn node_info(addresses: Vec<SocketAddr>) -> NodeInfo {
+        NodeInfo {
+            node_id: NodeId::from_keypair(&KeyPair::generate().unwrap()),
+            alias: "test".to_string(),
+            addresses,
+            node_type: NodeType::Normal,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay_grows_and_caps() {
+        assert_eq!(backoff_delay(1), INITIAL_BACKOFF);
+        assert_eq!(backoff_delay(2), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff_delay(3), INITIAL_BACKOFF * 4);
+        assert_eq!(backoff_delay(30), MAX_BACKOFF);
+        assert_eq!(backoff_delay(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_select_candidates() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let local = node_info(vec![addr]);
+        let connected = node_info(vec![addr]);
+        let no_addr = node_info(vec![]);
+        let backing_off = node_info(vec![addr]);
+        let retry_ready = node_info(vec![addr]);
+        let fresh = node_info(vec![addr]);
+        let mut outdated = node_info(vec![addr]);
+        outdated.version = MIN_PROTOCOL_VERSION - 1;
+
+        let now = Instant::now();
+        let mut backoff = HashMap::new();
+        backoff.insert(
+            backing_off.node_id.clone(),
+            Backoff {
+                failures: 1,
+                next_attempt: now + Duration::from_secs(60),
+            },
+        );
+        backoff.insert(
+            retry_ready.node_id.clone(),
+            Backoff {
+                failures: 2,
+                next_attempt: now,
+            },
+        );
+        let connected_set: HashSet<NodeId> = [connected.node_id.clone()].into_iter().collect();
+
+        let known = vec![
+            local.clone(),
+            connected,
+            no_addr,
+            backing_off,
+            retry_ready.clone(),
+            fresh.clone(),
+            outdated,
+        ];
+        let selected = select_candidates(
+            known.clone(),
+            &connected_set,
+            &local.node_id,
+            &backoff,
+            now,
+            10,
+        );
+        assert_eq!(selected, vec![fresh.clone(), retry_ready]);
+
+        let limited = select_candidates(known, &connected_set, &local.node_id, &backoff, now, 1);
+        assert_eq!(limited, vec![fresh]);
+    }
+}
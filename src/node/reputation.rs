@@ -0,0 +1,375 @@
+//! 节点信誉
+//!
+//! 直连节点发送无效签名、无法解析的消息、重放的公告、超大的库存或中途失败的 bundle 传输时扣分，
+//! 得分随时间逐渐恢复。得分低于阈值的节点被断开并临时封禁：封禁期间拒绝其入站连接，也不再拨号。
+//! 封禁记录写入数据库，节点重启后仍然有效；运营者可以用 `node bans` / `node ban` / `node unban`
+//! 查看、添加和解除封禁，运行中的节点定期重新读取。
+use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
+use crate::storage::ban_model;
+use crate::transport::transport::Transport;
+use crate::util::timestamp_now;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// 重新读取封禁记录并断开被封禁节点的周期
+const BAN_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type SharedReputation = Arc<Mutex<Reputation>>;
+
+/// 扣分的违规行为
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offence {
+    /// 签名无效，或签名者与消息声明的发送方不一致
+    InvalidSignature,
+    /// 无法解析的消息
+    MalformedPayload,
+    /// 直连节点重发自己不比已接受的同类公告更新的公告
+    Replay,
+    /// 仓库数超过 [`MAX_INVENTORY_REPOS`](crate::gossip::message::MAX_INVENTORY_REPOS) 的库存公告
+    OversizedInventory,
+    /// 大小与声明不符的 bundle 传输
+    FailedTransfer,
+}
+
+impl Offence {
+    /// 一次违规扣除的分数
+    pub fn penalty(self) -> f64 {
+        match self {
+            Offence::InvalidSignature => 50.0,
+            Offence::MalformedPayload => 20.0,
+            Offence::Replay => 5.0,
+            Offence::OversizedInventory => 50.0,
+            Offence::FailedTransfer => 25.0,
+        }
+    }
+}
+
+impl fmt::Display for Offence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Offence::InvalidSignature => "invalid signature",
+            Offence::MalformedPayload => "malformed payload",
+            Offence::Replay => "replayed message",
+            Offence::OversizedInventory => "oversized inventory",
+            Offence::FailedTransfer => "failed bundle transfer",
+        };
+        f.write_str(s)
+    }
+}
+
+/// 信誉得分的阈值和恢复速度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReputationConfig {
+    /// 得分降到该值及以下时封禁节点，得分从 0 开始
+    pub ban_threshold: f64,
+    /// 自动封禁的时长
+    pub ban_duration: Duration,
+    /// 每分钟向 0 恢复的分数
+    pub recovery_per_minute: f64,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            ban_threshold: -100.0,
+            ban_duration: Duration::from_secs(3600),
+            recovery_per_minute: 5.0,
+        }
+    }
+}
+
+impl ReputationConfig {
+    pub fn with_ban_threshold(mut self, ban_threshold: f64) -> Self {
+        self.ban_threshold = ban_threshold;
+        self
+    }
+
+    pub fn with_ban_duration(mut self, ban_duration: Duration) -> Self {
+        self.ban_duration = ban_duration;
+        self
+    }
+
+    pub fn with_recovery_per_minute(mut self, recovery_per_minute: f64) -> Self {
+        self.recovery_per_minute = recovery_per_minute;
+        self
+    }
+}
+
+/// 一条封禁记录，时间均为 Unix 时间（秒）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub node_id: NodeId,
+    pub reason: String,
+    pub banned_at: i64,
+    /// 到期时间，`None` 表示永久封禁
+    pub expires_at: Option<i64>,
+}
+
+impl Ban {
+    /// 在 `now` 时是否仍然有效
+    pub fn is_active(&self, now: i64) -> bool {
+        self.expires_at.map(|t| t > now).unwrap_or(true)
+    }
+}
+
+/// 节点当前的得分及其更新时间
+#[derive(Debug, Clone, Copy)]
+struct Standing {
+    score: f64,
+    updated: i64,
+}
+
+/// 各直连节点的信誉得分和封禁记录
+#[derive(Debug, Default)]
+pub struct Reputation {
+    config: ReputationConfig,
+    standings: HashMap<NodeId, Standing>,
+    bans: HashMap<NodeId, Ban>,
+}
+
+impl Reputation {
+    pub fn new(config: ReputationConfig) -> Self {
+        Self {
+            config,
+            standings: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    pub fn shared(config: ReputationConfig) -> SharedReputation {
+        Arc::new(Mutex::new(Self::new(config)))
+    }
+
+    pub fn config(&self) -> ReputationConfig {
+        self.config
+    }
+
+    /// 节点在 `now` 时的得分，没有违规记录的节点为 0
+    pub fn score(&self, node_id: &NodeId, now: i64) -> f64 {
+        self.standings
+            .get(node_id)
+            .map(|s| self.recovered(s, now))
+            .unwrap_or(0.0)
+    }
+
+    fn recovered(&self, standing: &Standing, now: i64) -> f64 {
+        let minutes = (now - standing.updated).max(0) as f64 / 60.0;
+        (standing.score + minutes * self.config.recovery_per_minute).min(0.0)
+    }
+
+    /// 记录一次违规，得分降到阈值及以下时封禁节点并返回新的封禁记录
+    ///
+    /// 已被封禁的节点不再重复封禁；封禁后得分清零，封禁到期的节点重新开始计分
+    pub fn penalize(&mut self, node_id: &NodeId, offence: Offence, now: i64) -> Option<Ban> {
+        if self.is_banned(node_id, now) {
+            return None;
+        }
+        let score = self.score(node_id, now) - offence.penalty();
+        if score > self.config.ban_threshold {
+            self.standings.insert(
+                node_id.clone(),
+                Standing {
+                    score,
+                    updated: now,
+                },
+            );
+            return None;
+        }
+        self.standings.remove(node_id);
+        let ban = Ban {
+            node_id: node_id.clone(),
+            reason: format!("score fell to {:.0} after {}", score, offence),
+            banned_at: now,
+            expires_at: Some(now + self.config.ban_duration.as_secs() as i64),
+        };
+        self.bans.insert(node_id.clone(), ban.clone());
+        Some(ban)
+    }
+
+    /// 节点在 `now` 时是否被封禁
+    pub fn is_banned(&self, node_id: &NodeId, now: i64) -> bool {
+        self.bans.get(node_id).is_some_and(|b| b.is_active(now))
+    }
+
+    /// 添加或替换一条封禁记录
+    pub fn ban(&mut self, ban: Ban) {
+        self.standings.remove(&ban.node_id);
+        self.bans.insert(ban.node_id.clone(), ban);
+    }
+
+    /// 解除封禁并清除违规记录，返回被解除的封禁
+    pub fn unban(&mut self, node_id: &NodeId) -> Option<Ban> {
+        self.standings.remove(node_id);
+        self.bans.remove(node_id)
+    }
+
+    /// 在 `now` 时仍然有效的封禁
+    pub fn bans(&self, now: i64) -> Vec<Ban> {
+        self.bans
+            .values()
+            .filter(|b| b.is_active(now))
+            .cloned()
+            .collect()
+    }
+
+    /// 用数据库中的封禁记录替换当前记录，被解除封禁的节点同时清除违规记录
+    pub fn replace_bans(&mut self, bans: Vec<Ban>) {
+        let bans: HashMap<NodeId, Ban> = bans.into_iter().map(|b| (b.node_id.clone(), b)).collect();
+        for node_id in self.bans.keys() {
+            if !bans.contains_key(node_id) {
+                self.standings.remove(node_id);
+            }
+        }
+        self.bans = bans;
+    }
+}
+
+/// 记录直连节点 `node_id` 的一次违规：降低其路由得分和信誉得分，
+/// 得分低于阈值时保存封禁记录并断开连接
+pub async fn report(transport: &dyn Transport, node_id: &NodeId, offence: Offence) {
+    transport
+        .routing()
+        .lock()
+        .await
+        .record_misbehaviour(node_id);
+    let now = timestamp_now();
+    let (ban, score) = {
+        let reputation = transport.reputation();
+        let mut reputation = reputation.lock().await;
+        let ban = reputation.penalize(node_id, offence, now);
+        (ban, reputation.score(node_id, now))
+    };
+    let Some(ban) = ban else {
+        debug!(
+            "Peer {} penalized for {}, score {:.0}",
+            node_id, offence, score
+        );
+        return;
+    };
+    warn!("Banning peer {}: {}", node_id, ban.reason);
+    if let Err(e) = ban_model::save_ban(&ban).await {
+        warn!("Failed to save ban of {}: {}", node_id, e);
+    }
+    transport.disconnect(node_id.clone()).await;
+}
+
+/// 启动后台任务：定期清理到期的封禁，读取 CLI 添加或解除的封禁，并断开仍连接着的被封禁节点
+pub fn start_reputation_task(transport: Arc<dyn Transport>, shutdown: Shutdown) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(BAN_RELOAD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tick.tick() => {}
+            }
+            let now = timestamp_now();
+            if let Err(e) = ban_model::delete_expired(now).await {
+                warn!("Failed to delete expired bans: {}", e);
+            }
+            let bans = match ban_model::list_bans().await {
+                Ok(bans) => bans,
+                Err(e) => {
+                    warn!("Failed to load bans: {}", e);
+                    continue;
+                }
+            };
+            let reputation = transport.reputation();
+            let banned: Vec<NodeId> = {
+                let mut reputation = reputation.lock().await;
+                reputation.replace_bans(bans);
+                transport
+                    .list_peers()
+                    .await
+                    .into_iter()
+                    .filter(|peer| reputation.is_banned(peer, now))
+                    .collect()
+            };
+            for peer in banned {
+                info!("Disconnecting banned peer {}", peer);
+                transport.disconnect(peer).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    #[test]
+    fn test_penalties_lead_to_ban() {
+        let peer = node_id();
+        let mut reputation = Reputation::new(ReputationConfig::default());
+
+        assert_eq!(
+            reputation.penalize(&peer, Offence::InvalidSignature, 0),
+            None
+        );
+        assert_eq!(reputation.score(&peer, 0), -50.0);
+        assert!(!reputation.is_banned(&peer, 0));
+
+        let ban = reputation
+            .penalize(&peer, Offence::InvalidSignature, 0)
+            .unwrap();
+        assert_eq!(ban.expires_at, Some(3600));
+        assert!(reputation.is_banned(&peer, 3599));
+        assert!(!reputation.is_banned(&peer, 3600));
+        // 封禁期间不再重复封禁，到期后重新计分
+        assert_eq!(
+            reputation.penalize(&peer, Offence::InvalidSignature, 10),
+            None
+        );
+        assert_eq!(reputation.score(&peer, 3600), 0.0);
+    }
+
+    #[test]
+    fn test_score_recovers_over_time() {
+        let peer = node_id();
+        let mut reputation = Reputation::new(ReputationConfig::default());
+
+        reputation.penalize(&peer, Offence::OversizedInventory, 0);
+        assert_eq!(reputation.score(&peer, 120), -40.0);
+        assert_eq!(reputation.score(&peer, 3600), 0.0);
+
+        // 偶尔的违规不会导致封禁
+        for round in 0..20 {
+            assert_eq!(
+                reputation.penalize(&peer, Offence::MalformedPayload, 4000 + round * 300),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_replace_bans() {
+        let (kept, lifted, added) = (node_id(), node_id(), node_id());
+        let mut reputation = Reputation::new(ReputationConfig::default());
+        let ban = |node_id: &NodeId| Ban {
+            node_id: node_id.clone(),
+            reason: "test".to_string(),
+            banned_at: 0,
+            expires_at: None,
+        };
+        reputation.ban(ban(&kept));
+        reputation.ban(ban(&lifted));
+
+        reputation.replace_bans(vec![ban(&kept), ban(&added)]);
+        assert!(reputation.is_banned(&kept, i64::MAX));
+        assert!(reputation.is_banned(&added, 0));
+        assert!(!reputation.is_banned(&lifted, 0));
+        assert_eq!(reputation.bans(0).len(), 2);
+
+        assert!(reputation.unban(&kept).is_some());
+        assert!(!reputation.is_banned(&kept, 0));
+    }
+}
@@ -0,0 +1,259 @@
+//! 路由表
+//!
+//! 记录已知节点的地址、连接状态和得分。由 gossip（NodeAnnouncement、违规消息）
+//! 和连接事件（建立、断开、RTT、传输结果）更新，供聊天投递选择下一跳、
+//! bundle 请求选择提供者。路由表定期淘汰过期条目，并把快照写入数据库，
+//! 以便 CLI 和 MCP 在节点进程之外查询。
+use crate::node::node::NodeRouting;
+use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
+use crate::storage::routing_model;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// 路由表快照写入数据库以及淘汰过期条目的周期
+const ROUTING_MAINTAIN_INTERVAL: Duration = Duration::from_secs(30);
+
+pub type SharedRoutingTable = Arc<Mutex<RoutingTable>>;
+
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    entries: HashMap<NodeId, NodeRouting>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared() -> SharedRoutingTable {
+        Arc::new(Mutex::new(Self::new()))
+    }
+
+    pub fn get(&self, node_id: &NodeId) -> Option<&NodeRouting> {
+        self.entries.get(node_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 所有条目，按得分从高到低排序
+    pub fn entries(&self) -> Vec<NodeRouting> {
+        let mut entries: Vec<NodeRouting> = self.entries.values().cloned().collect();
+        entries.sort_by(|a, b| b.score.total_cmp(&a.score));
+        entries
+    }
+
+    fn entry(&mut self, node_id: &NodeId) -> &mut NodeRouting {
+        self.entries
+            .entry(node_id.clone())
+            .or_insert_with(|| NodeRouting::new(node_id.clone(), Vec::new()))
+    }
+
+    /// 通过 gossip 得知节点存在及其地址
+    pub fn observe(&mut self, node_id: &NodeId, addresses: &[SocketAddr]) {
+        let entry = self.entry(node_id);
+        if !addresses.is_empty() {
+            entry.addresses = addresses.to_vec();
+        }
+        entry.refresh();
+    }
+
+    /// 与节点建立了连接，经由中继的连接没有可记录的对端地址
+    pub fn record_connected(&mut self, node_id: &NodeId, addr: Option<SocketAddr>, rtt: Duration) {
+        let entry = self.entry(node_id);
+        if let Some(addr) = addr.filter(|a| !entry.addresses.contains(a)) {
+            entry.addresses.push(addr);
+        }
+        entry.connected = true;
+        entry.latency = Some(rtt);
+        entry.refresh();
+        entry.update_score();
+    }
+
+    /// 与节点的连接已断开
+    pub fn record_disconnected(&mut self, node_id: &NodeId) {
+        if let Some(entry) = self.entries.get_mut(node_id) {
+            entry.connected = false;
+        }
+    }
+
+    /// 更新已连接节点的 RTT
+    pub fn record_latency(&mut self, node_id: &NodeId, rtt: Duration) {
+        let entry = self.entry(node_id);
+        entry.latency = Some(rtt);
+        entry.refresh();
+        entry.update_score();
+    }
+
+    /// 与节点的一次交互成功（传输完成、请求得到应答等）
+    pub fn record_success(&mut self, node_id: &NodeId) {
+        let entry = self.entry(node_id);
+        entry.successes = entry.successes.saturating_add(1);
+        entry.refresh();
+        entry.update_score();
+    }
+
+    /// 与节点的一次交互失败（拨号失败、请求出错等）
+    pub fn record_failure(&mut self, node_id: &NodeId) {
+        let entry = self.entry(node_id);
+        entry.failures = entry.failures.saturating_add(1);
+        entry.update_score();
+    }
+
+    /// 节点发送了无效或恶意的数据
+    pub fn record_misbehaviour(&mut self, node_id: &NodeId) {
+        let entry = self.entry(node_id);
+        entry.misbehaviour = entry.misbehaviour.saturating_add(1);
+        entry.update_score();
+    }
+
+    /// 淘汰过期且未连接的条目，返回被淘汰的节点
+    pub fn evict_expired(&mut self) -> Vec<NodeId> {
+        let expired: Vec<NodeId> = self
+            .entries
+            .values()
+            .filter(|e| !e.connected && e.expired())
+            .map(|e| e.node_id.clone())
+            .collect();
+        for node_id in &expired {
+            self.entries.remove(node_id);
+        }
+        expired
+    }
+
+    /// 按得分从高到低排列候选节点，不在路由表中的节点视为新节点（得分 1.0）
+    pub fn rank(&self, candidates: &[NodeId]) -> Vec<NodeId> {
+        let mut ranked: Vec<(f32, NodeId)> = candidates
+            .iter()
+            .map(|id| {
+                (
+                    self.entries.get(id).map(|e| e.score).unwrap_or(1.0),
+                    id.clone(),
+                )
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranked.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// 候选节点中得分最高的一个
+    pub fn best(&self, candidates: &[NodeId]) -> Option<NodeId> {
+        self.rank(candidates).into_iter().next()
+    }
+}
+
+/// 启动后台任务：定期淘汰过期条目，并把路由表快照写入数据库
+///
+/// 节点关闭时再写入一次快照后退出
+pub fn start_routing_task(routing: SharedRoutingTable, shutdown: Shutdown) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(ROUTING_MAINTAIN_INTERVAL);
+        loop {
+            let stopping = tokio::select! {
+                _ = shutdown.cancelled() => true,
+                _ = tick.tick() => false,
+            };
+
+            let entries = {
+                let mut table = routing.lock().await;
+                let evicted = table.evict_expired();
+                if !evicted.is_empty() {
+                    debug!("Evicted {} expired routing entries", evicted.len());
+                }
+                table.entries()
+            };
+
+            if let Err(e) = routing_model::save_routes(&entries).await {
+                warn!("Failed to persist routing table: {}", e);
+            }
+            if stopping {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    #[test]
+    fn test_rank_prefers_low_latency_and_reliable_peers() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let (fast, slow, flaky, unknown) = (node_id(), node_id(), node_id(), node_id());
+
+        let mut table = RoutingTable::new();
+        table.record_connected(&fast, Some(addr), Duration::from_millis(10));
+        table.record_connected(&slow, Some(addr), Duration::from_millis(500));
+        table.record_connected(&flaky, Some(addr), Duration::from_millis(10));
+        table.record_failure(&flaky);
+        table.record_failure(&flaky);
+
+        let ranked = table.rank(&[slow.clone(), flaky.clone(), fast.clone()]);
+        assert_eq!(ranked, vec![fast.clone(), flaky.clone(), slow.clone()]);
+        assert_eq!(
+            table.best(&[slow.clone(), fast.clone()]),
+            Some(fast.clone())
+        );
+
+        // 未知节点按新节点处理
+        assert_eq!(table.best(&[slow.clone(), unknown.clone()]), Some(unknown));
+        assert_eq!(table.best(&[]), None);
+    }
+
+    #[test]
+    fn test_misbehaviour_lowers_score() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let (good, bad) = (node_id(), node_id());
+
+        let mut table = RoutingTable::new();
+        table.record_connected(&good, Some(addr), Duration::from_millis(50));
+        table.record_connected(&bad, Some(addr), Duration::from_millis(50));
+        table.record_success(&bad);
+        table.record_misbehaviour(&bad);
+
+        assert!(table.get(&bad).unwrap().score < table.get(&good).unwrap().score);
+        assert_eq!(table.best(&[bad, good.clone()]), Some(good));
+    }
+
+    #[test]
+    fn test_evict_expired_keeps_connected() {
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let (stale, connected, fresh) = (node_id(), node_id(), node_id());
+
+        let mut table = RoutingTable::new();
+        table.observe(&stale, &[addr]);
+        table.record_connected(&connected, Some(addr), Duration::from_millis(10));
+        table.observe(&fresh, &[addr]);
+
+        for id in [&stale, &connected] {
+            let entry = table.entries.get_mut(id).unwrap();
+            entry.ttl = Duration::ZERO;
+            entry.last_seen -= Duration::from_secs(1);
+        }
+
+        assert_eq!(table.evict_expired(), vec![stale.clone()]);
+        assert!(table.get(&stale).is_none());
+        assert!(table.get(&connected).is_some());
+        assert!(table.get(&fresh).is_some());
+
+        table.record_disconnected(&connected);
+        assert_eq!(table.evict_expired(), vec![connected]);
+        assert_eq!(table.len(), 1);
+    }
+}
@@ -0,0 +1,110 @@
+//! 节点运行时的关闭协调
+//!
+//! 收到 SIGINT/SIGTERM 后触发 [`Shutdown`]：各后台任务停止接受新工作并退出循环，
+//! 已开始的 bundle 发送等受跟踪的任务在限定时间内完成，最后以关闭原因码断开所有 QUIC 连接。
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tokio_util::task::TaskTracker;
+
+/// 关闭协调器，克隆后共享同一个关闭状态
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 开始关闭，所有等待 [`Shutdown::cancelled`] 的任务被唤醒
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// 关闭开始时完成
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.token.cancelled()
+    }
+
+    /// 关闭开始时被取消的令牌，用于需要 `'static` future 的场景
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// 启动受跟踪的任务，[`Shutdown::drain`] 会等待它完成
+    pub fn spawn_tracked<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker.spawn(task)
+    }
+
+    /// 等待受跟踪的任务完成，超过 `timeout` 返回 false
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.tracker.close();
+        tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_ok()
+    }
+
+    /// 受跟踪且尚未完成的任务数
+    pub fn pending_tasks(&self) -> usize {
+        self.tracker.len()
+    }
+}
+
+/// 等待 SIGINT（Ctrl+C）或 SIGTERM
+pub async fn wait_for_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_tracked_tasks() {
+        let shutdown = Shutdown::new();
+        let waiter = shutdown.clone();
+        let loop_task = tokio::spawn(async move {
+            waiter.cancelled().await;
+        });
+
+        let finished = shutdown.spawn_tracked(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        });
+        assert_eq!(shutdown.pending_tasks(), 1);
+
+        shutdown.trigger();
+        assert!(shutdown.is_triggered());
+        loop_task.await.unwrap();
+        assert!(shutdown.drain(Duration::from_secs(5)).await);
+        assert!(finished.is_finished());
+
+        // 超过期限仍未完成的任务使 drain 返回 false
+        let slow = Shutdown::new();
+        slow.spawn_tracked(tokio::time::sleep(Duration::from_secs(60)));
+        assert!(!slow.drain(Duration::from_millis(50)).await);
+    }
+}
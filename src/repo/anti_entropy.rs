@@ -0,0 +1,473 @@
+//! 仓库目录的反熵同步
+//!
+//! gossip 只在消息经过时更新仓库目录：节点离线、TTL 耗尽或去重缓存命中都会错过
+//! `RepoAnnouncement`，要等源节点下一轮公告才能补上，源节点不可达时则一直缺失。
+//! 反熵任务定期随机挑选一个邻居，两两交换摘要：
+//!
+//! 1. 调用 `repo.inventory` 并带上本地目录的根摘要，双方一致时对方返回空列表，一次往返结束；
+//! 2. 否则对方返回每个仓库的摘要（所有者签名 refs 时的序号），
+//!    本节点挑出本地缺失或对方序号较大的仓库；
+//! 3. 调用 `repo.fetch` 只取这些仓库，按 gossip 公告的规则合并，只接受所有者签名的 refs。
+//!
+//! 序号由仓库所有者签名，其他节点无法伪造较新的条目。每次交换只拉取对方较新的条目，
+//! 跨分区的节点在重新连通后也能最终得到一致的目录。
+use crate::gossip::topic::SharedSubscriptions;
+use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
+use crate::repo::repo::Repo;
+use crate::repo::repo_sync::merge_subscribed_repo;
+use crate::storage::repo_model;
+use crate::transport::protocol::Capabilities;
+use crate::transport::rpc::RpcMethod;
+use crate::transport::transport::Transport;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// 两次反熵交换的间隔
+pub const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 单个仓库的摘要
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepoDigest {
+    pub repo_id: String,
+    /// 所有者签名 refs 时的序号，没有签名时为 0
+    #[serde(default)]
+    pub sequence: u64,
+}
+
+impl RepoDigest {
+    pub fn of(repo: &Repo) -> Self {
+        Self {
+            repo_id: repo.repo_id.clone(),
+            sequence: repo
+                .signed_refs
+                .as_ref()
+                .map_or(0, |signed| signed.sequence),
+        }
+    }
+
+    /// 是否应以本条为准：所有者签名的序号较大者优先
+    pub fn newer_than(&self, other: &RepoDigest) -> bool {
+        self.sequence > other.sequence
+    }
+}
+
+/// 整个目录的根摘要，与仓库顺序无关
+pub fn root_digest(digests: &[RepoDigest]) -> String {
+    let mut sorted: Vec<&RepoDigest> = digests.iter().collect();
+    sorted.sort_by(|a, b| a.repo_id.cmp(&b.repo_id));
+    let mut hasher = Sha256::new();
+    for digest in sorted {
+        hasher.update(digest.repo_id.as_bytes());
+        hasher.update([0]);
+        hasher.update(digest.sequence.to_be_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// 对比两端的摘要，返回本地缺失或对方较新的仓库；对方没有所有者签名的条目不会被合并，不拉取
+pub fn wanted(local: &[RepoDigest], remote: &[RepoDigest]) -> Vec<String> {
+    let local: HashMap<&str, &RepoDigest> = local.iter().map(|d| (d.repo_id.as_str(), d)).collect();
+    let mut ids: Vec<String> = remote
+        .iter()
+        .filter(|r| r.sequence > 0)
+        .filter(|r| match local.get(r.repo_id.as_str()) {
+            Some(l) => r.newer_than(l),
+            None => true,
+        })
+        .map(|r| r.repo_id.clone())
+        .collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// RPC：交换仓库目录摘要
+pub struct Inventory;
+
+impl RpcMethod for Inventory {
+    const NAME: &'static str = "repo.inventory";
+    type Request = InventoryParams;
+    type Response = InventoryResponse;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InventoryParams {
+    /// 调用方目录的根摘要
+    pub root: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InventoryResponse {
+    pub root: String,
+    /// 根摘要相同时为空
+    pub digests: Vec<RepoDigest>,
+}
+
+/// RPC：按 repo_id 拉取仓库记录
+pub struct FetchRepos;
+
+impl RpcMethod for FetchRepos {
+    const NAME: &'static str = "repo.fetch";
+    type Request = FetchReposParams;
+    type Response = Vec<Repo>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchReposParams {
+    pub repo_ids: Vec<String>,
+}
+
+/// 参与反熵同步的仓库目录
+pub trait RepoCatalog: Send + Sync {
+    /// 目录中的所有仓库，包括本地仓库和已知的外部仓库
+    fn list(&self) -> BoxFuture<'_, Result<Vec<Repo>>>;
+
+    /// 合并从 `from` 拉取的仓库，返回是否修改了目录
+    fn merge(&self, repo: Repo, from: NodeId) -> BoxFuture<'_, Result<bool>>;
+}
+
+/// 基于 `repos` / `refs` 表的目录，与 gossip 公告一样只插入订阅了其主题的新仓库
+pub struct DbCatalog {
+    subscriptions: SharedSubscriptions,
+}
+
+impl DbCatalog {
+    pub fn new(subscriptions: SharedSubscriptions) -> Self {
+        Self { subscriptions }
+    }
+}
+
+impl RepoCatalog for DbCatalog {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<Repo>>> {
+        Box::pin(repo_model::list_repos())
+    }
+
+    fn merge(&self, repo: Repo, from: NodeId) -> BoxFuture<'_, Result<bool>> {
+        // 拉取的记录经对方转发，只接受所有者签名的 refs
+        Box::pin(async move { merge_subscribed_repo(&repo, &from, 0, &self.subscriptions).await })
+    }
+}
+
+/// 内存中的目录，供同一进程内的多个节点各自持有独立的目录
+#[derive(Debug, Default)]
+pub struct MemoryCatalog {
+    repos: std::sync::Mutex<HashMap<String, Repo>>,
+}
+
+impl MemoryCatalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, repo: Repo) {
+        self.lock().insert(repo.repo_id.clone(), repo);
+    }
+
+    pub fn get(&self, repo_id: &str) -> Option<Repo> {
+        self.lock().get(repo_id).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Repo>> {
+        self.repos.lock().expect("memory catalog poisoned")
+    }
+}
+
+impl RepoCatalog for MemoryCatalog {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<Repo>>> {
+        let repos = self.lock().values().cloned().collect();
+        Box::pin(async move { Ok(repos) })
+    }
+
+    /// 与 [`merge_remote_repo`](crate::repo::repo_sync::merge_remote_repo) 相同：只接受所有者签名的 refs，
+    /// 不覆盖本地仓库和其他所有者的仓库，签名的序号不大于已有记录时不更新
+    fn merge(&self, mut repo: Repo, from: NodeId) -> BoxFuture<'_, Result<bool>> {
+        let signed = match repo.verified_refs() {
+            Ok(signed) => signed.clone(),
+            Err(e) => {
+                warn!("Ignoring repo {} from {}: {}", repo.repo_id, from, e);
+                return Box::pin(async { Ok(false) });
+            }
+        };
+        let mut repos = self.lock();
+        let changed = match repos.get(&repo.repo_id) {
+            Some(local) => {
+                local.is_external
+                    && local.p2p_description.creator == repo.p2p_description.creator
+                    && match &local.signed_refs {
+                        Some(stored) => stored.sequence < signed.sequence,
+                        None => true,
+                    }
+            }
+            None => true,
+        };
+        if changed {
+            repo.is_external = true;
+            repo.refs = signed.refs;
+            repos.insert(repo.repo_id.clone(), repo);
+        }
+        Box::pin(async move { Ok(changed) })
+    }
+}
+
+/// 反熵服务：响应其他节点的摘要交换，并定期与随机邻居交换
+pub struct AntiEntropy {
+    transport: Arc<dyn Transport>,
+    catalog: Arc<dyn RepoCatalog>,
+    interval: Duration,
+    shutdown: Shutdown,
+}
+
+impl AntiEntropy {
+    pub fn new(transport: Arc<dyn Transport>, catalog: Arc<dyn RepoCatalog>) -> Self {
+        Self {
+            transport,
+            catalog,
+            interval: ANTI_ENTROPY_INTERVAL,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// 节点关闭时停止周期交换
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 注册 RPC 并启动周期交换任务
+    pub fn start(self: Arc<Self>) {
+        self.register_rpc();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval_at(
+                tokio::time::Instant::now() + self.interval,
+                self.interval,
+            );
+            loop {
+                tokio::select! {
+                    _ = self.shutdown.cancelled() => break,
+                    _ = tick.tick() => {}
+                }
+                let peers = self.transport.peers_with(Capabilities::ANTI_ENTROPY).await;
+                if peers.is_empty() {
+                    continue;
+                }
+                let peer = peers[(OsRng.next_u64() % peers.len() as u64) as usize].clone();
+                match self.reconcile_with(peer.clone()).await {
+                    Ok(0) => {}
+                    Ok(n) => info!("Anti-entropy fetched {} repos from {}", n, peer),
+                    Err(e) => debug!("Anti-entropy with {} failed: {}", peer, e),
+                }
+            }
+        });
+    }
+
+    fn register_rpc(&self) {
+        let catalog = Arc::clone(&self.catalog);
+        self.transport
+            .register_rpc::<Inventory, _, _>(move |from, params| {
+                let catalog = Arc::clone(&catalog);
+                async move {
+                    let digests: Vec<RepoDigest> =
+                        catalog.list().await?.iter().map(RepoDigest::of).collect();
+                    let root = root_digest(&digests);
+                    debug!(
+                        "Inventory request from {}, in sync: {}",
+                        from,
+                        root == params.root
+                    );
+                    let digests = if root == params.root {
+                        Vec::new()
+                    } else {
+                        digests
+                    };
+                    Ok(InventoryResponse { root, digests })
+                }
+            });
+
+        let catalog = Arc::clone(&self.catalog);
+        self.transport
+            .register_rpc::<FetchRepos, _, _>(move |_from, params| {
+                let catalog = Arc::clone(&catalog);
+                async move {
+                    let wanted: HashSet<String> = params.repo_ids.into_iter().collect();
+                    // 与 RepoAnnouncement 一样不透露本地路径
+                    Ok(catalog
+                        .list()
+                        .await?
+                        .into_iter()
+                        .filter(|repo| wanted.contains(&repo.repo_id))
+                        .map(|mut repo| {
+                            repo.path = PathBuf::new();
+                            repo.bundle = PathBuf::new();
+                            repo
+                        })
+                        .collect())
+                }
+            });
+    }
+
+    /// 与 `peer` 交换一次摘要并拉取有差异的仓库，返回拉取的仓库数
+    pub async fn reconcile_with(&self, peer: NodeId) -> Result<usize> {
+        let repos = self.catalog.list().await?;
+        let digests: Vec<RepoDigest> = repos.iter().map(RepoDigest::of).collect();
+        let response = self
+            .transport
+            .call::<Inventory>(
+                peer.clone(),
+                InventoryParams {
+                    root: root_digest(&digests),
+                },
+            )
+            .await?;
+        if response.digests.is_empty() {
+            return Ok(0);
+        }
+
+        // 本地仓库以本节点为准
+        let local: HashSet<&str> = repos
+            .iter()
+            .filter(|repo| !repo.is_external)
+            .map(|repo| repo.repo_id.as_str())
+            .collect();
+        let repo_ids: Vec<String> = wanted(&digests, &response.digests)
+            .into_iter()
+            .filter(|id| !local.contains(id.as_str()))
+            .collect();
+        if repo_ids.is_empty() {
+            return Ok(0);
+        }
+
+        debug!(
+            "Catalog differs from {}, fetching {} repos",
+            peer,
+            repo_ids.len()
+        );
+        let fetched = self
+            .transport
+            .call::<FetchRepos>(peer.clone(), FetchReposParams { repo_ids })
+            .await?;
+        let count = fetched.len();
+        for repo in fetched {
+            let repo_id = repo.repo_id.clone();
+            if let Err(e) = self.catalog.merge(repo, peer.clone()).await {
+                warn!("Failed to merge repo {} from {}: {}", repo_id, peer, e);
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+    use crate::repo::repo::P2PDescription;
+
+    /// `creator` 以 `sequence` 签名 refs 的仓库
+    fn repo(id: &str, creator: &KeyPair, sequence: u64, refs: &[(&str, &str)]) -> Repo {
+        let mut repo = Repo::new(
+            id.to_string(),
+            P2PDescription {
+                creator: NodeId::from_keypair(creator).to_string(),
+                name: id.to_string(),
+                description: String::new(),
+                language: String::new(),
+                latest_commit_at: 0,
+                size: 0,
+            },
+            PathBuf::from("/tmp/repo"),
+        );
+        for (name, commit) in refs {
+            repo.add_ref(name.to_string(), commit.to_string());
+        }
+        repo.sign_refs(creator, sequence).unwrap();
+        repo
+    }
+
+    #[test]
+    fn test_digest_and_wanted() {
+        let creator = KeyPair::generate().unwrap();
+        let a = RepoDigest::of(&repo("a", &creator, 10, &[("refs/heads/main", "1")]));
+        let a_new = RepoDigest::of(&repo("a", &creator, 20, &[("refs/heads/main", "3")]));
+        let b = RepoDigest::of(&repo("b", &creator, 10, &[("refs/heads/main", "1")]));
+        let local = vec![a.clone()];
+        let remote = vec![a_new.clone(), b.clone()];
+
+        // 缺失和较新的条目都需要拉取，反方向没有需要拉取的条目
+        assert_eq!(wanted(&local, &remote), vec!["a", "b"]);
+        assert!(wanted(&remote, &local).is_empty());
+        assert_eq!(wanted(&remote, &remote), Vec::<String>::new());
+        assert!(a_new.newer_than(&a) && !a.newer_than(&a_new) && !a.newer_than(&a));
+
+        // 没有所有者签名的条目不拉取，也不会覆盖已签名的条目
+        let mut unsigned = repo("c", &creator, 1, &[]);
+        unsigned.signed_refs = None;
+        let unsigned = RepoDigest::of(&unsigned);
+        assert_eq!(unsigned.sequence, 0);
+        assert!(wanted(&local, &[unsigned]).is_empty());
+
+        // 根摘要与顺序无关
+        let reversed: Vec<RepoDigest> = remote.iter().rev().cloned().collect();
+        assert_eq!(root_digest(&remote), root_digest(&reversed));
+        assert_ne!(root_digest(&remote), root_digest(&local));
+    }
+
+    #[tokio::test]
+    async fn test_memory_catalog_merge() {
+        let catalog = MemoryCatalog::new();
+        let creator = KeyPair::generate().unwrap();
+        let peer = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        catalog.insert(repo("local", &creator, 1, &[("refs/heads/main", "1")]));
+
+        // 本地仓库不被覆盖
+        let merged = catalog
+            .merge(
+                repo("local", &creator, 9, &[("refs/heads/main", "9")]),
+                peer.clone(),
+            )
+            .await
+            .unwrap();
+        assert!(!merged);
+
+        // 新仓库作为外部仓库加入，序号未增大时不更新
+        let remote = repo("remote", &creator, 1, &[("refs/heads/main", "1")]);
+        assert!(catalog.merge(remote.clone(), peer.clone()).await.unwrap());
+        assert!(catalog.get("remote").unwrap().is_external);
+        assert!(!catalog.merge(remote, peer.clone()).await.unwrap());
+        let updated = repo("remote", &creator, 2, &[("refs/heads/main", "2")]);
+        assert!(catalog.merge(updated.clone(), peer.clone()).await.unwrap());
+        assert_eq!(catalog.get("remote").unwrap().refs["refs/heads/main"], "2");
+
+        // 篡改签名后的 refs、其他节点签名或更换所有者的记录都被拒绝
+        let mut tampered = updated.clone();
+        tampered
+            .refs
+            .insert("refs/heads/main".to_string(), "evil".to_string());
+        tampered.signed_refs.as_mut().unwrap().refs = tampered.refs.clone();
+        let mut forged = updated.clone();
+        forged.sign_refs(&KeyPair::generate().unwrap(), 3).unwrap();
+        let hijacked = repo(
+            "remote",
+            &KeyPair::generate().unwrap(),
+            3,
+            &[("refs/heads/main", "evil")],
+        );
+        for record in [tampered, forged, hijacked] {
+            assert!(!catalog.merge(record, peer.clone()).await.unwrap());
+        }
+        assert_eq!(catalog.get("remote").unwrap().refs["refs/heads/main"], "2");
+    }
+}
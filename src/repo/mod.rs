@@ -1,4 +1,5 @@
 #![allow(clippy::module_inception)]
+pub mod anti_entropy;
 pub mod repo;
 pub mod repo_id;
 pub mod repo_manager;
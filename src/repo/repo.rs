@@ -1,7 +1,16 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::Signature;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 
+use crate::identity::keypair::KeyPair;
+use crate::node::node_id::NodeId;
+use crate::transport::codec;
+
+/// 所有者对 refs 签名时的域标签
+const REFS_SIGNING_CONTEXT: &str = "megaengine/signed-refs/1";
+
 /// P2P 仓库描述
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct P2PDescription {
@@ -13,6 +22,19 @@ pub struct P2PDescription {
     pub size: u64,
 }
 
+/// 仓库所有者签名的一组 refs
+///
+/// 仓库记录可能经其他节点转发，只有带上所有者签名的 refs 才会被合并
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRefs {
+    /// 签名时的全部 refs
+    pub refs: HashMap<String, String>,
+    /// 所有者签名时使用的 gossip 序号，与 RefUpdate 的序号可以比较
+    pub sequence: u64,
+    /// 对仓库 ID、所有者、refs 和序号的签名，十六进制
+    pub signature: String,
+}
+
 /// P2P 仓库
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Repo {
@@ -22,6 +44,9 @@ pub struct Repo {
     pub path: PathBuf,
     pub is_external: bool,
     pub bundle: PathBuf,
+    /// 所有者最近一次签名的 refs，旧版本节点发出的记录没有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_refs: Option<SignedRefs>,
 }
 
 impl Repo {
@@ -34,7 +59,48 @@ impl Repo {
             path,
             is_external: false,
             bundle: PathBuf::new(),
+            signed_refs: None,
+        }
+    }
+
+    /// 签名的字节：域标签、仓库 ID、所有者、按名称排序的 refs 和序号的规范编码
+    fn refs_signing_bytes(&self, refs: &HashMap<String, String>, sequence: u64) -> Result<Vec<u8>> {
+        let refs: BTreeMap<&String, &String> = refs.iter().collect();
+        codec::canonical(&(
+            REFS_SIGNING_CONTEXT,
+            &self.repo_id,
+            &self.p2p_description.creator,
+            refs,
+            sequence,
+        ))
+    }
+
+    /// 所有者以 `sequence` 对当前的 refs 签名
+    pub fn sign_refs(&mut self, keypair: &KeyPair, sequence: u64) -> Result<()> {
+        let bytes = self.refs_signing_bytes(&self.refs, sequence)?;
+        let signature = keypair.sign(&bytes)?;
+        self.signed_refs = Some(SignedRefs {
+            refs: self.refs.clone(),
+            sequence,
+            signature: hex::encode(signature.to_bytes()),
+        });
+        Ok(())
+    }
+
+    /// 校验 [`Self::signed_refs`] 确实由 `p2p_description.creator` 签名
+    pub fn verified_refs(&self) -> Result<&SignedRefs> {
+        let signed = self
+            .signed_refs
+            .as_ref()
+            .ok_or_else(|| anyhow!("refs are not signed by the creator"))?;
+        let key = NodeId::from_string(&self.p2p_description.creator)?.to_keypair()?;
+        let bytes = self.refs_signing_bytes(&signed.refs, signed.sequence)?;
+        let signature = <[u8; 64]>::try_from(hex::decode(&signed.signature)?.as_slice())
+            .map_err(|_| anyhow!("invalid refs signature length"))?;
+        if !key.verify(&bytes, &Signature::from_bytes(&signature)) {
+            return Err(anyhow!("refs signature does not match the creator"));
         }
+        Ok(signed)
     }
 
     /// 添加 ref
@@ -119,4 +185,44 @@ mod tests {
             Some(&"commit1".to_string())
         );
     }
+
+    #[test]
+    fn test_signed_refs() -> Result<()> {
+        let creator = KeyPair::generate()?;
+        let desc = P2PDescription {
+            creator: NodeId::from_keypair(&creator).to_string(),
+            name: "test-repo".to_string(),
+            description: String::new(),
+            language: String::new(),
+            latest_commit_at: 0,
+            size: 0,
+        };
+        let mut repo = Repo::new("did:repo:test".to_string(), desc, PathBuf::new());
+        repo.add_ref("refs/heads/main".to_string(), "commit1".to_string());
+        repo.add_ref("refs/tags/v1".to_string(), "commit0".to_string());
+        assert!(repo.verified_refs().is_err());
+
+        repo.sign_refs(&creator, 7)?;
+        let signed = repo.verified_refs()?;
+        assert_eq!((signed.sequence, &signed.refs), (7, &repo.refs));
+
+        // 签名只覆盖 signed_refs，修改 refs 或序号后校验失败
+        let mut tampered = repo.clone();
+        tampered
+            .signed_refs
+            .as_mut()
+            .unwrap()
+            .refs
+            .insert("refs/heads/main".to_string(), "evil".to_string());
+        assert!(tampered.verified_refs().is_err());
+        let mut tampered = repo.clone();
+        tampered.signed_refs.as_mut().unwrap().sequence = 8;
+        assert!(tampered.verified_refs().is_err());
+
+        // 其他节点不能以所有者的名义签名
+        let mut forged = repo.clone();
+        forged.sign_refs(&KeyPair::generate()?, 9)?;
+        assert!(forged.verified_refs().is_err());
+        Ok(())
+    }
 }
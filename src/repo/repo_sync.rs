@@ -1,19 +1,290 @@
 use crate::git::git_repo::read_repo_refs;
-use crate::storage::{ref_model, repo_model};
-use anyhow::Result;
+use crate::gossip::message::RefUpdate;
+use crate::gossip::topic::{SharedSubscriptions, Topic};
+use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
+use crate::repo::repo::Repo;
+use crate::storage::{ref_model, repo_model, signed_refs_model};
+use crate::transport::rpc::RpcMethod;
+use crate::transport::transport::Transport;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::time::Duration;
+use tokio::sync::mpsc;
 use tokio::time::interval;
 use tracing::{debug, info, warn};
 
 const REPO_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
-/// 后台任务：定时检查本地 repos 的 refs 是否有更新
-pub async fn start_repo_sync_task() {
+/// 本地仓库中一个 ref 的变化，由 gossip 服务作为 RefUpdate 广播
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefChange {
+    pub repo_id: String,
+    pub ref_name: String,
+    /// 变化前指向的提交，新建的 ref 为 None
+    pub old_oid: Option<String>,
+    /// 变化后指向的提交，删除的 ref 为 None
+    pub new_oid: Option<String>,
+}
+
+/// 比较两组 refs，按 ref 名称排序返回变化
+pub fn diff_refs(
+    repo_id: &str,
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+) -> Vec<RefChange> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| old.get(*name) != new.get(*name))
+        .map(|name| RefChange {
+            repo_id: repo_id.to_string(),
+            ref_name: name.clone(),
+            old_oid: old.get(name).cloned(),
+            new_oid: new.get(name).cloned(),
+        })
+        .collect()
+}
+
+/// RPC：查询对端记录的仓库 refs
+pub struct ListRefs;
+
+impl RpcMethod for ListRefs {
+    const NAME: &'static str = "repo.list_refs";
+    type Request = ListRefsParams;
+    type Response = RepoRefs;
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ListRefsParams {
+    pub repo_id: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RepoRefs {
+    pub repo_id: String,
+    /// ref 名称 -> commit hash
+    pub refs: HashMap<String, String>,
+}
+
+/// 注册 refs 查询 RPC，返回数据库中记录的 refs
+pub fn register_ref_rpc(transport: &dyn Transport) {
+    transport.register_rpc::<ListRefs, _, _>(|from, params| async move {
+        debug!("Refs query from {} for repo {}", from, params.repo_id);
+        if repo_model::load_repo_from_db(&params.repo_id)
+            .await?
+            .is_none()
+        {
+            return Err(anyhow!("repo {} not found", params.repo_id));
+        }
+        let refs = ref_model::load_refs_for_repo(&params.repo_id).await?;
+        Ok(RepoRefs {
+            repo_id: params.repo_id,
+            refs,
+        })
+    });
+}
+
+/// 向指定节点查询仓库的 refs
+pub async fn query_peer_refs(
+    transport: &dyn Transport,
+    node_id: NodeId,
+    repo_id: &str,
+) -> Result<HashMap<String, String>> {
+    let response = transport
+        .call::<ListRefs>(
+            node_id,
+            ListRefsParams {
+                repo_id: repo_id.to_string(),
+            },
+        )
+        .await?;
+    Ok(response.refs)
+}
+
+/// 合并其他节点公告或同步来的仓库记录
+///
+/// 记录可能经其他节点转发，只合并仓库所有者签名的 refs（[`Repo::signed_refs`]），
+/// 每个 ref 与 RefUpdate 一样按所有者的序号合并，迟到的旧记录不会回滚较新的更新。
+/// 没有签名的记录只在所有者直接公告时接受，`sequence` 为其消息序号；否则传入 0，记录被丢弃。
+/// 本地仓库不会被覆盖；已知的外部仓库在 refs 变化时清空 bundle，等待重新同步；
+/// 未知的仓库作为外部仓库插入。返回是否修改了数据库
+pub async fn merge_remote_repo(repo: &Repo, from: &NodeId, sequence: u64) -> Result<bool> {
+    let signed = repo.verified_refs().ok();
+    let (refs, sequence) = match signed {
+        Some(signed) => (&signed.refs, signed.sequence),
+        None if sequence > 0 => (&repo.refs, sequence),
+        None => {
+            warn!(
+                "Ignoring repo {} from {}, refs are not signed by its creator",
+                &repo.repo_id, from
+            );
+            return Ok(false);
+        }
+    };
+
+    match repo_model::load_repo_from_db(&repo.repo_id).await? {
+        Some(local_repo) => {
+            // 如果是本地仓库，不更新
+            if !local_repo.is_external {
+                debug!(
+                    "Repo {} is a local repository, skipping update",
+                    &repo.repo_id
+                );
+                return Ok(false);
+            }
+            if local_repo.p2p_description.creator != repo.p2p_description.creator {
+                warn!(
+                    "Ignoring repo {} from {}, creator {} does not match",
+                    &repo.repo_id, from, repo.p2p_description.creator
+                );
+                return Ok(false);
+            }
+
+            // 保存较新的签名，转发给其他节点
+            if let Some(signed) = signed {
+                let newer = match &local_repo.signed_refs {
+                    Some(stored) => stored.sequence < signed.sequence,
+                    None => true,
+                };
+                if newer {
+                    signed_refs_model::save_signed_refs(&repo.repo_id, signed).await?;
+                }
+            }
+
+            if !ref_model::merge_refs(&repo.repo_id, refs, sequence).await? {
+                debug!("Repo {} refs are up-to-date", &repo.repo_id);
+                return Ok(false);
+            }
+
+            // 有新的 refs 更新，清空 bundle 等待重新同步
+            info!(
+                "Merged ref updates for repo {} from node {} at sequence {}: {:?}",
+                &repo.repo_id, from, sequence, refs
+            );
+            clear_outdated_bundle(&local_repo).await;
+            Ok(true)
+        }
+        None => {
+            // Repo 不存在，插入为 external repo，refs 带上所有者的序号
+            debug!("Repo {} is new, adding as external", &repo.repo_id);
+            let mut new_repo = repo.clone();
+            new_repo.is_external = true;
+            new_repo.refs.clear();
+            new_repo.signed_refs = signed.cloned();
+            repo_model::save_repo_to_db(&new_repo).await?;
+            ref_model::merge_refs(&repo.repo_id, refs, sequence).await?;
+            Ok(true)
+        }
+    }
+}
+
+/// 按本节点的订阅合并仓库记录：已知的仓库照常合并，未知的仓库只在订阅了其主题时插入
+pub async fn merge_subscribed_repo(
+    repo: &Repo,
+    from: &NodeId,
+    sequence: u64,
+    subscriptions: &SharedSubscriptions,
+) -> Result<bool> {
+    let wanted = subscriptions.lock().await.wants(&Topic::of_repo(repo));
+    if !wanted
+        && repo_model::load_repo_from_db(&repo.repo_id)
+            .await?
+            .is_none()
+    {
+        debug!(
+            "Ignoring repo {} from {}, not subscribed to its topics",
+            repo.repo_id, from
+        );
+        return Ok(false);
+    }
+    merge_remote_repo(repo, from, sequence).await
+}
+
+/// 应用仓库所有者广播的 ref 更新，返回是否修改了数据库
+///
+/// 只接受所有者对已知外部仓库的更新；未知仓库等待完整库存或反熵补上。
+/// ref 变化后删除过期的 bundle，由调用方安排重新同步
+pub async fn apply_ref_update(update: &RefUpdate) -> Result<bool> {
+    let Some(repo) = repo_model::load_repo_from_db(&update.repo_id).await? else {
+        debug!(
+            "Ignoring ref update for unknown repo {}, waiting for its inventory",
+            update.repo_id
+        );
+        return Ok(false);
+    };
+    if !repo.is_external {
+        return Ok(false);
+    }
+    if repo.p2p_description.creator != update.node_id.to_string() {
+        warn!(
+            "Ignoring ref update for repo {} from {}, which is not its creator",
+            update.repo_id, update.node_id
+        );
+        return Ok(false);
+    }
+
+    let current = repo.refs.get(&update.ref_name);
+    if current != update.old_oid.as_ref() && current != update.new_oid.as_ref() {
+        // 错过了之前的更新，所有者的最新值仍然有效
+        debug!(
+            "Ref {} of repo {} was {:?}, update expected {:?}",
+            update.ref_name, update.repo_id, current, update.old_oid
+        );
+    }
+    if !ref_model::apply_ref_update(
+        &update.repo_id,
+        &update.ref_name,
+        update.new_oid.as_deref(),
+        update.sequence,
+    )
+    .await?
+    {
+        return Ok(false);
+    }
+
+    info!(
+        "Applied ref update for repo {}: {} {:?} -> {:?}",
+        update.repo_id, update.ref_name, update.old_oid, update.new_oid
+    );
+    clear_outdated_bundle(&repo).await;
+    Ok(true)
+}
+
+/// 删除 refs 变化后过期的 bundle 文件并清空 bundle 字段，等待自动同步
+async fn clear_outdated_bundle(repo: &Repo) {
+    if repo.bundle.as_os_str().is_empty() {
+        return;
+    }
+    let bundle_path = repo.bundle.to_string_lossy().to_string();
+    match tokio::fs::remove_file(&bundle_path).await {
+        Ok(_) => {
+            info!("Deleted outdated bundle for repo {}", &repo.repo_id);
+        }
+        Err(e) => {
+            warn!("Failed to delete bundle file {}: {}", bundle_path, e);
+        }
+    }
+    if let Err(e) = repo_model::update_repo_bundle(&repo.repo_id, "").await {
+        warn!("Failed to clear bundle for repo {}: {}", &repo.repo_id, e);
+    }
+}
+
+/// 后台任务：定时检查本地 repos 的 refs 是否有更新，把变化发送给 `changes`，节点关闭时退出
+pub async fn start_repo_sync_task(
+    shutdown: Shutdown,
+    changes: mpsc::UnboundedSender<Vec<RefChange>>,
+) {
     tokio::spawn(async move {
         let mut tick = interval(REPO_CHECK_INTERVAL);
 
         loop {
-            tick.tick().await;
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tick.tick() => {}
+            }
 
             debug!("Starting repo refs check");
 
@@ -23,8 +294,14 @@ pub async fn start_repo_sync_task() {
                     for repo in repos {
                         // 只检查本地 repos (is_external=false)
                         if !repo.is_external {
-                            if let Err(e) = check_and_update_repo_refs(&repo).await {
-                                warn!("Failed to check refs for repo {}: {}", repo.repo_id, e);
+                            match check_and_update_repo_refs(&repo).await {
+                                Ok(updates) if !updates.is_empty() => {
+                                    let _ = changes.send(updates);
+                                }
+                                Ok(_) => {}
+                                Err(e) => {
+                                    warn!("Failed to check refs for repo {}: {}", repo.repo_id, e)
+                                }
                             }
                         }
                     }
@@ -37,38 +314,30 @@ pub async fn start_repo_sync_task() {
     });
 }
 
-/// 检查仓库的 refs 是否有更新，如果有则更新数据库
-async fn check_and_update_repo_refs(repo: &crate::repo::repo::Repo) -> Result<()> {
+/// 检查仓库的 refs 是否有更新，如果有则更新数据库并返回变化
+async fn check_and_update_repo_refs(repo: &Repo) -> Result<Vec<RefChange>> {
     let repo_path = repo.path.to_string_lossy().to_string();
 
-    // 从 git 仓库读取最新的 refs
+    // 从 git 仓库读取最新的 refs，与数据库中记录的比较
     let current_refs = read_repo_refs(&repo_path)?;
-
-    // 检查是否有变化
-    if ref_model::has_refs_changed(&repo.repo_id, &current_refs).await? {
-        info!(
-            "Detected refs change in local repo {}, updating database",
-            repo.repo_id
-        );
-
-        // 更新 refs 到数据库
-        ref_model::batch_save_refs(&repo.repo_id, &current_refs).await?;
-
-        // 这里后续可以扩展：
-        // 1. 重新生成 bundle
-        // 2. 广播 repo announcement 告知其他节点有更新
-        // TODO: trigger bundle generation and gossip announcement
-
-        debug!(
-            "Successfully updated refs for repo {} ({} refs)",
-            repo.repo_id,
-            current_refs.len()
-        );
-    } else {
+    let changes = diff_refs(&repo.repo_id, &repo.refs, &current_refs);
+    if changes.is_empty() {
         debug!("No changes detected in repo {}", repo.repo_id);
+        return Ok(changes);
     }
 
-    Ok(())
+    info!(
+        "Detected {} ref changes in local repo {}, updating database",
+        changes.len(),
+        repo.repo_id
+    );
+    for change in &changes {
+        match &change.new_oid {
+            Some(oid) => ref_model::save_ref(&repo.repo_id, &change.ref_name, oid).await?,
+            None => ref_model::delete_ref(&repo.repo_id, &change.ref_name).await?,
+        }
+    }
+    Ok(changes)
 }
 
 #[cfg(test)]
@@ -77,9 +346,109 @@ mod tests {
 
     #[tokio::test]
     async fn test_repo_sync_task_spawns() {
-        // 只测试任务能否正常启动，不测试实际功能
-        start_repo_sync_task().await;
+        // 只测试任务能否正常启动和停止，不测试实际功能
+        let shutdown = Shutdown::new();
+        let (changes, _rx) = mpsc::unbounded_channel();
+        start_repo_sync_task(shutdown.clone(), changes).await;
         // 任务已在后台运行，测试通过
         tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.trigger();
+    }
+
+    #[test]
+    fn test_diff_refs() {
+        let old = HashMap::from([
+            ("refs/heads/main".to_string(), "a1".to_string()),
+            ("refs/heads/dev".to_string(), "b1".to_string()),
+            ("refs/tags/v1".to_string(), "c1".to_string()),
+        ]);
+        let new = HashMap::from([
+            ("refs/heads/main".to_string(), "a2".to_string()),
+            ("refs/tags/v1".to_string(), "c1".to_string()),
+            ("refs/heads/feature".to_string(), "d1".to_string()),
+        ]);
+
+        let change = |name: &str, old: Option<&str>, new: Option<&str>| RefChange {
+            repo_id: "did:repo:diff".to_string(),
+            ref_name: name.to_string(),
+            old_oid: old.map(str::to_string),
+            new_oid: new.map(str::to_string),
+        };
+        assert_eq!(
+            diff_refs("did:repo:diff", &old, &new),
+            vec![
+                change("refs/heads/dev", Some("b1"), None),
+                change("refs/heads/feature", None, Some("d1")),
+                change("refs/heads/main", Some("a1"), Some("a2")),
+            ]
+        );
+        assert!(diff_refs("did:repo:diff", &new, &new).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge_subscribed_repo() -> Result<()> {
+        use crate::gossip::topic::Subscriptions;
+        use crate::identity::keypair::KeyPair;
+        use crate::repo::repo::P2PDescription;
+
+        let keypair = KeyPair::generate()?;
+        let creator = NodeId::from_keypair(&keypair);
+        let repo = |name: &str| {
+            Repo::new(
+                format!("did:repo:{}-{}", name, creator),
+                P2PDescription {
+                    creator: creator.to_string(),
+                    name: name.to_string(),
+                    description: String::new(),
+                    language: String::new(),
+                    latest_commit_at: 0,
+                    size: 0,
+                },
+                std::path::PathBuf::new(),
+            )
+        };
+        let (followed, ignored) = (repo("followed"), repo("ignored"));
+        let subscriptions = Subscriptions::new([Topic::Repo(followed.repo_id.clone())]).shared();
+
+        // 没有订阅新仓库时只保存关注的仓库
+        assert!(merge_subscribed_repo(&followed, &creator, 1, &subscriptions).await?);
+        assert!(!merge_subscribed_repo(&ignored, &creator, 1, &subscriptions).await?);
+        assert!(repo_model::load_repo_from_db(&ignored.repo_id)
+            .await?
+            .is_none());
+
+        // 已保存的仓库取消关注后仍然合并更新
+        subscriptions.lock().await.set_local([]);
+        let mut updated = followed.clone();
+        updated.add_ref("refs/heads/main".to_string(), "c1".to_string());
+        assert!(merge_subscribed_repo(&updated, &creator, 3, &subscriptions).await?);
+
+        // 迟到的旧记录不回滚 refs
+        assert!(!merge_subscribed_repo(&followed, &creator, 2, &subscriptions).await?);
+
+        // 转发的记录没有所有者的签名，或由其他节点签名时被丢弃
+        let relay = NodeId::from_keypair(&KeyPair::generate()?);
+        let mut relayed = followed.clone();
+        relayed.add_ref("refs/heads/main".to_string(), "evil".to_string());
+        relayed.add_ref("refs/heads/dev".to_string(), "d1".to_string());
+        assert!(!merge_subscribed_repo(&relayed, &relay, 0, &subscriptions).await?);
+        let mut forged = relayed.clone();
+        forged.sign_refs(&KeyPair::generate()?, 9)?;
+        assert!(!merge_subscribed_repo(&forged, &relay, 0, &subscriptions).await?);
+        let refs = ref_model::load_refs_for_repo(&followed.repo_id).await?;
+        assert_eq!(refs, updated.refs);
+
+        // 所有者签名的记录经其他节点转发后照常合并，并保存签名
+        relayed.add_ref("refs/heads/main".to_string(), "c2".to_string());
+        relayed.sign_refs(&keypair, 4)?;
+        assert!(merge_subscribed_repo(&relayed, &relay, 0, &subscriptions).await?);
+        let stored = repo_model::load_repo_from_db(&followed.repo_id)
+            .await?
+            .unwrap();
+        assert_eq!(stored.refs, relayed.refs);
+        assert_eq!(stored.signed_refs, relayed.signed_refs);
+
+        repo_model::delete_repo_from_db(&followed.repo_id).await?;
+        Ok(())
     }
 }
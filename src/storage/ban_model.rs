@@ -0,0 +1,124 @@
+use anyhow::Result;
+use sea_orm::entity::prelude::*;
+use sea_orm::{QueryOrder, Set};
+
+use crate::node::node_id::NodeId;
+use crate::node::reputation::Ban;
+use crate::storage::get_db_conn;
+
+/// 被封禁的节点，由运行中的节点自动写入，或由 `node ban` / `node unban` 修改
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "bans")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub node_id: String,
+    pub reason: String,
+    pub banned_at: i64,
+    /// 封禁到期的 Unix 时间（秒），`None` 表示永久封禁
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 保存封禁记录，同一节点已有记录时替换
+pub async fn save_ban(ban: &Ban) -> Result<()> {
+    let db = get_db_conn().await?;
+    let active = ActiveModel {
+        node_id: Set(ban.node_id.to_string()),
+        reason: Set(ban.reason.clone()),
+        banned_at: Set(ban.banned_at),
+        expires_at: Set(ban.expires_at),
+    };
+    match Entity::find_by_id(ban.node_id.to_string()).one(&db).await? {
+        Some(_) => {
+            active.update(&db).await?;
+        }
+        None => {
+            Entity::insert(active).exec(&db).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 解除对节点的封禁，返回是否存在封禁记录
+pub async fn delete_ban(node_id: &NodeId) -> Result<bool> {
+    let db = get_db_conn().await?;
+    let result = Entity::delete_by_id(node_id.to_string()).exec(&db).await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// 删除在 `now` 之前到期的封禁记录
+pub async fn delete_expired(now: i64) -> Result<u64> {
+    let db = get_db_conn().await?;
+    let result = Entity::delete_many()
+        .filter(Column::ExpiresAt.lte(now))
+        .exec(&db)
+        .await?;
+    Ok(result.rows_affected)
+}
+
+/// 列出所有封禁记录（包括已到期但尚未清理的），按封禁时间从新到旧排序
+pub async fn list_bans() -> Result<Vec<Ban>> {
+    let db = get_db_conn().await?;
+    let models = Entity::find()
+        .order_by_desc(Column::BannedAt)
+        .all(&db)
+        .await?;
+    Ok(models
+        .into_iter()
+        .filter_map(|m| {
+            let node_id = match NodeId::from_string(&m.node_id) {
+                Ok(node_id) => node_id,
+                Err(e) => {
+                    tracing::warn!("Ignoring ban with invalid node id {}: {}", m.node_id, e);
+                    return None;
+                }
+            };
+            Some(Ban {
+                node_id,
+                reason: m.reason,
+                banned_at: m.banned_at,
+                expires_at: m.expires_at,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    #[tokio::test]
+    async fn test_save_list_and_delete_ban() -> Result<()> {
+        let node_id = NodeId::from_keypair(&KeyPair::generate()?);
+        let expired = NodeId::from_keypair(&KeyPair::generate()?);
+        let ban = |node_id: &NodeId, expires_at| Ban {
+            node_id: node_id.clone(),
+            reason: "invalid signature".to_string(),
+            banned_at: 100,
+            expires_at,
+        };
+
+        save_ban(&ban(&node_id, Some(200))).await?;
+        save_ban(&ban(&node_id, None)).await?;
+        save_ban(&ban(&expired, Some(150))).await?;
+        let find = |bans: &[Ban], id: &NodeId| bans.iter().find(|b| b.node_id == *id).cloned();
+        let bans = list_bans().await?;
+        assert_eq!(find(&bans, &node_id), Some(ban(&node_id, None)));
+        assert!(find(&bans, &expired).is_some());
+
+        delete_expired(160).await?;
+        let bans = list_bans().await?;
+        assert!(find(&bans, &node_id).is_some());
+        assert!(find(&bans, &expired).is_none());
+
+        assert!(delete_ban(&node_id).await?);
+        assert!(!delete_ban(&node_id).await?);
+        assert!(find(&list_bans().await?, &node_id).is_none());
+        Ok(())
+    }
+}
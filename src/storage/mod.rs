@@ -1,11 +1,19 @@
+pub mod ban_model;
 pub mod chat_message;
 pub mod node_model;
+pub mod peer_stats_model;
 pub mod ref_model;
 pub mod repo_model;
+pub mod routing_model;
+pub mod seen_model;
+pub mod sequence_model;
+pub mod signed_refs_model;
+pub mod subscription_model;
 
 use anyhow::{anyhow, Result};
 use sea_orm::{
     ConnectOptions, ConnectionTrait, Database, DatabaseConnection, DbBackend, Statement,
+    TransactionTrait,
 };
 use std::fs;
 use std::path::PathBuf;
@@ -47,28 +55,6 @@ pub fn keypair_path() -> PathBuf {
     p
 }
 
-/// 证书路径（默认放到根目录）
-pub fn cert_path() -> PathBuf {
-    let mut p = data_dir();
-    fs::create_dir_all(&p).ok();
-    p.push("cert.pem");
-    p
-}
-
-pub fn key_path() -> PathBuf {
-    let mut p = data_dir();
-    fs::create_dir_all(&p).ok();
-    p.push("key.pem");
-    p
-}
-
-pub fn ca_cert_path() -> PathBuf {
-    let mut p = data_dir();
-    fs::create_dir_all(&p).ok();
-    p.push("ca-cert.pem");
-    p
-}
-
 /// SQLite DB 路径
 pub fn db_path() -> PathBuf {
     let mut p = data_dir();
@@ -338,11 +324,8 @@ async fn rebuild_refs_table(db: &DatabaseConnection) -> Result<()> {
          WHERE repo_id IS NOT NULL AND ref_name IS NOT NULL"
     );
 
-    txn.execute(Statement::from_string(
-        DbBackend::Sqlite,
-        insert_sql,
-    ))
-    .await?;
+    txn.execute(Statement::from_string(DbBackend::Sqlite, insert_sql))
+        .await?;
 
     txn.execute(Statement::from_string(
         DbBackend::Sqlite,
@@ -394,6 +377,7 @@ async fn ensure_schema(db: &DatabaseConnection) -> Result<()> {
             addresses TEXT NOT NULL,
             node_type INTEGER NOT NULL,
             version INTEGER NOT NULL,
+            membership TEXT NOT NULL DEFAULT '',
             created_at INTEGER NOT NULL,
             updated_at INTEGER NOT NULL
         )",
@@ -405,6 +389,8 @@ async fn ensure_schema(db: &DatabaseConnection) -> Result<()> {
             repo_id TEXT NOT NULL,
             ref_name TEXT NOT NULL,
             commit_hash TEXT NOT NULL,
+            sequence INTEGER NOT NULL DEFAULT 0,
+            deleted INTEGER NOT NULL DEFAULT 0,
             created_at INTEGER NOT NULL,
             updated_at INTEGER NOT NULL,
             PRIMARY KEY (repo_id, ref_name)
@@ -424,8 +410,101 @@ async fn ensure_schema(db: &DatabaseConnection) -> Result<()> {
     )
     .await?;
 
+    db.execute_unprepared(
+        "CREATE TABLE IF NOT EXISTS routes (
+            node_id TEXT PRIMARY KEY,
+            addresses TEXT NOT NULL,
+            connected INTEGER NOT NULL,
+            latency_ms INTEGER,
+            successes INTEGER NOT NULL,
+            failures INTEGER NOT NULL,
+            misbehaviour INTEGER NOT NULL,
+            score REAL NOT NULL,
+            last_seen INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+    )
+    .await?;
+
+    db.execute_unprepared(
+        "CREATE TABLE IF NOT EXISTS peer_stats (
+            node_id TEXT PRIMARY KEY,
+            connected INTEGER NOT NULL,
+            stats TEXT NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+    )
+    .await?;
+
+    db.execute_unprepared(
+        "CREATE TABLE IF NOT EXISTS gossip_sequences (
+            node_id TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            sequence INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            PRIMARY KEY (node_id, kind)
+        )",
+    )
+    .await?;
+
+    db.execute_unprepared(
+        "CREATE TABLE IF NOT EXISTS subscriptions (
+            topic TEXT PRIMARY KEY,
+            subscribed BOOLEAN NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+    )
+    .await?;
+
+    db.execute_unprepared(
+        "CREATE TABLE IF NOT EXISTS bans (
+            node_id TEXT PRIMARY KEY,
+            reason TEXT NOT NULL,
+            banned_at INTEGER NOT NULL,
+            expires_at INTEGER
+        )",
+    )
+    .await?;
+
+    db.execute_unprepared(
+        "CREATE TABLE IF NOT EXISTS signed_refs (
+            repo_id TEXT PRIMARY KEY,
+            refs TEXT NOT NULL,
+            sequence INTEGER NOT NULL,
+            signature TEXT NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+    )
+    .await?;
+
+    db.execute_unprepared(
+        "CREATE TABLE IF NOT EXISTS gossip_seen (
+            node_id TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            seen_at INTEGER NOT NULL,
+            PRIMARY KEY (node_id, message_id)
+        )",
+    )
+    .await?;
+
     migrate_repos_table(db).await?;
     migrate_refs_table(db).await?;
+    execute_sql_ignore_duplicate_column(
+        db,
+        "ALTER TABLE nodes ADD COLUMN membership TEXT NOT NULL DEFAULT ''",
+    )
+    .await?;
+    execute_sql_ignore_duplicate_column(
+        db,
+        "ALTER TABLE refs ADD COLUMN sequence INTEGER NOT NULL DEFAULT 0",
+    )
+    .await?;
+    execute_sql_ignore_duplicate_column(
+        db,
+        "ALTER TABLE refs ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0",
+    )
+    .await?;
 
     // Align old refs rows that may have default timestamps after ALTER/rebuild.
     db.execute_unprepared(
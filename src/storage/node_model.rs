@@ -4,7 +4,10 @@ use anyhow::Result;
 use sea_orm::entity::prelude::*;
 use sea_orm::Set;
 
+use crate::gossip::membership::View;
 use crate::node::node::{NodeInfo, NodeType};
+use crate::node::node_id::NodeId;
+use crate::transport::protocol::MIN_PROTOCOL_VERSION;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "nodes")]
@@ -15,6 +18,8 @@ pub struct Model {
     pub addresses: String,
     pub node_type: i32,
     pub version: i32,
+    /// HyParView 视图：`active`、`passive`，不在视图中时为空
+    pub membership: String,
     pub created_at: i64,
     pub updated_at: i64,
 }
@@ -31,7 +36,12 @@ pub async fn save_node_info_to_db(info: &NodeInfo) -> Result<()> {
     let addresses_json = serde_json::to_string(&info.addresses)?;
     let now = chrono::Local::now().timestamp();
 
-    // 删除旧记录（如果存在）
+    // 删除旧记录（如果存在），保留成员视图
+    let membership = Entity::find_by_id(info.node_id.to_string())
+        .one(&db)
+        .await?
+        .map(|m| m.membership)
+        .unwrap_or_default();
     let _ = Entity::delete_by_id(info.node_id.to_string())
         .exec(&db)
         .await;
@@ -47,6 +57,7 @@ pub async fn save_node_info_to_db(info: &NodeInfo) -> Result<()> {
         addresses: Set(addresses_json),
         node_type: Set(node_type_int),
         version: Set(info.version as i32),
+        membership: Set(membership),
         created_at: Set(now),
         updated_at: Set(now),
     };
@@ -111,3 +122,124 @@ pub async fn list_nodes() -> Result<Vec<NodeInfo>> {
     }
     Ok(out)
 }
+
+/// 记录节点所在的成员视图，`None` 表示已移出视图
+///
+/// 节点不在表中时以空别名插入，收到其公告后由 [`save_node_info_to_db`] 补全
+pub async fn save_membership(
+    node_id: &NodeId,
+    addresses: &[SocketAddr],
+    view: Option<View>,
+) -> Result<()> {
+    let db = crate::storage::get_db_conn().await?;
+    let now = chrono::Local::now().timestamp();
+    let membership = view.map(View::as_str).unwrap_or_default().to_string();
+
+    match Entity::find_by_id(node_id.to_string()).one(&db).await? {
+        Some(m) => {
+            let mut active: ActiveModel = m.into();
+            if !addresses.is_empty() {
+                active.addresses = Set(serde_json::to_string(addresses)?);
+            }
+            active.membership = Set(membership);
+            active.updated_at = Set(now);
+            active.update(&db).await?;
+        }
+        None => {
+            if view.is_none() {
+                return Ok(());
+            }
+            let active = ActiveModel {
+                id: Set(node_id.to_string()),
+                alias: Set(String::new()),
+                addresses: Set(serde_json::to_string(addresses)?),
+                node_type: Set(0),
+                version: Set(MIN_PROTOCOL_VERSION as i32),
+                membership: Set(membership),
+                created_at: Set(now),
+                updated_at: Set(now),
+            };
+            Entity::insert(active).exec(&db).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 列出上次运行时处于成员视图中的节点
+pub async fn list_membership() -> Result<Vec<(NodeInfo, View)>> {
+    let db = crate::storage::get_db_conn().await?;
+    let models = Entity::find()
+        .filter(Column::Membership.ne(""))
+        .all(&db)
+        .await?;
+
+    let mut out = Vec::new();
+    for m in models {
+        let Some(view) = View::parse(&m.membership) else {
+            continue;
+        };
+        let Ok(node_id) = NodeId::from_string(&m.id) else {
+            continue;
+        };
+        let info = NodeInfo {
+            node_id,
+            alias: m.alias,
+            addresses: serde_json::from_str(&m.addresses).unwrap_or_default(),
+            node_type: match m.node_type {
+                0 => NodeType::Normal,
+                _ => NodeType::Relay,
+            },
+            version: m.version as u8,
+        };
+        out.push((info, view));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    #[tokio::test]
+    async fn test_save_membership() -> Result<()> {
+        let node_id = NodeId::from_keypair(&KeyPair::generate()?);
+        let addr: SocketAddr = "127.0.0.1:19400".parse()?;
+
+        save_membership(&node_id, &[addr], Some(View::Passive)).await?;
+        let found = list_membership().await?;
+        let (info, view) = found
+            .iter()
+            .find(|(info, _)| info.node_id == node_id)
+            .expect("node recorded");
+        assert_eq!(*view, View::Passive);
+        assert_eq!(info.addresses, vec![addr]);
+
+        // 收到节点公告后保留视图
+        let info = NodeInfo {
+            node_id: node_id.clone(),
+            alias: "member".to_string(),
+            addresses: vec![addr],
+            node_type: NodeType::Normal,
+            version: MIN_PROTOCOL_VERSION,
+        };
+        save_node_info_to_db(&info).await?;
+        save_membership(&node_id, &[], Some(View::Active)).await?;
+        let found = list_membership().await?;
+        let (info, view) = found
+            .iter()
+            .find(|(info, _)| info.node_id == node_id)
+            .expect("node recorded");
+        assert_eq!(*view, View::Active);
+        assert_eq!(info.alias, "member");
+
+        save_membership(&node_id, &[], None).await?;
+        assert!(!list_membership()
+            .await?
+            .iter()
+            .any(|(info, _)| info.node_id == node_id));
+
+        delete_node_from_db(&node_id.to_string()).await?;
+        Ok(())
+    }
+}
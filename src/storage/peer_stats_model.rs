@@ -0,0 +1,59 @@
+use anyhow::Result;
+use sea_orm::entity::prelude::*;
+use sea_orm::{QueryOrder, Set, TransactionTrait};
+
+use crate::transport::stats::PeerStats;
+
+/// 各节点连接和流量统计的快照，由运行中的节点定期写入，供 CLI / MCP 查询
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+#[sea_orm(table_name = "peer_stats")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub node_id: String,
+    pub connected: bool,
+    /// JSON 格式的 [`PeerStats`]
+    pub stats: String,
+    pub updated_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    pub fn peer_stats(&self) -> Result<PeerStats> {
+        Ok(serde_json::from_str(&self.stats)?)
+    }
+}
+
+/// 用当前统计整体替换数据库中的快照
+pub async fn save_peer_stats(stats: &[PeerStats]) -> Result<()> {
+    let db = crate::storage::get_db_conn().await?;
+    let now = chrono::Local::now().timestamp();
+
+    let txn = db.begin().await?;
+    Entity::delete_many().exec(&txn).await?;
+    for peer in stats {
+        let active = ActiveModel {
+            node_id: Set(peer.node_id.to_string()),
+            connected: Set(peer.connected),
+            stats: Set(serde_json::to_string(peer)?),
+            updated_at: Set(now),
+        };
+        Entity::insert(active).exec(&txn).await?;
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// 列出统计快照，已连接的节点在前
+pub async fn list_peer_stats() -> Result<Vec<PeerStats>> {
+    let db = crate::storage::get_db_conn().await?;
+    let rows = Entity::find()
+        .order_by_desc(Column::Connected)
+        .order_by_asc(Column::NodeId)
+        .all(&db)
+        .await?;
+    rows.iter().map(Model::peer_stats).collect()
+}
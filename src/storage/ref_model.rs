@@ -13,6 +13,10 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub ref_name: String,
     pub commit_hash: String,
+    /// 最近一次修改该 ref 的 RefUpdate 或完整库存的序号，由本地扫描写入时为 0
+    pub sequence: i64,
+    /// 墓碑：ref 已被所有者删除，保留记录以便拒绝序号更小的迟到更新
+    pub deleted: bool,
     pub created_at: i64,
     pub updated_at: i64,
 }
@@ -40,6 +44,8 @@ pub async fn save_ref(repo_id: &str, ref_name: &str, commit_hash: &str) -> Resul
             repo_id: Unchanged(existing_model.repo_id),
             ref_name: Unchanged(existing_model.ref_name),
             commit_hash: Set(commit_hash.to_string()),
+            sequence: Unchanged(existing_model.sequence),
+            deleted: Set(false),
             created_at: Unchanged(existing_model.created_at),
             updated_at: Set(now),
         };
@@ -50,6 +56,8 @@ pub async fn save_ref(repo_id: &str, ref_name: &str, commit_hash: &str) -> Resul
             repo_id: Set(repo_id.to_string()),
             ref_name: Set(ref_name.to_string()),
             commit_hash: Set(commit_hash.to_string()),
+            sequence: Set(0),
+            deleted: Set(false),
             created_at: Set(now),
             updated_at: Set(now),
         };
@@ -79,6 +87,8 @@ pub async fn batch_save_refs(
                 repo_id: Unchanged(existing_model.repo_id),
                 ref_name: Unchanged(existing_model.ref_name),
                 commit_hash: Set(commit_hash.clone()),
+                sequence: Unchanged(existing_model.sequence),
+                deleted: Set(false),
                 created_at: Unchanged(existing_model.created_at),
                 updated_at: Set(now),
             };
@@ -88,6 +98,8 @@ pub async fn batch_save_refs(
                 repo_id: Set(repo_id.to_string()),
                 ref_name: Set(ref_name.clone()),
                 commit_hash: Set(commit_hash.clone()),
+                sequence: Set(0),
+                deleted: Set(false),
                 created_at: Set(now),
                 updated_at: Set(now),
             };
@@ -106,6 +118,7 @@ pub async fn load_refs_for_repo(
 
     let refs = Entity::find()
         .filter(Column::RepoId.eq(repo_id))
+        .filter(Column::Deleted.eq(false))
         .all(&db)
         .await?;
 
@@ -124,6 +137,7 @@ pub async fn get_ref(repo_id: &str, ref_name: &str) -> Result<Option<String>> {
     if let Some(model) = Entity::find()
         .filter(Column::RepoId.eq(repo_id))
         .filter(Column::RefName.eq(ref_name))
+        .filter(Column::Deleted.eq(false))
         .one(&db)
         .await?
     {
@@ -154,6 +168,96 @@ pub async fn delete_ref(repo_id: &str, ref_name: &str) -> Result<()> {
     Ok(())
 }
 
+/// Apply a ref update announced by the repository owner.
+///
+/// `new_commit` of `None` deletes the ref. Updates whose `sequence` is not
+/// greater than the one that last modified the ref are ignored, so delayed or
+/// replayed updates cannot roll the ref back. Deleted refs are kept as
+/// tombstones carrying the deleting sequence, so a late, older update cannot
+/// bring them back. Returns whether the ref changed.
+pub async fn apply_ref_update(
+    repo_id: &str,
+    ref_name: &str,
+    new_commit: Option<&str>,
+    sequence: u64,
+) -> Result<bool> {
+    let db = get_db_conn().await?;
+    let now = chrono::Local::now().timestamp();
+
+    let existing = Entity::find()
+        .filter(Column::RepoId.eq(repo_id))
+        .filter(Column::RefName.eq(ref_name))
+        .one(&db)
+        .await?;
+
+    let Some(existing_model) = existing else {
+        // 删除未知的 ref 时也写入墓碑，记录删除的序号
+        let active_model = ActiveModel {
+            repo_id: Set(repo_id.to_string()),
+            ref_name: Set(ref_name.to_string()),
+            commit_hash: Set(new_commit.unwrap_or_default().to_string()),
+            sequence: Set(sequence as i64),
+            deleted: Set(new_commit.is_none()),
+            created_at: Set(now),
+            updated_at: Set(now),
+        };
+        Entity::insert(active_model).exec(&db).await?;
+        return Ok(new_commit.is_some());
+    };
+    if existing_model.sequence as u64 >= sequence {
+        return Ok(false);
+    }
+
+    let current = (!existing_model.deleted).then_some(existing_model.commit_hash.as_str());
+    let changed = current != new_commit;
+    // 值未变化时也记录更大的序号
+    let active_model = ActiveModel {
+        repo_id: Unchanged(existing_model.repo_id),
+        ref_name: Unchanged(existing_model.ref_name),
+        commit_hash: Set(new_commit
+            .map(str::to_string)
+            .unwrap_or(existing_model.commit_hash)),
+        sequence: Set(sequence as i64),
+        deleted: Set(new_commit.is_none()),
+        created_at: Unchanged(existing_model.created_at),
+        updated_at: Set(if changed {
+            now
+        } else {
+            existing_model.updated_at
+        }),
+    };
+    Entity::update(active_model).exec(&db).await?;
+    Ok(changed)
+}
+
+/// Merge a complete set of refs that the repository owner published at `sequence`.
+///
+/// Every ref goes through [`apply_ref_update`]: refs missing from `refs` are
+/// deleted, and refs last modified by a newer update are left alone. Returns
+/// whether any ref changed.
+pub async fn merge_refs(
+    repo_id: &str,
+    refs: &std::collections::HashMap<String, String>,
+    sequence: u64,
+) -> Result<bool> {
+    let db = get_db_conn().await?;
+    let mut names: std::collections::BTreeSet<String> = Entity::find()
+        .filter(Column::RepoId.eq(repo_id))
+        .all(&db)
+        .await?
+        .into_iter()
+        .map(|m| m.ref_name)
+        .collect();
+    names.extend(refs.keys().cloned());
+
+    let mut changed = false;
+    for name in names {
+        let commit = refs.get(&name).map(String::as_str);
+        changed |= apply_ref_update(repo_id, &name, commit, sequence).await?;
+    }
+    Ok(changed)
+}
+
 /// Check if any ref in the repository has been updated
 pub async fn has_refs_changed(
     repo_id: &str,
@@ -163,6 +267,7 @@ pub async fn has_refs_changed(
 
     let current_refs = Entity::find()
         .filter(Column::RepoId.eq(repo_id))
+        .filter(Column::Deleted.eq(false))
         .all(&db)
         .await?;
 
@@ -235,6 +340,78 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_apply_ref_update() -> Result<()> {
+        let repo_id = "did:repo:test-ref-004";
+        let ref_name = "refs/heads/main";
+        save_ref(repo_id, ref_name, "abc123").await?;
+
+        // Newer updates apply, older or replayed ones are ignored
+        assert!(apply_ref_update(repo_id, ref_name, Some("def456"), 5).await?);
+        assert!(!apply_ref_update(repo_id, ref_name, Some("abc123"), 4).await?);
+        assert!(!apply_ref_update(repo_id, ref_name, Some("def456"), 5).await?);
+        assert_eq!(
+            get_ref(repo_id, ref_name).await?,
+            Some("def456".to_string())
+        );
+
+        // Refs can be created and deleted
+        assert!(apply_ref_update(repo_id, "refs/tags/v1", Some("aaa111"), 6).await?);
+        assert!(apply_ref_update(repo_id, ref_name, None, 7).await?);
+        let loaded = load_refs_for_repo(repo_id).await?;
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.get("refs/tags/v1"), Some(&"aaa111".to_string()));
+
+        // A late, older update does not bring a deleted ref back; a newer one does
+        assert!(!apply_ref_update(repo_id, ref_name, Some("def456"), 6).await?);
+        assert_eq!(get_ref(repo_id, ref_name).await?, None);
+        assert!(!has_refs_changed(repo_id, &loaded).await?);
+        assert!(apply_ref_update(repo_id, ref_name, Some("fed654"), 8).await?);
+        assert_eq!(
+            get_ref(repo_id, ref_name).await?,
+            Some("fed654".to_string())
+        );
+
+        // Deleting an unknown ref leaves a tombstone
+        assert!(!apply_ref_update(repo_id, "refs/heads/gone", None, 9).await?);
+        assert!(!apply_ref_update(repo_id, "refs/heads/gone", Some("bbb222"), 8).await?);
+        assert_eq!(get_ref(repo_id, "refs/heads/gone").await?, None);
+
+        // Cleanup
+        delete_refs_for_repo(repo_id).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_refs() -> Result<()> {
+        let repo_id = "did:repo:test-ref-005";
+        let refs = |pairs: &[(&str, &str)]| -> std::collections::HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(name, commit)| (name.to_string(), commit.to_string()))
+                .collect()
+        };
+        assert!(merge_refs(repo_id, &refs(&[("main", "a1"), ("dev", "b1")]), 5).await?);
+        assert!(apply_ref_update(repo_id, "main", Some("a2"), 7).await?);
+
+        // An older inventory neither rolls back `main` nor deletes refs created later
+        assert!(apply_ref_update(repo_id, "tag", Some("t1"), 8).await?);
+        assert!(!merge_refs(repo_id, &refs(&[("main", "a1"), ("dev", "b1")]), 6).await?);
+        assert_eq!(
+            load_refs_for_repo(repo_id).await?,
+            refs(&[("main", "a2"), ("dev", "b1"), ("tag", "t1")])
+        );
+
+        // A newer inventory applies to every ref, deleting missing ones
+        assert!(merge_refs(repo_id, &refs(&[("main", "a3")]), 9).await?);
+        assert_eq!(load_refs_for_repo(repo_id).await?, refs(&[("main", "a3")]));
+        assert!(!merge_refs(repo_id, &refs(&[("main", "a3")]), 10).await?);
+
+        // Cleanup
+        delete_refs_for_repo(repo_id).await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_has_refs_changed() -> Result<()> {
         let repo_id = "did:repo:test-ref-003";
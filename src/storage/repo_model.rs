@@ -75,6 +75,9 @@ pub async fn save_repo_to_db(repo: &Repo) -> Result<()> {
 
     // 保存 refs 到 refs 表
     crate::storage::ref_model::batch_save_refs(&repo.repo_id, &repo.refs).await?;
+    if let Some(signed) = &repo.signed_refs {
+        crate::storage::signed_refs_model::save_signed_refs(&repo.repo_id, signed).await?;
+    }
 
     Ok(())
 }
@@ -87,6 +90,7 @@ pub async fn load_repo_from_db(repo_id: &str) -> Result<Option<Repo>> {
     if let Some(model) = Entity::find_by_id(repo_id).one(&db).await? {
         // Load refs from ref_model table
         let refs = crate::storage::ref_model::load_refs_for_repo(&model.id).await?;
+        let signed_refs = crate::storage::signed_refs_model::load_signed_refs(&model.id).await?;
 
         let repo = Repo {
             repo_id: model.id,
@@ -102,6 +106,7 @@ pub async fn load_repo_from_db(repo_id: &str) -> Result<Option<Repo>> {
             path: PathBuf::from(model.path),
             bundle: PathBuf::from(model.bundle),
             is_external: model.is_external,
+            signed_refs,
         };
         return Ok(Some(repo));
     }
@@ -115,6 +120,7 @@ pub async fn delete_repo_from_db(repo_id: &str) -> Result<()> {
     Entity::delete_by_id(repo_id).exec(&db).await?;
     // Delete associated refs
     crate::storage::ref_model::delete_refs_for_repo(repo_id).await?;
+    crate::storage::signed_refs_model::delete_signed_refs(repo_id).await?;
     Ok(())
 }
 
@@ -127,6 +133,7 @@ pub async fn list_repos() -> Result<Vec<Repo>> {
     for model in models {
         // Load refs from ref_model table
         let refs = crate::storage::ref_model::load_refs_for_repo(&model.id).await?;
+        let signed_refs = crate::storage::signed_refs_model::load_signed_refs(&model.id).await?;
 
         repos.push(Repo {
             repo_id: model.id,
@@ -142,6 +149,7 @@ pub async fn list_repos() -> Result<Vec<Repo>> {
             path: PathBuf::from(model.path),
             bundle: PathBuf::from(model.bundle),
             is_external: model.is_external,
+            signed_refs,
         });
     }
     Ok(repos)
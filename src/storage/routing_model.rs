@@ -0,0 +1,64 @@
+use anyhow::Result;
+use sea_orm::entity::prelude::*;
+use sea_orm::{QueryOrder, Set, TransactionTrait};
+
+use crate::node::node::NodeRouting;
+
+/// 路由表快照，由运行中的节点定期写入，供 CLI / MCP 查询
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+#[sea_orm(table_name = "routes")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub node_id: String,
+    pub addresses: String,
+    pub connected: bool,
+    pub latency_ms: Option<i64>,
+    pub successes: i64,
+    pub failures: i64,
+    pub misbehaviour: i64,
+    pub score: f64,
+    pub last_seen: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 用当前路由表整体替换数据库中的快照
+pub async fn save_routes(entries: &[NodeRouting]) -> Result<()> {
+    let db = crate::storage::get_db_conn().await?;
+    let now = chrono::Local::now().timestamp();
+
+    let txn = db.begin().await?;
+    Entity::delete_many().exec(&txn).await?;
+    for entry in entries {
+        let last_seen = entry
+            .last_seen
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        let active = ActiveModel {
+            node_id: Set(entry.node_id.to_string()),
+            addresses: Set(serde_json::to_string(&entry.addresses)?),
+            connected: Set(entry.connected),
+            latency_ms: Set(entry.latency.map(|l| l.as_millis() as i64)),
+            successes: Set(entry.successes as i64),
+            failures: Set(entry.failures as i64),
+            misbehaviour: Set(entry.misbehaviour as i64),
+            score: Set(entry.score as f64),
+            last_seen: Set(last_seen),
+            updated_at: Set(now),
+        };
+        Entity::insert(active).exec(&txn).await?;
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// 列出路由表快照，按得分从高到低排序
+pub async fn list_routes() -> Result<Vec<Model>> {
+    let db = crate::storage::get_db_conn().await?;
+    Ok(Entity::find().order_by_desc(Column::Score).all(&db).await?)
+}
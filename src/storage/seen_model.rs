@@ -0,0 +1,123 @@
+use anyhow::Result;
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{Set, TransactionTrait};
+
+use crate::gossip::dedup::{parse_id, MessageId};
+use crate::node::node_id::NodeId;
+use crate::storage::get_db_conn;
+
+/// 单条 INSERT 语句写入的记录数，避免超过 SQLite 的参数个数上限
+const INSERT_CHUNK: usize = 256;
+
+/// 节点最近收到的 gossip 消息 ID，重启后恢复去重记录
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "gossip_seen")]
+pub struct Model {
+    /// 记录所属的本地节点
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub node_id: String,
+    /// 十六进制的消息 ID
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub message_id: String,
+    pub seen_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 保存 `node_id` 收到的消息 ID，已有的记录保持不变
+pub async fn save_seen(node_id: &NodeId, entries: &[(MessageId, i64)]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let db = get_db_conn().await?;
+    let txn = db.begin().await?;
+    for chunk in entries.chunks(INSERT_CHUNK) {
+        let models = chunk.iter().map(|(id, seen_at)| ActiveModel {
+            node_id: Set(node_id.to_string()),
+            message_id: Set(hex::encode(id)),
+            seen_at: Set(*seen_at),
+        });
+        Entity::insert_many(models)
+            .on_conflict(
+                OnConflict::columns([Column::NodeId, Column::MessageId])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// 读取 `node_id` 在 `since` 之后收到的消息 ID
+pub async fn load_seen(node_id: &NodeId, since: i64) -> Result<Vec<(MessageId, i64)>> {
+    let db = get_db_conn().await?;
+    let models = Entity::find()
+        .filter(Column::NodeId.eq(node_id.to_string()))
+        .filter(Column::SeenAt.gt(since))
+        .all(&db)
+        .await?;
+    Ok(models
+        .into_iter()
+        .filter_map(|m| match parse_id(&m.message_id) {
+            Some(id) => Some((id, m.seen_at)),
+            None => {
+                tracing::warn!("Ignoring invalid seen message id {}", m.message_id);
+                None
+            }
+        })
+        .collect())
+}
+
+/// 删除 `node_id` 在 `before` 及之前收到的消息 ID
+pub async fn delete_seen_before(node_id: &NodeId, before: i64) -> Result<u64> {
+    let db = get_db_conn().await?;
+    let result = Entity::delete_many()
+        .filter(Column::NodeId.eq(node_id.to_string()))
+        .filter(Column::SeenAt.lte(before))
+        .exec(&db)
+        .await?;
+    Ok(result.rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    #[tokio::test]
+    async fn test_save_load_and_delete_seen() -> Result<()> {
+        let node_id = NodeId::from_keypair(&KeyPair::generate()?);
+        let other = NodeId::from_keypair(&KeyPair::generate()?);
+        let entries: Vec<(MessageId, i64)> = (0..300u16)
+            .map(|n| {
+                let mut id = [0u8; 32];
+                id[..2].copy_from_slice(&n.to_be_bytes());
+                (id, 1000 + n as i64)
+            })
+            .collect();
+
+        save_seen(&node_id, &entries).await?;
+        // 重复保存不覆盖已有记录
+        save_seen(&node_id, &[(entries[0].0, 5000)]).await?;
+        save_seen(&other, &entries[..1]).await?;
+
+        let mut loaded = load_seen(&node_id, 0).await?;
+        loaded.sort_by_key(|&(_, at)| at);
+        assert_eq!(loaded, entries);
+        assert_eq!(load_seen(&node_id, 1289).await?, entries[290..]);
+
+        assert_eq!(delete_seen_before(&node_id, 1099).await?, 100);
+        assert_eq!(load_seen(&node_id, 0).await?.len(), 200);
+        assert_eq!(load_seen(&other, 0).await?, entries[..1]);
+
+        delete_seen_before(&node_id, i64::MAX).await?;
+        delete_seen_before(&other, i64::MAX).await?;
+        Ok(())
+    }
+}
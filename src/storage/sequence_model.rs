@@ -0,0 +1,118 @@
+use anyhow::Result;
+use sea_orm::entity::prelude::*;
+use sea_orm::Set;
+
+use crate::node::node_id::NodeId;
+use crate::storage::get_db_conn;
+
+/// 本节点发出消息所用序号的记录，`kind` 取此值
+pub const LOCAL_KIND: &str = "local";
+
+/// gossip 消息序号：本节点的发送序号，以及各来源节点每类公告最新接受的序号
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "gossip_sequences")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub node_id: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub kind: String,
+    pub sequence: i64,
+    pub timestamp: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 读取 `node_id` 在 `kind` 下记录的 (序号, 时间戳)
+pub async fn load_sequence(node_id: &NodeId, kind: &str) -> Result<Option<(u64, i64)>> {
+    let db = get_db_conn().await?;
+    let model = Entity::find_by_id((node_id.to_string(), kind.to_string()))
+        .one(&db)
+        .await?;
+    Ok(model.map(|m| (m.sequence as u64, m.timestamp)))
+}
+
+/// 保存 `node_id` 在 `kind` 下的 (序号, 时间戳)
+pub async fn save_sequence(
+    node_id: &NodeId,
+    kind: &str,
+    sequence: u64,
+    timestamp: i64,
+) -> Result<()> {
+    let db = get_db_conn().await?;
+    let now = chrono::Local::now().timestamp();
+    match Entity::find_by_id((node_id.to_string(), kind.to_string()))
+        .one(&db)
+        .await?
+    {
+        Some(m) => {
+            let mut active: ActiveModel = m.into();
+            active.sequence = Set(sequence as i64);
+            active.timestamp = Set(timestamp);
+            active.updated_at = Set(now);
+            active.update(&db).await?;
+        }
+        None => {
+            let active = ActiveModel {
+                node_id: Set(node_id.to_string()),
+                kind: Set(kind.to_string()),
+                sequence: Set(sequence as i64),
+                timestamp: Set(timestamp),
+                updated_at: Set(now),
+            };
+            Entity::insert(active).exec(&db).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 列出所有来源节点最新接受的序号，不含本节点的发送序号
+pub async fn list_sequences() -> Result<Vec<(NodeId, String, u64, i64)>> {
+    let db = get_db_conn().await?;
+    let models = Entity::find()
+        .filter(Column::Kind.ne(LOCAL_KIND))
+        .all(&db)
+        .await?;
+    Ok(models
+        .into_iter()
+        .filter_map(|m| {
+            let node_id = NodeId::from_string(&m.node_id).ok()?;
+            Some((node_id, m.kind, m.sequence as u64, m.timestamp))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    #[tokio::test]
+    async fn test_save_and_load_sequence() -> Result<()> {
+        let node_id = NodeId::from_keypair(&KeyPair::generate()?);
+        assert_eq!(load_sequence(&node_id, "node_announcement").await?, None);
+
+        save_sequence(&node_id, "node_announcement", 7, 100).await?;
+        save_sequence(&node_id, "node_announcement", 9, 130).await?;
+        save_sequence(&node_id, LOCAL_KIND, 3, 0).await?;
+        assert_eq!(
+            load_sequence(&node_id, "node_announcement").await?,
+            Some((9, 130))
+        );
+        assert_eq!(load_sequence(&node_id, LOCAL_KIND).await?, Some((3, 0)));
+
+        let listed: Vec<_> = list_sequences()
+            .await?
+            .into_iter()
+            .filter(|(id, ..)| *id == node_id)
+            .collect();
+        assert_eq!(
+            listed,
+            vec![(node_id.clone(), "node_announcement".to_string(), 9, 130)]
+        );
+        Ok(())
+    }
+}
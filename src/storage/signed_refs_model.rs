@@ -0,0 +1,90 @@
+use anyhow::Result;
+use sea_orm::entity::prelude::*;
+use sea_orm::Set;
+
+use crate::repo::repo::SignedRefs;
+use crate::storage::get_db_conn;
+
+/// 仓库所有者最近一次签名的 refs，随仓库记录转发给其他节点
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "signed_refs")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub repo_id: String,
+    /// JSON 编码的 ref 名称 -> commit hash
+    pub refs: String,
+    pub sequence: i64,
+    pub signature: String,
+    pub updated_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 保存仓库的签名 refs，已有记录时替换
+pub async fn save_signed_refs(repo_id: &str, signed: &SignedRefs) -> Result<()> {
+    let db = get_db_conn().await?;
+    let now = chrono::Local::now().timestamp();
+    let active = ActiveModel {
+        repo_id: Set(repo_id.to_string()),
+        refs: Set(serde_json::to_string(&signed.refs)?),
+        sequence: Set(signed.sequence as i64),
+        signature: Set(signed.signature.clone()),
+        updated_at: Set(now),
+    };
+    match Entity::find_by_id(repo_id).one(&db).await? {
+        Some(_) => {
+            active.update(&db).await?;
+        }
+        None => {
+            Entity::insert(active).exec(&db).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 读取仓库的签名 refs
+pub async fn load_signed_refs(repo_id: &str) -> Result<Option<SignedRefs>> {
+    let db = get_db_conn().await?;
+    let Some(model) = Entity::find_by_id(repo_id).one(&db).await? else {
+        return Ok(None);
+    };
+    Ok(Some(SignedRefs {
+        refs: serde_json::from_str(&model.refs)?,
+        sequence: model.sequence as u64,
+        signature: model.signature,
+    }))
+}
+
+/// 删除仓库的签名 refs
+pub async fn delete_signed_refs(repo_id: &str) -> Result<()> {
+    let db = get_db_conn().await?;
+    Entity::delete_by_id(repo_id).exec(&db).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_save_load_and_delete_signed_refs() -> Result<()> {
+        let repo_id = "did:repo:test-signed-refs-001";
+        let signed = |sequence| SignedRefs {
+            refs: HashMap::from([("refs/heads/main".to_string(), "abc123".to_string())]),
+            sequence,
+            signature: "00".repeat(64),
+        };
+
+        save_signed_refs(repo_id, &signed(1)).await?;
+        save_signed_refs(repo_id, &signed(2)).await?;
+        assert_eq!(load_signed_refs(repo_id).await?, Some(signed(2)));
+
+        delete_signed_refs(repo_id).await?;
+        assert_eq!(load_signed_refs(repo_id).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use sea_orm::entity::prelude::*;
+use sea_orm::Set;
+use std::collections::HashSet;
+
+use crate::gossip::topic::Topic;
+use crate::storage::get_db_conn;
+
+/// 本节点的主题订阅；`subscribed` 为 false 的记录表示取消了默认订阅
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "subscriptions")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub topic: String,
+    pub subscribed: bool,
+    pub updated_at: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 订阅或取消订阅 `topic`
+pub async fn set_subscribed(topic: &Topic, subscribed: bool) -> Result<()> {
+    let db = get_db_conn().await?;
+    let now = chrono::Local::now().timestamp();
+    match Entity::find_by_id(topic.to_string()).one(&db).await? {
+        Some(m) => {
+            let mut active: ActiveModel = m.into();
+            active.subscribed = Set(subscribed);
+            active.updated_at = Set(now);
+            active.update(&db).await?;
+        }
+        None => {
+            let active = ActiveModel {
+                topic: Set(topic.to_string()),
+                subscribed: Set(subscribed),
+                updated_at: Set(now),
+            };
+            Entity::insert(active).exec(&db).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 本节点当前订阅的主题：默认订阅加上记录的订阅，去掉取消的订阅
+pub async fn load_topics() -> Result<HashSet<Topic>> {
+    let db = get_db_conn().await?;
+    let mut topics = Topic::defaults();
+    for m in Entity::find().all(&db).await? {
+        let topic = match m.topic.parse::<Topic>() {
+            Ok(topic) => topic,
+            Err(e) => {
+                tracing::warn!("Ignoring invalid subscription {}: {}", m.topic, e);
+                continue;
+            }
+        };
+        if m.subscribed {
+            topics.insert(topic);
+        } else {
+            topics.remove(&topic);
+        }
+    }
+    Ok(topics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+    use crate::node::node_id::NodeId;
+
+    #[tokio::test]
+    async fn test_set_subscribed() -> Result<()> {
+        let node_id = NodeId::from_keypair(&KeyPair::generate()?);
+        let repo = Topic::Repo(format!("did:repo:subscription-{}", node_id));
+        let creator = Topic::Creator(node_id);
+        assert!(!load_topics().await?.contains(&creator));
+
+        set_subscribed(&creator, true).await?;
+        set_subscribed(&repo, true).await?;
+        let topics = load_topics().await?;
+        assert!(topics.contains(&creator) && topics.contains(&repo));
+
+        set_subscribed(&repo, false).await?;
+        let topics = load_topics().await?;
+        assert!(topics.contains(&creator) && !topics.contains(&repo));
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! 地址分类、公告过滤和拨号顺序
+//!
+//! - 未指定地址（`0.0.0.0`、`::`）和端口 0 无法拨号，既不公告也不拨号
+//! - 环回地址只对同一台机器上的节点有意义，默认不公告，`allow_local` 时保留
+//! - 拨号时按公网、私有网络、环回的顺序尝试，同类地址保持原有顺序
+use std::net::{IpAddr, SocketAddr};
+
+/// 地址的可达范围，顺序即拨号优先级
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AddrScope {
+    /// 公网地址
+    Global,
+    /// 私有网络和链路本地地址
+    Private,
+    /// 环回地址
+    Loopback,
+}
+
+impl AddrScope {
+    pub fn of(ip: IpAddr) -> Self {
+        if ip.is_loopback() {
+            return AddrScope::Loopback;
+        }
+        let private = match ip {
+            IpAddr::V4(v4) => {
+                // 100.64.0.0/10 为运营商级 NAT 的共享地址
+                v4.is_private()
+                    || v4.is_link_local()
+                    || (v4.octets()[0] == 100 && v4.octets()[1] & 0xc0 == 64)
+            }
+            IpAddr::V6(v6) => {
+                let first = v6.segments()[0];
+                // fc00::/7 唯一本地地址，fe80::/10 链路本地地址
+                first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+            }
+        };
+        if private {
+            AddrScope::Private
+        } else {
+            AddrScope::Global
+        }
+    }
+}
+
+/// 把 IPv4 映射的 IPv6 地址（双栈 socket 上的 IPv4 对端）还原为 IPv4 地址
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// 地址能否被其他节点拨号
+pub fn is_dialable(addr: &SocketAddr) -> bool {
+    !addr.ip().is_unspecified() && addr.port() != 0
+}
+
+/// 地址能否出现在 NodeAnnouncement 中
+pub fn is_announceable(addr: &SocketAddr, allow_local: bool) -> bool {
+    is_dialable(addr) && (allow_local || !addr.ip().is_loopback())
+}
+
+/// 本地 endpoint 可以拨出的地址族
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Families {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl Families {
+    pub fn supports(&self, addr: &SocketAddr) -> bool {
+        match addr {
+            SocketAddr::V4(_) => self.ipv4,
+            SocketAddr::V6(_) => self.ipv6,
+        }
+    }
+}
+
+/// 整理拨号地址：去重，去掉无法拨号和本地不支持的地址族，按 [`AddrScope`] 排序
+pub fn dial_order(addrs: &[SocketAddr], families: Families) -> Vec<SocketAddr> {
+    let mut ordered: Vec<SocketAddr> = Vec::new();
+    for addr in addrs.iter().map(|a| canonical(*a)) {
+        if is_dialable(&addr) && families.supports(&addr) && !ordered.contains(&addr) {
+            ordered.push(addr);
+        }
+    }
+    // 稳定排序，同一范围内保持公告中的顺序
+    ordered.sort_by_key(|a| AddrScope::of(a.ip()));
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_scope_and_canonical() {
+        assert_eq!(AddrScope::of(addr("8.8.8.8:1").ip()), AddrScope::Global);
+        assert_eq!(
+            AddrScope::of(addr("192.168.1.2:1").ip()),
+            AddrScope::Private
+        );
+        assert_eq!(AddrScope::of(addr("100.72.0.1:1").ip()), AddrScope::Private);
+        assert_eq!(AddrScope::of(addr("[fd00::1]:1").ip()), AddrScope::Private);
+        assert_eq!(AddrScope::of(addr("[fe80::1]:1").ip()), AddrScope::Private);
+        assert_eq!(
+            AddrScope::of(addr("[2001:db8::1]:1").ip()),
+            AddrScope::Global
+        );
+        assert_eq!(AddrScope::of(addr("[::1]:1").ip()), AddrScope::Loopback);
+
+        assert_eq!(
+            canonical(addr("[::ffff:203.0.113.7]:9000")),
+            addr("203.0.113.7:9000")
+        );
+        assert_eq!(
+            canonical(addr("[2001:db8::1]:9000")),
+            addr("[2001:db8::1]:9000")
+        );
+    }
+
+    #[test]
+    fn test_announceable() {
+        assert!(!is_announceable(&addr("0.0.0.0:9000"), true));
+        assert!(!is_announceable(&addr("[::]:9000"), true));
+        assert!(!is_announceable(&addr("203.0.113.7:0"), true));
+        assert!(!is_announceable(&addr("127.0.0.1:9000"), false));
+        assert!(is_announceable(&addr("127.0.0.1:9000"), true));
+        assert!(is_announceable(&addr("192.168.1.2:9000"), false));
+    }
+
+    #[test]
+    fn test_dial_order() {
+        let v4_only = Families {
+            ipv4: true,
+            ipv6: false,
+        };
+        let dual = Families {
+            ipv4: true,
+            ipv6: true,
+        };
+
+        let addrs = [
+            addr("127.0.0.1:9000"),
+            addr("192.168.1.2:9000"),
+            addr("[2001:db8::1]:9000"),
+            addr("0.0.0.0:9000"),
+            addr("203.0.113.7:9000"),
+            addr("[::ffff:203.0.113.7]:9000"),
+        ];
+        assert_eq!(
+            dial_order(&addrs, v4_only),
+            vec![
+                addr("203.0.113.7:9000"),
+                addr("192.168.1.2:9000"),
+                addr("127.0.0.1:9000"),
+            ]
+        );
+        assert_eq!(
+            dial_order(&addrs, dual),
+            vec![
+                addr("[2001:db8::1]:9000"),
+                addr("203.0.113.7:9000"),
+                addr("192.168.1.2:9000"),
+                addr("127.0.0.1:9000"),
+            ]
+        );
+    }
+}
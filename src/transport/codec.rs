@@ -0,0 +1,142 @@
+//! 消息编码
+//!
+//! 身份握手之后的消息（gossip、聊天、bundle 传输、RPC、中继电路）按对端声明的能力选择编码：
+//! 声明了 `binary` 能力的节点收到二进制编码，其余节点收到 JSON。
+//!
+//! ```text
+//! +--------------+----------------+------------------+
+//! | magic (0x00) | version (u8)   | CBOR (RFC 8949)  |
+//! +--------------+----------------+------------------+
+//! ```
+//!
+//! JSON 文本不会以 0x00 开头，接收方据此自动识别两种编码。JSON 解码只为兼容旧版本节点再保留一个版本。
+//! 身份握手在能力协商之前进行，仍然使用 JSON。
+//!
+//! 签名使用 [`canonical`] 生成的确定性编码，与字段声明顺序、`HashMap` 的遍历顺序和 JSON 的规范化无关。
+use crate::transport::protocol::{Capabilities, PeerProtocol};
+use anyhow::{anyhow, Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// 二进制编码的首字节
+pub const BINARY_MAGIC: u8 = 0x00;
+/// 二进制编码的版本
+pub const CODEC_VERSION: u8 = 1;
+
+/// 消息编码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Binary,
+}
+
+impl Encoding {
+    /// 发给对端的消息使用的编码，对端协议未知时使用 JSON
+    pub fn for_peer(protocol: Option<&PeerProtocol>) -> Self {
+        match protocol {
+            Some(p) if p.supports(Capabilities::BINARY) => Encoding::Binary,
+            _ => Encoding::Json,
+        }
+    }
+
+    /// 已编码的消息使用的编码，用于以请求的编码回复
+    pub fn of(bytes: &[u8]) -> Self {
+        if bytes.first() == Some(&BINARY_MAGIC) {
+            Encoding::Binary
+        } else {
+            Encoding::Json
+        }
+    }
+}
+
+/// 以指定编码序列化消息
+pub fn encode<T: Serialize>(value: &T, encoding: Encoding) -> Result<Vec<u8>> {
+    match encoding {
+        Encoding::Json => Ok(serde_json::to_vec(value)?),
+        Encoding::Binary => {
+            let mut bytes = vec![BINARY_MAGIC, CODEC_VERSION];
+            serde_cbor::to_writer(&mut bytes, value)?;
+            Ok(bytes)
+        }
+    }
+}
+
+/// 反序列化消息，自动识别二进制编码和 JSON
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    match bytes {
+        [BINARY_MAGIC, CODEC_VERSION, body @ ..] => {
+            serde_cbor::from_slice(body).context("invalid binary message")
+        }
+        [BINARY_MAGIC, version, ..] => Err(anyhow!("unsupported codec version {}", version)),
+        [BINARY_MAGIC] => Err(anyhow!("truncated binary message")),
+        _ => serde_json::from_slice(bytes).context("invalid JSON message"),
+    }
+}
+
+/// 确定性编码，用于计算签名和消息 ID
+///
+/// 先转换为 CBOR 值再编码：map 的键按 RFC 7049 规范顺序排列（短键在前，等长按字节序），
+/// 整数使用最短编码，字节串保持原样。同一消息在任何节点上得到相同的字节
+pub fn canonical<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let value = serde_cbor::value::to_value(value)?;
+    Ok(serde_cbor::to_vec(&value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Chunk {
+        index: u32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    #[test]
+    fn test_round_trip_both_encodings() {
+        let chunk = Chunk {
+            index: 7,
+            data: vec![0xff; 1024],
+        };
+        let json = encode(&chunk, Encoding::Json).unwrap();
+        let binary = encode(&chunk, Encoding::Binary).unwrap();
+        assert_eq!(Encoding::of(&json), Encoding::Json);
+        assert_eq!(Encoding::of(&binary), Encoding::Binary);
+        assert_eq!(decode::<Chunk>(&json).unwrap(), chunk);
+        assert_eq!(decode::<Chunk>(&binary).unwrap(), chunk);
+        // 字节数组不再展开为数字列表
+        assert!(binary.len() < 1100);
+        assert!(json.len() > 3 * 1024);
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut binary = encode(&1u32, Encoding::Binary).unwrap();
+        binary[1] = CODEC_VERSION + 1;
+        assert!(decode::<u32>(&binary).is_err());
+        assert!(decode::<u32>(&[BINARY_MAGIC]).is_err());
+    }
+
+    #[test]
+    fn test_canonical_ignores_insertion_order() {
+        let mut a = HashMap::new();
+        let mut b = HashMap::new();
+        for i in 0..32 {
+            a.insert(format!("key-{}", i), i);
+        }
+        for i in (0..32).rev() {
+            b.insert(format!("key-{}", i), i);
+        }
+        assert_eq!(canonical(&a).unwrap(), canonical(&b).unwrap());
+
+        // 短键排在长键之前
+        let map = HashMap::from([("bb", 1u8), ("a", 2u8)]);
+        assert_eq!(hex::encode(canonical(&map).unwrap()), "a261610262626201");
+
+        // 按长度排序与按字典序排序结果不同的键："b" 排在 "aa" 之前
+        let map = HashMap::from([("aa", 1u8), ("b", 2u8)]);
+        assert_eq!(hex::encode(canonical(&map).unwrap()), "a261620262616101");
+    }
+}
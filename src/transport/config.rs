@@ -1,56 +1,145 @@
+use crate::identity::keypair::KeyPair;
+use crate::node::node_id::NodeId;
+use crate::node::reputation::ReputationConfig;
+use crate::transport::cert::{generate_identity_certificate, node_id_from_certificate};
+use crate::transport::limits::ConnectionLimits;
+use crate::transport::protocol::{self, Capabilities, PeerProtocol};
+use crate::transport::relay::RelayLimits;
 use anyhow::Result;
 use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
 use quinn::{ClientConfig, IdleTimeout, ServerConfig, TransportConfig, VarInt};
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use std::fs::File;
-use std::io::BufReader;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
-pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"h3"];
+fn crypto_provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or(Arc::new(rustls::crypto::ring::default_provider()))
+}
+
+fn verify_tls13(
+    provider: &CryptoProvider,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls13_signature(
+        message,
+        cert,
+        dss,
+        &provider.signature_verification_algorithms,
+    )
+}
+
+fn identity_error(e: anyhow::Error) -> rustls::Error {
+    tracing::warn!("Rejected peer certificate: {}", e);
+    rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+}
 
-/// 用于开发/测试环境的服务器证书验证器
-/// 跳过所有服务器证书验证，允许自签名证书和不同的 CA
+/// 服务端证书验证器：证书公钥必须对应本次拨号的目标 NodeId
+///
+/// 节点证书均为自签名，不依赖 CA，身份由 ed25519 公钥本身确定
 #[derive(Debug)]
-struct NoServerCertificateVerification;
+struct NodeIdServerVerifier {
+    expected: NodeId,
+    provider: Arc<CryptoProvider>,
+}
 
-impl rustls::client::danger::ServerCertVerifier for NoServerCertificateVerification {
+impl ServerCertVerifier for NodeIdServerVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &CertificateDer<'_>,
+        end_entity: &CertificateDer<'_>,
         _intermediates: &[CertificateDer<'_>],
-        _server_name: &rustls::pki_types::ServerName<'_>,
+        _server_name: &ServerName<'_>,
         _ocsp_response: &[u8],
-        _now: rustls::pki_types::UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        // 跳过验证，允许任何证书
-        Ok(rustls::client::danger::ServerCertVerified::assertion())
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let node_id = node_id_from_certificate(end_entity).map_err(identity_error)?;
+        if node_id != self.expected {
+            return Err(identity_error(anyhow::anyhow!(
+                "server certificate belongs to {}, expected {}",
+                node_id,
+                self.expected
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
     }
 
     fn verify_tls12_signature(
         &self,
         _message: &[u8],
         _cert: &CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::General("TLS 1.2 is not supported".into()))
     }
 
     fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13(&self.provider, message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// 客户端证书验证器：要求客户端出示由其 ed25519 身份密钥生成的证书
+///
+/// 入站时尚不知道对端身份，这里只校验证书能解析出合法的 NodeId，
+/// 与握手中声明的 NodeId 的一致性由 `ConnectionManager` 检查
+#[derive(Debug)]
+struct NodeIdClientVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for NodeIdClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        node_id_from_certificate(end_entity).map_err(identity_error)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
         &self,
         _message: &[u8],
         _cert: &CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::General("TLS 1.2 is not supported".into()))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13(&self.provider, message, cert, dss)
     }
 
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        let provider = rustls::crypto::CryptoProvider::get_default()
-            .cloned()
-            .unwrap_or(Arc::new(rustls::crypto::ring::default_provider()));
-        provider
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
             .signature_verification_algorithms
             .supported_schemes()
     }
@@ -58,121 +147,133 @@ impl rustls::client::danger::ServerCertVerifier for NoServerCertificateVerificat
 
 #[derive(Clone, Debug)]
 pub struct QuicConfig {
-    pub bind_addr: SocketAddr,
-    pub cert_path: String,
-    pub key_path: String,
-    pub ca_cert_path: String,
+    /// 监听地址，可同时包含 IPv4 和 IPv6 地址，每个地址一个 endpoint
+    pub bind_addrs: Vec<SocketAddr>,
+    /// 是否在 NodeAnnouncement 中公告环回地址，用于同一台机器上的测试网络
+    pub allow_local_addrs: bool,
+    /// 节点身份密钥，TLS 证书由它派生
+    pub keypair: KeyPair,
+    /// 作为中继节点为其他节点转发电路时的限制，`None` 表示不提供中继服务
+    pub relay: Option<RelayLimits>,
+    /// 入站连接数、收发速率和接收队列的限制
+    pub limits: ConnectionLimits,
+    /// 封禁节点的信誉阈值和封禁时长
+    pub reputation: ReputationConfig,
 }
 
 impl QuicConfig {
-    pub fn new(
-        bind_addr: SocketAddr,
-        cert_path: String,
-        key_path: String,
-        ca_cert_path: String,
-    ) -> Self {
+    pub fn new(bind_addr: SocketAddr, keypair: KeyPair) -> Self {
         QuicConfig {
-            bind_addr,
-            cert_path,
-            key_path,
-            ca_cert_path,
+            bind_addrs: vec![bind_addr],
+            allow_local_addrs: false,
+            keypair,
+            relay: None,
+            limits: ConnectionLimits::default(),
+            reputation: ReputationConfig::default(),
         }
     }
 
+    /// 在多个地址上监听，例如同时监听 `0.0.0.0:9000` 和 `[::]:9000`
+    pub fn with_bind_addrs(mut self, bind_addrs: Vec<SocketAddr>) -> Self {
+        self.bind_addrs = bind_addrs;
+        self
+    }
+
+    /// 允许公告环回地址
+    pub fn with_local_addrs(mut self, allow: bool) -> Self {
+        self.allow_local_addrs = allow;
+        self
+    }
+
+    /// 启用中继服务
+    pub fn with_relay(mut self, limits: RelayLimits) -> Self {
+        self.relay = Some(limits);
+        self
+    }
+
+    /// 设置连接和速率限制
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// 设置信誉阈值和封禁时长
+    pub fn with_reputation(mut self, reputation: ReputationConfig) -> Self {
+        self.reputation = reputation;
+        self
+    }
+
+    /// 本节点的 NodeId
+    pub fn node_id(&self) -> NodeId {
+        NodeId::from_keypair(&self.keypair)
+    }
+
+    /// 本节点在握手中声明的能力，启用中继服务时包含 `RELAY`
+    pub fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::standard();
+        if self.relay.is_some() {
+            capabilities |= Capabilities::RELAY;
+        }
+        capabilities
+    }
+
+    /// 本节点在握手中声明的协议版本和能力
+    pub fn protocol(&self) -> PeerProtocol {
+        PeerProtocol::local(self.capabilities())
+    }
+
     /// 获取服务器配置
-    /// 注意：不验证客户端证书，仅适用于开发/测试环境
-    /// 生产环境应该使用正确的 CA 证书验证
+    /// 使用身份密钥自签名证书，并要求客户端同样出示身份证书
     pub fn get_server_config(&self) -> Result<ServerConfig> {
-        let (certs, key) = self.get_certificate_from_file()?;
+        let (cert, key) = generate_identity_certificate(&self.keypair)?;
+        let provider = crypto_provider();
 
         let mut server_crypto = rustls::ServerConfig::builder()
-            .with_no_client_auth() // 不验证客户端证书
-            .with_single_cert(certs, key)?;
-        server_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+            .with_client_cert_verifier(Arc::new(NodeIdClientVerifier { provider }))
+            .with_single_cert(vec![cert], key)?;
+        server_crypto.alpn_protocols = protocol::alpn_protocols();
         server_crypto.max_early_data_size = u32::MAX;
 
         let mut server_config =
             ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_crypto)?));
 
-        let mut transport_config = TransportConfig::default();
-        transport_config.max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(300_000))));
-        transport_config.keep_alive_interval(Some(Duration::from_secs(30)));
-        server_config.transport_config(Arc::new(transport_config));
+        server_config.transport_config(Arc::new(self.transport_config()));
 
         Ok(server_config)
     }
 
-    /// 获取客户端配置
-    /// 注意：使用不验证服务器证书的配置，仅适用于开发/测试环境
-    /// 生产环境应该使用正确的 CA 证书验证
-    pub fn get_client_config(&self) -> Result<ClientConfig> {
-        let (certs, key) = self.get_certificate_from_file()?;
+    /// 获取拨号到 `expected` 节点时使用的客户端配置
+    /// 只接受公钥与 `expected` 对应的服务端证书
+    pub fn get_client_config(&self, expected: &NodeId) -> Result<ClientConfig> {
+        let (cert, key) = generate_identity_certificate(&self.keypair)?;
+        let verifier = NodeIdServerVerifier {
+            expected: expected.clone(),
+            provider: crypto_provider(),
+        };
 
-        // 创建一个不验证服务器证书的客户端配置
-        // 这对于开发/测试环境很有用，当每个节点都有独立的证书时
         let mut client_crypto = rustls::ClientConfig::builder()
             .dangerous()
-            .with_custom_certificate_verifier(Arc::new(NoServerCertificateVerification))
-            .with_client_auth_cert(certs, key)?;
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(vec![cert], key)?;
 
-        client_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+        client_crypto.alpn_protocols = protocol::alpn_protocols();
         client_crypto.enable_early_data = false;
         let mut client_config =
             ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
 
-        let mut transport_config = TransportConfig::default();
-        transport_config.max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(300_000))));
-        transport_config.keep_alive_interval(Some(Duration::from_secs(30)));
-        client_config.transport_config(Arc::new(transport_config));
+        client_config.transport_config(Arc::new(self.transport_config()));
 
         Ok(client_config)
     }
 
-    /// 从文件读取证书和密钥
-    pub fn get_certificate_from_file(
-        &self,
-    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-        let cert_file = File::open(self.cert_path.as_str())?;
-        let mut cert_reader = BufReader::new(cert_file);
-        let certs = rustls_pemfile::certs(&mut cert_reader).collect::<std::io::Result<Vec<_>>>()?;
-
-        if certs.is_empty() {
-            return Err(anyhow::anyhow!("No certificates found in PEM file"));
-        }
-
-        let file = File::open(self.key_path.as_str())?;
-        let mut reader = BufReader::new(file);
-
-        // 尝试读取PKCS8格式的私钥
-        if let Some(key) = rustls_pemfile::private_key(&mut reader)? {
-            return Ok((certs, key));
-        }
-
-        // 如果PKCS8格式读取失败，重新读取文件尝试其他格式
-        let file = File::open(self.key_path.as_str())?;
-        let mut reader = BufReader::new(file);
-
-        // 尝试读取所有可能的私钥格式
-        let keys =
-            rustls_pemfile::pkcs8_private_keys(&mut reader).collect::<std::io::Result<Vec<_>>>()?;
-
-        if !keys.is_empty() {
-            return Ok((certs, PrivateKeyDer::Pkcs8(keys[0].clone_key())));
-        }
-        Err(anyhow::anyhow!("No key found in PEM file"))
-    }
-
-    /// 从文件读取 CA 证书
-    pub fn get_ca_certificate_from_file(&self) -> Result<CertificateDer<'static>> {
-        let file = File::open(self.ca_cert_path.as_str())?;
-        let mut reader = BufReader::new(file);
-
-        let certs = rustls_pemfile::certs(&mut reader).collect::<std::io::Result<Vec<_>>>()?;
-
-        if certs.is_empty() {
-            return Err(anyhow::anyhow!("No certificates found in CA PEM file"));
-        }
-
-        Ok(certs[0].clone())
+    /// 两个方向共用的传输参数，对端可同时打开的流数受 `limits` 限制
+    fn transport_config(&self) -> TransportConfig {
+        let streams = VarInt::from_u32(self.limits.max_streams_per_peer);
+        let mut transport_config = TransportConfig::default();
+        transport_config.max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(300_000))));
+        transport_config.keep_alive_interval(Some(Duration::from_secs(30)));
+        transport_config.max_concurrent_uni_streams(streams);
+        transport_config.max_concurrent_bidi_streams(streams);
+        transport_config
     }
 }
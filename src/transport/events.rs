@@ -0,0 +1,80 @@
+//! 连接生命周期事件
+//!
+//! 传输层在与某个节点建立第一条连接、以及与它的最后一条连接断开时，通过 broadcast channel
+//! 发出 [`ConnectionEvent`]。同一节点的重复连接被替换时不会重复发出事件，
+//! 订阅方看到的 Connected 与 Disconnected 总是交替出现。
+use crate::node::node_id::NodeId;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+/// 事件 channel 的容量，订阅方处理过慢时丢失最早的事件并收到 `Lagged`
+pub const EVENT_CAPACITY: usize = 256;
+
+/// 连接由哪一方发起
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// 对端拨号到本节点
+    Inbound,
+    /// 本节点拨号到对端
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected {
+        node_id: NodeId,
+        direction: Direction,
+        /// 对端地址，经由中继连接或内存网络中为 `None`
+        addr: Option<SocketAddr>,
+        /// 经由哪个中继节点建立
+        relay: Option<NodeId>,
+    },
+    Disconnected {
+        node_id: NodeId,
+    },
+}
+
+impl ConnectionEvent {
+    pub fn node_id(&self) -> &NodeId {
+        match self {
+            ConnectionEvent::Connected { node_id, .. } => node_id,
+            ConnectionEvent::Disconnected { node_id } => node_id,
+        }
+    }
+}
+
+pub type EventSender = broadcast::Sender<ConnectionEvent>;
+
+pub fn channel() -> EventSender {
+    broadcast::channel(EVENT_CAPACITY).0
+}
+
+/// 两个节点同时互相拨号时应保留的连接方向：保留 NodeId 较小的一方发起的连接
+///
+/// 双方独立计算得到同一条连接，不需要额外协商
+pub fn preferred_direction(local: &NodeId, peer: &NodeId) -> Direction {
+    if local.0 < peer.0 {
+        Direction::Outbound
+    } else {
+        Direction::Inbound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    #[test]
+    fn test_preferred_direction_is_symmetric() {
+        let a = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let b = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let (low, high) = if a.0 < b.0 { (a, b) } else { (b, a) };
+
+        // 较小的一方保留出站连接，较大的一方保留入站连接，指向同一条连接
+        assert_eq!(preferred_direction(&low, &high), Direction::Outbound);
+        assert_eq!(preferred_direction(&high, &low), Direction::Inbound);
+    }
+}
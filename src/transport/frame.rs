@@ -0,0 +1,189 @@
+//! 帧格式：类型字节 + 长度 + 负载
+//!
+//! 每个对端、每个逻辑通道使用一条长期存在的单向流，流上连续写入帧：
+//!
+//! ```text
+//! +-----------+----------------+-------------------+
+//! | type (u8) | length (u32be) | payload (length)  |
+//! +-----------+----------------+-------------------+
+//! ```
+//!
+//! 类型字节即 [`Channel`]，接收方据此把负载路由到对应的处理器。
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// 单帧负载上限
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// 帧头长度：1 字节类型 + 4 字节长度
+pub const FRAME_HEADER_LEN: usize = 5;
+
+/// 逻辑通道
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Channel {
+    /// Gossip 控制消息
+    Gossip,
+    /// 数据传输（bundle 等大文件）
+    Data,
+    /// 请求/响应（仅用于双向流，见 `transport::rpc`）
+    Rpc,
+    /// 中继电路的建立消息和承载的数据报（仅用于双向流，见 `transport::relay`）
+    Relay,
+}
+
+impl Channel {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Channel::Gossip => 1,
+            Channel::Data => 2,
+            Channel::Rpc => 3,
+            Channel::Relay => 4,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Channel::Gossip),
+            2 => Some(Channel::Data),
+            3 => Some(Channel::Rpc),
+            4 => Some(Channel::Relay),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Channel::Gossip => write!(f, "gossip"),
+            Channel::Data => write!(f, "data"),
+            Channel::Rpc => write!(f, "rpc"),
+            Channel::Relay => write!(f, "relay"),
+        }
+    }
+}
+
+/// 一个完整的帧
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub channel: Channel,
+    pub payload: Vec<u8>,
+}
+
+/// 写入一帧
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    channel: Channel,
+    payload: &[u8],
+) -> Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "frame too large: {} bytes (max {})",
+            payload.len(),
+            MAX_FRAME_SIZE
+        ));
+    }
+
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header[0] = channel.as_u8();
+    header[1..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    writer.write_all(&header).await?;
+    writer.write_all(payload).await?;
+    Ok(())
+}
+
+/// 读取下一帧，流正常结束时返回 `None`
+///
+/// 未知类型的帧会被跳过，以便新版本可以增加通道而不破坏旧节点
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+    loop {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        match reader.read_exact(&mut header[..1]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        reader.read_exact(&mut header[1..]).await?;
+
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(anyhow!(
+                "frame too large: {} bytes (max {})",
+                len,
+                MAX_FRAME_SIZE
+            ));
+        }
+
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+
+        match Channel::from_u8(header[0]) {
+            Some(channel) => return Ok(Some(Frame { channel, payload })),
+            None => {
+                tracing::debug!(
+                    "Skipping frame with unknown type {} ({} bytes)",
+                    header[0],
+                    len
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+        let big = vec![7u8; 3 * 1024 * 1024];
+
+        let writer = tokio::spawn(async move {
+            write_frame(&mut a, Channel::Gossip, b"hello")
+                .await
+                .unwrap();
+            write_frame(&mut a, Channel::Data, &big).await.unwrap();
+            write_frame(&mut a, Channel::Data, b"").await.unwrap();
+        });
+
+        let first = read_frame(&mut b).await.unwrap().unwrap();
+        assert_eq!(first.channel, Channel::Gossip);
+        assert_eq!(first.payload, b"hello");
+
+        let second = read_frame(&mut b).await.unwrap().unwrap();
+        assert_eq!(second.channel, Channel::Data);
+        assert_eq!(second.payload.len(), 3 * 1024 * 1024);
+
+        let third = read_frame(&mut b).await.unwrap().unwrap();
+        assert!(third.payload.is_empty());
+
+        writer.await.unwrap();
+        assert!(read_frame(&mut b).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_frame_type_is_skipped() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&[0xff, 0, 0, 0, 3, 1, 2, 3]).await.unwrap();
+        write_frame(&mut a, Channel::Gossip, b"after")
+            .await
+            .unwrap();
+        drop(a);
+
+        let frame = read_frame(&mut b).await.unwrap().unwrap();
+        assert_eq!(frame.channel, Channel::Gossip);
+        assert_eq!(frame.payload, b"after");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let len = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        a.write_all(&[Channel::Data.as_u8(), len[0], len[1], len[2], len[3]])
+            .await
+            .unwrap();
+
+        assert!(read_frame(&mut b).await.is_err());
+    }
+}
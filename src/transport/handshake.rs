@@ -0,0 +1,451 @@
+//! 连接身份握手
+//!
+//! QUIC 连接建立后，双方在第一条双向流上完成 challenge-response：
+//! 各自用 NodeId 对应的 ed25519 私钥签名一份绑定到当前 TLS 会话（exporter）
+//! 以及双方随机数的 transcript，对端验签通过后才认为该连接属于声称的 NodeId。
+//!
+//! 服务端同时在 ServerHello 中告知客户端它观察到的客户端地址，
+//! 位于 NAT 之后的节点据此得知自己的外部地址。
+//!
+//! Hello 消息还携带协议版本和能力位（同样计入 transcript），
+//! 服务端对版本过旧的客户端回复 `Rejected` 并说明原因。
+use crate::identity::keypair::KeyPair;
+use crate::node::node_id::NodeId;
+use crate::transport::protocol::{Capabilities, PeerProtocol};
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::Signature;
+use quinn::{Connection, RecvStream, SendStream};
+use rand_core::{OsRng, RngCore};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// 握手整体超时时间
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 握手失败时关闭连接使用的应用错误码
+pub const HANDSHAKE_FAILED_CODE: u32 = 0x10;
+
+const MAX_HANDSHAKE_MESSAGE_SIZE: usize = 16 * 1024;
+const NONCE_LEN: usize = 32;
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-megaengine-handshake";
+const TRANSCRIPT_DOMAIN: &[u8] = b"megaengine-handshake-v1";
+/// 发送 `Rejected` 后等待客户端读完的时间，避免关闭连接时丢弃拒绝原因
+const REJECT_LINGER: Duration = Duration::from_secs(1);
+
+/// 握手消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum HandshakeMessage {
+    ClientHello {
+        node_id: NodeId,
+        nonce: String,
+        version: u8,
+        capabilities: Capabilities,
+    },
+    ServerHello {
+        node_id: NodeId,
+        nonce: String,
+        signature: String,
+        /// 服务端观察到的客户端地址
+        observed_addr: SocketAddr,
+        version: u8,
+        capabilities: Capabilities,
+    },
+    ClientFinish {
+        signature: String,
+    },
+    Accepted,
+    /// 服务端拒绝连接，例如客户端协议版本过旧
+    Rejected {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Role::Client => b"client",
+            Role::Server => b"server",
+        }
+    }
+}
+
+/// 客户端握手：证明自身身份，并校验服务端确实是 `expected` 节点
+///
+/// 返回服务端声明的协议以及服务端观察到的本节点地址
+pub async fn client_handshake(
+    connection: &Connection,
+    keypair: &KeyPair,
+    local: PeerProtocol,
+    expected: &NodeId,
+) -> Result<(PeerProtocol, SocketAddr)> {
+    tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        run_client_handshake(connection, keypair, local, expected),
+    )
+    .await
+    .map_err(|_| anyhow!("handshake with node[{}] timed out", expected))?
+}
+
+/// 服务端握手：证明自身身份，返回已通过验证的客户端 NodeId 及其声明的协议
+pub async fn server_handshake(
+    connection: &Connection,
+    keypair: &KeyPair,
+    local: PeerProtocol,
+) -> Result<(NodeId, PeerProtocol)> {
+    tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        run_server_handshake(connection, keypair, local),
+    )
+    .await
+    .map_err(|_| anyhow!("handshake with {} timed out", connection.remote_address()))?
+}
+
+async fn run_client_handshake(
+    connection: &Connection,
+    keypair: &KeyPair,
+    local: PeerProtocol,
+    expected: &NodeId,
+) -> Result<(PeerProtocol, SocketAddr)> {
+    let local_id = NodeId::from_keypair(keypair);
+    let exporter = exporter_secret(connection)?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    let client_nonce = random_nonce();
+    write_message(
+        &mut send,
+        &HandshakeMessage::ClientHello {
+            node_id: local_id.clone(),
+            nonce: hex::encode(client_nonce),
+            version: local.version,
+            capabilities: local.capabilities,
+        },
+    )
+    .await?;
+
+    let (server_id, server_nonce, server_signature, observed_addr, server_protocol) =
+        match read_message(&mut recv).await? {
+            HandshakeMessage::ServerHello {
+                node_id,
+                nonce,
+                signature,
+                observed_addr,
+                version,
+                capabilities,
+            } => (
+                node_id,
+                decode_nonce(&nonce)?,
+                signature,
+                observed_addr,
+                PeerProtocol {
+                    version,
+                    capabilities,
+                },
+            ),
+            HandshakeMessage::Rejected { reason } => {
+                return Err(anyhow!("node[{}] rejected handshake: {}", expected, reason))
+            }
+            other => return Err(anyhow!("unexpected handshake message: {:?}", other)),
+        };
+
+    if &server_id != expected {
+        return Err(anyhow!(
+            "server identity mismatch: expected {}, got {}",
+            expected,
+            server_id
+        ));
+    }
+    server_protocol.check_compatible()?;
+
+    let protocols = (&local, &server_protocol);
+    let server_transcript = transcript(
+        Role::Server,
+        &exporter,
+        (&local_id, &server_id),
+        &client_nonce,
+        &server_nonce,
+        protocols,
+    );
+    verify_signature(&server_id, &server_transcript, &server_signature)
+        .context("server failed to prove its identity")?;
+
+    let client_transcript = transcript(
+        Role::Client,
+        &exporter,
+        (&local_id, &server_id),
+        &client_nonce,
+        &server_nonce,
+        protocols,
+    );
+    let signature = keypair.sign(&client_transcript)?;
+    write_message(
+        &mut send,
+        &HandshakeMessage::ClientFinish {
+            signature: hex::encode(signature.to_bytes()),
+        },
+    )
+    .await?;
+    send.finish()?;
+
+    match read_message(&mut recv).await? {
+        HandshakeMessage::Accepted => Ok((server_protocol, observed_addr)),
+        other => Err(anyhow!("unexpected handshake message: {:?}", other)),
+    }
+}
+
+async fn run_server_handshake(
+    connection: &Connection,
+    keypair: &KeyPair,
+    local: PeerProtocol,
+) -> Result<(NodeId, PeerProtocol)> {
+    let local_id = NodeId::from_keypair(keypair);
+    let exporter = exporter_secret(connection)?;
+    let (mut send, mut recv) = connection.accept_bi().await?;
+
+    let (client_id, client_nonce, client_protocol) = match read_message(&mut recv).await? {
+        HandshakeMessage::ClientHello {
+            node_id,
+            nonce,
+            version,
+            capabilities,
+        } => (
+            node_id,
+            decode_nonce(&nonce)?,
+            PeerProtocol {
+                version,
+                capabilities,
+            },
+        ),
+        other => return Err(anyhow!("unexpected handshake message: {:?}", other)),
+    };
+    // 拒绝无法解析出公钥的 NodeId
+    client_id.to_keypair().context("invalid client NodeId")?;
+
+    if let Err(e) = client_protocol.check_compatible() {
+        write_message(
+            &mut send,
+            &HandshakeMessage::Rejected {
+                reason: e.to_string(),
+            },
+        )
+        .await?;
+        send.finish()?;
+        // 等待客户端读完拒绝原因，再由调用方关闭连接
+        let _ = tokio::time::timeout(REJECT_LINGER, send.stopped()).await;
+        return Err(e.context(format!("rejected node[{}]", client_id)));
+    }
+
+    let protocols = (&client_protocol, &local);
+    let server_nonce = random_nonce();
+    let server_transcript = transcript(
+        Role::Server,
+        &exporter,
+        (&client_id, &local_id),
+        &client_nonce,
+        &server_nonce,
+        protocols,
+    );
+    let signature = keypair.sign(&server_transcript)?;
+    write_message(
+        &mut send,
+        &HandshakeMessage::ServerHello {
+            node_id: local_id.clone(),
+            nonce: hex::encode(server_nonce),
+            signature: hex::encode(signature.to_bytes()),
+            observed_addr: crate::transport::addr::canonical(connection.remote_address()),
+            version: local.version,
+            capabilities: local.capabilities,
+        },
+    )
+    .await?;
+
+    let client_signature = match read_message(&mut recv).await? {
+        HandshakeMessage::ClientFinish { signature } => signature,
+        other => return Err(anyhow!("unexpected handshake message: {:?}", other)),
+    };
+    let client_transcript = transcript(
+        Role::Client,
+        &exporter,
+        (&client_id, &local_id),
+        &client_nonce,
+        &server_nonce,
+        protocols,
+    );
+    verify_signature(&client_id, &client_transcript, &client_signature)
+        .context("client failed to prove its identity")?;
+
+    write_message(&mut send, &HandshakeMessage::Accepted).await?;
+    send.finish()?;
+
+    Ok((client_id, client_protocol))
+}
+
+/// 从 TLS 会话导出握手绑定用的密钥材料，保证签名无法在其他连接上重放
+fn exporter_secret(connection: &Connection) -> Result<[u8; 32]> {
+    let mut out = [0u8; 32];
+    connection
+        .export_keying_material(&mut out, EXPORTER_LABEL, b"")
+        .map_err(|e| anyhow!("failed to export keying material: {:?}", e))?;
+    Ok(out)
+}
+
+/// 计算签名用的 transcript，`ids` 与 `protocols` 均按 (客户端, 服务端) 排列
+fn transcript(
+    role: Role,
+    exporter: &[u8; 32],
+    ids: (&NodeId, &NodeId),
+    client_nonce: &[u8; NONCE_LEN],
+    server_nonce: &[u8; NONCE_LEN],
+    protocols: (&PeerProtocol, &PeerProtocol),
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(TRANSCRIPT_DOMAIN);
+    hasher.update(role.label());
+    hasher.update(exporter);
+    for id in [ids.0, ids.1] {
+        hasher.update((id.as_bytes().len() as u32).to_be_bytes());
+        hasher.update(id.as_bytes());
+    }
+    hasher.update(client_nonce);
+    hasher.update(server_nonce);
+    for protocol in [protocols.0, protocols.1] {
+        hasher.update([protocol.version]);
+        hasher.update(protocol.capabilities.bits().to_be_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+fn verify_signature(node_id: &NodeId, message: &[u8], signature_hex: &str) -> Result<()> {
+    let kp = node_id.to_keypair()?;
+    let sig_bytes = hex::decode(signature_hex)?;
+    let arr: [u8; 64] = sig_bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("invalid signature length"))?;
+    if kp.verify(message, &Signature::from_bytes(&arr)) {
+        Ok(())
+    } else {
+        Err(anyhow!("signature verification failed for {}", node_id))
+    }
+}
+
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn decode_nonce(nonce_hex: &str) -> Result<[u8; NONCE_LEN]> {
+    hex::decode(nonce_hex)?
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("invalid nonce length"))
+}
+
+async fn write_message<T: Serialize>(send: &mut SendStream, message: &T) -> Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    send.write_all(&(payload.len() as u32).to_be_bytes())
+        .await?;
+    send.write_all(&payload).await?;
+    Ok(())
+}
+
+async fn read_message<T: DeserializeOwned>(recv: &mut RecvStream) -> Result<T> {
+    let mut len_buf = [0u8; 4];
+    recv.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_HANDSHAKE_MESSAGE_SIZE {
+        return Err(anyhow!("handshake message too large: {} bytes", len));
+    }
+    let mut payload = vec![0u8; len];
+    recv.read_exact(&mut payload).await?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::config::QuicConfig;
+    use crate::transport::protocol::MIN_PROTOCOL_VERSION;
+
+    #[test]
+    fn test_transcript_is_bound_to_role() {
+        let client = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let server = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let exporter = [7u8; 32];
+        let (cn, sn) = (random_nonce(), random_nonce());
+        let ids = (&client, &server);
+        let p = PeerProtocol::local(Capabilities::standard());
+
+        let as_client = transcript(Role::Client, &exporter, ids, &cn, &sn, (&p, &p));
+        let as_server = transcript(Role::Server, &exporter, ids, &cn, &sn, (&p, &p));
+        assert_ne!(as_client, as_server);
+
+        let other_exporter = transcript(Role::Client, &[8u8; 32], ids, &cn, &sn, (&p, &p));
+        assert_ne!(as_client, other_exporter);
+
+        // 篡改声明的能力会使签名失效
+        let downgraded = PeerProtocol::local(Capabilities::GOSSIP);
+        let other_caps = transcript(Role::Client, &exporter, ids, &cn, &sn, (&p, &downgraded));
+        assert_ne!(as_client, other_caps);
+    }
+
+    #[tokio::test]
+    async fn test_old_protocol_version_rejected() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server_config = QuicConfig::new(addr, KeyPair::generate().unwrap());
+        let client_config = QuicConfig::new(addr, KeyPair::generate().unwrap());
+        let server_id = server_config.node_id();
+
+        let server =
+            quinn::Endpoint::server(server_config.get_server_config().unwrap(), addr).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let accept = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            server_handshake(
+                &connection,
+                &server_config.keypair,
+                server_config.protocol(),
+            )
+            .await
+        });
+
+        let client = quinn::Endpoint::client(addr).unwrap();
+        let connection = client
+            .connect_with(
+                client_config.get_client_config(&server_id).unwrap(),
+                server_addr,
+                "localhost",
+            )
+            .unwrap()
+            .await
+            .unwrap();
+        let old = PeerProtocol {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::standard(),
+        };
+        let err = client_handshake(&connection, &client_config.keypair, old, &server_id)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unsupported protocol version"));
+        assert!(accept.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_verify_signature_rejects_other_key() {
+        let kp = KeyPair::generate().unwrap();
+        let other = NodeId::from_keypair(&KeyPair::generate().unwrap());
+        let msg = b"transcript";
+        let sig = hex::encode(kp.sign(msg).unwrap().to_bytes());
+
+        assert!(verify_signature(&NodeId::from_keypair(&kp), msg, &sig).is_ok());
+        assert!(verify_signature(&other, msg, &sig).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! 协同打洞
+//!
+//! 两个节点都位于 NAT 之后、直连失败时，借助一个与双方都保持连接的节点 C 协调：
+//!
+//! 1. A 调用 C 的 `holepunch.request`，携带目标 B 和自己已知的外部地址；
+//! 2. C 带上它观察到的 A 的地址，调用 B 的 `holepunch.notify`；
+//! 3. B 从与 QUIC endpoint 共享的 socket 向该地址发送若干打洞数据报，
+//!    在自己的 NAT 上打开到 A 的映射，同时返回自己的外部地址；
+//!    C 补充它观察到的 B 的地址后回复 A；
+//! 4. A 用同一个 endpoint 向这些地址拨号，B 的映射打开后 QUIC Initial 包即可通过
+//!    （A 自己的 Initial 包同时在 A 的 NAT 上打开了到 B 的映射）。
+//!    B 也向该地址拨号，身份握手确认对端就是 A，双方各自的连接按重复连接的规则只保留一条。
+//!
+//! B 只响应直连的协调节点的通知，只向协调节点观察到的地址发送流量，且按（协调节点，发起方）
+//! 限制响应频率，协调节点无法借 B 向任意地址反射大量流量。
+//!
+//! 节点的外部地址来自身份握手：服务端在 ServerHello 中告知客户端它观察到的地址。
+use crate::node::node_id::NodeId;
+use crate::transport::addr;
+use crate::transport::rpc::RpcMethod;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// 协调打洞（含 C 转发给 B 的通知）的超时时间
+pub const HOLEPUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// 单次打洞交换的地址数上限，避免被用来放大流量
+pub const MAX_PUNCH_ADDRS: usize = 8;
+/// 公告中携带的观察地址数上限
+pub const MAX_OBSERVED_ADDRS: usize = 4;
+/// 同一协调节点转发同一发起方的通知时，两次响应之间的最短间隔
+pub const PUNCH_NOTIFY_INTERVAL: Duration = HOLEPUNCH_TIMEOUT;
+
+/// 每个地址发送的打洞数据报个数
+const PUNCH_PACKETS: usize = 5;
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+/// 打洞数据报的内容不重要，对端 endpoint 会将其作为无效包丢弃
+const PUNCH_PAYLOAD: &[u8] = b"\0";
+
+/// 请求协调节点向 `target` 发起打洞
+pub struct HolePunchRequest;
+
+impl RpcMethod for HolePunchRequest {
+    const NAME: &'static str = "holepunch.request";
+    type Request = PunchRequest;
+    type Response = PunchAddrs;
+}
+
+/// 协调节点通知目标：`source` 希望与其建立直连
+pub struct HolePunchNotify;
+
+impl RpcMethod for HolePunchNotify {
+    const NAME: &'static str = "holepunch.notify";
+    type Request = PunchNotify;
+    type Response = PunchAddrs;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PunchRequest {
+    pub target: NodeId,
+    /// 发起方自己已知的外部地址
+    pub addrs: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PunchNotify {
+    pub source: NodeId,
+    /// 协调节点观察到的发起方地址，目标只使用第一个
+    pub addrs: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PunchAddrs {
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// 合并候选地址：观察到的地址优先，去掉未指定地址和重复项，最多 `MAX_PUNCH_ADDRS` 个
+pub fn merge_addrs(observed: Option<SocketAddr>, reported: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in observed.iter().chain(reported.iter()) {
+        if !addr.ip().is_unspecified() && addr.port() != 0 && !addrs.contains(addr) {
+            addrs.push(*addr);
+        }
+    }
+    addrs.truncate(MAX_PUNCH_ADDRS);
+    addrs
+}
+
+/// 计算 NodeAnnouncement 中公告的地址
+///
+/// 配置的公告地址在前，对端观察到的外部地址在后。`0.0.0.0` 之类的未指定地址对其他节点没有意义，
+/// 总是去掉；环回地址只在 `allow_local` 时保留。没有可用地址时返回空列表，
+/// 其他节点只能经由打洞或中继连接本节点
+pub fn announce_addresses(
+    configured: &[SocketAddr],
+    observed: &[SocketAddr],
+    allow_local: bool,
+) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for a in configured.iter().chain(observed) {
+        let a = addr::canonical(*a);
+        if addr::is_announceable(&a, allow_local) && !addrs.contains(&a) {
+            addrs.push(a);
+        }
+    }
+    addrs
+}
+
+/// 从 endpoint 共享的 socket 向各地址发送打洞数据报
+pub fn send_punch_packets(socket: Arc<UdpSocket>, addrs: Vec<SocketAddr>) {
+    tokio::spawn(async move {
+        for _ in 0..PUNCH_PACKETS {
+            for addr in &addrs {
+                // socket 为非阻塞模式，偶尔的 WouldBlock 不影响打洞
+                if let Err(e) = socket.send_to(PUNCH_PAYLOAD, addr) {
+                    debug!("Failed to send punch packet to {}: {}", addr, e);
+                }
+            }
+            tokio::time::sleep(PUNCH_INTERVAL).await;
+        }
+    });
+}
+
+/// 按（协调节点，发起方）记录最近一次响应打洞通知的时间
+///
+/// 间隔不足 [`PUNCH_NOTIFY_INTERVAL`] 的通知不再响应，避免协调节点反复让本节点向同一地址发送流量
+#[derive(Debug, Default)]
+pub struct PunchLimiter {
+    last: HashMap<(NodeId, NodeId), Instant>,
+}
+
+impl PunchLimiter {
+    /// 是否响应 `coordinator` 转发的 `source` 的通知，响应时记录本次时间
+    pub fn allow(&mut self, coordinator: &NodeId, source: &NodeId, now: Instant) -> bool {
+        self.last
+            .retain(|_, at| now.duration_since(*at) < PUNCH_NOTIFY_INTERVAL);
+        let key = (coordinator.clone(), source.clone());
+        if self.last.contains_key(&key) {
+            return false;
+        }
+        self.last.insert(key, now);
+        true
+    }
+}
+
+/// 对端在握手中报告的本节点外部地址，每个对端只保留最近一次报告
+#[derive(Debug, Default)]
+pub struct ObservedAddrs {
+    reports: HashMap<NodeId, SocketAddr>,
+}
+
+impl ObservedAddrs {
+    pub fn record(&mut self, reporter: &NodeId, addr: SocketAddr) {
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return;
+        }
+        self.reports.insert(reporter.clone(), addr);
+    }
+
+    /// 按报告的对端数从多到少排列的外部地址
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        for addr in self.reports.values() {
+            *counts.entry(*addr).or_insert(0) += 1;
+        }
+        let mut addrs: Vec<(usize, SocketAddr)> =
+            counts.into_iter().map(|(addr, n)| (n, addr)).collect();
+        addrs.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        addrs
+            .into_iter()
+            .take(MAX_OBSERVED_ADDRS)
+            .map(|(_, addr)| addr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    #[test]
+    fn test_merge_addrs() {
+        let observed: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let local: SocketAddr = "192.168.1.2:9000".parse().unwrap();
+        let unspecified: SocketAddr = "0.0.0.0:9000".parse().unwrap();
+
+        let merged = merge_addrs(Some(observed), &[unspecified, local, observed]);
+        assert_eq!(merged, vec![observed, local]);
+
+        let many: Vec<SocketAddr> = (1..=20)
+            .map(|p| format!("10.0.0.1:{}", p).parse().unwrap())
+            .collect();
+        assert_eq!(merge_addrs(None, &many).len(), MAX_PUNCH_ADDRS);
+    }
+
+    #[test]
+    fn test_announce_addresses_filters_unroutable() {
+        let any: SocketAddr = "0.0.0.0:9000".parse().unwrap();
+        let any_v6: SocketAddr = "[::]:9000".parse().unwrap();
+        let loopback: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let public: SocketAddr = "203.0.113.7:9000".parse().unwrap();
+        let configured: SocketAddr = "198.51.100.1:9000".parse().unwrap();
+
+        assert_eq!(announce_addresses(&[any], &[public], false), vec![public]);
+        assert_eq!(
+            announce_addresses(&[configured], &[public], false),
+            vec![configured, public]
+        );
+        // 尚未观察到外部地址时不公告无法拨号的监听地址
+        assert!(announce_addresses(&[any, any_v6], &[], false).is_empty());
+
+        // 环回地址只在显式允许时公告
+        assert_eq!(
+            announce_addresses(&[loopback, any], &[loopback], false),
+            Vec::<SocketAddr>::new()
+        );
+        assert_eq!(
+            announce_addresses(&[loopback, any], &[], true),
+            vec![loopback]
+        );
+    }
+
+    #[test]
+    fn test_punch_limiter_per_coordinator_and_source() {
+        let (coordinator, source, other) = (node_id(), node_id(), node_id());
+        let now = Instant::now();
+        let mut limiter = PunchLimiter::default();
+
+        assert!(limiter.allow(&coordinator, &source, now));
+        assert!(!limiter.allow(&coordinator, &source, now + PUNCH_NOTIFY_INTERVAL / 2));
+
+        // 其他协调节点或其他发起方不受影响
+        assert!(limiter.allow(&other, &source, now));
+        assert!(limiter.allow(&coordinator, &other, now));
+
+        // 间隔过后再次响应
+        assert!(limiter.allow(&coordinator, &source, now + PUNCH_NOTIFY_INTERVAL));
+    }
+
+    #[test]
+    fn test_observed_addrs_ranked_by_reporters() {
+        let a: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let b: SocketAddr = "203.0.113.7:40001".parse().unwrap();
+        let (p1, p2, p3) = (node_id(), node_id(), node_id());
+
+        let mut observed = ObservedAddrs::default();
+        observed.record(&p1, b);
+        observed.record(&p2, a);
+        observed.record(&p3, a);
+        observed.record(&p3, "0.0.0.0:0".parse().unwrap());
+        assert_eq!(observed.addresses(), vec![a, b]);
+
+        // 同一对端的新报告替换旧报告
+        observed.record(&p1, a);
+        assert_eq!(observed.addresses(), vec![a]);
+    }
+}
@@ -0,0 +1,332 @@
+//! 入站连接数、单个节点的收发速率和接收队列的限制
+//!
+//! 节点暴露在公网地址上时，防止单个 IP 或节点耗尽连接、CPU 和内存：
+//! - 入站连接在握手前按总数和来源 IP 计数，超出时直接拒绝
+//! - 每个节点的接收速率由令牌桶限制，超出时暂停读取，由 QUIC 流量控制把压力传回发送方
+//! - 每个节点、每个通道的接收队列有界，队列满时按 [`OverflowPolicy`] 丢弃消息或关闭连接
+//!
+//! 触发限制时计入 [`LimitMetrics`]，并输出 warn 日志。
+use crate::transport::frame::Channel;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 因接收队列溢出关闭连接时使用的错误码
+pub const QUEUE_OVERFLOW_CODE: u32 = 0x12;
+
+/// 接收队列已满时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// 立即丢弃新到达的消息，适合可以重传或周期性重发的 gossip 消息
+    Drop,
+    /// 等待最多 `overflow_grace`，仍然没有空位则关闭连接，适合不能丢失的 bundle 数据
+    Close,
+}
+
+/// 连接和收发速率的限制，速率为 0 表示不限制
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// 同时存在的入站连接总数（包括握手中的连接）
+    pub max_connections: usize,
+    /// 来自同一 IP 的入站连接数
+    pub max_connections_per_ip: usize,
+    /// 单个节点同时打开的单向流数和双向流数
+    pub max_streams_per_peer: u32,
+    /// 单个节点每秒发来的消息数
+    pub max_messages_per_sec: u32,
+    /// 单个节点每秒发来的字节数
+    pub max_bytes_per_sec: u64,
+    /// 每个节点、每个通道的接收队列长度
+    pub peer_queue_capacity: usize,
+    /// Gossip 通道接收队列满时的处理方式
+    pub gossip_overflow: OverflowPolicy,
+    /// Data 通道接收队列满时的处理方式
+    pub data_overflow: OverflowPolicy,
+    /// `OverflowPolicy::Close` 关闭连接前等待队列腾出空位的时间
+    pub overflow_grace: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            max_connections_per_ip: 16,
+            max_streams_per_peer: 100,
+            max_messages_per_sec: 1000,
+            max_bytes_per_sec: 32 * 1024 * 1024,
+            peer_queue_capacity: 64,
+            gossip_overflow: OverflowPolicy::Drop,
+            data_overflow: OverflowPolicy::Close,
+            overflow_grace: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// 指定通道接收队列满时的处理方式
+    pub fn overflow_policy(&self, channel: Channel) -> OverflowPolicy {
+        match channel {
+            Channel::Data => self.data_overflow,
+            _ => self.gossip_overflow,
+        }
+    }
+}
+
+/// 限制触发次数的计数器
+#[derive(Debug, Default)]
+pub struct LimitMetrics {
+    connections_refused_total: AtomicU64,
+    connections_refused_per_ip: AtomicU64,
+    rate_limited: AtomicU64,
+    messages_dropped: AtomicU64,
+    connections_closed_overflow: AtomicU64,
+}
+
+/// [`LimitMetrics`] 的快照
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LimitStats {
+    /// 因入站连接总数已满而拒绝的连接
+    pub connections_refused_total: u64,
+    /// 因同一 IP 的连接数已满而拒绝的连接
+    pub connections_refused_per_ip: u64,
+    /// 因超出速率而暂停读取的次数
+    pub rate_limited: u64,
+    /// 因接收队列已满而丢弃的消息
+    pub messages_dropped: u64,
+    /// 因接收队列溢出而关闭的连接
+    pub connections_closed_overflow: u64,
+}
+
+impl LimitMetrics {
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_overflow_close(&self) {
+        self.connections_closed_overflow
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LimitStats {
+        LimitStats {
+            connections_refused_total: self.connections_refused_total.load(Ordering::Relaxed),
+            connections_refused_per_ip: self.connections_refused_per_ip.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            connections_closed_overflow: self.connections_closed_overflow.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SlotState {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// 入站连接计数，握手开始前占用名额，连接关闭后释放
+#[derive(Debug, Clone)]
+pub struct ConnectionSlots {
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    state: Arc<Mutex<SlotState>>,
+    metrics: Arc<LimitMetrics>,
+}
+
+impl ConnectionSlots {
+    pub fn new(limits: &ConnectionLimits, metrics: Arc<LimitMetrics>) -> Self {
+        Self {
+            max_connections: limits.max_connections,
+            max_connections_per_ip: limits.max_connections_per_ip,
+            state: Arc::new(Mutex::new(SlotState::default())),
+            metrics,
+        }
+    }
+
+    /// 为来自 `ip` 的连接占用一个名额，超出限制时返回错误并计数
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<ConnectionSlot> {
+        let mut state = self.state.lock().expect("connection slots poisoned");
+        if state.total >= self.max_connections {
+            self.metrics
+                .connections_refused_total
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(anyhow!(
+                "too many connections ({} max)",
+                self.max_connections
+            ));
+        }
+        let from_ip = state.per_ip.entry(ip).or_insert(0);
+        if *from_ip >= self.max_connections_per_ip {
+            self.metrics
+                .connections_refused_per_ip
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(anyhow!(
+                "too many connections from {} ({} max)",
+                ip,
+                self.max_connections_per_ip
+            ));
+        }
+        *from_ip += 1;
+        state.total += 1;
+        Ok(ConnectionSlot {
+            ip,
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    /// 当前占用的名额数
+    pub fn in_use(&self) -> usize {
+        self.state.lock().expect("connection slots poisoned").total
+    }
+}
+
+/// 占用的入站连接名额，drop 时释放
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    ip: IpAddr,
+    state: Arc<Mutex<SlotState>>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("connection slots poisoned");
+        state.total -= 1;
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// 令牌桶，容量为一秒的配额；允许透支，透支部分换算为需要等待的时间
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    /// 消耗 `cost` 个令牌，返回需要等待多久才能还清透支
+    fn reserve(&mut self, cost: f64, now: Instant) -> Duration {
+        if self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens -= cost;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// 单个节点的接收速率限制
+#[derive(Debug)]
+pub struct PeerRateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl PeerRateLimiter {
+    pub fn new(limits: &ConnectionLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            messages: TokenBucket::new(limits.max_messages_per_sec as f64, now),
+            bytes: TokenBucket::new(limits.max_bytes_per_sec as f64, now),
+        }
+    }
+
+    /// 记录收到一条 `len` 字节的消息，返回继续读取前需要等待的时间
+    pub fn reserve(&mut self, len: usize, now: Instant) -> Duration {
+        let messages = self.messages.reserve(1.0, now);
+        let bytes = self.bytes.reserve(len as f64, now);
+        messages.max(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_slots() {
+        let limits = ConnectionLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..Default::default()
+        };
+        let metrics = Arc::new(LimitMetrics::default());
+        let slots = ConnectionSlots::new(&limits, Arc::clone(&metrics));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let a1 = slots.try_acquire(a).unwrap();
+        let _a2 = slots.try_acquire(a).unwrap();
+        assert!(slots.try_acquire(a).is_err());
+        let _b1 = slots.try_acquire(b).unwrap();
+        assert!(slots.try_acquire(b).is_err());
+        assert_eq!(slots.in_use(), 3);
+
+        // 释放后名额可以重新使用
+        drop(a1);
+        let _a3 = slots.try_acquire(a).unwrap();
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.connections_refused_per_ip, 1);
+        assert_eq!(stats.connections_refused_total, 1);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limits = ConnectionLimits {
+            max_messages_per_sec: 10,
+            max_bytes_per_sec: 1000,
+            ..Default::default()
+        };
+        let mut limiter = PeerRateLimiter::new(&limits);
+        let start = Instant::now();
+
+        // 一秒的配额内不需要等待
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(10, start), Duration::ZERO);
+        }
+        // 第 11 条消息透支一条，需要等待 0.1 秒
+        let wait = limiter.reserve(10, start);
+        assert!((wait.as_secs_f64() - 0.1).abs() < 1e-6);
+
+        // 过一秒后配额恢复；超过字节配额的大消息按透支的字节数等待
+        let later = start + Duration::from_secs(2);
+        let wait = limiter.reserve(1500, later);
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-6);
+
+        // 速率为 0 表示不限制
+        let mut unlimited = PeerRateLimiter::new(&ConnectionLimits {
+            max_messages_per_sec: 0,
+            max_bytes_per_sec: 0,
+            ..Default::default()
+        });
+        assert_eq!(unlimited.reserve(usize::MAX, start), Duration::ZERO);
+    }
+}
@@ -0,0 +1,685 @@
+//! 进程内的 [`Transport`] 实现，用于测试
+//!
+//! 同一个 [`MemoryNetwork`] 上的节点通过 channel 直接交换消息，不需要 UDP socket 和证书。
+//! 网络可以注入固定的单向延迟、消息丢失率和节点间的分区：
+//!
+//! - 延迟对通道消息和 RPC 的请求、响应各计一次，同一方向上的消息保持发送顺序；
+//! - 丢失只作用于通道消息（QUIC 流本身是可靠的），由固定种子的伪随机数决定，结果可重复；
+//! - 分区会断开两个节点之间的连接并拒绝重连，在途消息被丢弃，直到 [`MemoryNetwork::heal`]。
+//!
+//! 连接建立和分区断开时，双方都会收到与 QUIC 实现相同的 [`ConnectionEvent`]。
+//! 任一方封禁了另一方时连接被拒绝。
+use crate::node::node_id::NodeId;
+use crate::node::reputation::{Reputation, SharedReputation};
+use crate::node::routing::{RoutingTable, SharedRoutingTable};
+use crate::transport::events::{self, ConnectionEvent, Direction, EventSender};
+use crate::transport::frame::Channel;
+use crate::transport::holepunch;
+use crate::transport::protocol::{Capabilities, PeerProtocol};
+use crate::transport::rpc::{RemoteError, RpcRegistry, RpcValue};
+use crate::transport::transport::Transport;
+use crate::util::timestamp_now;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, Sender, UnboundedSender};
+use tokio::time::Instant;
+
+type ChannelSenders = Arc<tokio::sync::Mutex<HashMap<Channel, Sender<(NodeId, Vec<u8>)>>>>;
+type QueuedMessage = (Instant, Channel, Vec<u8>);
+
+/// 无序节点对，用作连接和分区的键
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Link(NodeId, NodeId);
+
+impl Link {
+    fn new(a: &NodeId, b: &NodeId) -> Self {
+        if a.0 <= b.0 {
+            Link(a.clone(), b.clone())
+        } else {
+            Link(b.clone(), a.clone())
+        }
+    }
+
+    fn other(&self, node_id: &NodeId) -> Option<&NodeId> {
+        if &self.0 == node_id {
+            Some(&self.1)
+        } else if &self.1 == node_id {
+            Some(&self.0)
+        } else {
+            None
+        }
+    }
+}
+
+/// 网络中一个节点的接收端状态
+struct MemoryPeer {
+    protocol: PeerProtocol,
+    channels: ChannelSenders,
+    rpc: RpcRegistry,
+    events: EventSender,
+    reputation: SharedReputation,
+}
+
+struct NetworkState {
+    peers: HashMap<NodeId, Arc<MemoryPeer>>,
+    links: HashSet<Link>,
+    partitions: HashSet<Link>,
+    /// 每个方向一个投递队列，保证同一方向上的消息按顺序到达
+    queues: HashMap<(NodeId, NodeId), UnboundedSender<QueuedMessage>>,
+    latency: Duration,
+    loss: f64,
+    rng: u64,
+}
+
+impl NetworkState {
+    /// xorshift64，返回 [0, 1) 之间的伪随机数
+    fn next_random(&mut self) -> f64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn connected(&self, a: &NodeId, b: &NodeId) -> bool {
+        self.links.contains(&Link::new(a, b))
+    }
+
+    fn emit(&self, to: &NodeId, event: ConnectionEvent) {
+        if let Some(peer) = self.peers.get(to) {
+            let _ = peer.events.send(event);
+        }
+    }
+
+    /// 断开两个节点之间的连接，双方都收到 Disconnected 事件
+    fn disconnect(&mut self, a: &NodeId, b: &NodeId) {
+        if self.links.remove(&Link::new(a, b)) {
+            self.emit(a, ConnectionEvent::Disconnected { node_id: b.clone() });
+            self.emit(b, ConnectionEvent::Disconnected { node_id: a.clone() });
+        }
+    }
+}
+
+/// 进程内的模拟网络
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::with_seed(0x5eed)
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 指定丢包使用的随机数种子
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetworkState {
+                peers: HashMap::new(),
+                links: HashSet::new(),
+                partitions: HashSet::new(),
+                queues: HashMap::new(),
+                latency: Duration::ZERO,
+                loss: 0.0,
+                // xorshift 的状态不能为 0
+                rng: seed.max(1),
+            })),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, NetworkState> {
+        self.state.lock().expect("memory network poisoned")
+    }
+
+    /// 加入一个声明标准能力的节点
+    pub fn add_node(&self, node_id: NodeId) -> Arc<MemoryTransport> {
+        self.add_node_with(node_id, Capabilities::standard())
+    }
+
+    /// 加入一个声明指定能力的节点，同一 NodeId 重复加入会替换之前的节点
+    pub fn add_node_with(
+        &self,
+        node_id: NodeId,
+        capabilities: Capabilities,
+    ) -> Arc<MemoryTransport> {
+        let peer = Arc::new(MemoryPeer {
+            protocol: PeerProtocol::local(capabilities),
+            channels: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            rpc: RpcRegistry::default(),
+            events: events::channel(),
+            reputation: Arc::new(tokio::sync::Mutex::new(Reputation::default())),
+        });
+        self.state()
+            .peers
+            .insert(node_id.clone(), Arc::clone(&peer));
+        Arc::new(MemoryTransport {
+            node_id,
+            network: self.clone(),
+            peer,
+            routing: RoutingTable::shared(),
+        })
+    }
+
+    /// 设置单向延迟
+    pub fn set_latency(&self, latency: Duration) {
+        self.state().latency = latency;
+    }
+
+    /// 设置通道消息的丢失率，取值 [0, 1]
+    pub fn set_loss(&self, loss: f64) {
+        self.state().loss = loss.clamp(0.0, 1.0);
+    }
+
+    /// 断开两个节点并阻止它们重新连接
+    pub fn partition(&self, a: &NodeId, b: &NodeId) {
+        let mut state = self.state();
+        state.disconnect(a, b);
+        state.partitions.insert(Link::new(a, b));
+    }
+
+    /// 解除分区，节点需要重新连接
+    pub fn heal(&self, a: &NodeId, b: &NodeId) {
+        self.state().partitions.remove(&Link::new(a, b));
+    }
+
+    /// 取得 `from` 到 `to` 的投递队列，首次使用时启动投递任务
+    fn queue(&self, from: &NodeId, to: &NodeId) -> UnboundedSender<QueuedMessage> {
+        let mut state = self.state();
+        let key = (from.clone(), to.clone());
+        if let Some(tx) = state.queues.get(&key).filter(|tx| !tx.is_closed()) {
+            return tx.clone();
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<QueuedMessage>();
+        state.queues.insert(key, tx.clone());
+        let network = self.clone();
+        let (from, to) = (from.clone(), to.clone());
+        tokio::spawn(async move {
+            while let Some((deliver_at, channel, message)) = rx.recv().await {
+                tokio::time::sleep_until(deliver_at).await;
+                let receiver = {
+                    let state = network.state();
+                    // 在途期间被分区或断开的消息直接丢弃
+                    if !state.connected(&from, &to) {
+                        continue;
+                    }
+                    state.peers.get(&to).map(|p| Arc::clone(&p.channels))
+                };
+                let Some(channels) = receiver else { continue };
+                let sender = channels.lock().await.get(&channel).cloned();
+                match sender {
+                    Some(sender) => {
+                        let _ = sender.send((from.clone(), message)).await;
+                    }
+                    None => tracing::debug!("No receiver for {} message to {}", channel, to),
+                }
+            }
+        });
+        tx
+    }
+}
+
+/// [`MemoryNetwork`] 中一个节点的传输端
+pub struct MemoryTransport {
+    node_id: NodeId,
+    network: MemoryNetwork,
+    peer: Arc<MemoryPeer>,
+    routing: SharedRoutingTable,
+}
+
+impl MemoryTransport {
+    fn not_connected(&self, node_id: &NodeId) -> anyhow::Error {
+        anyhow!(
+            "node[{}] is not connected to node[{}]",
+            self.node_id,
+            node_id
+        )
+    }
+
+    async fn connect_to(&self, target: NodeId) -> Result<()> {
+        let remote = self.network.state().peers.get(&target).cloned();
+        let now = timestamp_now();
+        if self.peer.reputation.lock().await.is_banned(&target, now) {
+            return Err(anyhow!("node[{}] is banned", target));
+        }
+        if let Some(remote) = remote {
+            if remote.reputation.lock().await.is_banned(&self.node_id, now) {
+                return Err(anyhow!("node[{}] refused the connection", target));
+            }
+        }
+        let latency = {
+            let mut state = self.network.state();
+            if !state.peers.contains_key(&target) || target == self.node_id {
+                return Err(anyhow!("node[{}] is not reachable", target));
+            }
+            let link = Link::new(&self.node_id, &target);
+            if state.partitions.contains(&link) {
+                return Err(anyhow!("node[{}] is partitioned away", target));
+            }
+            if state.links.insert(link) {
+                let (local, remote) = (self.node_id.clone(), target.clone());
+                let connected =
+                    |node_id: NodeId, direction: Direction| ConnectionEvent::Connected {
+                        node_id,
+                        direction,
+                        addr: None,
+                        relay: None,
+                    };
+                state.emit(&local, connected(remote.clone(), Direction::Outbound));
+                state.emit(&remote, connected(local, Direction::Inbound));
+            }
+            state.latency
+        };
+        self.routing
+            .lock()
+            .await
+            .record_connected(&target, None, latency * 2);
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        node_id: NodeId,
+        channel: Channel,
+        message: Vec<u8>,
+    ) -> Result<()> {
+        let deliver_at = {
+            let mut state = self.network.state();
+            if !state.connected(&self.node_id, &node_id) {
+                return Err(self.not_connected(&node_id));
+            }
+            if state.loss > 0.0 && state.next_random() < state.loss {
+                tracing::debug!("Dropped {} message to {}", channel, node_id);
+                return Ok(());
+            }
+            Instant::now() + state.latency
+        };
+        self.network
+            .queue(&self.node_id, &node_id)
+            .send((deliver_at, channel, message))
+            .map_err(|_| anyhow!("delivery queue to node[{}] closed", node_id))
+    }
+
+    async fn call_remote(
+        &self,
+        node_id: NodeId,
+        method: &'static str,
+        params: RpcValue,
+    ) -> Result<RpcValue> {
+        let (target, latency) = {
+            let state = self.network.state();
+            let target = state
+                .peers
+                .get(&node_id)
+                .filter(|_| state.connected(&self.node_id, &node_id))
+                .cloned()
+                .ok_or_else(|| self.not_connected(&node_id))?;
+            (target, state.latency)
+        };
+
+        tokio::time::sleep(latency).await;
+        let result = target
+            .rpc
+            .dispatch(self.node_id.clone(), method, params)
+            .await;
+        tokio::time::sleep(latency).await;
+
+        result.map_err(|message| {
+            RemoteError {
+                method: method.to_string(),
+                message,
+            }
+            .into()
+        })
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn local_id(&self) -> NodeId {
+        self.node_id.clone()
+    }
+
+    fn list_peers(&self) -> BoxFuture<'_, Vec<NodeId>> {
+        Box::pin(async move {
+            let state = self.network.state();
+            state
+                .links
+                .iter()
+                .filter_map(|link| link.other(&self.node_id).cloned())
+                .collect()
+        })
+    }
+
+    fn peers_with(&self, capabilities: Capabilities) -> BoxFuture<'_, Vec<NodeId>> {
+        Box::pin(async move {
+            let state = self.network.state();
+            state
+                .links
+                .iter()
+                .filter_map(|link| link.other(&self.node_id))
+                .filter(|peer| {
+                    state
+                        .peers
+                        .get(*peer)
+                        .is_some_and(|p| p.protocol.supports(capabilities))
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn peer_protocol<'a>(&'a self, node_id: &'a NodeId) -> BoxFuture<'a, Option<PeerProtocol>> {
+        Box::pin(async move {
+            let state = self.network.state();
+            if !state.connected(&self.node_id, node_id) {
+                return None;
+            }
+            state.peers.get(node_id).map(|p| p.protocol)
+        })
+    }
+
+    fn connect(&self, target: NodeId, _addrs: Vec<SocketAddr>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.connect_to(target))
+    }
+
+    fn send(
+        &self,
+        node_id: NodeId,
+        channel: Channel,
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.send_message(node_id, channel, message))
+    }
+
+    fn register_channel(
+        &self,
+        channel: Channel,
+        tx: Sender<(NodeId, Vec<u8>)>,
+    ) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.peer.channels.lock().await.insert(channel, tx);
+        })
+    }
+
+    fn rpc_registry(&self) -> &RpcRegistry {
+        &self.peer.rpc
+    }
+
+    fn call_value(
+        &self,
+        node_id: NodeId,
+        method: &'static str,
+        params: RpcValue,
+        timeout: Duration,
+    ) -> BoxFuture<'_, Result<RpcValue>> {
+        Box::pin(async move {
+            let target = node_id.clone();
+            tokio::time::timeout(timeout, self.call_remote(node_id, method, params))
+                .await
+                .map_err(|_| anyhow!("{} call to node[{}] timed out", method, target))?
+        })
+    }
+
+    fn routing(&self) -> SharedRoutingTable {
+        Arc::clone(&self.routing)
+    }
+
+    fn external_addresses(&self) -> BoxFuture<'_, Vec<SocketAddr>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    fn announce_addresses(&self, configured: Vec<SocketAddr>) -> BoxFuture<'_, Vec<SocketAddr>> {
+        // 内存网络中的节点都在同一进程内，保留环回地址
+        Box::pin(async move { holepunch::announce_addresses(&configured, &[], true) })
+    }
+
+    fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.peer.events.subscribe()
+    }
+
+    fn add_relay(&self, _node_id: NodeId) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    fn reputation(&self) -> SharedReputation {
+        Arc::clone(&self.peer.reputation)
+    }
+
+    fn disconnect(&self, node_id: NodeId) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.network.state().disconnect(&self.node_id, &node_id);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+    use crate::transport::codec::{self, Encoding};
+    use crate::transport::rpc::RpcMethod;
+
+    struct Echo;
+
+    impl RpcMethod for Echo {
+        const NAME: &'static str = "test.echo";
+        type Request = String;
+        type Response = String;
+    }
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    async fn connected_pair(network: &MemoryNetwork) -> (Arc<dyn Transport>, Arc<dyn Transport>) {
+        let a: Arc<dyn Transport> = network.add_node(node_id());
+        let b: Arc<dyn Transport> = network.add_node(node_id());
+        a.connect(b.local_id(), Vec::new()).await.unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn test_send_is_ordered_and_delayed() {
+        let network = MemoryNetwork::new();
+        network.set_latency(Duration::from_millis(50));
+        let (a, b) = connected_pair(&network).await;
+        assert_eq!(b.list_peers().await, vec![a.local_id()]);
+
+        let (tx, mut rx) = mpsc::channel(16);
+        b.register_channel(Channel::Gossip, tx).await;
+        let start = Instant::now();
+        for i in 0..5u8 {
+            a.send(b.local_id(), Channel::Gossip, vec![i])
+                .await
+                .unwrap();
+        }
+        for i in 0..5u8 {
+            let (from, message) = rx.recv().await.unwrap();
+            assert_eq!(from, a.local_id());
+            assert_eq!(message, vec![i]);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_loss_is_deterministic() {
+        async fn delivered(seed: u64) -> usize {
+            let network = MemoryNetwork::with_seed(seed);
+            network.set_loss(0.5);
+            let (a, b) = connected_pair(&network).await;
+            let (tx, mut rx) = mpsc::channel(128);
+            b.register_channel(Channel::Gossip, tx).await;
+            for _ in 0..100 {
+                a.send(b.local_id(), Channel::Gossip, vec![0])
+                    .await
+                    .unwrap();
+            }
+            let mut count = 0;
+            while let Ok(Some(_)) =
+                tokio::time::timeout(Duration::from_millis(100), rx.recv()).await
+            {
+                count += 1;
+            }
+            count
+        }
+
+        let first = delivered(7).await;
+        assert!(first > 0 && first < 100);
+        assert_eq!(first, delivered(7).await);
+    }
+
+    #[tokio::test]
+    async fn test_partition_blocks_traffic_until_healed() {
+        let network = MemoryNetwork::new();
+        let (a, b) = connected_pair(&network).await;
+
+        network.partition(&a.local_id(), &b.local_id());
+        assert!(a.list_peers().await.is_empty());
+        assert!(a
+            .send(b.local_id(), Channel::Gossip, vec![1])
+            .await
+            .is_err());
+        assert!(a.connect(b.local_id(), Vec::new()).await.is_err());
+
+        network.heal(&a.local_id(), &b.local_id());
+        a.connect(b.local_id(), Vec::new()).await.unwrap();
+        assert_eq!(a.list_peers().await, vec![b.local_id()]);
+    }
+
+    #[tokio::test]
+    async fn test_connection_events() {
+        let network = MemoryNetwork::new();
+        let a: Arc<dyn Transport> = network.add_node(node_id());
+        let b: Arc<dyn Transport> = network.add_node(node_id());
+        let (mut a_events, mut b_events) = (a.subscribe_events(), b.subscribe_events());
+
+        a.connect(b.local_id(), Vec::new()).await.unwrap();
+        // 重复连接不产生新的事件
+        b.connect(a.local_id(), Vec::new()).await.unwrap();
+        network.partition(&a.local_id(), &b.local_id());
+
+        let connected = |node_id: NodeId, direction| ConnectionEvent::Connected {
+            node_id,
+            direction,
+            addr: None,
+            relay: None,
+        };
+        assert_eq!(
+            a_events.try_recv().unwrap(),
+            connected(b.local_id(), Direction::Outbound)
+        );
+        assert_eq!(
+            b_events.try_recv().unwrap(),
+            connected(a.local_id(), Direction::Inbound)
+        );
+        for (events, peer) in [(&mut a_events, b.local_id()), (&mut b_events, a.local_id())] {
+            assert_eq!(
+                events.try_recv().unwrap(),
+                ConnectionEvent::Disconnected { node_id: peer }
+            );
+            assert!(events.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_banned_peer_is_disconnected_and_refused() {
+        use crate::node::reputation::Ban;
+
+        let network = MemoryNetwork::new();
+        let a: Arc<dyn Transport> = network.add_node(node_id());
+        let b: Arc<dyn Transport> = network.add_node(node_id());
+        a.connect(b.local_id(), Vec::new()).await.unwrap();
+        let mut b_events = b.subscribe_events();
+
+        a.reputation().lock().await.ban(Ban {
+            node_id: b.local_id(),
+            reason: "test".to_string(),
+            banned_at: 0,
+            expires_at: None,
+        });
+        a.disconnect(b.local_id()).await;
+        assert_eq!(
+            b_events.try_recv().unwrap(),
+            ConnectionEvent::Disconnected {
+                node_id: a.local_id()
+            }
+        );
+        assert!(a.list_peers().await.is_empty());
+
+        // 双方都不能重新建立连接，解除封禁后恢复
+        assert!(a.connect(b.local_id(), Vec::new()).await.is_err());
+        assert!(b.connect(a.local_id(), Vec::new()).await.is_err());
+        a.reputation().lock().await.unban(&b.local_id());
+        b.connect(a.local_id(), Vec::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rpc_and_capabilities() {
+        let network = MemoryNetwork::new();
+        let a: Arc<dyn Transport> = network.add_node(node_id());
+        let b: Arc<dyn Transport> = network.add_node_with(node_id(), Capabilities::GOSSIP);
+        a.connect(b.local_id(), Vec::new()).await.unwrap();
+
+        b.register_rpc::<Echo, _, _>(|from, req| async move { Ok(format!("{}: {}", from, req)) });
+        let reply = a
+            .call::<Echo>(b.local_id(), "hi".to_string())
+            .await
+            .unwrap();
+        assert_eq!(reply, format!("{}: hi", a.local_id()));
+
+        // 对端未注册的方法返回远程错误
+        let err = b
+            .call::<Echo>(a.local_id(), "hi".to_string())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<RemoteError>().is_some());
+
+        assert_eq!(a.peers_with(Capabilities::GOSSIP).await, vec![b.local_id()]);
+        assert!(a.peers_with(Capabilities::CHAT).await.is_empty());
+        assert_eq!(b.peers_with(Capabilities::CHAT).await, vec![a.local_id()]);
+    }
+
+    #[tokio::test]
+    async fn test_send_many_encodes_per_peer() {
+        let network = MemoryNetwork::new();
+        let a: Arc<dyn Transport> = network.add_node(node_id());
+        let new: Arc<dyn Transport> = network.add_node(node_id());
+        let old: Arc<dyn Transport> = network.add_node_with(node_id(), Capabilities::GOSSIP);
+        let mut receivers = Vec::new();
+        for peer in [&new, &old] {
+            let (tx, rx) = mpsc::channel(4);
+            peer.register_channel(Channel::Gossip, tx).await;
+            a.connect(peer.local_id(), Vec::new()).await.unwrap();
+            receivers.push(rx);
+        }
+
+        let message = vec!["hello".to_string()];
+        let results = a
+            .send_many(
+                vec![new.local_id(), old.local_id()],
+                Channel::Gossip,
+                &message,
+            )
+            .await;
+        assert!(results.iter().all(|(_, r)| r.is_ok()));
+
+        // 声明了 binary 能力的节点收到二进制编码，旧版本节点收到 JSON
+        for (rx, encoding) in receivers.iter_mut().zip([Encoding::Binary, Encoding::Json]) {
+            let (_, data) = rx.recv().await.unwrap();
+            assert_eq!(Encoding::of(&data), encoding);
+            assert_eq!(codec::decode::<Vec<String>>(&data).unwrap(), message);
+        }
+    }
+}
@@ -1,3 +1,18 @@
+#![allow(clippy::module_inception)]
+pub mod addr;
 pub mod cert;
+pub mod codec;
 pub mod config;
+pub mod events;
+pub mod frame;
+pub mod handshake;
+pub mod holepunch;
+pub mod limits;
+pub mod memory;
+pub mod peer_map;
+pub mod protocol;
 pub mod quic;
+pub mod relay;
+pub mod rpc;
+pub mod stats;
+pub mod transport;
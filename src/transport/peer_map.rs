@@ -0,0 +1,178 @@
+//! 按 NodeId 分片的连接表
+//!
+//! 每个分片是一个 `std::sync::RwLock<HashMap>`，锁只在同步代码中短暂持有、从不跨越 await。
+//! 不同节点的查找和更新大多落在不同分片上，并发的发送、拨号和清理任务互不阻塞。
+use crate::node::node_id::NodeId;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const SHARDS: usize = 16;
+
+type Shard<V> = RwLock<HashMap<NodeId, V>>;
+
+pub struct PeerMap<V> {
+    shards: Vec<Shard<V>>,
+}
+
+impl<V> Default for PeerMap<V> {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+}
+
+impl<V> std::fmt::Debug for PeerMap<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerMap").field("len", &self.len()).finish()
+    }
+}
+
+impl<V> PeerMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn shard(&self, node_id: &NodeId) -> &Shard<V> {
+        let mut hasher = DefaultHasher::new();
+        node_id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    fn read(shard: &Shard<V>) -> RwLockReadGuard<'_, HashMap<NodeId, V>> {
+        shard.read().expect("peer map poisoned")
+    }
+
+    fn write(shard: &Shard<V>) -> RwLockWriteGuard<'_, HashMap<NodeId, V>> {
+        shard.write().expect("peer map poisoned")
+    }
+
+    pub fn contains_key(&self, node_id: &NodeId) -> bool {
+        Self::read(self.shard(node_id)).contains_key(node_id)
+    }
+
+    /// 插入或替换，返回被替换的值
+    pub fn insert(&self, node_id: NodeId, value: V) -> Option<V> {
+        Self::write(self.shard(&node_id)).insert(node_id, value)
+    }
+
+    /// 在同一次加锁中决定是否插入：已有值时由 `replace(existing, &value)` 决定是否替换
+    ///
+    /// 插入成功返回 `Ok(被替换的值)`，保留已有值时返回 `Err(value)`
+    pub fn insert_or_keep(
+        &self,
+        node_id: NodeId,
+        value: V,
+        replace: impl FnOnce(&V, &V) -> bool,
+    ) -> Result<Option<V>, V> {
+        let mut shard = Self::write(self.shard(&node_id));
+        match shard.get(&node_id) {
+            Some(existing) if !replace(existing, &value) => Err(value),
+            _ => Ok(shard.insert(node_id, value)),
+        }
+    }
+
+    pub fn remove(&self, node_id: &NodeId) -> Option<V> {
+        Self::write(self.shard(node_id)).remove(node_id)
+    }
+
+    /// 当前值满足条件时删除，用于只删除某一条特定的连接
+    pub fn remove_if(&self, node_id: &NodeId, f: impl FnOnce(&V) -> bool) -> Option<V> {
+        let mut shard = Self::write(self.shard(node_id));
+        if shard.get(node_id).is_some_and(f) {
+            shard.remove(node_id)
+        } else {
+            None
+        }
+    }
+
+    pub fn keys(&self) -> Vec<NodeId> {
+        self.shards
+            .iter()
+            .flat_map(|shard| Self::read(shard).keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| Self::read(shard).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 逐个分片保留满足条件的条目
+    pub fn retain(&self, mut f: impl FnMut(&NodeId, &V) -> bool) {
+        for shard in &self.shards {
+            Self::write(shard).retain(|k, v| f(k, v));
+        }
+    }
+}
+
+impl<V: Clone> PeerMap<V> {
+    pub fn get(&self, node_id: &NodeId) -> Option<V> {
+        Self::read(self.shard(node_id)).get(node_id).cloned()
+    }
+
+    /// 所有值的快照
+    pub fn values(&self) -> Vec<V> {
+        self.shards
+            .iter()
+            .flat_map(|shard| Self::read(shard).values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    #[test]
+    fn test_peer_map() {
+        let map: PeerMap<u32> = PeerMap::new();
+        let ids: Vec<NodeId> = (0..40)
+            .map(|_| NodeId::from_keypair(&KeyPair::generate().unwrap()))
+            .collect();
+        for (i, id) in ids.iter().enumerate() {
+            assert!(map.insert(id.clone(), i as u32).is_none());
+        }
+        assert_eq!(map.len(), ids.len());
+        assert_eq!(map.get(&ids[3]), Some(3));
+        assert_eq!(map.insert(ids[3].clone(), 100), Some(3));
+
+        map.retain(|_, v| *v % 2 == 0);
+        assert!(!map.contains_key(&ids[1]));
+        assert!(map.contains_key(&ids[2]));
+        // 20 个偶数下标，加上被替换为 100 的 ids[3]
+        assert_eq!(map.len(), 21);
+
+        assert_eq!(map.remove(&ids[2]), Some(2));
+        let mut values = map.values();
+        values.sort();
+        assert_eq!(values.len(), 20);
+        assert_eq!(values.last(), Some(&100));
+        assert_eq!(map.keys().len(), 20);
+
+        // 条件插入和删除
+        assert_eq!(
+            map.insert_or_keep(ids[3].clone(), 5, |old, new| new > old),
+            Err(5)
+        );
+        assert_eq!(
+            map.insert_or_keep(ids[3].clone(), 200, |old, new| new > old),
+            Ok(Some(100))
+        );
+        assert_eq!(
+            map.insert_or_keep(ids[1].clone(), 1, |_, _| false),
+            Ok(None)
+        );
+        assert_eq!(map.remove_if(&ids[3], |v| *v == 100), None);
+        assert_eq!(map.remove_if(&ids[3], |v| *v == 200), Some(200));
+    }
+}
@@ -0,0 +1,223 @@
+//! 协议版本与能力协商
+//!
+//! TLS 层通过 ALPN `megaengine/<n>` 区分协议，旧版本（如使用 `h3` 的节点）在 TLS 握手阶段即被拒绝。
+//! 身份握手中双方再交换协议版本和能力位，低于 [`MIN_PROTOCOL_VERSION`] 的对端会收到明确的拒绝原因。
+//! 上层服务按对端声明的能力决定向哪些节点发送哪些消息，使不同版本的节点可以共存。
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// 当前协议版本
+pub const PROTOCOL_VERSION: u8 = 1;
+/// 可以互通的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+const ALPN_PREFIX: &str = "megaengine/";
+
+/// 本节点支持的 ALPN 列表，新版本在前
+pub fn alpn_protocols() -> Vec<Vec<u8>> {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION)
+        .rev()
+        .map(|v| format!("{}{}", ALPN_PREFIX, v).into_bytes())
+        .collect()
+}
+
+/// 能力位集合，未知的位原样保留，以便转发和展示新版本的能力
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// 参与 gossip（节点和仓库公告）
+    pub const GOSSIP: Self = Self(1 << 0);
+    /// 点对点聊天消息
+    pub const CHAT: Self = Self(1 << 1);
+    /// 通过 `bundle.request` RPC 请求、经 Data 通道传输 bundle
+    pub const BUNDLE_V2: Self = Self(1 << 2);
+    /// 提供中继服务
+    pub const RELAY: Self = Self(1 << 3);
+    /// 可以协调打洞
+    pub const HOLEPUNCH: Self = Self(1 << 4);
+    /// 理解 Plumtree 控制消息（IHAVE / GRAFT / PRUNE），可以参与广播树
+    pub const PLUMTREE: Self = Self(1 << 5);
+    /// 参与 HyParView 成员协议，gossip 只在 active view 的邻居之间传播
+    pub const MEMBERSHIP: Self = Self(1 << 6);
+    /// 响应仓库目录的反熵摘要交换（`repo.inventory` / `repo.fetch`）
+    pub const ANTI_ENTROPY: Self = Self(1 << 7);
+    /// 理解带发送序号的签名消息，旧节点无法校验这类消息的签名
+    pub const SEQUENCE: Self = Self(1 << 8);
+    /// 理解增量的 RefUpdate 和分页的完整库存公告
+    pub const REF_UPDATE: Self = Self(1 << 9);
+    /// 声明主题订阅，只接收订阅主题的仓库消息
+    pub const TOPICS: Self = Self(1 << 10);
+    /// 理解二进制编码的消息（见 `transport::codec`），未声明的节点只收到 JSON
+    pub const BINARY: Self = Self(1 << 11);
+
+    const NAMES: [(Self, &'static str); 12] = [
+        (Self::GOSSIP, "gossip"),
+        (Self::CHAT, "chat"),
+        (Self::BUNDLE_V2, "bundle-v2"),
+        (Self::RELAY, "relay"),
+        (Self::HOLEPUNCH, "holepunch"),
+        (Self::PLUMTREE, "plumtree"),
+        (Self::MEMBERSHIP, "membership"),
+        (Self::ANTI_ENTROPY, "anti-entropy"),
+        (Self::SEQUENCE, "sequence"),
+        (Self::REF_UPDATE, "ref-update"),
+        (Self::TOPICS, "topics"),
+        (Self::BINARY, "binary"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// 每个节点默认提供的能力，中继能力需要显式启用
+    pub const fn standard() -> Self {
+        Self::GOSSIP
+            .union(Self::CHAT)
+            .union(Self::BUNDLE_V2)
+            .union(Self::HOLEPUNCH)
+            .union(Self::PLUMTREE)
+            .union(Self::MEMBERSHIP)
+            .union(Self::ANTI_ENTROPY)
+            .union(Self::SEQUENCE)
+            .union(Self::REF_UPDATE)
+            .union(Self::TOPICS)
+            .union(Self::BINARY)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// 已知能力的名称
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(cap, _)| self.contains(*cap))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl std::ops::BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.names().join(","))
+    }
+}
+
+/// 握手中声明的协议版本和能力
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerProtocol {
+    pub version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl PeerProtocol {
+    /// 本节点以当前协议版本声明的能力
+    pub fn local(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// 检查对端版本能否与本节点互通
+    pub fn check_compatible(&self) -> Result<()> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "unsupported protocol version {} (minimum {})",
+                self.version,
+                MIN_PROTOCOL_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    /// 双方都支持的协议版本
+    pub fn negotiated_version(&self) -> u8 {
+        self.version.min(PROTOCOL_VERSION)
+    }
+
+    pub fn supports(&self, capability: Capabilities) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alpn_protocols() {
+        assert_eq!(alpn_protocols()[0], b"megaengine/1".to_vec());
+        assert!(!alpn_protocols().contains(&b"h3".to_vec()));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::standard();
+        assert!(caps.contains(Capabilities::CHAT));
+        assert!(!caps.contains(Capabilities::RELAY));
+        assert!(!caps.contains(Capabilities::CHAT | Capabilities::RELAY));
+        assert!((caps | Capabilities::RELAY).contains(Capabilities::RELAY));
+        assert_eq!(
+            Capabilities::standard().to_string(),
+            "gossip,chat,bundle-v2,holepunch,plumtree,membership,anti-entropy,sequence,ref-update,topics,binary"
+        );
+
+        // 未知的能力位在序列化时保留
+        let future = Capabilities::from_bits(1 << 31) | Capabilities::GOSSIP;
+        let json = serde_json::to_string(&future).unwrap();
+        let decoded: Capabilities = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, future);
+        assert_eq!(decoded.names(), vec!["gossip"]);
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let current = PeerProtocol::local(Capabilities::standard());
+        assert!(current.check_compatible().is_ok());
+        assert_eq!(current.negotiated_version(), PROTOCOL_VERSION);
+
+        let newer = PeerProtocol {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::empty(),
+        };
+        assert!(newer.check_compatible().is_ok());
+        assert_eq!(newer.negotiated_version(), PROTOCOL_VERSION);
+
+        let old = PeerProtocol {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::standard(),
+        };
+        let err = old.check_compatible().unwrap_err();
+        assert!(err.to_string().contains("unsupported protocol version"));
+    }
+}
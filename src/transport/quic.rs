@@ -1,38 +1,101 @@
+use crate::identity::keypair::KeyPair;
 use crate::node::node_id::NodeId;
+use crate::node::reputation::{Reputation, SharedReputation};
+use crate::node::routing::{RoutingTable, SharedRoutingTable};
+use crate::transport::addr::{self, Families};
+use crate::transport::cert::node_id_from_certificate;
+use crate::transport::codec::Encoding;
 use crate::transport::config::QuicConfig;
-use anyhow::{Context, Result};
-use quinn::{Connection, Endpoint, Incoming};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use crate::transport::events::{self, ConnectionEvent, Direction, EventSender};
+use crate::transport::frame::{self, Channel};
+use crate::transport::handshake::{self, HANDSHAKE_FAILED_CODE};
+use crate::transport::holepunch::{
+    self, HolePunchNotify, HolePunchRequest, ObservedAddrs, PunchAddrs, PunchLimiter, PunchNotify,
+    PunchRequest, HOLEPUNCH_TIMEOUT,
+};
+use crate::transport::limits::{
+    ConnectionLimits, ConnectionSlots, LimitMetrics, LimitStats, OverflowPolicy, PeerRateLimiter,
+    QUEUE_OVERFLOW_CODE,
+};
+use crate::transport::peer_map::PeerMap;
+use crate::transport::protocol::{Capabilities, PeerProtocol};
+use crate::transport::relay::{
+    self, CircuitEndpoint, CircuitMessage, RelayReserve, RelayService, Reservation,
+    CIRCUIT_SETUP_TIMEOUT, RESERVATION_RENEW_INTERVAL,
+};
+use crate::transport::rpc::{self, RpcMethod, RpcRegistry, RpcValue, RPC_TIMEOUT};
+use crate::transport::stats::{PeerCounters, PeerStats, TrafficStats};
+use crate::transport::transport::Transport;
+use crate::util::timestamp_now;
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use quinn::{
+    Connection, Endpoint, EndpointConfig, Incoming, RecvStream, SendStream, TokioRuntime, VarInt,
+};
+use rustls::pki_types::CertificateDer;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc::Receiver;
-use tokio::sync::{mpsc, Mutex};
-use tracing::{error, info};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tracing::{debug, error, info, warn};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender as TokioSender;
 
-const READ_BUF_SIZE: usize = 1024 * 1024;
 const CONNECTION_CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
-
-// 消息前缀：用于区分 Gossip 控制消息和数据传输
-const GOSSIP_MESSAGE_PREFIX: &[u8] = b"GOSSIP:";
-const DATA_MESSAGE_PREFIX: &[u8] = b"DATA:";
-
-// Type alias for Gossip 消息发送端（控制流）
-type GossipMessageSender = Arc<Mutex<Option<TokioSender<(NodeId, Vec<u8>)>>>>;
-// Type alias for 数据传输发送端（数据流）
-type DataMessageSender = Arc<Mutex<Option<TokioSender<(NodeId, Vec<u8>)>>>>;
+/// 单个直连地址的连接超时，超时后尝试下一个地址或经由中继连接
+const DIRECT_DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// 节点关闭时断开连接使用的错误码
+pub const NODE_SHUTDOWN_CODE: u32 = 0x13;
+/// 与同一节点存在多条连接时关闭多余连接使用的错误码
+pub const DUPLICATE_CONNECTION_CODE: u32 = 0x14;
+/// 断开或拒绝被封禁节点的连接使用的错误码
+pub const PEER_BANNED_CODE: u32 = 0x15;
+/// 节点关闭时等待对端确认连接关闭的最长时间
+const SHUTDOWN_IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+/// 每条连接、每个逻辑通道的发送队列长度，队列满时发送方等待写任务
+const SEND_QUEUE_CAPACITY: usize = 64;
+
+// Type alias for 各逻辑通道的消息接收端，每收到一帧读取一次，使用同步读写锁
+type ChannelSenders = Arc<RwLock<HashMap<Channel, TokioSender<(NodeId, Vec<u8>)>>>>;
+/// 待写入的一帧及其写入结果的回执
+type OutboundFrame = (Vec<u8>, oneshot::Sender<Result<()>>);
+type SendQueues = Arc<std::sync::Mutex<HashMap<Channel, mpsc::Sender<OutboundFrame>>>>;
 
 #[derive(Debug, Clone)]
 pub struct ConnectionManager {
-    #[allow(dead_code)]
     config: QuicConfig,
-    endpoint: Arc<Endpoint>,
+    /// 每个监听地址一个 endpoint
+    endpoints: Arc<Vec<BoundEndpoint>>,
     connection_tx: mpsc::Sender<QuicConnection>,
-    connections: Arc<Mutex<HashMap<NodeId, Arc<QuicConnection>>>>,
-    gossip_sender: GossipMessageSender,
-    data_sender: DataMessageSender,
+    /// 已连接的节点，按 NodeId 分片，查找和发送不经过全局锁
+    connections: Arc<PeerMap<Arc<QuicConnection>>>,
+    channels: ChannelSenders,
+    rpc: RpcRegistry,
+    routing: SharedRoutingTable,
+    /// 节点信誉和封禁记录
+    reputation: SharedReputation,
+    /// 本节点作为中继时的服务状态
+    relay: Option<Arc<RelayService>>,
+    /// 已知的中继节点
+    relays: Arc<Mutex<HashSet<NodeId>>>,
+    /// 在各中继节点上的预约到期时间
+    reservations: Arc<Mutex<HashMap<NodeId, Instant>>>,
+    /// 对端观察到的本节点外部地址
+    observed: Arc<Mutex<ObservedAddrs>>,
+    /// 按（协调节点，发起方）限制响应打洞通知的频率
+    punch_limiter: Arc<Mutex<PunchLimiter>>,
+    /// 入站连接名额
+    slots: ConnectionSlots,
+    /// 连接和速率限制的触发次数
+    limit_metrics: Arc<LimitMetrics>,
+    /// 连接生命周期事件
+    events: EventSender,
+    /// 各节点的收发计数
+    traffic: Arc<TrafficStats>,
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +104,16 @@ pub struct QuicConnection {
     pub peer_addr: SocketAddr,
     pub node_id: NodeId,
     pub connection_type: ConnectionType,
+    /// 经由哪个中继节点建立，直连时为 `None`
+    pub relay: Option<NodeId>,
+    /// 对端在握手中声明的协议版本和能力
+    pub protocol: PeerProtocol,
+    /// 连接建立的时间
+    pub established: Instant,
+    /// 每个逻辑通道一个发送队列，由独立的写任务写入一条长期存在的单向流，首次发送时创建
+    ///
+    /// 不同通道互不阻塞：大文件传输占用 Data 通道时，gossip 和聊天消息照常发出
+    queues: SendQueues,
 }
 
 #[derive(Debug, Clone)]
@@ -49,57 +122,428 @@ pub enum ConnectionType {
     Server,
 }
 
+impl QuicConnection {
+    fn new(
+        connection: Connection,
+        peer_addr: SocketAddr,
+        node_id: NodeId,
+        connection_type: ConnectionType,
+        protocol: PeerProtocol,
+    ) -> Self {
+        Self {
+            connection,
+            peer_addr,
+            node_id,
+            connection_type,
+            relay: None,
+            protocol,
+            established: Instant::now(),
+            queues: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn with_relay(mut self, relay: NodeId) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    pub fn direction(&self) -> Direction {
+        match self.connection_type {
+            ConnectionType::Client => Direction::Outbound,
+            ConnectionType::Server => Direction::Inbound,
+        }
+    }
+
+    /// 将一帧放入指定通道的发送队列并等待写入完成，同一通道内的消息按发送顺序到达
+    async fn send_frame(&self, channel: Channel, payload: Vec<u8>) -> Result<()> {
+        let queue = self.send_queue(channel);
+        let (ack_tx, ack_rx) = oneshot::channel();
+        queue
+            .send((payload, ack_tx))
+            .await
+            .map_err(|_| anyhow!("send queue for {} channel is closed", channel))?;
+        ack_rx
+            .await
+            .map_err(|_| anyhow!("send queue for {} channel is closed", channel))?
+    }
+
+    /// 获取通道的发送队列，不存在时创建队列并启动对应的写任务
+    fn send_queue(&self, channel: Channel) -> mpsc::Sender<OutboundFrame> {
+        let mut queues = self.queues.lock().expect("send queues poisoned");
+        if let Some(queue) = queues.get(&channel).filter(|q| !q.is_closed()) {
+            return queue.clone();
+        }
+        let (tx, rx) = mpsc::channel(SEND_QUEUE_CAPACITY);
+        tokio::spawn(Self::write_channel(self.connection.clone(), channel, rx));
+        queues.insert(channel, tx.clone());
+        tx
+    }
+
+    /// 通道的写任务：按入队顺序写入长期流，流损坏时丢弃，下一帧重新打开
+    ///
+    /// 所有发送端释放（连接被移除）或连接关闭后退出
+    async fn write_channel(
+        connection: Connection,
+        channel: Channel,
+        mut rx: mpsc::Receiver<OutboundFrame>,
+    ) {
+        let mut stream: Option<SendStream> = None;
+        while let Some((payload, ack)) = rx.recv().await {
+            let result = match stream.as_mut() {
+                Some(stream) => frame::write_frame(stream, channel, &payload).await,
+                None => match connection.open_uni().await {
+                    Ok(opened) => {
+                        let stream = stream.insert(opened);
+                        frame::write_frame(stream, channel, &payload).await
+                    }
+                    Err(e) => Err(e.into()),
+                },
+            };
+            if result.is_err() {
+                stream = None;
+            }
+            let _ = ack.send(result);
+            if connection.close_reason().is_some() {
+                break;
+            }
+        }
+    }
+}
+
+/// 单个节点的入站消息处理：速率限制，以及每个通道一个有界接收队列
+///
+/// 读取任务把帧放入该节点自己的队列，再由转发任务交给通道的处理器；
+/// 处理器变慢时只有这个节点的队列会满，不影响其他节点的读取
+struct PeerInbound {
+    peer_id: NodeId,
+    connection: Connection,
+    channels: ChannelSenders,
+    limits: ConnectionLimits,
+    metrics: Arc<LimitMetrics>,
+    counters: Arc<PeerCounters>,
+    rate: std::sync::Mutex<PeerRateLimiter>,
+    queues: std::sync::Mutex<HashMap<Channel, mpsc::Sender<Vec<u8>>>>,
+}
+
+impl PeerInbound {
+    /// 超出速率时暂停读取，由 QUIC 流量控制把压力传回发送方
+    async fn throttle(&self, len: usize) {
+        let wait = self
+            .rate
+            .lock()
+            .expect("rate limiter poisoned")
+            .reserve(len, Instant::now());
+        if !wait.is_zero() {
+            self.metrics.record_rate_limited();
+            debug!(
+                "Node[{}] exceeded its rate limit, pausing reads for {:?}",
+                self.peer_id, wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// 将消息放入通道的接收队列，队列满时按通道的溢出策略处理
+    ///
+    /// 连接因溢出被关闭时返回 false
+    async fn deliver(&self, channel: Channel, payload: Vec<u8>) -> bool {
+        let queue = self.queue(channel);
+        let payload = match queue.try_send(payload) {
+            Ok(()) => return true,
+            Err(e) => e.into_inner(),
+        };
+
+        match self.limits.overflow_policy(channel) {
+            OverflowPolicy::Drop => {
+                self.metrics.record_dropped();
+                warn!(
+                    "Receive queue for {} messages from node[{}] is full, dropping message",
+                    channel, self.peer_id
+                );
+                true
+            }
+            OverflowPolicy::Close => {
+                let sent = tokio::time::timeout(self.limits.overflow_grace, queue.send(payload));
+                if let Ok(Ok(())) = sent.await {
+                    return true;
+                }
+                self.metrics.record_overflow_close();
+                warn!(
+                    "Receive queue for {} messages from node[{}] stayed full, closing connection",
+                    channel, self.peer_id
+                );
+                self.connection.close(
+                    VarInt::from_u32(QUEUE_OVERFLOW_CODE),
+                    b"receive queue overflow",
+                );
+                false
+            }
+        }
+    }
+
+    /// 获取通道的接收队列，不存在时创建队列并启动转发任务
+    fn queue(&self, channel: Channel) -> mpsc::Sender<Vec<u8>> {
+        let mut queues = self.queues.lock().expect("receive queues poisoned");
+        queues
+            .entry(channel)
+            .or_insert_with(|| {
+                let (tx, rx) = mpsc::channel(self.limits.peer_queue_capacity.max(1));
+                tokio::spawn(Self::forward(
+                    self.peer_id.clone(),
+                    channel,
+                    Arc::clone(&self.channels),
+                    rx,
+                ));
+                tx
+            })
+            .clone()
+    }
+
+    /// 把接收队列中的消息依次交给通道的处理器，队列的发送端全部释放后退出
+    async fn forward(
+        peer_id: NodeId,
+        channel: Channel,
+        channels: ChannelSenders,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+    ) {
+        while let Some(payload) = rx.recv().await {
+            let sender = channels
+                .read()
+                .expect("channel senders poisoned")
+                .get(&channel)
+                .cloned();
+            match sender {
+                Some(tx) => {
+                    if tx.send((peer_id.clone(), payload)).await.is_err() {
+                        error!("Receiver for {} channel is closed", channel);
+                    }
+                }
+                None => {
+                    info!(
+                        "Dropping {} message from {} ({} bytes), no receiver registered",
+                        channel,
+                        peer_id,
+                        payload.len()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// 绑定在一个监听地址上的 QUIC endpoint
+#[derive(Debug)]
+struct BoundEndpoint {
+    endpoint: Endpoint,
+    /// 与 endpoint 共享的 UDP socket，用于发送打洞数据报
+    socket: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    /// 绑定在 `::` 上且未设置 IPV6_V6ONLY，可以收发 IPv4 数据报
+    dual_stack: bool,
+}
+
+impl BoundEndpoint {
+    /// 绑定 `addr`；同时配置了 IPv4 地址时 IPv6 socket 只处理 IPv6，
+    /// 避免与 `0.0.0.0` 上的同一端口冲突，否则 `::` 作为双栈 socket 同时接收 IPv4 连接
+    fn bind(addr: SocketAddr, v6_only: bool, server_config: quinn::ServerConfig) -> Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket
+            .bind(&addr.into())
+            .with_context(|| format!("Failed to bind {}", addr))?;
+        let socket: UdpSocket = socket.into();
+        let local_addr = socket.local_addr()?;
+
+        // 保留一份 socket 句柄，打洞数据报需要从 endpoint 使用的同一端口发出
+        let punch_socket = Arc::new(socket.try_clone()?);
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config),
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .context("Failed to create QUIC server endpoint")?;
+
+        Ok(Self {
+            endpoint,
+            socket: punch_socket,
+            local_addr,
+            dual_stack: addr.is_ipv6() && addr.ip().is_unspecified() && !v6_only,
+        })
+    }
+}
+
+/// 选择向 `target` 发送数据使用的 endpoint：优先同一地址族，IPv4 目标其次使用双栈 endpoint
+fn select_endpoint<'a>(endpoints: &'a [BoundEndpoint], target: &SocketAddr) -> &'a BoundEndpoint {
+    endpoints
+        .iter()
+        .find(|e| e.local_addr.is_ipv4() == target.is_ipv4())
+        .or_else(|| endpoints.iter().find(|e| e.dual_stack))
+        .unwrap_or(&endpoints[0])
+}
+
+/// 从各目标对应的 endpoint socket 发送打洞数据报
+fn send_punch_packets(endpoints: &[BoundEndpoint], targets: Vec<SocketAddr>) {
+    let mut by_socket: Vec<(Arc<UdpSocket>, Vec<SocketAddr>)> = Vec::new();
+    for target in targets {
+        let bound = select_endpoint(endpoints, &target);
+        // 双栈 socket 只接受 IPv6 形式的目标地址
+        let target = match target {
+            SocketAddr::V4(v4) if bound.local_addr.is_ipv6() => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            _ => target,
+        };
+        match by_socket
+            .iter_mut()
+            .find(|(socket, _)| Arc::ptr_eq(socket, &bound.socket))
+        {
+            Some((_, addrs)) => addrs.push(target),
+            None => by_socket.push((Arc::clone(&bound.socket), vec![target])),
+        }
+    }
+    for (socket, addrs) in by_socket {
+        holepunch::send_punch_packets(socket, addrs);
+    }
+}
+
+/// 与同一节点已有一条连接时，新连接是否应替换它
+///
+/// 已关闭的连接总是被替换，直连优先于中继连接；方向相同时新连接替换旧连接（通常是对端重启后重连），
+/// 方向不同（双方同时互相拨号）时保留 [`events::preferred_direction`] 选出的那一条
+fn keep_new_connection(local: &NodeId, existing: &QuicConnection, new: &QuicConnection) -> bool {
+    if existing.connection.close_reason().is_some() {
+        return true;
+    }
+    match (existing.relay.is_some(), new.relay.is_some()) {
+        (true, false) => return true,
+        (false, true) => return false,
+        _ => {}
+    }
+    if existing.direction() == new.direction() {
+        return true;
+    }
+    new.direction() == events::preferred_direction(local, &new.node_id)
+}
+
 impl ConnectionManager {
     fn server(config: QuicConfig) -> Result<(Self, Receiver<QuicConnection>)> {
+        if config.bind_addrs.is_empty() {
+            return Err(anyhow!("No bind address configured"));
+        }
         let server_config = config.get_server_config()?;
 
-        let mut endpoint = Endpoint::server(server_config, config.bind_addr)
-            .context("Failed to create QUIC server endpoint")?;
-        let client_config = config.get_client_config()?;
-        endpoint.set_default_client_config(client_config);
-
-        info!(
-            "The quic service starts on address {}",
-            endpoint.local_addr()?
-        );
+        // 客户端配置按拨号目标生成（见 `connect`），这里不设置默认值
+        let v6_only = config.bind_addrs.iter().any(|a| a.is_ipv4());
+        let mut endpoints = Vec::new();
+        for bind_addr in &config.bind_addrs {
+            let bound = BoundEndpoint::bind(*bind_addr, v6_only, server_config.clone())?;
+            info!("The quic service starts on address {}", bound.local_addr);
+            endpoints.push(bound);
+        }
 
         let (connection_tx, connection_rx) = mpsc::channel(8);
+        let limit_metrics = Arc::new(LimitMetrics::default());
+        let slots = ConnectionSlots::new(&config.limits, Arc::clone(&limit_metrics));
+        let relay = config
+            .relay
+            .clone()
+            .map(|limits| Arc::new(RelayService::new(limits)));
+
+        let reputation = Reputation::shared(config.reputation);
 
         let transport = Self {
             config,
-            endpoint: Arc::new(endpoint),
+            endpoints: Arc::new(endpoints),
             connection_tx,
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            gossip_sender: Arc::new(Mutex::new(None)),
-            data_sender: Arc::new(Mutex::new(None)),
+            connections: Arc::new(PeerMap::new()),
+            channels: Arc::new(RwLock::new(HashMap::new())),
+            rpc: RpcRegistry::default(),
+            routing: RoutingTable::shared(),
+            reputation,
+            relay,
+            relays: Arc::new(Mutex::new(HashSet::new())),
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+            observed: Arc::new(Mutex::new(ObservedAddrs::default())),
+            punch_limiter: Arc::new(Mutex::new(PunchLimiter::default())),
+            slots,
+            limit_metrics,
+            events: events::channel(),
+            traffic: Arc::new(TrafficStats::default()),
         };
+        transport.register_holepunch_rpc();
+
+        if let Some(relay) = &transport.relay {
+            let relay = Arc::clone(relay);
+            transport.register_rpc::<RelayReserve, _, _>(move |from, ()| {
+                let relay = Arc::clone(&relay);
+                async move { relay.reserve(&from) }
+            });
+            info!("Relay service enabled");
+        }
         Ok((transport, connection_rx))
     }
 
     pub async fn run_server(config: QuicConfig) -> Result<Self> {
         let (manager, mut conn_rx) = ConnectionManager::server(config)?;
-        let endpoint = Arc::clone(&manager.endpoint);
-        let connection_tx = manager.connection_tx.clone();
-        let connections = Arc::clone(&manager.connections);
-        let manager_clone = manager.clone();
+        let registrar = manager.clone();
 
         manager.start_connection_cleanup();
+        relay::start_reservation_task(manager.clone());
+
+        for index in 0..manager.endpoints.len() {
+            manager.spawn_accept_loop(index);
+        }
+
+        // 保存入站连接
+        tokio::spawn(async move {
+            while let Some(conn) = conn_rx.recv().await {
+                registrar.register_connection(conn).await;
+            }
+        });
+
+        Ok(manager.clone())
+    }
+
+    /// 接受第 `index` 个 endpoint 上的入站连接
+    fn spawn_accept_loop(&self, index: usize) {
+        let endpoints = Arc::clone(&self.endpoints);
+        let connection_tx = self.connection_tx.clone();
+        let manager_clone = self.clone();
 
         tokio::spawn(async move {
+            let endpoint = &endpoints[index].endpoint;
             while let Some(incoming) = endpoint.accept().await {
-                info!("Accepting connection from {}", incoming.remote_address());
+                let remote = addr::canonical(incoming.remote_address());
+                // 握手前占用名额，避免大量半开连接耗尽资源
+                let slot = match manager_clone.slots.try_acquire(remote.ip()) {
+                    Ok(slot) => slot,
+                    Err(e) => {
+                        warn!("Refusing connection from {}: {}", remote, e);
+                        incoming.refuse();
+                        continue;
+                    }
+                };
+                info!("Accepting connection from {}", remote);
                 let tx = connection_tx.clone();
                 let manager_clone = manager_clone.clone();
                 tokio::spawn(async move {
-                    match Self::accept_connection(incoming).await {
-                        Ok((conn, msg_rx)) => {
-                            if let Err(e) = tx.send(conn.clone()).await {
+                    let config = &manager_clone.config;
+                    match Self::accept_connection(incoming, &config.keypair, config.protocol())
+                        .await
+                    {
+                        Ok(conn) => {
+                            // 连接关闭后释放名额
+                            let connection = conn.connection.clone();
+                            tokio::spawn(async move {
+                                connection.closed().await;
+                                drop(slot);
+                            });
+                            if let Err(e) = tx.send(conn).await {
                                 error!("Failed to send connection: {}", e);
-                                return;
                             }
-                            manager_clone
-                                .spawn_message_handler(conn.node_id.clone(), msg_rx)
-                                .await;
                         }
                         Err(e) => {
                             error!("Connection failed: {}", e);
@@ -108,278 +552,1167 @@ impl ConnectionManager {
                 });
             }
         });
-
-        // 保存连接
-        tokio::spawn(async move {
-            while let Some(conn) = conn_rx.recv().await {
-                connections
-                    .lock()
-                    .await
-                    .insert(conn.node_id.clone(), Arc::from(conn.clone()));
-            }
-        });
-
-        Ok(manager.clone())
     }
 
+    /// 接受入站连接，并通过握手验证对端确实持有其声称的 NodeId
     pub async fn accept_connection(
         incoming: Incoming,
-    ) -> Result<(QuicConnection, Receiver<Vec<u8>>)> {
+        keypair: &KeyPair,
+        local: PeerProtocol,
+    ) -> Result<QuicConnection> {
         let connection = incoming.await?;
-        let peer_addr = connection.remote_address();
-
-        // 等待客户端发来的身份流
-        let mut recv = connection.accept_uni().await?;
-        let node_id_bytes = recv.read_to_end(READ_BUF_SIZE).await?;
-        let node_id_str = String::from_utf8(node_id_bytes)?;
-        let node_id: NodeId = node_id_str.parse().unwrap();
+        let peer_addr = addr::canonical(connection.remote_address());
+
+        let verified = match handshake::server_handshake(&connection, keypair, local).await {
+            Ok((node_id, protocol)) => {
+                peer_certificate_node_id(&connection).and_then(|cert_node_id| {
+                    // 握手声明的身份必须与 TLS 证书中的身份一致
+                    if cert_node_id == node_id {
+                        Ok((node_id, protocol))
+                    } else {
+                        Err(anyhow!(
+                            "handshake identity {} does not match certificate identity {}",
+                            node_id,
+                            cert_node_id
+                        ))
+                    }
+                })
+            }
+            Err(e) => Err(e),
+        };
+        let (node_id, protocol) = match verified {
+            Ok(verified) => verified,
+            Err(e) => {
+                connection.close(VarInt::from_u32(HANDSHAKE_FAILED_CODE), b"handshake failed");
+                return Err(e.context(format!("Handshake with {} failed", peer_addr)));
+            }
+        };
 
         info!(
-            "Accepted connection from {}, NodeId = {}",
-            peer_addr, node_id
+            "Accepted connection from {}, NodeId = {}, protocol v{} [{}]",
+            peer_addr, node_id, protocol.version, protocol.capabilities
         );
 
-        let (message_tx, message_rx) = mpsc::channel(32);
-        let connection_clone = connection.clone();
+        Ok(QuicConnection::new(
+            connection,
+            peer_addr,
+            node_id,
+            ConnectionType::Server,
+            protocol,
+        ))
+    }
+
+    /// 接收对端打开的流：单向流为通道流，由独立任务逐帧读取；双向流为 RPC 调用或中继电路
+    fn spawn_stream_acceptor(&self, conn: &QuicConnection) {
+        let connection = conn.connection.clone();
+        let limits = self.config.limits.clone();
+        let inbound = Arc::new(PeerInbound {
+            peer_id: conn.node_id.clone(),
+            connection: conn.connection.clone(),
+            channels: Arc::clone(&self.channels),
+            rate: std::sync::Mutex::new(PeerRateLimiter::new(&limits)),
+            limits,
+            metrics: Arc::clone(&self.limit_metrics),
+            counters: self.traffic.peer(&conn.node_id),
+            queues: std::sync::Mutex::new(HashMap::new()),
+        });
+
         tokio::spawn(async move {
-            while let Ok(mut recv) = connection_clone.accept_uni().await {
-                if let Ok(msg) = recv.read_to_end(READ_BUF_SIZE).await {
-                    if message_tx.send(msg).await.is_err() {
-                        break;
+            while let Ok(recv) = connection.accept_uni().await {
+                tokio::spawn(Self::read_stream(recv, Arc::clone(&inbound)));
+            }
+        });
+
+        let connection = conn.connection.clone();
+        let peer_id = conn.node_id.clone();
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            while let Ok((send, recv)) = connection.accept_bi().await {
+                let manager = manager.clone();
+                let peer_id = peer_id.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = manager.serve_bi_stream(peer_id.clone(), send, recv).await {
+                        error!("Failed to serve stream from node[{}]: {}", peer_id, e);
                     }
-                }
+                });
             }
         });
-        Ok((
-            QuicConnection {
-                connection,
-                peer_addr,
-                node_id,
-                connection_type: ConnectionType::Server,
-            },
-            message_rx,
-        ))
     }
 
-    /// 生成消息处理任务，将接收到的消息路由到对应的处理器（Gossip 或数据传输）
-    ///
-    /// 路由策略基于消息前缀：
-    /// - b"GOSSIP:" 前缀：路由到 gossip_sender（控制流消息）
-    /// - b"DATA:" 前缀：路由到 data_sender（数据传输）
-    /// - 无前缀：默认路由到 gossip_sender（向后兼容）
-    async fn spawn_message_handler(&self, peer_id: NodeId, mut receiver: Receiver<Vec<u8>>) {
-        let gossip = Arc::clone(&self.gossip_sender);
-        let data = Arc::clone(&self.data_sender);
+    /// 注册打洞相关的 RPC：作为协调节点转发请求，以及作为目标节点发送打洞数据报
+    fn register_holepunch_rpc(&self) {
+        let connections = Arc::clone(&self.connections);
+        self.register_rpc::<HolePunchRequest, _, _>(move |from, request| {
+            let connections = Arc::clone(&connections);
+            async move {
+                let (source, target) = (connections.get(&from), connections.get(&request.target));
+                // 中继连接上观察到的是中继的地址，对打洞没有意义
+                let target = target
+                    .filter(|c| c.relay.is_none())
+                    .ok_or_else(|| anyhow!("node[{}] is not directly connected", request.target))?;
+                // 目标只向这里观察到的发起方地址发送流量，发起方自己报告的地址不转发
+                let source_addr = source
+                    .filter(|c| c.relay.is_none())
+                    .map(|c| c.peer_addr)
+                    .ok_or_else(|| anyhow!("node[{}] is not directly connected", from))?;
+
+                let notify = PunchNotify {
+                    source: from,
+                    addrs: holepunch::merge_addrs(Some(source_addr), &[]),
+                };
+                let reply =
+                    Self::call_connection::<HolePunchNotify>(&target, notify, HOLEPUNCH_TIMEOUT)
+                        .await?;
+                Ok(PunchAddrs {
+                    addrs: holepunch::merge_addrs(Some(target.peer_addr), &reply.addrs),
+                })
+            }
+        });
+
+        let manager = self.clone();
+        self.register_rpc::<HolePunchNotify, _, _>(move |from, notify| {
+            let manager = manager.clone();
+            async move {
+                // 只响应直连的协调节点，经中继转发的通知无法确认协调节点观察到的地址
+                manager
+                    .connections
+                    .get(&from)
+                    .filter(|c| c.relay.is_none())
+                    .ok_or_else(|| anyhow!("node[{}] is not directly connected", from))?;
+                let target = holepunch::merge_addrs(None, &notify.addrs)
+                    .first()
+                    .copied()
+                    .ok_or_else(|| anyhow!("no address for node[{}]", notify.source))?;
+                if !manager
+                    .punch_limiter
+                    .lock()
+                    .await
+                    .allow(&from, &notify.source, Instant::now())
+                {
+                    return Err(anyhow!(
+                        "hole punching towards node[{}] via node[{}] was requested too often",
+                        notify.source,
+                        from
+                    ));
+                }
+                debug!(
+                    "Hole punching towards node[{}] at {}, coordinated by node[{}]",
+                    notify.source, target, from
+                );
+                send_punch_packets(&manager.endpoints, vec![target]);
+                manager.spawn_punch_dial(notify.source, target);
+
+                let mut own = manager.observed.lock().await.addresses();
+                own.extend(manager.local_addrs());
+                Ok(PunchAddrs {
+                    addrs: holepunch::merge_addrs(None, &own),
+                })
+            }
+        });
+    }
 
+    /// 打洞时向发起方拨号，身份握手确认该地址上确实是 `source`
+    fn spawn_punch_dial(&self, source: NodeId, addr: SocketAddr) {
+        let manager = self.clone();
         tokio::spawn(async move {
-            while let Some(bytes) = receiver.recv().await {
-                // 检查消息前缀来路由
-                let is_data_transfer = bytes.starts_with(DATA_MESSAGE_PREFIX);
-
-                if is_data_transfer {
-                    // 移除前缀并转发到 data_sender
-                    let payload = bytes[DATA_MESSAGE_PREFIX.len()..].to_vec();
-                    let maybe_data = data.lock().await;
-                    if let Some(tx) = maybe_data.as_ref() {
-                        let _ = tx.send((peer_id.clone(), payload)).await;
-                        continue;
-                    }
+            let connected = manager
+                .connections
+                .get(&source)
+                .is_some_and(|c| c.relay.is_none() && c.connection.close_reason().is_none());
+            if connected
+                || manager
+                    .reputation
+                    .lock()
+                    .await
+                    .is_banned(&source, timestamp_now())
+            {
+                return;
+            }
+            match manager.dial_direct(&source, &[addr]).await {
+                Ok(Some(conn)) => {
+                    manager.register_connection(conn).await;
                 }
+                Ok(None) => debug!("Hole punching dial to node[{}] at {} failed", source, addr),
+                Err(e) => debug!("Hole punching dial to node[{}] failed: {:#}", source, e),
+            }
+        });
+    }
 
-                // 检查并移除 GOSSIP 前缀（如果存在）
-                let payload = if bytes.starts_with(GOSSIP_MESSAGE_PREFIX) {
-                    bytes[GOSSIP_MESSAGE_PREFIX.len()..].to_vec()
-                } else {
-                    bytes.clone()
-                };
+    /// 按第一帧的类型分派入站双向流
+    async fn serve_bi_stream(
+        &self,
+        from: NodeId,
+        send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<()> {
+        let first = frame::read_frame(&mut recv)
+            .await?
+            .ok_or_else(|| anyhow!("stream closed before a message was received"))?;
+        match first.channel {
+            Channel::Rpc => self.rpc.respond(from, &first.payload, send).await,
+            Channel::Relay => match relay::parse_circuit_message(&first)? {
+                CircuitMessage::Connect { target } => {
+                    let encoding = Encoding::of(&first.payload);
+                    self.serve_relay_circuit(from, target, send, recv, encoding)
+                        .await
+                }
+                CircuitMessage::Incoming { source } => {
+                    let encoding = Encoding::of(&first.payload);
+                    self.accept_relayed_connection(from, source, send, recv, encoding)
+                        .await
+                }
+                other => Err(anyhow!("unexpected relay message: {:?}", other)),
+            },
+            other => Err(anyhow!(
+                "unexpected {} frame on bidirectional stream",
+                other
+            )),
+        }
+    }
 
-                // 路由到 gossip_sender
-                let maybe_gossip = gossip.lock().await;
-                if let Some(tx) = maybe_gossip.as_ref() {
-                    let _ = tx.send((peer_id.clone(), payload)).await;
-                } else {
-                    let message = String::from_utf8(payload).unwrap_or_default();
-                    info!("Received message from {}: {}", peer_id, message);
+    /// 作为中继转发 `source` 到 `target` 的电路，以请求的编码 `encoding` 回复
+    async fn serve_relay_circuit(
+        &self,
+        source: NodeId,
+        target: NodeId,
+        mut send: SendStream,
+        recv: RecvStream,
+        encoding: Encoding,
+    ) -> Result<()> {
+        let Some(relay) = self.relay.clone() else {
+            let reason = "not a relay node".to_string();
+            relay::write_circuit_message(&mut send, &CircuitMessage::Rejected { reason }, encoding)
+                .await?;
+            send.finish()?;
+            return Ok(());
+        };
+
+        // 只经由直连转发，不嵌套中继
+        let target_conn = self
+            .connections
+            .get(&target)
+            .filter(|c| c.relay.is_none())
+            .map(|c| (c.connection.clone(), Encoding::for_peer(Some(&c.protocol))));
+        relay
+            .serve_circuit(source, target, target_conn, send, recv, encoding)
+            .await
+    }
+
+    /// 接受中继节点转发来的、由 `source` 发起的电路，并在其上完成 QUIC 连接和身份握手
+    ///
+    /// 只接受本节点持有有效预约的中继，以中继通知的编码 `encoding` 回复
+    async fn accept_relayed_connection(
+        &self,
+        relay: NodeId,
+        source: NodeId,
+        mut send: SendStream,
+        recv: RecvStream,
+        encoding: Encoding,
+    ) -> Result<()> {
+        let reserved = self
+            .reservations
+            .lock()
+            .await
+            .get(&relay)
+            .is_some_and(|expires| *expires > Instant::now());
+        let relay_addr = self.connections.get(&relay).map(|c| c.peer_addr);
+        let relay_addr = match relay_addr {
+            Some(addr) if reserved => addr,
+            _ => {
+                let reason = "no reservation with this relay".to_string();
+                relay::write_circuit_message(
+                    &mut send,
+                    &CircuitMessage::Rejected { reason },
+                    encoding,
+                )
+                .await?;
+                send.finish()?;
+                return Ok(());
+            }
+        };
+        relay::write_circuit_message(&mut send, &CircuitMessage::Accepted, encoding).await?;
+
+        let circuit = CircuitEndpoint::new(
+            send,
+            recv,
+            relay_addr,
+            Some(self.config.get_server_config()?),
+        )?;
+        let incoming = tokio::time::timeout(CIRCUIT_SETUP_TIMEOUT, circuit.endpoint.accept())
+            .await
+            .map_err(|_| anyhow!("relayed connection from node[{}] timed out", source))?
+            .ok_or_else(|| anyhow!("relay circuit closed"))?;
+        let conn =
+            Self::accept_connection(incoming, &self.config.keypair, self.config.protocol()).await?;
+        if conn.node_id != source {
+            conn.connection.close(
+                VarInt::from_u32(HANDSHAKE_FAILED_CODE),
+                b"unexpected identity",
+            );
+            return Err(anyhow!(
+                "relay announced node[{}] but node[{}] connected",
+                source,
+                conn.node_id
+            ));
+        }
+
+        circuit.close_with(&conn.connection);
+        info!(
+            "Accepted relayed connection from node[{}] via node[{}]",
+            source, relay
+        );
+        self.register_connection(conn.with_relay(relay)).await;
+        Ok(())
+    }
+
+    /// 逐帧读取一条流，按帧类型放入该节点对应通道的接收队列
+    async fn read_stream(mut recv: RecvStream, inbound: Arc<PeerInbound>) {
+        loop {
+            let frame = match frame::read_frame(&mut recv).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read frame from node[{}]: {}", inbound.peer_id, e);
+                    break;
                 }
+            };
+
+            inbound
+                .counters
+                .record_received(frame.channel, frame.payload.len());
+            inbound.throttle(frame.payload.len()).await;
+            if !inbound.deliver(frame.channel, frame.payload).await {
+                break;
             }
+        }
+    }
+
+    /// 注册逻辑通道的消息接收器，重复注册会替换之前的接收器
+    pub async fn register_channel(&self, channel: Channel, tx: TokioSender<(NodeId, Vec<u8>)>) {
+        self.channels
+            .write()
+            .expect("channel senders poisoned")
+            .insert(channel, tx);
+    }
+
+    /// 注册 RPC 方法处理器
+    pub fn register_rpc<M, F, Fut>(&self, handler: F)
+    where
+        M: RpcMethod,
+        F: Fn(NodeId, M::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<M::Response>> + Send + 'static,
+    {
+        self.rpc.register::<M, F, Fut>(handler);
+    }
+
+    /// 记录一个可用的中继节点（通常来自 NodeAnnouncement）
+    pub async fn add_relay(&self, node_id: NodeId) {
+        if node_id != self.config.node_id() {
+            self.relays.lock().await.insert(node_id);
+        }
+    }
+
+    /// 向中继节点预约，使其他节点可以经由它连接到本节点
+    pub async fn reserve_relay(&self, relay: &NodeId) -> Result<Reservation> {
+        let reservation = self.call::<RelayReserve>(relay.clone(), ()).await?;
+        let expires = Instant::now() + Duration::from_secs(reservation.ttl_secs);
+        self.reservations
+            .lock()
+            .await
+            .insert(relay.clone(), expires);
+        debug!(
+            "Reserved on relay node[{}] for {}s",
+            relay, reservation.ttl_secs
+        );
+        Ok(reservation)
+    }
+
+    /// 已直连、且尚未预约或预约即将到期的中继节点
+    pub async fn relays_due_for_reservation(&self) -> Vec<NodeId> {
+        let relays = self.relays.lock().await.clone();
+        let reservations = self.reservations.lock().await;
+        let renew_before = Instant::now() + 2 * RESERVATION_RENEW_INTERVAL;
+        relays
+            .into_iter()
+            .filter(|r| self.connections.get(r).is_some_and(|c| c.relay.is_none()))
+            .filter(|r| match reservations.get(r) {
+                Some(expires) => *expires < renew_before,
+                None => true,
+            })
+            .collect()
+    }
+
+    /// 对端观察到的本节点外部地址，按报告的对端数排序
+    pub async fn external_addresses(&self) -> Vec<SocketAddr> {
+        self.observed.lock().await.addresses()
+    }
+
+    /// 计算 NodeAnnouncement 中公告的地址，见 [`holepunch::announce_addresses`]
+    pub async fn announce_addresses(&self, configured: &[SocketAddr]) -> Vec<SocketAddr> {
+        let observed = self.external_addresses().await;
+        holepunch::announce_addresses(configured, &observed, self.config.allow_local_addrs)
+    }
+
+    /// 各 endpoint 实际绑定的本地地址
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.endpoints.iter().map(|e| e.local_addr).collect()
+    }
+
+    /// 本地 endpoint 能够拨号的地址族
+    fn families(&self) -> Families {
+        Families {
+            ipv4: self
+                .endpoints
+                .iter()
+                .any(|e| e.local_addr.is_ipv4() || e.dual_stack),
+            ipv6: self.endpoints.iter().any(|e| e.local_addr.is_ipv6()),
+        }
+    }
+
+    /// 曾经连接过的各节点的连接和流量统计，已连接的节点在前
+    pub async fn peer_stats(&self) -> Vec<PeerStats> {
+        let routing = self.routing.lock().await;
+        let mut stats: Vec<PeerStats> = self
+            .traffic
+            .peers()
+            .into_iter()
+            .filter_map(|node_id| {
+                let counters = self.traffic.get(&node_id)?;
+                let conn = self.connections.get(&node_id);
+                let route = routing.get(&node_id);
+                Some(PeerStats::collect(
+                    node_id,
+                    &counters,
+                    conn.as_deref(),
+                    route,
+                ))
+            })
+            .collect();
+        stats.sort_by(|a, b| {
+            b.connected
+                .cmp(&a.connected)
+                .then_with(|| a.node_id.0.cmp(&b.node_id.0))
         });
+        stats
+    }
+
+    /// 订阅连接生命周期事件
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// 连接和速率限制的触发次数
+    pub fn limit_stats(&self) -> LimitStats {
+        self.limit_metrics.snapshot()
     }
 
-    /// 注册 Gossip 消息接收器（用于控制流消息）
-    pub async fn register_gossip_sender(&self, tx: TokioSender<(NodeId, Vec<u8>)>) {
-        let mut guard = self.gossip_sender.lock().await;
-        *guard = Some(tx);
+    /// 节点路由表，由连接事件和上层服务共同维护
+    pub fn routing(&self) -> SharedRoutingTable {
+        Arc::clone(&self.routing)
     }
 
-    /// 注册数据传输接收器（用于大文件/二进制数据）
-    pub async fn register_data_sender(&self, tx: TokioSender<(NodeId, Vec<u8>)>) {
-        let mut guard = self.data_sender.lock().await;
-        *guard = Some(tx);
+    /// 节点信誉和封禁记录，由上层服务报告违规
+    pub fn reputation(&self) -> SharedReputation {
+        Arc::clone(&self.reputation)
     }
 
     /// Return list of connected peer NodeIds
     pub async fn list_peers(&self) -> Vec<NodeId> {
-        let connections = self.connections.lock().await;
-        connections.keys().cloned().collect()
+        self.connections.keys()
+    }
+
+    /// 已连接且声明了全部 `capabilities` 的节点
+    pub async fn peers_with(&self, capabilities: Capabilities) -> Vec<NodeId> {
+        self.connections
+            .values()
+            .into_iter()
+            .filter(|c| c.protocol.supports(capabilities))
+            .map(|c| c.node_id.clone())
+            .collect()
+    }
+
+    /// 已连接节点在握手中声明的协议
+    pub async fn peer_protocol(&self, node_id: &NodeId) -> Option<PeerProtocol> {
+        self.connections.get(node_id).map(|c| c.protocol)
+    }
+
+    /// 以 [`NODE_SHUTDOWN_CODE`] 关闭所有连接并停止接受新连接，等待对端确认或超时后返回
+    pub async fn shutdown(&self) {
+        let peers = self.connections.values();
+        info!("Closing {} connections", peers.len());
+        for conn in peers {
+            conn.connection
+                .close(VarInt::from_u32(NODE_SHUTDOWN_CODE), b"node shutting down");
+            self.remove_connection(&conn).await;
+        }
+        for bound in self.endpoints.iter() {
+            bound
+                .endpoint
+                .close(VarInt::from_u32(NODE_SHUTDOWN_CODE), b"node shutting down");
+        }
+        let idle = futures::future::join_all(self.endpoints.iter().map(|e| e.endpoint.wait_idle()));
+        if tokio::time::timeout(SHUTDOWN_IDLE_TIMEOUT, idle)
+            .await
+            .is_err()
+        {
+            debug!("Timed out waiting for peers to acknowledge connection close");
+        }
     }
 
     /// Start background task to periodically clean up stale connections
     pub fn start_connection_cleanup(&self) {
-        let connections = Arc::clone(&self.connections);
+        let manager = self.clone();
 
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(CONNECTION_CLEANUP_INTERVAL);
             loop {
                 interval.tick().await;
-                let mut conns = connections.lock().await;
-                let mut dead_nodes = Vec::new();
-
-                for (node_id, conn) in conns.iter() {
-                    if let Some(reason) = conn.connection.close_reason() {
-                        info!(
-                            "Connection to node[{}] closed, reason: {:?}",
-                            node_id, reason
-                        );
-                        dead_nodes.push(node_id.clone());
+                let mut dead = Vec::new();
+                {
+                    let mut routing = manager.routing.lock().await;
+                    for conn in manager.connections.values() {
+                        if conn.connection.close_reason().is_some() {
+                            dead.push(conn);
+                        } else {
+                            routing.record_latency(&conn.node_id, conn.connection.rtt());
+                        }
                     }
                 }
 
-                for node_id in dead_nodes {
-                    conns.remove(&node_id);
-                    info!("Cleaned up stale connection for node: {}", node_id);
+                for conn in dead {
+                    if manager.remove_connection(&conn).await {
+                        info!("Cleaned up stale connection for node: {}", conn.node_id);
+                    }
                 }
             }
         });
     }
 
+    /// 连接到节点：先依次尝试直连地址，全部不可达时借助共同连接的节点打洞，
+    /// 仍然失败则经由已知的中继节点连接
     pub async fn connect(
         &self,
         self_node_id: NodeId,
         target_node_id: NodeId,
         addrs: Vec<SocketAddr>,
     ) -> Result<()> {
-        let endpoint = self.endpoint.clone();
+        // 已有可用的直连时不重复拨号，避免与对端之间出现多条连接
+        if self
+            .connections
+            .get(&target_node_id)
+            .is_some_and(|c| c.relay.is_none() && c.connection.close_reason().is_none())
+        {
+            debug!("Already connected to node[{}]", target_node_id);
+            return Ok(());
+        }
+        if self
+            .reputation
+            .lock()
+            .await
+            .is_banned(&target_node_id, timestamp_now())
+        {
+            return Err(anyhow!("node[{}] is banned", target_node_id));
+        }
+        info!("Trying to connect to node[{}]", target_node_id.to_string());
+        let mut quic_conn = self.dial_direct(&target_node_id, &addrs).await?;
+        if quic_conn.is_none() {
+            quic_conn = self.dial_with_holepunch(&target_node_id).await?;
+        }
+        // 目标收到打洞通知后也会拨号本节点，可能先连上
+        if quic_conn.is_none()
+            && self
+                .connections
+                .get(&target_node_id)
+                .is_some_and(|c| c.relay.is_none() && c.connection.close_reason().is_none())
+        {
+            debug!(
+                "Node[{}] connected to us while hole punching",
+                target_node_id
+            );
+            return Ok(());
+        }
+        let quic_conn = match quic_conn {
+            Some(conn) => conn,
+            None => match self.dial_via_relay(&target_node_id).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    self.routing.lock().await.record_failure(&target_node_id);
+                    return Err(e.context(format!(
+                        "Failed to connect to node[{}], no address available",
+                        target_node_id
+                    )));
+                }
+            },
+        };
+
+        info!(
+            "Node[{}] connect to[[{}] successfully: {}{}",
+            self_node_id.to_string(),
+            target_node_id.to_string(),
+            quic_conn.peer_addr,
+            quic_conn
+                .relay
+                .as_ref()
+                .map(|r| format!(" (via relay {})", r))
+                .unwrap_or_default()
+        );
+
+        self.register_connection(quic_conn).await;
+        Ok(())
+    }
+
+    /// 依次尝试直连地址，所有地址都不可达时返回 `None`，身份握手失败时返回错误
+    ///
+    /// 地址按 [`addr::dial_order`] 排序：公网地址优先，跳过本地没有对应地址族 endpoint 的地址
+    async fn dial_direct(
+        &self,
+        target_node_id: &NodeId,
+        addrs: &[SocketAddr],
+    ) -> Result<Option<QuicConnection>> {
+        let client_config = self.config.get_client_config(target_node_id)?;
         let mut connection = None;
 
-        info!("Trying to connect to node[{}]", target_node_id.to_string());
-        for addr in addrs.iter() {
-            match endpoint.connect(*addr, "localhost")?.await {
-                Ok(c) => {
+        for addr in addr::dial_order(addrs, self.families()) {
+            let endpoint = &select_endpoint(&self.endpoints, &addr).endpoint;
+            let connecting = match endpoint.connect_with(client_config.clone(), addr, "localhost") {
+                Ok(c) => c,
+                Err(e) => {
+                    debug!("Cannot dial {}: {}", addr, e);
+                    continue;
+                }
+            };
+            match tokio::time::timeout(DIRECT_DIAL_TIMEOUT, connecting).await {
+                Ok(Ok(c)) => {
                     connection = Some(c);
                     break;
                 }
-                Err(_) => continue,
+                _ => continue,
             }
         }
 
-        let connection = match connection {
-            Some(c) => c,
-            None => {
-                return Err(anyhow::anyhow!(
-                    "Failed to connect to node[{}], no address available",
-                    target_node_id
-                ))
-            }
-        };
+        let Some(connection) = connection else {
+            return Ok(None);
+        };
+        let peer_addr = addr::canonical(connection.remote_address());
+
+        // 双向身份验证：确认对端就是 target_node_id，同时向对端证明自身身份
+        let protocol = match handshake::client_handshake(
+            &connection,
+            &self.config.keypair,
+            self.config.protocol(),
+            target_node_id,
+        )
+        .await
+        {
+            Ok((protocol, observed_addr)) => {
+                self.observed
+                    .lock()
+                    .await
+                    .record(target_node_id, observed_addr);
+                protocol
+            }
+            Err(e) => {
+                connection.close(VarInt::from_u32(HANDSHAKE_FAILED_CODE), b"handshake failed");
+                self.routing.lock().await.record_failure(target_node_id);
+                return Err(e.context(format!(
+                    "Handshake with node[{}] at {} failed",
+                    target_node_id, peer_addr
+                )));
+            }
+        };
+
+        Ok(Some(QuicConnection::new(
+            connection,
+            peer_addr,
+            target_node_id.clone(),
+            ConnectionType::Client,
+            protocol,
+        )))
+    }
+
+    /// 请求一个同时与双方直连的节点协调打洞，然后直连目标
+    ///
+    /// 找不到能协调的节点或打洞后仍无法连接时返回 `None`
+    async fn dial_with_holepunch(&self, target: &NodeId) -> Result<Option<QuicConnection>> {
+        let coordinators: Vec<NodeId> = self
+            .connections
+            .values()
+            .into_iter()
+            .filter(|c| c.relay.is_none() && &c.node_id != target)
+            .filter(|c| c.protocol.supports(Capabilities::HOLEPUNCH))
+            .map(|c| c.node_id.clone())
+            .collect();
+        let coordinators = self.routing.lock().await.rank(&coordinators);
+
+        let mut own = self.external_addresses().await;
+        own.extend(self.local_addrs());
+        let own = holepunch::merge_addrs(None, &own);
+        for coordinator in coordinators {
+            let request = PunchRequest {
+                target: target.clone(),
+                addrs: own.clone(),
+            };
+            let reply = match self
+                .call_with_timeout::<HolePunchRequest>(
+                    coordinator.clone(),
+                    request,
+                    HOLEPUNCH_TIMEOUT,
+                )
+                .await
+            {
+                Ok(reply) => reply,
+                Err(e) => {
+                    debug!(
+                        "Node[{}] cannot coordinate hole punching to node[{}]: {:#}",
+                        coordinator, target, e
+                    );
+                    continue;
+                }
+            };
+
+            // 目标已经开始打洞，换一个协调节点得到的地址也是相同的，不再继续尝试
+            debug!(
+                "Hole punching to node[{}] at {:?} via node[{}]",
+                target, reply.addrs, coordinator
+            );
+            return self.dial_direct(target, &reply.addrs).await;
+        }
+        Ok(None)
+    }
+
+    /// 经由已直连的中继节点连接 `target`，按路由得分依次尝试
+    async fn dial_via_relay(&self, target: &NodeId) -> Result<QuicConnection> {
+        let candidates: Vec<NodeId> = {
+            let relays = self.relays.lock().await;
+            relays
+                .iter()
+                .filter(|r| *r != target)
+                .filter(|r| {
+                    self.connections.get(r).is_some_and(|c| {
+                        c.relay.is_none() && c.protocol.supports(Capabilities::RELAY)
+                    })
+                })
+                .cloned()
+                .collect()
+        };
+        if candidates.is_empty() {
+            return Err(anyhow!(
+                "no direct address reachable and no relay connected"
+            ));
+        }
+
+        let candidates = self.routing.lock().await.rank(&candidates);
+        let mut last_error = None;
+        for relay in candidates {
+            match self.dial_through_relay(&relay, target).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    debug!(
+                        "Relay node[{}] could not reach node[{}]: {:#}",
+                        relay, target, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no relay could reach node[{}]", target)))
+    }
+
+    /// 通过指定中继建立电路，并在电路上完成 QUIC 连接和身份握手
+    async fn dial_through_relay(&self, relay: &NodeId, target: &NodeId) -> Result<QuicConnection> {
+        let relay_conn = self
+            .connections
+            .get(relay)
+            .with_context(|| format!("relay node[{}] is not connected", relay))?;
+
+        let (mut send, mut recv) = relay_conn.connection.open_bi().await?;
+        relay::write_circuit_message(
+            &mut send,
+            &CircuitMessage::Connect {
+                target: target.clone(),
+            },
+            Encoding::for_peer(Some(&relay_conn.protocol)),
+        )
+        .await?;
+        // 中继需要再向目标转发请求，超时时间留出两段的余量
+        let reply = tokio::time::timeout(
+            CIRCUIT_SETUP_TIMEOUT * 2,
+            relay::read_circuit_message(&mut recv),
+        )
+        .await
+        .map_err(|_| anyhow!("relay node[{}] did not answer in time", relay))??;
+        match reply {
+            CircuitMessage::Accepted => {}
+            CircuitMessage::Rejected { reason } => {
+                return Err(anyhow!(
+                    "relay node[{}] rejected circuit: {}",
+                    relay,
+                    reason
+                ))
+            }
+            other => return Err(anyhow!("unexpected relay message: {:?}", other)),
+        }
+
+        let peer_addr = relay_conn.peer_addr;
+        let circuit = CircuitEndpoint::new(send, recv, peer_addr, None)?;
+        let connecting = circuit.endpoint.connect_with(
+            self.config.get_client_config(target)?,
+            peer_addr,
+            "localhost",
+        )?;
+        let connection = tokio::time::timeout(CIRCUIT_SETUP_TIMEOUT, connecting)
+            .await
+            .map_err(|_| anyhow!("relayed connection to node[{}] timed out", target))??;
+
+        let protocol = match handshake::client_handshake(
+            &connection,
+            &self.config.keypair,
+            self.config.protocol(),
+            target,
+        )
+        .await
+        {
+            Ok((protocol, _)) => protocol,
+            Err(e) => {
+                connection.close(VarInt::from_u32(HANDSHAKE_FAILED_CODE), b"handshake failed");
+                return Err(e.context(format!(
+                    "Handshake with node[{}] via relay node[{}] failed",
+                    target, relay
+                )));
+            }
+        };
+
+        circuit.close_with(&connection);
+        Ok(QuicConnection::new(
+            connection,
+            peer_addr,
+            target.clone(),
+            ConnectionType::Client,
+            protocol,
+        )
+        .with_relay(relay.clone()))
+    }
+
+    /// 保存新建立的连接并开始接收对端打开的流，返回连接是否被保留
+    ///
+    /// 与同一节点已有连接时（例如双方同时互相拨号）按 [`keep_new_connection`] 只保留一条，
+    /// 另一条以 [`DUPLICATE_CONNECTION_CODE`] 关闭，它的接收任务随连接关闭而退出
+    async fn register_connection(&self, conn: QuicConnection) -> bool {
+        if self
+            .reputation
+            .lock()
+            .await
+            .is_banned(&conn.node_id, timestamp_now())
+        {
+            info!("Refusing connection from banned node[{}]", conn.node_id);
+            conn.connection
+                .close(VarInt::from_u32(PEER_BANNED_CODE), b"banned");
+            return false;
+        }
+        let conn = Arc::new(conn);
+        let local = self.config.node_id();
+        let replaced = match self.connections.insert_or_keep(
+            conn.node_id.clone(),
+            Arc::clone(&conn),
+            |existing, new| keep_new_connection(&local, existing, new),
+        ) {
+            Ok(replaced) => replaced,
+            Err(rejected) => {
+                debug!(
+                    "Closing duplicate {:?} connection to node[{}]",
+                    rejected.direction(),
+                    rejected.node_id
+                );
+                rejected.connection.close(
+                    VarInt::from_u32(DUPLICATE_CONNECTION_CODE),
+                    b"duplicate connection",
+                );
+                return false;
+            }
+        };
+
+        self.traffic.peer(&conn.node_id);
+        self.spawn_stream_acceptor(&conn);
+        self.watch_connection(&conn);
+
+        let mut was_connected = false;
+        if let Some(old) = replaced {
+            if old.connection.close_reason().is_none() {
+                debug!(
+                    "Replacing {:?} connection to node[{}] with {:?} connection",
+                    old.direction(),
+                    old.node_id,
+                    conn.direction()
+                );
+                old.connection.close(
+                    VarInt::from_u32(DUPLICATE_CONNECTION_CODE),
+                    b"duplicate connection",
+                );
+                was_connected = true;
+            } else {
+                // 旧连接已关闭但尚未被移除，先补发断开事件
+                let _ = self.events.send(ConnectionEvent::Disconnected {
+                    node_id: old.node_id.clone(),
+                });
+            }
+        }
+
+        // 中继连接的地址是中继节点的地址，不计入对端地址
+        let addr = conn.relay.is_none().then_some(conn.peer_addr);
+        self.routing
+            .lock()
+            .await
+            .record_connected(&conn.node_id, addr, conn.connection.rtt());
+        if !was_connected {
+            let _ = self.events.send(ConnectionEvent::Connected {
+                node_id: conn.node_id.clone(),
+                direction: conn.direction(),
+                addr,
+                relay: conn.relay.clone(),
+            });
+        }
+        true
+    }
+
+    /// 连接关闭后立即从连接表中移除
+    fn watch_connection(&self, conn: &Arc<QuicConnection>) {
+        let manager = self.clone();
+        let conn = Arc::clone(conn);
+        tokio::spawn(async move {
+            let reason = conn.connection.closed().await;
+            if manager.remove_connection(&conn).await {
+                info!(
+                    "Connection to node[{}] closed, reason: {}",
+                    conn.node_id, reason
+                );
+            }
+        });
+    }
+
+    /// 以 [`PEER_BANNED_CODE`] 关闭与节点的连接
+    pub async fn disconnect(&self, node_id: &NodeId) {
+        if let Some(conn) = self.connections.get(node_id) {
+            conn.connection
+                .close(VarInt::from_u32(PEER_BANNED_CODE), b"banned");
+            self.remove_connection(&conn).await;
+        }
+    }
+
+    /// 连接表中仍是 `conn` 时移除它，记录断开并发出 Disconnected 事件
+    ///
+    /// `conn` 已被同一节点的新连接替换时不做任何事，返回 false
+    async fn remove_connection(&self, conn: &Arc<QuicConnection>) -> bool {
+        if self
+            .connections
+            .remove_if(&conn.node_id, |c| Arc::ptr_eq(c, conn))
+            .is_none()
+        {
+            return false;
+        }
+        self.routing.lock().await.record_disconnected(&conn.node_id);
+        let _ = self.events.send(ConnectionEvent::Disconnected {
+            node_id: conn.node_id.clone(),
+        });
+        true
+    }
+
+    /// 通过指定逻辑通道向节点发送一条消息
+    pub async fn send(&self, node_id: NodeId, channel: Channel, message: Vec<u8>) -> Result<()> {
+        let conn = self.connections.get(&node_id).with_context(|| {
+            format!(
+                "Failed to send message to node[{}], connection not found",
+                node_id
+            )
+        })?;
+
+        let counters = self.traffic.peer(&node_id);
+        let len = message.len();
+        match conn.send_frame(channel, message).await {
+            Ok(()) => {
+                counters.record_sent(channel, len);
+                Ok(())
+            }
+            Err(e) => {
+                counters.record_send_failure();
+                Err(e.context(format!(
+                    "Failed to send {} message to node[{}]",
+                    channel, node_id
+                )))
+            }
+        }
+    }
+
+    /// 向节点发起 RPC 调用，使用默认超时
+    ///
+    /// 对端处理失败时返回的错误可以 downcast 为 [`rpc::RemoteError`]
+    pub async fn call<M: RpcMethod>(
+        &self,
+        node_id: NodeId,
+        request: M::Request,
+    ) -> Result<M::Response> {
+        self.call_with_timeout::<M>(node_id, request, RPC_TIMEOUT)
+            .await
+    }
+
+    /// 向节点发起 RPC 调用，超过 `timeout` 未收到响应则返回错误
+    pub async fn call_with_timeout<M: RpcMethod>(
+        &self,
+        node_id: NodeId,
+        request: M::Request,
+        timeout: Duration,
+    ) -> Result<M::Response> {
+        let params = rpc::to_value(&request)?;
+        let value = self.call_value(node_id, M::NAME, params, timeout).await?;
+        rpc::decode_response::<M>(value)
+    }
+
+    /// 以 [`RpcValue`] 形式向节点发起 RPC 调用
+    pub async fn call_value(
+        &self,
+        node_id: NodeId,
+        method: &'static str,
+        params: RpcValue,
+        timeout: Duration,
+    ) -> Result<RpcValue> {
+        let conn = self.connections.get(&node_id).with_context(|| {
+            format!(
+                "Failed to call {} on node[{}], connection not found",
+                method, node_id
+            )
+        })?;
+        Self::call_connection_value(&conn, method, params, timeout).await
+    }
+
+    async fn call_connection<M: RpcMethod>(
+        conn: &QuicConnection,
+        request: M::Request,
+        timeout: Duration,
+    ) -> Result<M::Response> {
+        let params = rpc::to_value(&request)?;
+        let value = Self::call_connection_value(conn, M::NAME, params, timeout).await?;
+        rpc::decode_response::<M>(value)
+    }
+
+    async fn call_connection_value(
+        conn: &QuicConnection,
+        method: &str,
+        params: RpcValue,
+        timeout: Duration,
+    ) -> Result<RpcValue> {
+        let call = async {
+            let (send, recv) = conn.connection.open_bi().await?;
+            let encoding = Encoding::for_peer(Some(&conn.protocol));
+            rpc::invoke_value(send, recv, method, params, encoding).await
+        };
+        tokio::time::timeout(timeout, call)
+            .await
+            .map_err(|_| anyhow!("{} call to node[{}] timed out", method, conn.node_id))?
+    }
+}
+
+impl Transport for ConnectionManager {
+    fn local_id(&self) -> NodeId {
+        self.config.node_id()
+    }
+
+    fn list_peers(&self) -> BoxFuture<'_, Vec<NodeId>> {
+        Box::pin(ConnectionManager::list_peers(self))
+    }
+
+    fn peers_with(&self, capabilities: Capabilities) -> BoxFuture<'_, Vec<NodeId>> {
+        Box::pin(ConnectionManager::peers_with(self, capabilities))
+    }
 
-        let peer_addr = connection.remote_address();
-        info!(
-            "Node[{}] connect to[[{}] successfully: {}",
-            self_node_id.to_string(),
-            target_node_id.to_string(),
-            peer_addr
-        );
+    fn peer_protocol<'a>(&'a self, node_id: &'a NodeId) -> BoxFuture<'a, Option<PeerProtocol>> {
+        Box::pin(ConnectionManager::peer_protocol(self, node_id))
+    }
 
-        //Send node_id
-        let mut send = connection.open_uni().await?;
-        send.write_all(self_node_id.as_bytes()).await?;
-        send.finish()?;
+    fn connect(&self, target: NodeId, addrs: Vec<SocketAddr>) -> BoxFuture<'_, Result<()>> {
+        Box::pin(ConnectionManager::connect(
+            self,
+            self.config.node_id(),
+            target,
+            addrs,
+        ))
+    }
 
-        let quic_conn = QuicConnection {
-            connection: connection.clone(),
-            peer_addr,
-            node_id: target_node_id.clone(),
-            connection_type: ConnectionType::Client,
-        };
-        let connections = Arc::clone(&self.connections);
-        connections
-            .lock()
-            .await
-            .insert(target_node_id.clone(), Arc::from(quic_conn.clone()));
+    fn send(
+        &self,
+        node_id: NodeId,
+        channel: Channel,
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Result<()>> {
+        Box::pin(ConnectionManager::send(self, node_id, channel, message))
+    }
 
-        // 启动消息接收任务，用于接收服务端发来的消息
-        let peer_id = target_node_id.clone();
-        let connection_clone = connection.clone();
-        let gossip_sender = Arc::clone(&self.gossip_sender);
-        let data_sender = Arc::clone(&self.data_sender);
+    fn register_channel(
+        &self,
+        channel: Channel,
+        tx: TokioSender<(NodeId, Vec<u8>)>,
+    ) -> BoxFuture<'_, ()> {
+        Box::pin(ConnectionManager::register_channel(self, channel, tx))
+    }
 
-        tokio::spawn(async move {
-            while let Ok(mut recv) = connection_clone.accept_uni().await {
-                if let Ok(msg) = recv.read_to_end(READ_BUF_SIZE).await {
-                    // 基于前缀路由消息
-                    let is_data_transfer = msg.starts_with(DATA_MESSAGE_PREFIX);
-
-                    if is_data_transfer {
-                        // 移除前缀并路由到 data_sender
-                        let payload = msg[DATA_MESSAGE_PREFIX.len()..].to_vec();
-                        let maybe_data = data_sender.lock().await;
-                        if let Some(tx) = maybe_data.as_ref() {
-                            let _ = tx.send((peer_id.clone(), payload)).await;
-                            continue;
-                        }
-                    }
+    fn rpc_registry(&self) -> &RpcRegistry {
+        &self.rpc
+    }
 
-                    // 检查并移除 GOSSIP 前缀（如果存在）
-                    let payload = if msg.starts_with(GOSSIP_MESSAGE_PREFIX) {
-                        msg[GOSSIP_MESSAGE_PREFIX.len()..].to_vec()
-                    } else {
-                        msg.clone()
-                    };
+    fn call_value(
+        &self,
+        node_id: NodeId,
+        method: &'static str,
+        params: RpcValue,
+        timeout: Duration,
+    ) -> BoxFuture<'_, Result<RpcValue>> {
+        Box::pin(ConnectionManager::call_value(
+            self, node_id, method, params, timeout,
+        ))
+    }
 
-                    // 路由到 gossip_sender
-                    let maybe_gossip = gossip_sender.lock().await;
-                    if let Some(tx) = maybe_gossip.as_ref() {
-                        let _ = tx.send((peer_id.clone(), payload)).await;
-                    }
-                }
-            }
-        });
+    fn routing(&self) -> SharedRoutingTable {
+        ConnectionManager::routing(self)
+    }
 
-        Ok(())
+    fn external_addresses(&self) -> BoxFuture<'_, Vec<SocketAddr>> {
+        Box::pin(ConnectionManager::external_addresses(self))
     }
 
-    pub async fn send_message(&self, node_id: NodeId, message: Vec<u8>) -> Result<()> {
-        let connections = self.connections.lock().await;
-        let conn = connections.get(&node_id).with_context(|| {
-            format!(
-                "Failed to send message to node[{}], connection not found",
-                node_id
-            )
-        })?;
+    fn announce_addresses(&self, configured: Vec<SocketAddr>) -> BoxFuture<'_, Vec<SocketAddr>> {
+        Box::pin(async move { ConnectionManager::announce_addresses(self, &configured).await })
+    }
 
-        let mut sender = conn.connection.open_uni().await?;
-        sender.write_all(message.as_slice()).await?;
-        sender.finish()?;
-        Ok(())
+    fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        ConnectionManager::subscribe_events(self)
+    }
+
+    fn add_relay(&self, node_id: NodeId) -> BoxFuture<'_, ()> {
+        Box::pin(ConnectionManager::add_relay(self, node_id))
     }
 
-    /// 发送 Gossip 消息（会自动添加 GOSSIP: 前缀）
-    pub async fn send_gossip_message(&self, node_id: NodeId, message: Vec<u8>) -> Result<()> {
-        let mut prefixed = Vec::with_capacity(GOSSIP_MESSAGE_PREFIX.len() + message.len());
-        prefixed.extend_from_slice(GOSSIP_MESSAGE_PREFIX);
-        prefixed.extend_from_slice(&message);
-        self.send_message(node_id, prefixed).await
+    fn reputation(&self) -> SharedReputation {
+        ConnectionManager::reputation(self)
     }
 
-    /// 发送数据消息（会自动添加 DATA: 前缀，用于大文件传输）
-    pub async fn send_data_message(&self, node_id: NodeId, message: Vec<u8>) -> Result<()> {
-        let mut prefixed = Vec::with_capacity(DATA_MESSAGE_PREFIX.len() + message.len());
-        prefixed.extend_from_slice(DATA_MESSAGE_PREFIX);
-        prefixed.extend_from_slice(&message);
-        self.send_message(node_id, prefixed).await
+    fn disconnect(&self, node_id: NodeId) -> BoxFuture<'_, ()> {
+        Box::pin(async move { ConnectionManager::disconnect(self, &node_id).await })
     }
 }
 
+/// 从 TLS 会话中取出对端证书并推导其 NodeId
+fn peer_certificate_node_id(connection: &Connection) -> Result<NodeId> {
+    let identity = connection
+        .peer_identity()
+        .ok_or_else(|| anyhow!("peer did not present a certificate"))?;
+    let certs = identity
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .map_err(|_| anyhow!("unexpected peer identity type"))?;
+    let cert = certs
+        .first()
+        .ok_or_else(|| anyhow!("peer certificate chain is empty"))?;
+    node_id_from_certificate(cert)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -404,98 +1737,22 @@ mod tests {
         });
     }
 
-    fn cleanup_test_certs() {
-        let files = [
-            "cert/cert.pem",
-            "cert/key.pem",
-            "cert/cert2.pem",
-            "cert/key2.pem",
-            "cert/ca-cert.pem",
-            "cert/ca-cert-key.pem",
-            "cert/no-shared-ca-cert1.pem",
-            "cert/no-shared-ca-key1.pem",
-            "cert/no-shared-ca-cert2.pem",
-            "cert/no-shared-ca-key2.pem",
-            "cert/no-shared-ca1.pem",
-            "cert/no-shared-ca1-key.pem",
-            "cert/no-shared-ca2.pem",
-            "cert/no-shared-ca2-key.pem",
-        ];
-
-        for file in files {
-            let _ = std::fs::remove_file(file);
-        }
-    }
-
     // Mock configuration for the tests
-    fn mock_quic_config() -> QuicConfig {
+    fn mock_quic_config(keypair: &KeyPair) -> QuicConfig {
         // tracing subscriber may only be initialized once per process; ignore error if already set.
         let _ = tracing_subscriber::fmt()
             .with_max_level(tracing::Level::DEBUG)
             .with_test_writer()
             .try_init();
 
-        // Ensure certificates exist for tests - generate separate cert/key for server 1
-        let _ = crate::transport::cert::ensure_certificates(
-            "cert/cert.pem",
-            "cert/key.pem",
-            "cert/ca-cert.pem",
-        );
-
-        // Use mock configuration for the tests, ideally mock the actual methods
-        QuicConfig::new(
-            "0.0.0.0:0".parse().unwrap(),
-            "cert/cert.pem".to_string(),
-            "cert/key.pem".to_string(),
-            "cert/ca-cert.pem".to_string(),
-        )
-    }
-
-    fn mock_quic_config2() -> QuicConfig {
-        // Ensure certificates exist for tests - generate separate cert/key for server 2
-        let _ = crate::transport::cert::ensure_certificates(
-            "cert/cert2.pem",
-            "cert/key2.pem",
-            "cert/ca-cert.pem",
-        );
-
-        // Use mock configuration for the tests, ideally mock the actual methods
-        QuicConfig::new(
-            "0.0.0.0:0".parse().unwrap(),
-            "cert/cert2.pem".to_string(),
-            "cert/key2.pem".to_string(),
-            "cert/ca-cert.pem".to_string(),
-        )
-    }
-
-    fn mock_quic_config_no_shared_ca_1() -> QuicConfig {
-        let _ = crate::transport::cert::ensure_certificates(
-            "cert/no-shared-ca-cert1.pem",
-            "cert/no-shared-ca-key1.pem",
-            "cert/no-shared-ca1.pem",
-        );
-
-        QuicConfig::new(
-            "0.0.0.0:0".parse().unwrap(),
-            "cert/no-shared-ca-cert1.pem".to_string(),
-            "cert/no-shared-ca-key1.pem".to_string(),
-            "cert/no-shared-ca1.pem".to_string(),
-        )
+        QuicConfig::new("0.0.0.0:0".parse().unwrap(), keypair.clone())
     }
 
-    fn mock_quic_config_no_shared_ca_2() -> QuicConfig {
-        let _ = crate::transport::cert::ensure_certificates(
-            "cert/no-shared-ca-cert2.pem",
-            "cert/no-shared-ca-key2.pem",
-            "cert/no-shared-ca2.pem",
-        );
-
-        QuicConfig::new(
-            "0.0.0.0:0".parse().unwrap(),
-            "cert/no-shared-ca-cert2.pem".to_string(),
-            "cert/no-shared-ca-key2.pem".to_string(),
-            "cert/no-shared-ca2.pem".to_string(),
-        )
+    async fn recv(rx: &mut Receiver<(NodeId, Vec<u8>)>) -> (NodeId, Vec<u8>) {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("message not delivered")
+            .expect("channel closed")
     }
 
     // Test the `server` method
@@ -503,16 +1760,14 @@ mod tests {
     async fn test_server_creation() {
         let _guard = serial_lock().lock().await;
         init();
-        cleanup_test_certs();
-        let config = mock_quic_config();
+        let config = mock_quic_config(&KeyPair::generate().unwrap());
 
         let manager = ConnectionManager::run_server(config).await;
         assert!(manager.is_ok());
 
         tokio::time::sleep(Duration::from_millis(500)).await;
         let quic_transport = manager.unwrap();
-        assert!(quic_transport.connections.lock().await.is_empty());
-        cleanup_test_certs();
+        assert!(quic_transport.connections.is_empty());
     }
 
     // Test the `connect` method
@@ -520,18 +1775,16 @@ mod tests {
     async fn test_client_connection() {
         let _guard = serial_lock().lock().await;
         init();
-        cleanup_test_certs();
         let keypair1 = KeyPair::generate().expect("generate keypair");
         let keypair2 = KeyPair::generate().expect("generate keypair");
 
-        let config = mock_quic_config();
-        let manager = ConnectionManager::run_server(config).await;
+        let manager = ConnectionManager::run_server(mock_quic_config(&keypair1)).await;
         assert!(manager.is_ok());
         let manager = manager.unwrap();
         // give the server a moment to start and bind
         tokio::time::sleep(Duration::from_millis(200)).await;
 
-        let addr1 = manager.endpoint.local_addr().expect("get local addr");
+        let addr1 = manager.local_addrs()[0];
         let addr1 = format!("127.0.0.1:{}", addr1.port()).parse().unwrap();
         let node1 = Node::new(
             NodeId::from_keypair(&keypair1),
@@ -541,13 +1794,12 @@ mod tests {
             keypair1.clone(),
         );
 
-        let config2 = mock_quic_config2();
-        let manager2 = ConnectionManager::run_server(config2).await;
+        let manager2 = ConnectionManager::run_server(mock_quic_config(&keypair2)).await;
         assert!(manager2.is_ok());
         let manager2 = manager2.unwrap();
         tokio::time::sleep(Duration::from_millis(200)).await;
 
-        let addr2 = manager2.endpoint.local_addr().expect("get local addr");
+        let addr2 = manager2.local_addrs()[0];
         let addr2 = format!("127.0.0.1:{}", addr2.port()).parse().unwrap();
         let node2 = Node::new(
             NodeId::from_keypair(&keypair2),
@@ -567,31 +1819,28 @@ mod tests {
             .unwrap();
         tokio::time::sleep(Duration::from_millis(500)).await;
 
-        let connections1 = manager.connections.lock().await;
-        let connections2 = manager2.connections.lock().await;
+        let connections1 = &manager.connections;
+        let connections2 = &manager2.connections;
         assert!(!connections1.is_empty());
         assert!(!connections2.is_empty());
 
         assert!(connections1.contains_key(&node2.node_id().clone()));
         assert!(connections2.contains_key(&node1.node_id().clone()));
-        cleanup_test_certs();
     }
 
     #[tokio::test]
     async fn test_send_message() {
         let _guard = serial_lock().lock().await;
         init();
-        cleanup_test_certs();
         let keypair1 = KeyPair::generate().expect("generate keypair");
         let keypair2 = KeyPair::generate().expect("generate keypair");
 
-        let config = mock_quic_config();
-        let manager = ConnectionManager::run_server(config).await;
+        let manager = ConnectionManager::run_server(mock_quic_config(&keypair1)).await;
         assert!(manager.is_ok());
         let manager = manager.unwrap();
         tokio::time::sleep(Duration::from_millis(200)).await;
 
-        let addr1 = manager.endpoint.local_addr().expect("get local addr");
+        let addr1 = manager.local_addrs()[0];
         let addr1 = format!("127.0.0.1:{}", addr1.port()).parse().unwrap();
         let node1 = Node::new(
             NodeId::from_keypair(&keypair1),
@@ -601,13 +1850,12 @@ mod tests {
             keypair1.clone(),
         );
 
-        let config2 = mock_quic_config2();
-        let manager2 = ConnectionManager::run_server(config2).await;
+        let manager2 = ConnectionManager::run_server(mock_quic_config(&keypair2)).await;
         assert!(manager2.is_ok());
         let manager2 = manager2.unwrap();
         tokio::time::sleep(Duration::from_millis(200)).await;
 
-        let addr2 = manager2.endpoint.local_addr().expect("get local addr");
+        let addr2 = manager2.local_addrs()[0];
         let node2 = Node::new(
             NodeId::from_keypair(&keypair2),
             "",
@@ -627,75 +1875,488 @@ mod tests {
         tokio::time::sleep(Duration::from_millis(200)).await;
 
         {
-            let connections1 = manager.connections.lock().await;
-            let connections2 = manager2.connections.lock().await;
+            let connections1 = &manager.connections;
+            let connections2 = &manager2.connections;
             assert!(!connections1.is_empty());
             assert!(!connections2.is_empty());
         }
 
+        let (gossip_tx, mut gossip_rx) = mpsc::channel(8);
+        let (data_tx, mut data_rx) = mpsc::channel(8);
+        manager.register_channel(Channel::Gossip, gossip_tx).await;
+        manager.register_channel(Channel::Data, data_tx).await;
+
+        // 超过旧的 1 MiB 读取上限的消息也必须完整送达
+        let large = vec![0xabu8; 3 * 1024 * 1024];
         manager2
-            .send_message(node1.node_id().clone(), b"hello".to_vec())
+            .send(node1.node_id().clone(), Channel::Gossip, b"hello".to_vec())
             .await
             .unwrap();
-        tokio::time::sleep(Duration::from_millis(500)).await;
-        cleanup_test_certs();
+        manager2
+            .send(node1.node_id().clone(), Channel::Data, large.clone())
+            .await
+            .unwrap();
+        manager2
+            .send(node1.node_id().clone(), Channel::Gossip, b"world".to_vec())
+            .await
+            .unwrap();
+
+        let (from, msg) = recv(&mut gossip_rx).await;
+        assert_eq!(&from, node2.node_id());
+        assert_eq!(msg, b"hello");
+        let (_, msg) = recv(&mut gossip_rx).await;
+        assert_eq!(msg, b"world");
+        let (from, msg) = recv(&mut data_rx).await;
+        assert_eq!(&from, node2.node_id());
+        assert_eq!(msg, large);
+
+        // 不同通道的发送并发进行，大消息写入期间 gossip 消息照常送达
+        let id1 = node1.node_id().clone();
+        let (data_result, gossip_result) = tokio::join!(
+            manager2.send(id1.clone(), Channel::Data, large.clone()),
+            manager2.send(id1.clone(), Channel::Gossip, b"ping".to_vec()),
+        );
+        data_result.unwrap();
+        gossip_result.unwrap();
+        let (_, msg) = recv(&mut gossip_rx).await;
+        assert_eq!(msg, b"ping");
+        let (_, msg) = recv(&mut data_rx).await;
+        assert_eq!(msg, large);
+
+        // 两端按通道统计收发的消息数和字节数
+        let sent = &manager2.peer_stats().await[0];
+        assert!(sent.connected);
+        assert_eq!(sent.direction, Some(Direction::Outbound));
+        assert_eq!(sent.gossip.messages_sent, 3);
+        assert_eq!(sent.gossip.bytes_sent, 14);
+        assert_eq!(sent.data.bytes_sent, 2 * large.len() as u64);
+        let received = &manager.peer_stats().await[0];
+        assert_eq!(&received.node_id, node2.node_id());
+        assert_eq!(received.direction, Some(Direction::Inbound));
+        assert_eq!(received.gossip.messages_received, 3);
+        assert_eq!(received.data.messages_received, 2);
+        assert_eq!(received.data.bytes_received, 2 * large.len() as u64);
+    }
+
+    struct Greet;
+
+    impl RpcMethod for Greet {
+        const NAME: &'static str = "test.greet";
+        type Request = String;
+        type Response = String;
     }
 
     #[tokio::test]
-    async fn test_client_connection_without_shared_ca() {
+    async fn test_rpc_call() {
         let _guard = serial_lock().lock().await;
         init();
-        cleanup_test_certs();
         let keypair1 = KeyPair::generate().expect("generate keypair");
         let keypair2 = KeyPair::generate().expect("generate keypair");
+        let node_id1 = NodeId::from_keypair(&keypair1);
+        let node_id2 = NodeId::from_keypair(&keypair2);
 
-        let config1 = mock_quic_config_no_shared_ca_1();
-        let manager1 = ConnectionManager::run_server(config1).await;
-        assert!(manager1.is_ok());
-        let manager1 = manager1.unwrap();
+        let manager1 = ConnectionManager::run_server(mock_quic_config(&keypair1))
+            .await
+            .unwrap();
+        let manager2 = ConnectionManager::run_server(mock_quic_config(&keypair2))
+            .await
+            .unwrap();
         tokio::time::sleep(Duration::from_millis(200)).await;
 
-        let addr1 = manager1.endpoint.local_addr().expect("get local addr");
-        let addr1 = format!("127.0.0.1:{}", addr1.port()).parse().unwrap();
-        let node1 = Node::new(
-            NodeId::from_keypair(&keypair1),
-            "",
-            vec![addr1],
-            NodeType::Normal,
-            keypair1.clone(),
+        // 处理器能拿到已认证的调用方身份
+        manager1.register_rpc::<Greet, _, _>(|from, name| async move {
+            match name.as_str() {
+                "slow" => {
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    Ok(String::new())
+                }
+                "" => Err(anyhow!("name is empty")),
+                _ => Ok(format!("hello {} from {}", name, from)),
+            }
+        });
+
+        let addr1 = manager1.local_addrs()[0];
+        let addr1: SocketAddr = format!("127.0.0.1:{}", addr1.port()).parse().unwrap();
+        manager2
+            .connect(node_id2.clone(), node_id1.clone(), vec![addr1])
+            .await
+            .unwrap();
+
+        let response = manager2
+            .call::<Greet>(node_id1.clone(), "alice".to_string())
+            .await
+            .unwrap();
+        assert_eq!(response, format!("hello alice from {}", node_id2));
+
+        let err = manager2
+            .call::<Greet>(node_id1.clone(), String::new())
+            .await
+            .unwrap_err();
+        let remote = err
+            .downcast_ref::<rpc::RemoteError>()
+            .expect("remote error");
+        assert_eq!(remote.message, "name is empty");
+
+        let err = manager2
+            .call_with_timeout::<Greet>(
+                node_id1.clone(),
+                "slow".to_string(),
+                Duration::from_millis(200),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<rpc::RemoteError>().is_none());
+        assert!(err.to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_via_relay() {
+        let _guard = serial_lock().lock().await;
+        init();
+        let relay_kp = KeyPair::generate().expect("generate keypair");
+        let (kp_a, kp_b, kp_c) = (
+            KeyPair::generate().expect("generate keypair"),
+            KeyPair::generate().expect("generate keypair"),
+            KeyPair::generate().expect("generate keypair"),
+        );
+        let relay_id = NodeId::from_keypair(&relay_kp);
+        let (id_a, id_b, id_c) = (
+            NodeId::from_keypair(&kp_a),
+            NodeId::from_keypair(&kp_b),
+            NodeId::from_keypair(&kp_c),
         );
 
-        let config2 = mock_quic_config_no_shared_ca_2();
-        let manager2 = ConnectionManager::run_server(config2).await;
-        assert!(manager2.is_ok());
-        let manager2 = manager2.unwrap();
+        let relay_mgr = ConnectionManager::run_server(
+            mock_quic_config(&relay_kp).with_relay(relay::RelayLimits::default()),
+        )
+        .await
+        .unwrap();
+        let manager_a = ConnectionManager::run_server(mock_quic_config(&kp_a))
+            .await
+            .unwrap();
+        let manager_b = ConnectionManager::run_server(mock_quic_config(&kp_b))
+            .await
+            .unwrap();
+        let manager_c = ConnectionManager::run_server(mock_quic_config(&kp_c))
+            .await
+            .unwrap();
         tokio::time::sleep(Duration::from_millis(200)).await;
 
-        let addr2 = manager2.endpoint.local_addr().expect("get local addr");
-        let addr2 = format!("127.0.0.1:{}", addr2.port()).parse().unwrap();
-        let node2 = Node::new(
-            NodeId::from_keypair(&keypair2),
-            "",
-            vec![addr2],
-            NodeType::Normal,
-            keypair2.clone(),
+        let relay_addr = relay_mgr.local_addrs()[0];
+        let relay_addr: SocketAddr = format!("127.0.0.1:{}", relay_addr.port()).parse().unwrap();
+        for (manager, id) in [
+            (&manager_a, &id_a),
+            (&manager_b, &id_b),
+            (&manager_c, &id_c),
+        ] {
+            manager
+                .connect(id.clone(), relay_id.clone(), vec![relay_addr])
+                .await
+                .unwrap();
+            manager.add_relay(relay_id.clone()).await;
+        }
+
+        // b 预约后才可以被中继；c 没有预约
+        manager_b.reserve_relay(&relay_id).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // 回环地址上打洞总会成功，这里直接走中继路径
+        let conn = manager_a.dial_via_relay(&id_b).await.unwrap();
+        assert_eq!(conn.relay, Some(relay_id.clone()));
+        manager_a.register_connection(conn).await;
+        assert!(manager_a.dial_via_relay(&id_c).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let conn = manager_b.connections.get(&id_a);
+        assert_eq!(conn.unwrap().relay, Some(relay_id.clone()));
+
+        // 通道消息和 RPC 都可以经由中继连接
+        let (data_tx, mut data_rx) = mpsc::channel(8);
+        manager_b.register_channel(Channel::Data, data_tx).await;
+        manager_b.register_rpc::<Greet, _, _>(|from, name| async move {
+            Ok(format!("hello {} from {}", name, from))
+        });
+
+        let payload = vec![0x5au8; 256 * 1024];
+        manager_a
+            .send(id_b.clone(), Channel::Data, payload.clone())
+            .await
+            .unwrap();
+        let (from, msg) = recv(&mut data_rx).await;
+        assert_eq!(from, id_a);
+        assert_eq!(msg, payload);
+
+        let response = manager_a
+            .call::<Greet>(id_b.clone(), "bob".to_string())
+            .await
+            .unwrap();
+        assert_eq!(response, format!("hello bob from {}", id_a));
+    }
+
+    #[tokio::test]
+    async fn test_connect_via_holepunch() {
+        let _guard = serial_lock().lock().await;
+        init();
+        let (kp_a, kp_b, kp_c) = (
+            KeyPair::generate().expect("generate keypair"),
+            KeyPair::generate().expect("generate keypair"),
+            KeyPair::generate().expect("generate keypair"),
+        );
+        let (id_a, id_b, id_c) = (
+            NodeId::from_keypair(&kp_a),
+            NodeId::from_keypair(&kp_b),
+            NodeId::from_keypair(&kp_c),
         );
 
+        let manager_a = ConnectionManager::run_server(mock_quic_config(&kp_a))
+            .await
+            .unwrap();
+        let manager_b = ConnectionManager::run_server(mock_quic_config(&kp_b))
+            .await
+            .unwrap();
+        let manager_c = ConnectionManager::run_server(mock_quic_config(&kp_c))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let addr_c = manager_c.local_addrs()[0];
+        let addr_c: SocketAddr = format!("127.0.0.1:{}", addr_c.port()).parse().unwrap();
+        for (manager, id) in [(&manager_a, &id_a), (&manager_b, &id_b)] {
+            manager
+                .connect(id.clone(), id_c.clone(), vec![addr_c])
+                .await
+                .unwrap();
+        }
+
+        // 握手时 c 报告了 a 的外部地址
+        let port_a = manager_a.local_addrs()[0].port();
+        let observed: SocketAddr = format!("127.0.0.1:{}", port_a).parse().unwrap();
+        assert_eq!(manager_a.external_addresses().await, vec![observed]);
+
+        // a 不知道 b 的地址，由 c 协调后直连，b 不需要同时发起连接
+        manager_a
+            .connect(id_a.clone(), id_b.clone(), vec![])
+            .await
+            .unwrap();
+        let conn = manager_a.connections.get(&id_b);
+        assert!(conn.unwrap().relay.is_none());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(manager_b.connections.contains_key(&id_a));
+    }
+
+    #[tokio::test]
+    async fn test_connect_rejects_unexpected_server_identity() {
+        let _guard = serial_lock().lock().await;
+        init();
+        let keypair1 = KeyPair::generate().expect("generate keypair");
+        let keypair2 = KeyPair::generate().expect("generate keypair");
+        let impostor = NodeId::from_keypair(&KeyPair::generate().expect("generate keypair"));
+
+        let manager1 = ConnectionManager::run_server(mock_quic_config(&keypair1))
+            .await
+            .unwrap();
+        let manager2 = ConnectionManager::run_server(mock_quic_config(&keypair2))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let addr1 = manager1.local_addrs()[0];
+        let addr1: SocketAddr = format!("127.0.0.1:{}", addr1.port()).parse().unwrap();
+
+        // node2 以为 addr1 上运行的是 impostor，证书校验必须失败
         let result = manager2
             .connect(
-                node2.node_id().clone(),
-                node1.node_id().clone(),
-                node1.addresses().to_vec(),
+                NodeId::from_keypair(&keypair2),
+                impostor.clone(),
+                vec![addr1],
             )
             .await;
-        assert!(result.is_ok());
+        assert!(result.is_err());
 
         tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(manager1.connections.is_empty());
+        assert!(manager2.connections.is_empty());
+    }
+    #[tokio::test]
+    async fn test_per_ip_connection_limit() {
+        let _guard = serial_lock().lock().await;
+        init();
+        let keypair1 = KeyPair::generate().expect("generate keypair");
+        let keypair2 = KeyPair::generate().expect("generate keypair");
+        let keypair3 = KeyPair::generate().expect("generate keypair");
+        let id1 = NodeId::from_keypair(&keypair1);
 
-        let connections1 = manager1.connections.lock().await;
-        let connections2 = manager2.connections.lock().await;
-        assert!(connections1.contains_key(&node2.node_id().clone()));
-        assert!(connections2.contains_key(&node1.node_id().clone()));
-        cleanup_test_certs();
+        let limits = ConnectionLimits {
+            max_connections_per_ip: 1,
+            ..Default::default()
+        };
+        let manager1 =
+            ConnectionManager::run_server(mock_quic_config(&keypair1).with_limits(limits))
+                .await
+                .unwrap();
+        let manager2 = ConnectionManager::run_server(mock_quic_config(&keypair2))
+            .await
+            .unwrap();
+        let manager3 = ConnectionManager::run_server(mock_quic_config(&keypair3))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let addr1 = manager1.local_addrs()[0];
+        let addr1: SocketAddr = format!("127.0.0.1:{}", addr1.port()).parse().unwrap();
+
+        // 同一 IP 的第二个连接在握手前被拒绝
+        manager2
+            .connect(NodeId::from_keypair(&keypair2), id1.clone(), vec![addr1])
+            .await
+            .unwrap();
+        let result = manager3
+            .connect(NodeId::from_keypair(&keypair3), id1.clone(), vec![addr1])
+            .await;
+        assert!(result.is_err());
+        assert_eq!(manager1.limit_stats().connections_refused_per_ip, 1);
+        assert_eq!(manager1.slots.in_use(), 1);
+
+        // 第一个连接关闭后名额释放
+        manager2
+            .connections
+            .get(&id1)
+            .unwrap()
+            .connection
+            .close(0u32.into(), b"");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while manager1.slots.in_use() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(manager1.slots.in_use(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_endpoints() {
+        let _guard = serial_lock().lock().await;
+        init();
+        let keypair1 = KeyPair::generate().expect("generate keypair");
+        let keypair2 = KeyPair::generate().expect("generate keypair");
+        let keypair3 = KeyPair::generate().expect("generate keypair");
+        let (id1, id2, id3) = (
+            NodeId::from_keypair(&keypair1),
+            NodeId::from_keypair(&keypair2),
+            NodeId::from_keypair(&keypair3),
+        );
+
+        // node1 同时监听 IPv4 和 IPv6 环回地址，IPv6 socket 为 v6-only
+        let v4: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let v6: SocketAddr = "[::1]:0".parse().unwrap();
+        let manager1 = ConnectionManager::run_server(
+            mock_quic_config(&keypair1)
+                .with_bind_addrs(vec![v4, v6])
+                .with_local_addrs(true),
+        )
+        .await
+        .unwrap();
+        let addrs1 = manager1.local_addrs();
+        assert_eq!(addrs1.len(), 2);
+        assert!(addrs1[0].is_ipv4() && addrs1[1].is_ipv6());
+
+        // 只有 IPv6 endpoint 的 node2 跳过 IPv4 地址，只有 IPv4 endpoint 的 node3 跳过 IPv6 地址
+        let manager2 =
+            ConnectionManager::run_server(mock_quic_config(&keypair2).with_bind_addrs(vec![v6]))
+                .await
+                .unwrap();
+        let manager3 =
+            ConnectionManager::run_server(mock_quic_config(&keypair3).with_bind_addrs(vec![v4]))
+                .await
+                .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        manager2
+            .connect(id2.clone(), id1.clone(), addrs1.clone())
+            .await
+            .unwrap();
+        assert!(manager2.connections.get(&id1).unwrap().peer_addr.is_ipv6());
+        manager3
+            .connect(id3.clone(), id1.clone(), addrs1.clone())
+            .await
+            .unwrap();
+        assert!(manager3.connections.get(&id1).unwrap().peer_addr.is_ipv4());
+
+        // 允许本地地址时公告两个环回地址，未指定地址和端口 0 总是被过滤
+        let any: SocketAddr = "0.0.0.0:9000".parse().unwrap();
+        let announced = manager1
+            .announce_addresses(&[any, addrs1[0], addrs1[1]])
+            .await;
+        assert_eq!(announced, addrs1);
+        let announced = manager2.announce_addresses(&[any, v6]).await;
+        assert!(announced.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_connect_keeps_one_connection() {
+        let _guard = serial_lock().lock().await;
+        init();
+        let keypair1 = KeyPair::generate().expect("generate keypair");
+        let keypair2 = KeyPair::generate().expect("generate keypair");
+        let (id1, id2) = (
+            NodeId::from_keypair(&keypair1),
+            NodeId::from_keypair(&keypair2),
+        );
+
+        let manager1 = ConnectionManager::run_server(mock_quic_config(&keypair1))
+            .await
+            .unwrap();
+        let manager2 = ConnectionManager::run_server(mock_quic_config(&keypair2))
+            .await
+            .unwrap();
+        let mut events1 = manager1.subscribe_events();
+        let (data_tx, mut data_rx) = mpsc::channel(8);
+        manager1.register_channel(Channel::Data, data_tx).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let addr1: SocketAddr = format!("127.0.0.1:{}", manager1.local_addrs()[0].port())
+            .parse()
+            .unwrap();
+        let addr2: SocketAddr = format!("127.0.0.1:{}", manager2.local_addrs()[0].port())
+            .parse()
+            .unwrap();
+        let (r1, r2) = tokio::join!(
+            manager1.connect(id1.clone(), id2.clone(), vec![addr2]),
+            manager2.connect(id2.clone(), id1.clone(), vec![addr1]),
+        );
+        r1.unwrap();
+        r2.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // 双方保留的是同一条连接：NodeId 较小的一方发起的那一条
+        let conn1 = manager1.connections.get(&id2).expect("1 connected to 2");
+        let conn2 = manager2.connections.get(&id1).expect("2 connected to 1");
+        assert_eq!(conn1.direction(), events::preferred_direction(&id1, &id2));
+        assert_eq!(conn2.direction(), events::preferred_direction(&id2, &id1));
+        assert!(conn1.connection.close_reason().is_none());
+        assert!(conn2.connection.close_reason().is_none());
+        assert_eq!(manager1.connections.len(), 1);
+        assert_eq!(manager2.connections.len(), 1);
+
+        manager2
+            .send(id1.clone(), Channel::Data, b"after tie-break".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut data_rx).await.1, b"after tie-break".to_vec());
+
+        // 只发出一次 Connected，对端关闭后立即发出 Disconnected
+        let event = events1.recv().await.unwrap();
+        assert!(matches!(event, ConnectionEvent::Connected { ref node_id, .. } if node_id == &id2));
+        manager2.shutdown().await;
+        let event = tokio::time::timeout(Duration::from_secs(5), events1.recv())
+            .await
+            .expect("disconnect event")
+            .unwrap();
+        assert_eq!(
+            event,
+            ConnectionEvent::Disconnected {
+                node_id: id2.clone()
+            }
+        );
+        assert!(manager1.connections.is_empty());
     }
 }
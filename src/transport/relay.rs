@@ -0,0 +1,642 @@
+//! 中继电路（circuit relay）
+//!
+//! 无法直连的两个节点（例如都位于 NAT 之后）可以经由一个 `NodeType::Relay` 节点通信：
+//!
+//! 1. 目标节点 B 与中继 R 保持连接，并通过 `relay.reserve` RPC 预约；R 只为持有有效预约的节点转发；
+//! 2. 发起方 A 在与 R 的连接上打开一条双向流，发送 `Connect { target: B }`；
+//! 3. R 在与 B 的连接上打开一条双向流，发送 `Incoming { source: A }`，B 同意后 R 向 A 应答，
+//!    随后按字节拼接两条流，并施加时长、流量和速率限制；
+//! 4. A 与 B 在拼接后的流上运行一条完整的 QUIC 连接，QUIC 数据报以 `Channel::Relay` 帧承载。
+//!    TLS 和身份握手都在端到端完成，中继无法读取或篡改其中的内容。
+//!
+//! 中继连接对上层是透明的：它同样是一个 `QuicConnection`，通道消息、RPC 和 bundle 传输无需改动。
+use crate::node::node_id::NodeId;
+use crate::transport::codec::{self, Encoding};
+use crate::transport::frame::{self, Channel};
+use crate::transport::quic::ConnectionManager;
+use crate::transport::rpc::RpcMethod;
+use anyhow::{anyhow, Result};
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{
+    AsyncUdpSocket, Connection, Endpoint, EndpointConfig, ServerConfig, UdpPoller, VarInt,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
+
+/// 客户端检查并续约预约的周期
+pub const RESERVATION_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+/// 电路建立（中继转发请求并等待目标应答）的超时时间
+pub const CIRCUIT_SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// 中继电路关闭时关闭其上 QUIC 连接使用的应用错误码
+pub const RELAY_CIRCUIT_CLOSED_CODE: u32 = 0x11;
+
+/// 虚拟 socket 收发队列长度（以数据报计）
+const CIRCUIT_QUEUE_LEN: usize = 256;
+/// 拼接两条流时每次读取的缓冲区大小
+const SPLICE_BUF_SIZE: usize = 16 * 1024;
+
+/// 中继节点的资源限制
+#[derive(Debug, Clone)]
+pub struct RelayLimits {
+    /// 同时有效的预约数
+    pub max_reservations: usize,
+    /// 预约有效期，客户端需要在到期前续约
+    pub reservation_ttl: Duration,
+    /// 同时存在的电路总数
+    pub max_circuits: usize,
+    /// 单个节点（作为发起方或目标）同时参与的电路数
+    pub max_circuits_per_peer: usize,
+    /// 单条电路的最长存活时间
+    pub max_circuit_duration: Duration,
+    /// 单条电路每个方向允许转发的总字节数
+    pub max_circuit_bytes: u64,
+    /// 单条电路每个方向的转发速率上限（字节/秒）
+    pub max_circuit_rate: u64,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            reservation_ttl: Duration::from_secs(15 * 60),
+            max_circuits: 64,
+            max_circuits_per_peer: 8,
+            max_circuit_duration: Duration::from_secs(30 * 60),
+            max_circuit_bytes: 512 * 1024 * 1024,
+            max_circuit_rate: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// 向中继预约：预约有效期内，中继会为其他节点转发到本节点的电路
+pub struct RelayReserve;
+
+impl RpcMethod for RelayReserve {
+    const NAME: &'static str = "relay.reserve";
+    type Request = ();
+    type Response = Reservation;
+}
+
+/// 预约结果，告知客户端有效期以及电路限制
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Reservation {
+    pub ttl_secs: u64,
+    pub max_circuit_duration_secs: u64,
+    pub max_circuit_bytes: u64,
+}
+
+/// 电路建立阶段的消息，以 `Channel::Relay` 帧发送，编码见 [`codec`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CircuitMessage {
+    /// 发起方 -> 中继：请求建立到 `target` 的电路
+    Connect { target: NodeId },
+    /// 中继 -> 目标：`source` 请求建立电路
+    Incoming { source: NodeId },
+    /// 电路已建立，此后流上承载 QUIC 数据报
+    Accepted,
+    /// 电路被拒绝
+    Rejected { reason: String },
+}
+
+pub async fn write_circuit_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &CircuitMessage,
+    encoding: Encoding,
+) -> Result<()> {
+    frame::write_frame(writer, Channel::Relay, &codec::encode(message, encoding)?).await
+}
+
+pub async fn read_circuit_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<CircuitMessage> {
+    let frame = frame::read_frame(reader)
+        .await?
+        .ok_or_else(|| anyhow!("relay stream closed before a message was received"))?;
+    parse_circuit_message(&frame)
+}
+
+/// 解析一个 `Channel::Relay` 帧中的电路消息
+pub fn parse_circuit_message(frame: &frame::Frame) -> Result<CircuitMessage> {
+    if frame.channel != Channel::Relay {
+        return Err(anyhow!(
+            "unexpected {} frame on relay stream",
+            frame.channel
+        ));
+    }
+    codec::decode(&frame.payload)
+}
+
+#[derive(Debug, Default)]
+struct RelayState {
+    /// 预约到期时间
+    reservations: HashMap<NodeId, Instant>,
+    circuits: usize,
+    circuits_per_peer: HashMap<NodeId, usize>,
+}
+
+/// 中继节点侧的预约和电路管理
+#[derive(Debug)]
+pub struct RelayService {
+    limits: RelayLimits,
+    state: Mutex<RelayState>,
+}
+
+impl RelayService {
+    pub fn new(limits: RelayLimits) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(RelayState::default()),
+        }
+    }
+
+    pub fn limits(&self) -> &RelayLimits {
+        &self.limits
+    }
+
+    /// 处理预约或续约，超过预约数上限时拒绝新节点
+    pub fn reserve(&self, peer: &NodeId) -> Result<Reservation> {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("relay state poisoned");
+        state.reservations.retain(|_, expires| *expires > now);
+
+        if !state.reservations.contains_key(peer)
+            && state.reservations.len() >= self.limits.max_reservations
+        {
+            return Err(anyhow!("relay reservation limit reached"));
+        }
+        state
+            .reservations
+            .insert(peer.clone(), now + self.limits.reservation_ttl);
+
+        Ok(Reservation {
+            ttl_secs: self.limits.reservation_ttl.as_secs(),
+            max_circuit_duration_secs: self.limits.max_circuit_duration.as_secs(),
+            max_circuit_bytes: self.limits.max_circuit_bytes,
+        })
+    }
+
+    pub fn has_reservation(&self, peer: &NodeId) -> bool {
+        let state = self.state.lock().expect("relay state poisoned");
+        state
+            .reservations
+            .get(peer)
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+
+    /// 占用一条电路的配额，返回的 guard 释放时归还
+    pub fn open_circuit(
+        self: &Arc<Self>,
+        source: &NodeId,
+        target: &NodeId,
+    ) -> Result<CircuitGuard> {
+        if !self.has_reservation(target) {
+            return Err(anyhow!("node[{}] has no reservation on this relay", target));
+        }
+
+        let mut state = self.state.lock().expect("relay state poisoned");
+        if state.circuits >= self.limits.max_circuits {
+            return Err(anyhow!("relay circuit limit reached"));
+        }
+        for peer in [source, target] {
+            if state.circuits_per_peer.get(peer).copied().unwrap_or(0)
+                >= self.limits.max_circuits_per_peer
+            {
+                return Err(anyhow!("circuit limit reached for node[{}]", peer));
+            }
+        }
+
+        state.circuits += 1;
+        for peer in [source, target] {
+            *state.circuits_per_peer.entry(peer.clone()).or_insert(0) += 1;
+        }
+        Ok(CircuitGuard {
+            service: Arc::clone(self),
+            source: source.clone(),
+            target: target.clone(),
+        })
+    }
+
+    fn release(&self, source: &NodeId, target: &NodeId) {
+        let mut state = self.state.lock().expect("relay state poisoned");
+        state.circuits = state.circuits.saturating_sub(1);
+        for peer in [source, target] {
+            if let Some(count) = state.circuits_per_peer.get_mut(peer) {
+                *count -= 1;
+                if *count == 0 {
+                    state.circuits_per_peer.remove(peer);
+                }
+            }
+        }
+    }
+
+    /// 作为中继处理 `source` 发来的建立电路请求，`target_conn` 为到目标节点的连接（若有）及其使用的编码
+    ///
+    /// 以请求的编码 `encoding` 回复 `source`；电路建立后一直转发到任一方向结束或超出限制
+    pub async fn serve_circuit<W, R>(
+        self: &Arc<Self>,
+        source: NodeId,
+        target: NodeId,
+        target_conn: Option<(Connection, Encoding)>,
+        mut send: W,
+        recv: R,
+        encoding: Encoding,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        R: AsyncRead + Unpin,
+    {
+        let opened = match (self.open_circuit(&source, &target), target_conn) {
+            (Ok(guard), Some((conn, target_encoding))) => {
+                match tokio::time::timeout(
+                    CIRCUIT_SETUP_TIMEOUT,
+                    open_target_stream(&conn, &source, target_encoding),
+                )
+                .await
+                {
+                    Ok(Ok(streams)) => Ok((guard, streams)),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(anyhow!("node[{}] did not answer in time", target)),
+                }
+            }
+            (Ok(_), None) => Err(anyhow!("node[{}] is not connected to this relay", target)),
+            (Err(e), _) => Err(e),
+        };
+
+        let (_guard, (target_send, target_recv)) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                debug!("Rejected relay circuit {} -> {}: {}", source, target, e);
+                let reason = e.to_string();
+                write_circuit_message(&mut send, &CircuitMessage::Rejected { reason }, encoding)
+                    .await?;
+                send.shutdown().await?;
+                return Ok(());
+            }
+        };
+
+        write_circuit_message(&mut send, &CircuitMessage::Accepted, encoding).await?;
+        info!("Relaying circuit {} -> {}", source, target);
+
+        let result = splice(recv, send, target_recv, target_send, &self.limits).await;
+        info!("Relay circuit {} -> {} closed", source, target);
+        result
+    }
+}
+
+/// 在到目标节点的连接上通知有新的电路，目标同意后返回该流
+async fn open_target_stream(
+    conn: &Connection,
+    source: &NodeId,
+    encoding: Encoding,
+) -> Result<(quinn::SendStream, quinn::RecvStream)> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    write_circuit_message(
+        &mut send,
+        &CircuitMessage::Incoming {
+            source: source.clone(),
+        },
+        encoding,
+    )
+    .await?;
+    match read_circuit_message(&mut recv).await? {
+        CircuitMessage::Accepted => Ok((send, recv)),
+        CircuitMessage::Rejected { reason } => Err(anyhow!("target rejected circuit: {}", reason)),
+        other => Err(anyhow!("unexpected relay message: {:?}", other)),
+    }
+}
+
+/// 电路配额，释放时自动归还
+#[derive(Debug)]
+pub struct CircuitGuard {
+    service: Arc<RelayService>,
+    source: NodeId,
+    target: NodeId,
+}
+
+impl Drop for CircuitGuard {
+    fn drop(&mut self) {
+        self.service.release(&self.source, &self.target);
+    }
+}
+
+/// 双向拼接两条流，直到两个方向都结束、任一方向出错或超出限制
+pub async fn splice<R1, W1, R2, W2>(
+    a_recv: R1,
+    a_send: W1,
+    b_recv: R2,
+    b_send: W2,
+    limits: &RelayLimits,
+) -> Result<()>
+where
+    R1: AsyncRead + Unpin,
+    W1: AsyncWrite + Unpin,
+    R2: AsyncRead + Unpin,
+    W2: AsyncWrite + Unpin,
+{
+    let both = async {
+        tokio::try_join!(pipe(a_recv, b_send, limits), pipe(b_recv, a_send, limits))?;
+        Ok(())
+    };
+    tokio::time::timeout(limits.max_circuit_duration, both)
+        .await
+        .map_err(|_| anyhow!("relay circuit exceeded its maximum duration"))?
+}
+
+/// 单向转发，按速率上限节流
+async fn pipe<R, W>(mut reader: R, mut writer: W, limits: &RelayLimits) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let mut total: u64 = 0;
+    let mut buf = vec![0u8; SPLICE_BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+
+        total += n as u64;
+        if total > limits.max_circuit_bytes {
+            return Err(anyhow!("relay circuit exceeded its byte limit"));
+        }
+        writer.write_all(&buf[..n]).await?;
+
+        if limits.max_circuit_rate > 0 {
+            let due = Duration::from_secs_f64(total as f64 / limits.max_circuit_rate as f64);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+}
+
+/// 把 QUIC 数据报映射到电路流上的虚拟 UDP socket
+///
+/// 与 UDP 一样，队列满时直接丢弃数据报，由 QUIC 的重传和拥塞控制处理
+#[derive(Debug)]
+struct CircuitSocket {
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    incoming: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+#[derive(Debug)]
+struct AlwaysWritable;
+
+impl UdpPoller for AlwaysWritable {
+    fn poll_writable(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncUdpSocket for CircuitSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(AlwaysWritable)
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        // 电路关闭后同样丢弃，其上的连接由 `CircuitEndpoint::close_with` 关闭
+        let _ = self.outgoing.try_send(transmit.contents.to_vec());
+        Ok(())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut incoming = self.incoming.lock().expect("circuit socket poisoned");
+        match incoming.poll_recv(cx) {
+            Poll::Ready(Some(datagram)) => {
+                let len = datagram.len().min(bufs[0].len());
+                bufs[0][..len].copy_from_slice(&datagram[..len]);
+                meta[0] = RecvMeta {
+                    addr: self.peer_addr,
+                    len,
+                    stride: len,
+                    ecn: None,
+                    dst_ip: None,
+                };
+                Poll::Ready(Ok(1))
+            }
+            // 电路已关闭，不会再有数据报
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// 运行在一条中继电路上的 QUIC endpoint
+pub struct CircuitEndpoint {
+    pub endpoint: Endpoint,
+    closed: oneshot::Receiver<()>,
+}
+
+impl CircuitEndpoint {
+    /// 在已建立的电路流上创建 endpoint，`server_config` 为 `None` 时只能发起连接
+    ///
+    /// `peer_addr` 是对端在该 endpoint 上呈现的地址，通常使用中继节点的地址
+    pub fn new<W, R>(
+        mut send: W,
+        mut recv: R,
+        peer_addr: SocketAddr,
+        server_config: Option<ServerConfig>,
+    ) -> Result<Self>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(CIRCUIT_QUEUE_LEN);
+        let (in_tx, in_rx) = mpsc::channel::<Vec<u8>>(CIRCUIT_QUEUE_LEN);
+        let (closed_tx, closed) = oneshot::channel();
+
+        tokio::spawn(async move {
+            while let Some(datagram) = out_rx.recv().await {
+                if frame::write_frame(&mut send, Channel::Relay, &datagram)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            let _ = send.shutdown().await;
+        });
+
+        tokio::spawn(async move {
+            while let Ok(Some(frame)) = frame::read_frame(&mut recv).await {
+                if frame.channel == Channel::Relay {
+                    let _ = in_tx.try_send(frame.payload);
+                }
+            }
+            let _ = closed_tx.send(());
+        });
+
+        let local_ip: IpAddr = match peer_addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = CircuitSocket {
+            local_addr: SocketAddr::new(local_ip, 0),
+            peer_addr,
+            outgoing: out_tx,
+            incoming: Mutex::new(in_rx),
+        };
+        let endpoint = Endpoint::new_with_abstract_socket(
+            EndpointConfig::default(),
+            server_config,
+            Arc::new(socket),
+            Arc::new(quinn::TokioRuntime),
+        )?;
+
+        Ok(Self { endpoint, closed })
+    }
+
+    /// 电路断开时关闭其上的连接；连接关闭后释放 endpoint
+    pub fn close_with(self, connection: &Connection) {
+        let connection = connection.clone();
+        let Self { endpoint, closed } = self;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = closed => {
+                    connection.close(
+                        VarInt::from_u32(RELAY_CIRCUIT_CLOSED_CODE),
+                        b"relay circuit closed",
+                    );
+                }
+                _ = connection.closed() => {}
+            }
+            drop(endpoint);
+        });
+    }
+}
+
+/// 启动后台任务：定期向已连接的中继节点预约或续约
+pub fn start_reservation_task(manager: ConnectionManager) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(RESERVATION_RENEW_INTERVAL);
+        loop {
+            tick.tick().await;
+            for relay in manager.relays_due_for_reservation().await {
+                if let Err(e) = manager.reserve_relay(&relay).await {
+                    debug!("Failed to reserve on relay node[{}]: {}", relay, e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    fn node_id() -> NodeId {
+        NodeId::from_keypair(&KeyPair::generate().unwrap())
+    }
+
+    #[test]
+    fn test_reservation_limit_and_renewal() {
+        let relay = RelayService::new(RelayLimits {
+            max_reservations: 1,
+            ..RelayLimits::default()
+        });
+        let (a, b) = (node_id(), node_id());
+
+        let reservation = relay.reserve(&a).unwrap();
+        assert_eq!(
+            reservation.ttl_secs,
+            RelayLimits::default().reservation_ttl.as_secs()
+        );
+        assert!(relay.has_reservation(&a));
+
+        // 已有预约的节点可以续约，新节点被拒绝
+        assert!(relay.reserve(&a).is_ok());
+        assert!(relay.reserve(&b).is_err());
+        assert!(!relay.has_reservation(&b));
+    }
+
+    #[test]
+    fn test_circuit_limits() {
+        let relay = Arc::new(RelayService::new(RelayLimits {
+            max_circuits: 2,
+            max_circuits_per_peer: 1,
+            ..RelayLimits::default()
+        }));
+        let (a, b, c, d) = (node_id(), node_id(), node_id(), node_id());
+
+        // 目标必须持有预约
+        assert!(relay.open_circuit(&a, &b).is_err());
+        relay.reserve(&b).unwrap();
+        relay.reserve(&d).unwrap();
+
+        let first = relay.open_circuit(&a, &b).unwrap();
+        // b 已达到单节点上限
+        assert!(relay.open_circuit(&c, &b).is_err());
+        let _second = relay.open_circuit(&c, &d).unwrap();
+        relay.reserve(&a).unwrap();
+        // 总数达到上限
+        assert!(relay.open_circuit(&node_id(), &a).is_err());
+
+        drop(first);
+        assert!(relay.open_circuit(&node_id(), &b).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_splice_forwards_both_directions() {
+        let (mut a, a_relay) = tokio::io::duplex(1024);
+        let (b_relay, mut b) = tokio::io::duplex(1024);
+        let (a_recv, a_send) = tokio::io::split(a_relay);
+        let (b_recv, b_send) = tokio::io::split(b_relay);
+
+        let relay = tokio::spawn(async move {
+            splice(a_recv, a_send, b_recv, b_send, &RelayLimits::default()).await
+        });
+
+        a.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        b.write_all(b"pong").await.unwrap();
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        a.shutdown().await.unwrap();
+        b.shutdown().await.unwrap();
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_splice_enforces_byte_limit() {
+        let (mut a, a_relay) = tokio::io::duplex(1024);
+        let (b_relay, _b) = tokio::io::duplex(64 * 1024);
+        let (a_recv, a_send) = tokio::io::split(a_relay);
+        let (b_recv, b_send) = tokio::io::split(b_relay);
+        let limits = RelayLimits {
+            max_circuit_bytes: 100,
+            ..RelayLimits::default()
+        };
+
+        let relay =
+            tokio::spawn(async move { splice(a_recv, a_send, b_recv, b_send, &limits).await });
+        a.write_all(&[0u8; 200]).await.unwrap();
+
+        let err = relay.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("byte limit"));
+    }
+}
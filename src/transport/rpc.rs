@@ -0,0 +1,362 @@
+//! 基于 QUIC 双向流的请求/响应 RPC
+//!
+//! 每次调用打开一条新的双向流：调用方写入一个请求帧并关闭发送端，
+//! 服务方按方法名找到处理器，执行后写回一个响应帧。两者都是 `Channel::Rpc` 帧，
+//! 调用方按对端能力选择编码（见 [`codec`]），服务方以请求的编码回复。
+//! 参数和结果以 [`RpcValue`] 嵌入帧中，二进制编码时字节串仍是 CBOR 字节串。
+use crate::node::node_id::NodeId;
+use crate::transport::codec::{self, Encoding};
+use crate::transport::frame::{self, Channel};
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// 默认调用超时时间
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// RPC 方法定义：方法名以及请求、响应类型
+pub trait RpcMethod: Send + Sync + 'static {
+    const NAME: &'static str;
+    type Request: Serialize + DeserializeOwned + Send + 'static;
+    type Response: Serialize + DeserializeOwned + Send + 'static;
+}
+
+/// RPC 参数和结果的形式：CBOR 数据模型中的值
+pub type RpcValue = serde_cbor::Value;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcRequest {
+    method: String,
+    params: RpcValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RpcResponse {
+    Ok(RpcValue),
+    Err(String),
+}
+
+/// 将请求或响应转换为 [`RpcValue`]
+pub fn to_value<T: Serialize>(value: &T) -> Result<RpcValue> {
+    Ok(serde_cbor::value::to_value(value)?)
+}
+
+/// 以指定编码编码一次调用的请求帧负载
+pub fn encode_request(method: &str, params: RpcValue, encoding: Encoding) -> Result<Vec<u8>> {
+    let request = RpcRequest {
+        method: method.to_string(),
+        params,
+    };
+    codec::encode(&request, encoding)
+}
+
+/// 对端处理请求失败时返回的错误，调用方可以通过 `downcast_ref` 区分本地错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub method: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "remote call {} failed: {}", self.method, self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+type Handler = Arc<dyn Fn(NodeId, RpcValue) -> BoxFuture<'static, Result<RpcValue>> + Send + Sync>;
+
+/// RPC 处理器注册表
+#[derive(Clone, Default)]
+pub struct RpcRegistry {
+    handlers: Arc<RwLock<HashMap<&'static str, Handler>>>,
+}
+
+impl std::fmt::Debug for RpcRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let methods: Vec<&str> = self
+            .handlers
+            .read()
+            .map(|h| h.keys().copied().collect())
+            .unwrap_or_default();
+        f.debug_struct("RpcRegistry")
+            .field("methods", &methods)
+            .finish()
+    }
+}
+
+impl RpcRegistry {
+    /// 注册方法处理器，处理器的第一个参数是已认证的调用方 NodeId
+    ///
+    /// 同名方法重复注册会替换之前的处理器
+    pub fn register<M, F, Fut>(&self, handler: F)
+    where
+        M: RpcMethod,
+        F: Fn(NodeId, M::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<M::Response>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let erased: Handler = Arc::new(move |from, params| {
+            let handler = Arc::clone(&handler);
+            Box::pin(async move {
+                let request: M::Request = serde_cbor::value::from_value(params)
+                    .with_context(|| format!("invalid params for {}", M::NAME))?;
+                let response = handler(from, request).await?;
+                to_value(&response)
+            })
+        });
+
+        self.handlers
+            .write()
+            .expect("rpc registry poisoned")
+            .insert(M::NAME, erased);
+    }
+
+    fn get(&self, method: &str) -> Option<Handler> {
+        self.handlers
+            .read()
+            .expect("rpc registry poisoned")
+            .get(method)
+            .cloned()
+    }
+
+    /// 调用已注册的处理器，失败时返回发给调用方的错误信息
+    pub async fn dispatch(
+        &self,
+        from: NodeId,
+        method: &str,
+        params: RpcValue,
+    ) -> std::result::Result<RpcValue, String> {
+        match self.get(method) {
+            Some(handler) => handler(from.clone(), params).await.map_err(|e| {
+                tracing::debug!("RPC {} from {} failed: {:#}", method, from, e);
+                format!("{:#}", e)
+            }),
+            None => Err(format!("unknown method {}", method)),
+        }
+    }
+
+    /// 处理一条入站双向流上的请求
+    pub async fn serve<W, R>(&self, from: NodeId, send: W, mut recv: R) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        R: AsyncRead + Unpin,
+    {
+        let frame = frame::read_frame(&mut recv)
+            .await?
+            .ok_or_else(|| anyhow!("rpc stream closed before a message was received"))?;
+        if frame.channel != Channel::Rpc {
+            return Err(anyhow!("unexpected {} frame on rpc stream", frame.channel));
+        }
+        self.respond(from, &frame.payload, send).await
+    }
+
+    /// 处理已读出的请求帧负载，并在 `send` 上写回响应
+    ///
+    /// 用于调用方已经读取了流上第一帧、据此分派到 RPC 的场景
+    pub async fn respond<W: AsyncWrite + Unpin>(
+        &self,
+        from: NodeId,
+        payload: &[u8],
+        mut send: W,
+    ) -> Result<()> {
+        let request: RpcRequest = codec::decode(payload)?;
+        let response = match self.dispatch(from, &request.method, request.params).await {
+            Ok(value) => RpcResponse::Ok(value),
+            Err(message) => RpcResponse::Err(message),
+        };
+
+        let data = codec::encode(&response, Encoding::of(payload))?;
+        frame::write_frame(&mut send, Channel::Rpc, &data).await?;
+        send.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// 在已打开的双向流上以指定编码发起一次调用并等待响应
+pub async fn invoke<M, W, R>(
+    send: W,
+    recv: R,
+    request: &M::Request,
+    encoding: Encoding,
+) -> Result<M::Response>
+where
+    M: RpcMethod,
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let params = to_value(request)?;
+    let value = invoke_value(send, recv, M::NAME, params, encoding).await?;
+    decode_response::<M>(value)
+}
+
+/// 以 [`RpcValue`] 形式发起调用，供不知道具体方法类型的调用方使用
+pub async fn invoke_value<W, R>(
+    mut send: W,
+    mut recv: R,
+    method: &str,
+    params: RpcValue,
+    encoding: Encoding,
+) -> Result<RpcValue>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let request = encode_request(method, params, encoding)?;
+    frame::write_frame(&mut send, Channel::Rpc, &request).await?;
+    send.shutdown().await?;
+
+    match read_payload(&mut recv).await? {
+        RpcResponse::Ok(value) => Ok(value),
+        RpcResponse::Err(message) => Err(RemoteError {
+            method: method.to_string(),
+            message,
+        }
+        .into()),
+    }
+}
+
+/// 将响应值解码为方法的响应类型
+pub fn decode_response<M: RpcMethod>(value: RpcValue) -> Result<M::Response> {
+    serde_cbor::value::from_value(value)
+        .with_context(|| format!("invalid response for {}", M::NAME))
+}
+
+async fn read_payload<T: DeserializeOwned, R: AsyncRead + Unpin>(recv: &mut R) -> Result<T> {
+    let frame = frame::read_frame(recv)
+        .await?
+        .ok_or_else(|| anyhow!("rpc stream closed before a message was received"))?;
+    if frame.channel != Channel::Rpc {
+        return Err(anyhow!("unexpected {} frame on rpc stream", frame.channel));
+    }
+    codec::decode(&frame.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    struct Echo;
+
+    impl RpcMethod for Echo {
+        const NAME: &'static str = "test.echo";
+        type Request = String;
+        type Response = String;
+    }
+
+    struct Blob;
+
+    impl RpcMethod for Blob {
+        const NAME: &'static str = "test.blob";
+        type Request = serde_bytes::ByteBuf;
+        type Response = serde_bytes::ByteBuf;
+    }
+
+    struct Fail;
+
+    impl RpcMethod for Fail {
+        const NAME: &'static str = "test.fail";
+        type Request = ();
+        type Response = ();
+    }
+
+    async fn call<M: RpcMethod>(
+        registry: &RpcRegistry,
+        request: M::Request,
+    ) -> Result<M::Response> {
+        call_with::<M>(registry, request, Encoding::Binary).await
+    }
+
+    async fn call_with<M: RpcMethod>(
+        registry: &RpcRegistry,
+        request: M::Request,
+        encoding: Encoding,
+    ) -> Result<M::Response> {
+        let (client_send, server_recv) = tokio::io::duplex(4096);
+        let (server_send, client_recv) = tokio::io::duplex(4096);
+        let from = NodeId::from_keypair(&KeyPair::generate().unwrap());
+
+        let registry = registry.clone();
+        let server =
+            tokio::spawn(async move { registry.serve(from, server_send, server_recv).await });
+        let response = invoke::<M, _, _>(client_send, client_recv, &request, encoding).await;
+        server.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_call_returns_typed_response() {
+        let registry = RpcRegistry::default();
+        registry.register::<Echo, _, _>(|_, req| async move { Ok(format!("echo: {}", req)) });
+
+        let response = call::<Echo>(&registry, "hi".to_string()).await.unwrap();
+        assert_eq!(response, "echo: hi");
+
+        // 旧版本节点发出的 JSON 请求收到 JSON 响应
+        let response = call_with::<Echo>(&registry, "old".to_string(), Encoding::Json)
+            .await
+            .unwrap();
+        assert_eq!(response, "echo: old");
+    }
+
+    #[tokio::test]
+    async fn test_bytes_round_trip_in_both_encodings() {
+        let registry = RpcRegistry::default();
+        registry.register::<Blob, _, _>(|_, mut req| async move {
+            req.reverse();
+            Ok(req)
+        });
+
+        for encoding in [Encoding::Binary, Encoding::Json] {
+            let request = serde_bytes::ByteBuf::from(vec![1, 2, 3]);
+            let response = call_with::<Blob>(&registry, request, encoding)
+                .await
+                .unwrap();
+            assert_eq!(response.into_vec(), vec![3, 2, 1]);
+        }
+    }
+
+    #[test]
+    fn test_envelope_golden_vectors() {
+        let request = RpcRequest {
+            method: Echo::NAME.to_string(),
+            params: RpcValue::Text("hi".to_string()),
+        };
+        assert_eq!(
+            hex::encode(codec::encode(&request, Encoding::Binary).unwrap()),
+            "0001a2666d6574686f6469746573742e6563686f66706172616d73626869"
+        );
+        let response = RpcResponse::Err("nope".to_string());
+        assert_eq!(
+            hex::encode(codec::encode(&response, Encoding::Binary).unwrap()),
+            "0001a163457272646e6f7065"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remote_error_is_surfaced() {
+        let registry = RpcRegistry::default();
+        registry.register::<Fail, _, _>(|_, _| async move { Err(anyhow!("repo not found")) });
+
+        let err = call::<Fail>(&registry, ()).await.unwrap_err();
+        let remote = err.downcast_ref::<RemoteError>().expect("remote error");
+        assert_eq!(remote.method, Fail::NAME);
+        assert_eq!(remote.message, "repo not found");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method() {
+        let registry = RpcRegistry::default();
+
+        let err = call::<Echo>(&registry, "hi".to_string()).await.unwrap_err();
+        let remote = err.downcast_ref::<RemoteError>().expect("remote error");
+        assert!(remote.message.contains("unknown method"));
+    }
+}
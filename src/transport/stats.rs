@@ -0,0 +1,221 @@
+//! 每个节点的连接和流量统计
+//!
+//! 计数器在与节点第一次连接时创建，断线重连后继续累加，直到本节点进程退出。
+//! 运行中的节点定期把 [`PeerStats`] 快照写入数据库，供 `node peers` 和 MCP 查询；
+//! 启用 `--metrics-port` 时还会以 Prometheus 文本格式导出（见 `node::metrics`）。
+use crate::node::node::NodeRouting;
+use crate::node::node_id::NodeId;
+use crate::node::shutdown::Shutdown;
+use crate::storage::peer_stats_model;
+use crate::transport::events::Direction;
+use crate::transport::frame::Channel;
+use crate::transport::peer_map::PeerMap;
+use crate::transport::quic::{ConnectionManager, QuicConnection};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// 统计快照写入数据库的间隔
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct ChannelCounters {
+    messages_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl ChannelCounters {
+    fn snapshot(&self) -> ChannelStats {
+        ChannelStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 单个节点的流量计数器，只统计单向流上的 Gossip 和 Data 通道
+#[derive(Debug, Default)]
+pub struct PeerCounters {
+    gossip: ChannelCounters,
+    data: ChannelCounters,
+    send_failures: AtomicU64,
+}
+
+impl PeerCounters {
+    fn channel(&self, channel: Channel) -> Option<&ChannelCounters> {
+        match channel {
+            Channel::Gossip => Some(&self.gossip),
+            Channel::Data => Some(&self.data),
+            Channel::Rpc | Channel::Relay => None,
+        }
+    }
+
+    pub fn record_sent(&self, channel: Channel, len: usize) {
+        if let Some(counters) = self.channel(channel) {
+            counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_received(&self, channel: Channel, len: usize) {
+        if let Some(counters) = self.channel(channel) {
+            counters.messages_received.fetch_add(1, Ordering::Relaxed);
+            counters
+                .bytes_received
+                .fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_send_failure(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 所有节点的流量计数器
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    peers: PeerMap<Arc<PeerCounters>>,
+}
+
+impl TrafficStats {
+    /// 节点的计数器，首次使用时创建
+    pub fn peer(&self, node_id: &NodeId) -> Arc<PeerCounters> {
+        if let Some(counters) = self.peers.get(node_id) {
+            return counters;
+        }
+        let counters = Arc::new(PeerCounters::default());
+        match self
+            .peers
+            .insert_or_keep(node_id.clone(), Arc::clone(&counters), |_, _| false)
+        {
+            Ok(_) => counters,
+            // 并发创建时使用先插入的那一份
+            Err(_) => self.peers.get(node_id).unwrap_or(counters),
+        }
+    }
+
+    pub fn get(&self, node_id: &NodeId) -> Option<Arc<PeerCounters>> {
+        self.peers.get(node_id)
+    }
+
+    pub fn peers(&self) -> Vec<NodeId> {
+        self.peers.keys()
+    }
+}
+
+/// 一个通道的收发计数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+/// 单个节点的连接和流量统计快照
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub node_id: NodeId,
+    pub connected: bool,
+    /// 当前连接的方向和地址，未连接时为 `None`
+    pub direction: Option<Direction>,
+    pub addr: Option<SocketAddr>,
+    pub relay: Option<NodeId>,
+    /// quinn 估计的平滑 RTT
+    pub rtt_ms: Option<f64>,
+    /// 当前连接上判定丢失的数据包数
+    pub lost_packets: u64,
+    /// 当前连接已建立的时间
+    pub connected_secs: Option<u64>,
+    pub gossip: ChannelStats,
+    pub data: ChannelStats,
+    /// 向该节点发送消息失败的次数
+    pub send_failures: u64,
+    /// 路由表记录的失败次数（拨号失败、请求失败等）
+    pub failures: u32,
+}
+
+impl PeerStats {
+    pub fn collect(
+        node_id: NodeId,
+        counters: &PeerCounters,
+        conn: Option<&QuicConnection>,
+        routing: Option<&NodeRouting>,
+    ) -> Self {
+        let path = conn.map(|c| c.connection.stats().path);
+        Self {
+            node_id,
+            connected: conn.is_some(),
+            direction: conn.map(|c| c.direction()),
+            addr: conn.filter(|c| c.relay.is_none()).map(|c| c.peer_addr),
+            relay: conn.and_then(|c| c.relay.clone()),
+            rtt_ms: path.map(|p| p.rtt.as_secs_f64() * 1000.0),
+            lost_packets: path.map(|p| p.lost_packets).unwrap_or(0),
+            connected_secs: conn.map(|c| c.established.elapsed().as_secs()),
+            gossip: counters.gossip.snapshot(),
+            data: counters.data.snapshot(),
+            send_failures: counters.send_failures.load(Ordering::Relaxed),
+            failures: routing.map(|r| r.failures).unwrap_or(0),
+        }
+    }
+}
+
+/// 启动后台任务：定期把各节点的统计快照写入数据库，节点关闭时再写入一次后退出
+pub fn start_stats_task(manager: ConnectionManager, shutdown: Shutdown) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(STATS_SAVE_INTERVAL);
+        loop {
+            let stopping = tokio::select! {
+                _ = shutdown.cancelled() => true,
+                _ = tick.tick() => false,
+            };
+
+            let stats = manager.peer_stats().await;
+            if let Err(e) = peer_stats_model::save_peer_stats(&stats).await {
+                warn!("Failed to persist peer stats: {}", e);
+            }
+            if stopping {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::keypair::KeyPair;
+
+    #[test]
+    fn test_traffic_counters() {
+        let stats = TrafficStats::default();
+        let peer = NodeId::from_keypair(&KeyPair::generate().unwrap());
+
+        let counters = stats.peer(&peer);
+        counters.record_sent(Channel::Gossip, 10);
+        counters.record_sent(Channel::Gossip, 5);
+        counters.record_received(Channel::Data, 1000);
+        // RPC 走双向流，不计入通道统计
+        counters.record_sent(Channel::Rpc, 7);
+        stats.peer(&peer).record_send_failure();
+
+        // 断线后计数器保留，重连继续累加
+        assert!(Arc::ptr_eq(&counters, &stats.peer(&peer)));
+        let snapshot = PeerStats::collect(peer.clone(), &counters, None, None);
+        assert!(!snapshot.connected);
+        assert_eq!(snapshot.gossip.messages_sent, 2);
+        assert_eq!(snapshot.gossip.bytes_sent, 15);
+        assert_eq!(snapshot.data.messages_received, 1);
+        assert_eq!(snapshot.data.bytes_received, 1000);
+        assert_eq!(snapshot.data.bytes_sent, 0);
+        assert_eq!(snapshot.send_failures, 1);
+        assert_eq!(stats.peers(), vec![peer]);
+    }
+}
@@ -0,0 +1,177 @@
+//! 上层服务使用的传输抽象
+//!
+//! `GossipService`、`BundleService`、聊天和同步任务只通过 [`Transport`] 收发消息，
+//! 不直接依赖 QUIC。[`ConnectionManager`](crate::transport::quic::ConnectionManager) 是基于 QUIC 的实现，
+//! [`MemoryNetwork`](crate::transport::memory::MemoryNetwork) 是用于测试的进程内实现。
+use crate::node::node_id::NodeId;
+use crate::node::reputation::SharedReputation;
+use crate::node::routing::SharedRoutingTable;
+use crate::transport::codec::{self, Encoding};
+use crate::transport::events::ConnectionEvent;
+use crate::transport::frame::Channel;
+use crate::transport::protocol::{Capabilities, PeerProtocol};
+use crate::transport::rpc::{self, RpcMethod, RpcRegistry, RpcValue, RPC_TIMEOUT};
+use anyhow::Result;
+use futures::future::{join_all, BoxFuture};
+use serde::Serialize;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
+
+/// 节点间的消息传输
+///
+/// 方法返回 `BoxFuture` 以便以 `Arc<dyn Transport>` 的形式在服务之间共享；
+/// 类型化的 RPC 注册和调用见 `impl dyn Transport`
+pub trait Transport: Send + Sync + 'static {
+    /// 本节点的 NodeId
+    fn local_id(&self) -> NodeId;
+
+    /// 当前已连接的节点
+    fn list_peers(&self) -> BoxFuture<'_, Vec<NodeId>>;
+
+    /// 已连接且声明了全部 `capabilities` 的节点
+    fn peers_with(&self, capabilities: Capabilities) -> BoxFuture<'_, Vec<NodeId>>;
+
+    /// 已连接节点在握手中声明的协议
+    fn peer_protocol<'a>(&'a self, node_id: &'a NodeId) -> BoxFuture<'a, Option<PeerProtocol>>;
+
+    /// 连接到节点，`addrs` 为已知的直连地址
+    fn connect(&self, target: NodeId, addrs: Vec<SocketAddr>) -> BoxFuture<'_, Result<()>>;
+
+    /// 通过指定逻辑通道向已连接节点发送一条消息，同一通道内按发送顺序到达
+    fn send(
+        &self,
+        node_id: NodeId,
+        channel: Channel,
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Result<()>>;
+
+    /// 注册逻辑通道的消息接收端，同一通道重复注册会替换之前的接收端
+    fn register_channel(
+        &self,
+        channel: Channel,
+        tx: Sender<(NodeId, Vec<u8>)>,
+    ) -> BoxFuture<'_, ()>;
+
+    /// 本节点的 RPC 处理器注册表
+    fn rpc_registry(&self) -> &RpcRegistry;
+
+    /// 以 [`RpcValue`] 形式向节点发起 RPC 调用
+    fn call_value(
+        &self,
+        node_id: NodeId,
+        method: &'static str,
+        params: RpcValue,
+        timeout: Duration,
+    ) -> BoxFuture<'_, Result<RpcValue>>;
+
+    /// 节点路由表
+    fn routing(&self) -> SharedRoutingTable;
+
+    /// 对端观察到的本节点外部地址
+    fn external_addresses(&self) -> BoxFuture<'_, Vec<SocketAddr>>;
+
+    /// NodeAnnouncement 中公告的地址：过滤 `configured` 中无法拨号的地址，并补充观察到的外部地址
+    fn announce_addresses(&self, configured: Vec<SocketAddr>) -> BoxFuture<'_, Vec<SocketAddr>>;
+
+    /// 订阅连接生命周期事件，见 [`ConnectionEvent`]
+    fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent>;
+
+    /// 记录一个可用的中继节点
+    fn add_relay(&self, node_id: NodeId) -> BoxFuture<'_, ()>;
+
+    /// 节点信誉和封禁记录，被封禁的节点不能建立连接
+    fn reputation(&self) -> SharedReputation;
+
+    /// 断开与被封禁节点的连接
+    fn disconnect(&self, node_id: NodeId) -> BoxFuture<'_, ()>;
+}
+
+impl dyn Transport {
+    /// 注册 RPC 方法处理器
+    pub fn register_rpc<M, F, Fut>(&self, handler: F)
+    where
+        M: RpcMethod,
+        F: Fn(NodeId, M::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<M::Response>> + Send + 'static,
+    {
+        self.rpc_registry().register::<M, F, Fut>(handler);
+    }
+
+    /// 向节点发起 RPC 调用，使用默认超时
+    pub async fn call<M: RpcMethod>(
+        &self,
+        node_id: NodeId,
+        request: M::Request,
+    ) -> Result<M::Response> {
+        self.call_with_timeout::<M>(node_id, request, RPC_TIMEOUT)
+            .await
+    }
+
+    /// 向节点发起 RPC 调用，超过 `timeout` 未收到响应则返回错误
+    pub async fn call_with_timeout<M: RpcMethod>(
+        &self,
+        node_id: NodeId,
+        request: M::Request,
+        timeout: Duration,
+    ) -> Result<M::Response> {
+        let params = rpc::to_value(&request)?;
+        let value = self.call_value(node_id, M::NAME, params, timeout).await?;
+        rpc::decode_response::<M>(value)
+    }
+    /// 发给节点的消息使用的编码：声明了 `binary` 能力的节点使用二进制编码
+    pub async fn encoding_for(&self, node_id: &NodeId) -> Encoding {
+        Encoding::for_peer(self.peer_protocol(node_id).await.as_ref())
+    }
+
+    /// 按节点支持的编码序列化并发送一条消息
+    pub async fn send_message<T: Serialize>(
+        &self,
+        node_id: NodeId,
+        channel: Channel,
+        message: &T,
+    ) -> Result<()> {
+        let data = codec::encode(message, self.encoding_for(&node_id).await)?;
+        self.send(node_id, channel, data).await
+    }
+
+    /// 并行向多个节点发送同一条消息，返回各节点的发送结果
+    ///
+    /// 每种编码只序列化一次；各节点的发送互不等待，个别慢节点不会拖慢整体广播
+    pub async fn send_many<T: Serialize>(
+        &self,
+        peers: Vec<NodeId>,
+        channel: Channel,
+        message: &T,
+    ) -> Vec<(NodeId, Result<()>)> {
+        let mut encoded: Vec<(Encoding, Vec<u8>)> = Vec::new();
+        let mut targets = Vec::new();
+        let mut results = Vec::new();
+        for peer in peers {
+            let encoding = self.encoding_for(&peer).await;
+            let data = match encoded.iter().find(|(e, _)| *e == encoding) {
+                Some((_, data)) => data.clone(),
+                None => match codec::encode(message, encoding) {
+                    Ok(data) => {
+                        encoded.push((encoding, data.clone()));
+                        data
+                    }
+                    Err(e) => {
+                        results.push((peer, Err(e)));
+                        continue;
+                    }
+                },
+            };
+            targets.push((peer, data));
+        }
+
+        let sends = targets.into_iter().map(|(peer, data)| async move {
+            let result = self.send(peer.clone(), channel, data).await;
+            (peer, result)
+        });
+        results.extend(join_all(sends).await);
+        results
+    }
+}
@@ -0,0 +1,144 @@
+//! 集成测试：节点经由进程内网络交换仓库目录摘要，跨分区后目录最终一致
+//!
+//! 同一进程内的节点共用一个数据库，因此每个节点使用独立的 `MemoryCatalog`。
+use megaengine::identity::keypair::KeyPair;
+use megaengine::node::node_id::NodeId;
+use megaengine::repo::anti_entropy::{AntiEntropy, MemoryCatalog};
+use megaengine::repo::repo::{P2PDescription, Repo};
+use megaengine::transport::memory::MemoryNetwork;
+use megaengine::transport::transport::Transport;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// `creator` 以 `sequence` 签名 refs 的仓库
+fn repo(id: &str, creator: &KeyPair, sequence: u64, head: &str) -> Repo {
+    let mut repo = Repo::new(
+        id.to_string(),
+        P2PDescription {
+            creator: NodeId::from_keypair(creator).to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            language: String::new(),
+            latest_commit_at: 0,
+            size: 0,
+        },
+        PathBuf::from(format!("/tmp/{}", id)),
+    );
+    repo.add_ref("refs/heads/main".to_string(), head.to_string());
+    repo.sign_refs(creator, sequence).unwrap();
+    repo
+}
+
+fn head(catalog: &MemoryCatalog, repo_id: &str) -> Option<String> {
+    catalog
+        .get(repo_id)
+        .map(|repo| repo.refs["refs/heads/main"].clone())
+}
+
+/// 等待所有目录中 `repo_id` 的 main 指向 `commit`
+async fn wait_converged(catalogs: &[Arc<MemoryCatalog>], repo_id: &str, commit: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if catalogs
+            .iter()
+            .all(|c| head(c, repo_id).as_deref() == Some(commit))
+        {
+            return true;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_anti_entropy_converges_across_partition() {
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_test_writer()
+        .try_init();
+
+    // 1. 三个节点连成链 a <-> b <-> c，a 和 c 各有一个本地仓库
+    let network = MemoryNetwork::new();
+    let keypairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate().unwrap()).collect();
+    let ids: Vec<NodeId> = keypairs.iter().map(NodeId::from_keypair).collect();
+    let transports: Vec<Arc<dyn Transport>> = ids
+        .iter()
+        .map(|id| network.add_node(id.clone()) as Arc<dyn Transport>)
+        .collect();
+    let catalogs: Vec<Arc<MemoryCatalog>> =
+        (0..3).map(|_| Arc::new(MemoryCatalog::new())).collect();
+    catalogs[0].insert(repo("did:repo:ae-a", &keypairs[0], 100, "a1"));
+    catalogs[2].insert(repo("did:repo:ae-c", &keypairs[2], 100, "c1"));
+
+    let services: Vec<Arc<AntiEntropy>> = transports
+        .iter()
+        .zip(&catalogs)
+        .map(|(transport, catalog)| {
+            Arc::new(
+                AntiEntropy::new(Arc::clone(transport), catalog.clone())
+                    .with_interval(Duration::from_millis(100)),
+            )
+        })
+        .collect();
+    for service in &services {
+        Arc::clone(service).start();
+    }
+    transports[0].connect(ids[1].clone(), vec![]).await.unwrap();
+    transports[1].connect(ids[2].clone(), vec![]).await.unwrap();
+
+    // 2. 两个仓库经 b 传到链的另一端
+    assert!(wait_converged(&catalogs, "did:repo:ae-a", "a1").await);
+    assert!(wait_converged(&catalogs, "did:repo:ae-c", "c1").await);
+    assert!(catalogs[2].get("did:repo:ae-a").unwrap().is_external);
+    assert!(!catalogs[0].get("did:repo:ae-a").unwrap().is_external);
+    // 拉取的记录不包含对方的本地路径
+    assert_eq!(
+        catalogs[1].get("did:repo:ae-a").unwrap().path,
+        PathBuf::new()
+    );
+
+    // 3. 目录一致时只交换根摘要，不拉取任何仓库
+    assert_eq!(services[0].reconcile_with(ids[1].clone()).await.unwrap(), 0);
+
+    // 4. a 与 b 分区期间 a 的仓库更新，其余节点看不到
+    network.partition(&ids[0], &ids[1]);
+    catalogs[0].insert(repo("did:repo:ae-a", &keypairs[0], 200, "a2"));
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(head(&catalogs[2], "did:repo:ae-a").as_deref(), Some("a1"));
+
+    // 5. 分区恢复后，a 的更新经 b 传到 c
+    network.heal(&ids[0], &ids[1]);
+    transports[1].connect(ids[0].clone(), vec![]).await.unwrap();
+    assert!(wait_converged(&catalogs, "did:repo:ae-a", "a2").await);
+    assert_eq!(head(&catalogs[0], "did:repo:ae-c").as_deref(), Some("c1"));
+
+    // 6. 两个节点先前经 gossip 得到同一外部仓库的不同版本，所有者签名序号较大的版本胜出
+    let owner = KeyPair::generate().unwrap();
+    for (catalog, sequence, commit) in [(&catalogs[1], 300, "new"), (&catalogs[2], 100, "old")] {
+        let mut external = repo("did:repo:ae-ext", &owner, sequence, commit);
+        external.is_external = true;
+        catalog.insert(external);
+    }
+
+    // 7. b 持有未签名和冒用所有者签名的记录，序号再大也不会传给其他节点
+    let mut unsigned = repo("did:repo:ae-unsigned", &owner, 500, "evil");
+    unsigned.signed_refs = None;
+    let mut forged = repo("did:repo:ae-forged", &owner, 1, "evil");
+    forged.sign_refs(&keypairs[1], 500).unwrap();
+    let mut hijacked = repo("did:repo:ae-ext", &keypairs[1], 500, "evil");
+    hijacked.p2p_description.creator = NodeId::from_keypair(&owner).to_string();
+    for record in [unsigned, forged] {
+        catalogs[1].insert(record);
+    }
+    assert!(wait_converged(&catalogs, "did:repo:ae-ext", "new").await);
+
+    // 冒充所有者的新版本同样不会覆盖其他节点的记录
+    catalogs[1].insert(hijacked);
+    sleep(Duration::from_millis(500)).await;
+    for catalog in [&catalogs[0], &catalogs[2]] {
+        assert_eq!(head(catalog, "did:repo:ae-ext").as_deref(), Some("new"));
+        assert!(catalog.get("did:repo:ae-unsigned").is_none());
+        assert!(catalog.get("did:repo:ae-forged").is_none());
+    }
+}
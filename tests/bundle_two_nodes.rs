@@ -1,16 +1,17 @@
-//! 集成测试：两个节点之间通过网络传输 bundle
+//! 集成测试：两个节点之间经由进程内网络传输 bundle
 use megaengine::bundle::BundleService;
 use megaengine::git::pack::{pack_repo_bundle, restore_repo_from_bundle};
 use megaengine::gossip::GossipService;
 use megaengine::identity::keypair::KeyPair;
 use megaengine::node::node::{Node, NodeType};
-use megaengine::transport::config::QuicConfig;
+use megaengine::transport::memory::MemoryNetwork;
+use megaengine::transport::transport::Transport;
 use std::fs;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process::Command;
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 
 /// Helper function to run git commands
 fn run_git_command(cwd: &str, args: &[&str]) -> bool {
@@ -56,58 +57,13 @@ async fn test_bundle_transfer_between_two_nodes() {
     println!("🔄 Bundle Transfer Between Two Nodes Test");
     println!("========================================\n");
 
-    // Initialize rustls crypto provider
-    let _ = rustls::crypto::ring::default_provider().install_default();
-
     // Initialize logging
     let _ = tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
         .with_test_writer()
         .try_init();
 
-    let cert_dir = std::env::current_dir()
-        .unwrap()
-        .join("tmp/bundle_two_nodes_certs");
-    fs::remove_dir_all(&cert_dir).ok();
-    fs::create_dir_all(&cert_dir).expect("Failed to create test cert directory");
-
-    let sender_cert_path = cert_dir
-        .join("cert_sender.pem")
-        .to_string_lossy()
-        .to_string();
-    let sender_key_path = cert_dir
-        .join("key_sender.pem")
-        .to_string_lossy()
-        .to_string();
-    let receiver_cert_path = cert_dir
-        .join("cert_receiver.pem")
-        .to_string_lossy()
-        .to_string();
-    let receiver_key_path = cert_dir
-        .join("key_receiver.pem")
-        .to_string_lossy()
-        .to_string();
-    let ca_cert_path = cert_dir.join("ca-cert.pem").to_string_lossy().to_string();
-
-    println!("📋 Step 1: Setting up certificates");
-    // Ensure certificates exist
-    megaengine::transport::cert::ensure_certificates(
-        &sender_cert_path,
-        &sender_key_path,
-        &ca_cert_path,
-    )
-    .expect("Failed to ensure sender certificates");
-
-    megaengine::transport::cert::ensure_certificates(
-        &receiver_cert_path,
-        &receiver_key_path,
-        &ca_cert_path,
-    )
-    .expect("Failed to ensure receiver certificates");
-
-    println!("✅ Certificates ready");
-
-    println!("\n📋 Step 2: Generating node keys and identities");
+    println!("📋 Step 1: Generating node keys and identities");
     // Generate keypairs for both nodes
     let sender_kp = KeyPair::generate().expect("Failed to generate sender keypair");
     let receiver_kp = KeyPair::generate().expect("Failed to generate receiver keypair");
@@ -136,41 +92,24 @@ async fn test_bundle_transfer_between_two_nodes() {
         receiver_addr
     );
 
-    println!("\n📋 Step 3: Starting QUIC servers");
-    // Start QUIC servers
-    let sender_config = QuicConfig::new(
-        sender_addr,
-        sender_cert_path.clone(),
-        sender_key_path.clone(),
-        ca_cert_path.clone(),
-    );
-    let receiver_config = QuicConfig::new(
-        receiver_addr,
-        receiver_cert_path.clone(),
-        receiver_key_path.clone(),
-        ca_cert_path.clone(),
-    );
-
-    sender_node
-        .start_quic_server(sender_config)
-        .await
-        .expect("Failed to start sender QUIC server");
-    receiver_node
-        .start_quic_server(receiver_config)
-        .await
-        .expect("Failed to start receiver QUIC server");
+    println!("\n📋 Step 2: Joining the in-process network");
+    let network = MemoryNetwork::new();
+    let sender_transport: Arc<dyn Transport> = network.add_node(sender_node.node_id().clone());
+    let receiver_transport: Arc<dyn Transport> = network.add_node(receiver_node.node_id().clone());
+    sender_node.transport = Some(Arc::clone(&sender_transport));
+    receiver_node.transport = Some(Arc::clone(&receiver_transport));
 
-    println!("✅ QUIC servers started");
+    println!("✅ Transports ready");
 
-    println!("\n📋 Step 4: Starting Gossip and Bundle services");
+    println!("\n📋 Step 3: Starting Gossip and Bundle services");
     // Create and start gossip services
     let sender_gossip = Arc::new(GossipService::new(
-        Arc::clone(sender_node.connection_manager.as_ref().unwrap()),
+        Arc::clone(&sender_transport),
         sender_node.clone(),
         None,
     ));
     let receiver_gossip = Arc::new(GossipService::new(
-        Arc::clone(receiver_node.connection_manager.as_ref().unwrap()),
+        Arc::clone(&receiver_transport),
         receiver_node.clone(),
         None,
     ));
@@ -196,11 +135,11 @@ async fn test_bundle_transfer_between_two_nodes() {
     fs::create_dir_all(&receiver_bundle_storage).ok();
 
     let sender_bundle = Arc::new(BundleService::new(
-        Arc::clone(sender_node.connection_manager.as_ref().unwrap()),
+        Arc::clone(&sender_transport),
         sender_bundle_storage.clone(),
     ));
     let receiver_bundle = Arc::new(BundleService::new(
-        Arc::clone(receiver_node.connection_manager.as_ref().unwrap()),
+        Arc::clone(&receiver_transport),
         receiver_bundle_storage.clone(),
     ));
 
@@ -225,7 +164,7 @@ async fn test_bundle_transfer_between_two_nodes() {
         receiver_bundle_storage.display()
     );
 
-    println!("\n📋 Step 5: Creating test repository and packing bundle");
+    println!("\n📋 Step 4: Creating test repository and packing bundle");
     // Create test repository
     let repo_path = std::env::current_dir()
         .unwrap()
@@ -251,24 +190,16 @@ async fn test_bundle_transfer_between_two_nodes() {
     println!("   - Path: {}", bundle_path.display());
     println!("   - Size: {} bytes", bundle_size);
 
-    println!("\n📋 Step 6: Connecting nodes");
+    println!("\n📋 Step 5: Connecting nodes");
     // Connect sender to receiver
-    let sender_mgr = sender_node.connection_manager.as_ref().unwrap().clone();
-    sender_mgr
-        .lock()
-        .await
-        .connect(
-            sender_node.node_id().clone(),
-            receiver_node.node_id().clone(),
-            vec![receiver_addr],
-        )
+    sender_transport
+        .connect(receiver_node.node_id().clone(), vec![receiver_addr])
         .await
         .expect("Failed to connect sender to receiver");
 
     println!("✅ Nodes connected");
-    sleep(Duration::from_millis(500)).await;
 
-    println!("\n📋 Step 7: Sender transmitting bundle to receiver");
+    println!("\n📋 Step 6: Sender transmitting bundle to receiver");
     println!("   - Repo ID: test_transfer_repo");
     println!("   - Bundle path: {}", bundle_path.display());
 
@@ -284,10 +215,7 @@ async fn test_bundle_transfer_between_two_nodes() {
 
     println!("✅ Bundle transmission initiated");
 
-    // Wait for transfer to complete
-    sleep(Duration::from_secs(2)).await;
-
-    println!("\n📋 Step 8: Verifying bundle reception");
+    println!("\n📋 Step 7: Verifying bundle reception");
     // Check if bundle was received
     // The bundle is stored in the receiver's storage with encoded sender_node_id directory
     // encode_node_id 函数提取 NodeId 的最后一段并用 _ 替换 :
@@ -311,6 +239,18 @@ async fn test_bundle_transfer_between_two_nodes() {
         encoded_sender_id, repo_id_last_part
     ));
 
+    // Wait for transfer to complete
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        let received = fs::metadata(&received_bundle_path)
+            .map(|m| m.len())
+            .unwrap_or(0);
+        if received == bundle_size {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+
     if received_bundle_path.exists() {
         let received_size = fs::metadata(&received_bundle_path)
             .expect("Failed to read received bundle metadata")
@@ -329,7 +269,7 @@ async fn test_bundle_transfer_between_two_nodes() {
             );
         }
 
-        println!("\n📋 Step 9: Verifying bundle content by restoration");
+        println!("\n📋 Step 8: Verifying bundle content by restoration");
         // Verify bundle by restoring it using the provided utility function
         let restored_repo_path = std::env::current_dir()
             .unwrap()
@@ -375,7 +315,7 @@ async fn test_bundle_transfer_between_two_nodes() {
             }
         }
 
-        println!("\n📋 Step 10: Cleanup");
+        println!("\n📋 Step 9: Cleanup");
         // Cleanup
         fs::remove_dir_all(
             std::env::current_dir()
@@ -392,7 +332,6 @@ async fn test_bundle_transfer_between_two_nodes() {
         fs::remove_dir_all(&sender_bundle_storage).ok();
         fs::remove_dir_all(&receiver_bundle_storage).ok();
         fs::remove_file(&bundle_path).ok();
-        fs::remove_dir_all(&cert_dir).ok();
         println!("✅ Cleanup completed");
 
         println!("\n========================================");
@@ -429,7 +368,6 @@ async fn test_bundle_transfer_between_two_nodes() {
         fs::remove_dir_all(&sender_bundle_storage).ok();
         fs::remove_dir_all(&receiver_bundle_storage).ok();
         fs::remove_file(&bundle_path).ok();
-        fs::remove_dir_all(&cert_dir).ok();
 
         panic!("Bundle reception failed");
     }
@@ -441,7 +379,4 @@ async fn test_bundle_transfer_between_two_nodes() {
     let _ =
         megaengine::storage::node_model::delete_node_from_db(&receiver_node.node_id().to_string())
             .await;
-
-    // 清理生成的证书目录（包含 CA 文件）
-    fs::remove_dir_all(&cert_dir).ok();
 }
@@ -0,0 +1,359 @@
+//! 二进制编码的黄金向量
+//!
+//! 每种线上消息用固定的内容编码，与 `tests/vectors/codec.txt` 中记录的十六进制逐字节比较，
+//! 并确认记录的字节和同一消息的 JSON 都能解码回相同的消息。签名消息另外记录规范签名覆盖的字节和消息 ID。
+//!
+//! 编码有意变化时，用 `UPDATE_CODEC_VECTORS=1 cargo test --test codec_vectors` 重新生成，
+//! 并在评审中确认与已发布版本的兼容性。
+use megaengine::bundle::transfer::BundleMessageType;
+use megaengine::gossip::message::{
+    ChatAckMessage, EncryptedChatMessage, Envelope, GossipControl, GossipMessage, InventoryPage,
+    MembershipMessage, NodeAnnouncement, PeerEntry, RefUpdate, RepoAnnouncement, TopicMessage,
+};
+use megaengine::gossip::topic::Topic;
+use megaengine::gossip::SignedMessage;
+use megaengine::identity::keypair::KeyPair;
+use megaengine::node::node::{Node, NodeType};
+use megaengine::node::node_id::NodeId;
+use megaengine::repo::repo::{P2PDescription, Repo};
+use megaengine::transport::codec::{self, Encoding};
+use megaengine::transport::relay::CircuitMessage;
+use megaengine::transport::rpc::{self, RpcValue};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+const VECTORS_FILE: &str = "tests/vectors/codec.txt";
+const TIMESTAMP: i64 = 1_700_000_000;
+
+/// 记录的向量和本次运行得到的编码
+struct Vectors {
+    expected: BTreeMap<String, String>,
+    actual: BTreeMap<String, String>,
+}
+
+impl Vectors {
+    fn load() -> Self {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(VECTORS_FILE);
+        let text = std::fs::read_to_string(path).unwrap_or_default();
+        let expected = text
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, hex)| (name.to_string(), hex.to_string()))
+            .collect();
+        Self {
+            expected,
+            actual: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, name: &str, bytes: &[u8]) {
+        self.actual.insert(name.to_string(), hex::encode(bytes));
+    }
+
+    /// 记录消息的二进制编码，并检查记录的字节和 JSON 都能解码回该消息
+    fn message<T: Serialize + DeserializeOwned>(&mut self, name: &str, value: &T) {
+        let binary = codec::encode(value, Encoding::Binary).unwrap();
+        self.record(name, &binary);
+
+        let from_json: T = codec::decode(&codec::encode(value, Encoding::Json).unwrap()).unwrap();
+        assert_eq!(
+            codec::encode(&from_json, Encoding::Binary).unwrap(),
+            binary,
+            "{}: JSON round trip changed the message",
+            name
+        );
+        if let Some(expected) = self.expected.get(name) {
+            let decoded: T = codec::decode(&hex::decode(expected).unwrap())
+                .unwrap_or_else(|e| panic!("{}: recorded vector no longer decodes: {}", name, e));
+            assert_eq!(
+                codec::encode(&decoded, Encoding::Binary).unwrap(),
+                binary,
+                "{}: recorded vector decodes to a different message",
+                name
+            );
+        }
+    }
+
+    /// 签名消息：记录信封的编码、规范签名覆盖的字节和消息 ID
+    fn signed(&mut self, name: &str, node: &Node, message: GossipMessage, sequence: u64) {
+        let mut signed = SignedMessage {
+            node_id: node.node_id().clone(),
+            message,
+            timestamp: TIMESTAMP,
+            sequence,
+            signature: String::new(),
+            canonical_signature: None,
+        };
+        signed.sign(node).unwrap();
+        assert!(signed.verify(node.keypair()));
+
+        self.record(
+            &format!("{}.signing_bytes", name),
+            &signed.signing_bytes().unwrap(),
+        );
+        self.record(&format!("{}.id", name), &signed.self_hash());
+        let envelope = Envelope {
+            payload: signed,
+            ttl: 16,
+        };
+        self.message(&format!("{}.envelope", name), &envelope);
+    }
+
+    fn finish(self) {
+        if std::env::var_os("UPDATE_CODEC_VECTORS").is_some() {
+            let mut text = String::from(
+                "# 二进制编码的黄金向量，由 tests/codec_vectors.rs 生成：<名称> <十六进制>\n",
+            );
+            for (name, hex) in &self.actual {
+                text.push_str(&format!("{} {}\n", name, hex));
+            }
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(VECTORS_FILE);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+            return;
+        }
+
+        let mismatched: Vec<String> = self
+            .actual
+            .iter()
+            .filter(|(name, hex)| self.expected.get(*name) != Some(*hex))
+            .map(|(name, hex)| format!("{} {}", name, hex))
+            .collect();
+        assert!(
+            mismatched.is_empty(),
+            "encoding changed for:\n{}",
+            mismatched.join("\n")
+        );
+        let missing: Vec<&String> = self
+            .expected
+            .keys()
+            .filter(|name| !self.actual.contains_key(*name))
+            .collect();
+        assert!(
+            missing.is_empty(),
+            "vectors no longer produced: {:?}",
+            missing
+        );
+    }
+}
+
+/// 携带字节数据的 RPC 参数
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BlobParams {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+fn node(seed: u8, alias: &str) -> Node {
+    let keypair = KeyPair::from_signing_key_bytes([seed; 32]).unwrap();
+    Node::from_keypair(
+        &keypair,
+        alias,
+        vec!["127.0.0.1:9000".parse().unwrap()],
+        NodeType::Normal,
+    )
+}
+
+fn repo(creator: &NodeId) -> Repo {
+    let mut repo = Repo::new(
+        "did:repo:golden".to_string(),
+        P2PDescription {
+            creator: creator.to_string(),
+            name: "golden".to_string(),
+            description: "golden vectors".to_string(),
+            language: "Rust".to_string(),
+            latest_commit_at: TIMESTAMP,
+            size: 1024,
+        },
+        PathBuf::new(),
+    );
+    // 只有一个 ref：多个 ref 的 HashMap 在二进制编码中的顺序不固定，只有规范编码是确定的
+    repo.add_ref("refs/heads/main".to_string(), "c".repeat(40));
+    repo
+}
+
+#[test]
+fn test_codec_golden_vectors() {
+    let mut vectors = Vectors::load();
+    let alice = node(1, "alice");
+    let bob = node(2, "bob");
+    let alice_id = alice.node_id().clone();
+    let bob_id = bob.node_id().clone();
+
+    // 签名的 gossip 消息
+    vectors.signed(
+        "gossip.node_announcement",
+        &alice,
+        GossipMessage::NodeAnnouncement(NodeAnnouncement::from(alice.clone())),
+        1,
+    );
+    vectors.signed(
+        "gossip.repo_announcement",
+        &alice,
+        GossipMessage::RepoAnnouncement(RepoAnnouncement {
+            node_id: alice_id.clone(),
+            repos: vec![repo(&alice_id)],
+            page: Some(InventoryPage { index: 0, count: 2 }),
+        }),
+        2,
+    );
+    vectors.signed(
+        "gossip.ref_update",
+        &alice,
+        GossipMessage::RefUpdate(RefUpdate {
+            node_id: alice_id.clone(),
+            repo_id: "did:repo:golden".to_string(),
+            ref_name: "refs/heads/main".to_string(),
+            old_oid: Some("a".repeat(40)),
+            new_oid: None,
+            sequence: 3,
+        }),
+        3,
+    );
+    vectors.signed(
+        "gossip.chat",
+        &alice,
+        GossipMessage::Chat(EncryptedChatMessage {
+            sender_id: alice_id.clone(),
+            receiver_id: bob_id.clone(),
+            msg_id: "msg-1".to_string(),
+            ciphertext: (0..=255).collect(),
+        }),
+        0,
+    );
+    vectors.signed(
+        "gossip.chat_ack",
+        &bob,
+        GossipMessage::ChatAck(ChatAckMessage {
+            sender_id: bob_id.clone(),
+            target_id: alice_id.clone(),
+            msg_id: "msg-1".to_string(),
+            timestamp: TIMESTAMP,
+            signature: "00".repeat(64),
+        }),
+        0,
+    );
+
+    // gossip 控制消息
+    let id = "ab".repeat(32);
+    vectors.message("plumtree.ihave", &GossipControl::IHave(vec![id.clone()]));
+    vectors.message("plumtree.graft", &GossipControl::Graft(vec![id]));
+    vectors.message("plumtree.prune", &GossipControl::Prune);
+
+    let peer = PeerEntry {
+        node_id: bob_id.clone(),
+        addresses: vec!["[::1]:9001".parse().unwrap()],
+    };
+    let addresses = vec!["10.0.0.1:9000".parse().unwrap()];
+    vectors.message(
+        "membership.join",
+        &MembershipMessage::Join {
+            addresses: addresses.clone(),
+        },
+    );
+    vectors.message(
+        "membership.forward_join",
+        &MembershipMessage::ForwardJoin {
+            node: peer.clone(),
+            ttl: 6,
+        },
+    );
+    vectors.message(
+        "membership.neighbor",
+        &MembershipMessage::Neighbor {
+            high_priority: true,
+            addresses,
+        },
+    );
+    vectors.message(
+        "membership.neighbor_reply",
+        &MembershipMessage::NeighborReply { accepted: false },
+    );
+    vectors.message("membership.disconnect", &MembershipMessage::Disconnect);
+    vectors.message(
+        "membership.shuffle",
+        &MembershipMessage::Shuffle {
+            origin: peer.clone(),
+            nodes: vec![peer.clone()],
+            ttl: 4,
+        },
+    );
+    vectors.message(
+        "membership.shuffle_reply",
+        &MembershipMessage::ShuffleReply { nodes: vec![peer] },
+    );
+
+    vectors.message(
+        "topics.subscriptions",
+        &TopicMessage::Subscriptions(vec![
+            Topic::Repo("did:repo:golden".to_string()),
+            Topic::Creator(alice_id.clone()),
+            Topic::NewRepos,
+        ]),
+    );
+
+    // bundle 传输
+    vectors.message(
+        "bundle.start",
+        &BundleMessageType::Start {
+            repo_id: "did:repo:golden".to_string(),
+            file_name: "golden.bundle".to_string(),
+            total_size: 256,
+        },
+    );
+    vectors.message(
+        "bundle.chunk",
+        &BundleMessageType::Chunk {
+            repo_id: "did:repo:golden".to_string(),
+            chunk_idx: 0,
+            data: (0..=255).collect(),
+        },
+    );
+    vectors.message(
+        "bundle.done",
+        &BundleMessageType::Done {
+            repo_id: "did:repo:golden".to_string(),
+        },
+    );
+
+    // 中继电路
+    vectors.message(
+        "relay.connect",
+        &CircuitMessage::Connect {
+            target: bob_id.clone(),
+        },
+    );
+    vectors.message(
+        "relay.incoming",
+        &CircuitMessage::Incoming { source: alice_id },
+    );
+    vectors.message("relay.accepted", &CircuitMessage::Accepted);
+    vectors.message(
+        "relay.rejected",
+        &CircuitMessage::Rejected {
+            reason: "no reservation".to_string(),
+        },
+    );
+
+    // RPC 请求中的字节数据是 CBOR 字节串（主类型 2），不是整数数组
+    let blob = BlobParams {
+        data: vec![0, 1, 2, 255],
+    };
+    let request =
+        rpc::encode_request("test.blob", rpc::to_value(&blob).unwrap(), Encoding::Binary).unwrap();
+    vectors.record("rpc.request_bytes", &request);
+    let decoded: BTreeMap<String, RpcValue> = codec::decode(&request).unwrap();
+    assert_eq!(decoded["params"], rpc::to_value(&blob).unwrap());
+    let RpcValue::Map(params) = &decoded["params"] else {
+        panic!("params is not a map: {:?}", decoded["params"]);
+    };
+    assert_eq!(
+        params[&RpcValue::Text("data".to_string())],
+        RpcValue::Bytes(blob.data.clone())
+    );
+    assert!(hex::encode(&request).contains("646461746144000102ff"));
+
+    vectors.finish();
+}
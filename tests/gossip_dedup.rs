@@ -0,0 +1,187 @@
+//! 集成测试：gossip 服务只记录通过校验的消息；开启持久化时，重启后仍把重启前收到的消息识别为重复消息
+//!
+//! 发送方不运行 gossip 服务，直接在 Gossip 通道上发送签名的公告。
+use megaengine::gossip::dedup::{DedupConfig, DedupStats};
+use megaengine::gossip::{GossipService, SignedMessage};
+use megaengine::identity::keypair::KeyPair;
+use megaengine::node::node::{Node, NodeType};
+use megaengine::node::shutdown::Shutdown;
+use megaengine::storage::{node_model, seen_model};
+use megaengine::transport::frame::Channel;
+use megaengine::transport::memory::MemoryNetwork;
+use megaengine::transport::transport::Transport;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+fn announcement(node: &Node, sequence: u64) -> SignedMessage {
+    SignedMessage::new_node_sign_message(node.clone(), sequence).unwrap()
+}
+
+fn envelope(signed: &SignedMessage) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap()
+}
+
+fn start_service(
+    transport: &Arc<dyn Transport>,
+    node: &Node,
+    shutdown: &Shutdown,
+) -> Arc<GossipService> {
+    Arc::new(
+        GossipService::new(Arc::clone(transport), node.clone(), None)
+            .with_dedup(DedupConfig::default().with_persistence(true))
+            .with_shutdown(shutdown.clone()),
+    )
+}
+
+/// 等待去重记录的命中次数和未命中次数达到 `hits` 和 `misses`
+async fn wait_dedup(service: &GossipService, hits: u64, misses: u64) -> DedupStats {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = service.dedup_stats().await;
+        if (stats.hits, stats.misses) == (hits, misses) || Instant::now() > deadline {
+            return stats;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_forged_copy_does_not_suppress_original() {
+    let network = MemoryNetwork::new();
+    let sender = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "sender",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let ts: Arc<dyn Transport> = network.add_node(sender.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let shutdown = Shutdown::new();
+    let service = Arc::new(
+        GossipService::new(Arc::clone(&tr), receiver.clone(), None).with_shutdown(shutdown.clone()),
+    );
+    Arc::clone(&service).start().await.unwrap();
+    ts.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+
+    // 伪造的副本与原消息的 ID 相同，只有签名无效
+    let original = announcement(&sender, 1);
+    let mut forged = original.clone();
+    forged.signature = hex::encode([0u8; 64]);
+    forged.canonical_signature = Some(hex::encode([0u8; 64]));
+    assert_eq!(forged.self_hash(), original.self_hash());
+
+    for signed in [&forged, &original] {
+        ts.send(
+            receiver.node_id().clone(),
+            Channel::Gossip,
+            envelope(signed),
+        )
+        .await
+        .unwrap();
+    }
+    let stats = wait_dedup(&service, 0, 1).await;
+    assert_eq!((stats.hits, stats.misses), (0, 1));
+    assert_eq!(service.stats().delivered, 1);
+    assert!(service.dedup().lock().await.contains(&original.self_hash()));
+
+    shutdown.trigger();
+    for node in [&sender, &receiver] {
+        let _ = node_model::delete_node_from_db(&node.node_id().to_string()).await;
+    }
+}
+
+#[tokio::test]
+async fn test_seen_messages_survive_restart() {
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_test_writer()
+        .try_init();
+
+    // 1. sender 直接发送公告，receiver 运行开启持久化的 gossip 服务
+    let network = MemoryNetwork::new();
+    let sender = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "sender",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let ts: Arc<dyn Transport> = network.add_node(sender.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let shutdown = Shutdown::new();
+    let service = start_service(&tr, &receiver, &shutdown);
+    Arc::clone(&service).start().await.unwrap();
+    ts.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+
+    let send = |signed: &SignedMessage| {
+        let ts = Arc::clone(&ts);
+        let to = receiver.node_id().clone();
+        let data = envelope(signed);
+        async move { ts.send(to, Channel::Gossip, data).await.unwrap() }
+    };
+
+    // 2. 首次收到的公告被处理，重复的公告命中去重记录
+    let first = announcement(&sender, 1);
+    send(&first).await;
+    send(&first).await;
+    let stats = wait_dedup(&service, 1, 1).await;
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!(stats.hit_rate(), 0.5);
+    assert_eq!(service.stats().delivered, 1);
+
+    // 3. 关闭时去重记录写入数据库
+    shutdown.trigger();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while seen_model::load_seen(receiver.node_id(), 0)
+        .await
+        .unwrap()
+        .is_empty()
+    {
+        assert!(Instant::now() < deadline, "seen messages were not saved");
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    // 4. 重启后重放的公告按重复消息处理，不再被处理或转发，也不计为违规
+    let shutdown = Shutdown::new();
+    let restarted = start_service(&tr, &receiver, &shutdown);
+    Arc::clone(&restarted).start().await.unwrap();
+    assert!(restarted.dedup().lock().await.contains(&first.self_hash()));
+    send(&first).await;
+    assert_eq!(wait_dedup(&restarted, 1, 0).await.hits, 1);
+    let stats = restarted.stats();
+    assert_eq!(
+        (stats.delivered, stats.duplicates, stats.rejected),
+        (0, 1, 0)
+    );
+
+    // 5. 新公告照常处理
+    send(&announcement(&sender, 2)).await;
+    assert_eq!(wait_dedup(&restarted, 1, 1).await.misses, 1);
+
+    shutdown.trigger();
+    seen_model::delete_seen_before(receiver.node_id(), i64::MAX)
+        .await
+        .unwrap();
+    for node in [&sender, &receiver] {
+        let _ = node_model::delete_node_from_db(&node.node_id().to_string()).await;
+    }
+}
@@ -0,0 +1,144 @@
+//! 集成测试：gossip 服务拒绝重放、过期和来自未来的签名消息
+//!
+//! 发送方不运行 gossip 服务，直接在 Gossip 通道上发送构造的消息；
+//! 接收方重启后仍拒绝重放此前接受过的公告。
+use megaengine::gossip::replay::FreshnessConfig;
+use megaengine::gossip::{GossipService, GossipStats, SignedMessage};
+use megaengine::identity::keypair::KeyPair;
+use megaengine::node::node::{Node, NodeType};
+use megaengine::node::node_id::NodeId;
+use megaengine::node::shutdown::Shutdown;
+use megaengine::storage::node_model;
+use megaengine::transport::frame::Channel;
+use megaengine::transport::memory::MemoryNetwork;
+use megaengine::transport::transport::Transport;
+use megaengine::util::timestamp_now;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// 以指定序号和时间戳签名的节点公告，封装为 Gossip 通道上的消息
+fn announcement(node: &Node, addr: SocketAddr, sequence: u64, timestamp: i64) -> Vec<u8> {
+    let mut node = node.clone();
+    node.info.addresses = vec![addr];
+    let mut signed = SignedMessage::new_node_sign_message(node.clone(), sequence).unwrap();
+    signed.timestamp = timestamp;
+    signed.sign(&node).unwrap();
+    serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap()
+}
+
+/// 等待服务的统计达到 `delivered` 和 `rejected`
+async fn wait_stats(service: &GossipService, delivered: u64, rejected: u64) -> GossipStats {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = service.stats();
+        if (stats.delivered, stats.rejected) == (delivered, rejected) || Instant::now() > deadline {
+            return stats;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// 等待 `transport` 的路由表中 `node_id` 的地址变为 `expected`，超时返回 false
+async fn wait_addresses(
+    transport: &Arc<dyn Transport>,
+    node_id: &NodeId,
+    expected: &[SocketAddr],
+) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        let route = transport.routing().lock().await.get(node_id).cloned();
+        if route.is_some_and(|route| route.addresses == expected) {
+            return true;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+fn start_service(
+    transport: &Arc<dyn Transport>,
+    node: &Node,
+    shutdown: &Shutdown,
+) -> Arc<GossipService> {
+    Arc::new(
+        GossipService::new(Arc::clone(transport), node.clone(), None)
+            .with_freshness(FreshnessConfig::default().with_max_clock_skew(Duration::from_secs(5)))
+            .with_shutdown(shutdown.clone()),
+    )
+}
+
+#[tokio::test]
+async fn test_gossip_rejects_replayed_and_stale_messages() {
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_test_writer()
+        .try_init();
+
+    // 1. sender 直接发送构造的消息，receiver 运行 gossip 服务
+    let network = MemoryNetwork::new();
+    let sender = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "sender",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let ts: Arc<dyn Transport> = network.add_node(sender.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let shutdown = Shutdown::new();
+    let service = start_service(&tr, &receiver, &shutdown);
+    Arc::clone(&service).start().await.unwrap();
+    ts.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+
+    let send = |data: Vec<u8>| {
+        let ts = Arc::clone(&ts);
+        let to = receiver.node_id().clone();
+        async move { ts.send(to, Channel::Gossip, data).await.unwrap() }
+    };
+    let addr = |port: u16| -> SocketAddr { format!("127.0.0.1:{}", port).parse().unwrap() };
+    let now = timestamp_now();
+
+    // 2. 首个公告被接受
+    let first = announcement(&sender, addr(19301), 10, now);
+    send(first.clone()).await;
+    assert_eq!(wait_stats(&service, 1, 0).await.delivered, 1);
+    assert!(wait_addresses(&tr, sender.node_id(), &[addr(19301)]).await);
+
+    // 3. 序号更小的公告即使时间戳更新也被拒绝，不会回滚地址
+    send(announcement(&sender, addr(19302), 9, now + 1)).await;
+    assert_eq!(wait_stats(&service, 1, 1).await.rejected, 1);
+
+    // 4. 过期消息和超过时钟偏差的消息被拒绝
+    send(announcement(&sender, addr(19303), 11, now - 400)).await;
+    send(announcement(&sender, addr(19304), 12, now + 30)).await;
+    assert_eq!(wait_stats(&service, 1, 3).await.rejected, 3);
+
+    // 5. 序号更大的公告更新地址
+    send(announcement(&sender, addr(19305), 13, now)).await;
+    assert_eq!(wait_stats(&service, 2, 3).await.delivered, 2);
+    assert!(wait_addresses(&tr, sender.node_id(), &[addr(19305)]).await);
+
+    // 6. 重启后去重记录清空，重放首个公告仍因持久化的序号被拒绝
+    shutdown.trigger();
+    let shutdown = Shutdown::new();
+    let restarted = start_service(&tr, &receiver, &shutdown);
+    Arc::clone(&restarted).start().await.unwrap();
+    send(first).await;
+    assert_eq!(wait_stats(&restarted, 0, 1).await.rejected, 1);
+    assert!(wait_addresses(&tr, sender.node_id(), &[addr(19305)]).await);
+
+    shutdown.trigger();
+    for node in [&sender, &receiver] {
+        let _ = node_model::delete_node_from_db(&node.node_id().to_string()).await;
+    }
+}
@@ -0,0 +1,168 @@
+//! 集成测试：仓库消息只转发给订阅了其主题的邻居
+//!
+//! 所有者直连三个节点：关注其仓库的节点、没有订阅任何主题的节点，以及不支持主题订阅的旧版本节点。
+//! 旧版本节点不运行 gossip 服务，只统计 Gossip 通道上收到的 RefUpdate。
+use megaengine::gossip::topic::Topic;
+use megaengine::gossip::GossipService;
+use megaengine::identity::keypair::KeyPair;
+use megaengine::node::node::{Node, NodeType};
+use megaengine::node::node_id::NodeId;
+use megaengine::node::shutdown::Shutdown;
+use megaengine::repo::repo_sync::RefChange;
+use megaengine::storage::node_model;
+use megaengine::transport::frame::Channel;
+use megaengine::transport::memory::MemoryNetwork;
+use megaengine::transport::protocol::Capabilities;
+use megaengine::transport::transport::Transport;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// 等待 `service` 记录的 `peer` 的订阅满足 `check`，超时返回 false
+async fn wait_peer_topics(
+    service: &GossipService,
+    peer: &NodeId,
+    check: impl Fn(Option<&HashSet<Topic>>) -> bool,
+) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if check(service.subscriptions().lock().await.peer(peer)) {
+            return true;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+/// 等待 follower、bystander 的 delivered 和旧版本节点收到的 RefUpdate 数达到 `expected`，
+/// 再等待一段时间确认没有多余的消息；`expected` 为 None 时只等待消息平息
+async fn wait_received(
+    services: [&Arc<GossipService>; 2],
+    legacy_rx: &mut mpsc::Receiver<(NodeId, Vec<u8>)>,
+    legacy_count: &mut u64,
+    expected: Option<[u64; 3]>,
+) -> [u64; 3] {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut settled = false;
+    loop {
+        while let Ok((_, data)) = legacy_rx.try_recv() {
+            if String::from_utf8_lossy(&data).contains("RefUpdate") {
+                *legacy_count += 1;
+            }
+        }
+        let received = [
+            services[0].stats().delivered,
+            services[1].stats().delivered,
+            *legacy_count,
+        ];
+        if settled {
+            return received;
+        }
+        if expected.is_none() || expected == Some(received) || Instant::now() >= deadline {
+            sleep(Duration::from_millis(300)).await;
+            settled = true;
+            continue;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_repo_messages_follow_subscriptions() {
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_test_writer()
+        .try_init();
+
+    // 1. owner 与 follower、bystander、legacy 直连
+    let network = MemoryNetwork::new();
+    let legacy_caps = Capabilities::GOSSIP | Capabilities::REF_UPDATE | Capabilities::SEQUENCE;
+    let mut nodes = Vec::new();
+    let mut transports: Vec<Arc<dyn Transport>> = Vec::new();
+    for (alias, caps) in [
+        ("owner", Capabilities::standard()),
+        ("follower", Capabilities::standard()),
+        ("bystander", Capabilities::standard()),
+        ("legacy", legacy_caps),
+    ] {
+        let mut node = Node::from_keypair(
+            &KeyPair::generate().unwrap(),
+            alias,
+            vec![],
+            NodeType::Normal,
+        );
+        let transport: Arc<dyn Transport> = network.add_node_with(node.node_id().clone(), caps);
+        node.transport = Some(Arc::clone(&transport));
+        nodes.push(node);
+        transports.push(transport);
+    }
+    let ids: Vec<NodeId> = nodes.iter().map(|n| n.node_id().clone()).collect();
+    let repo_id = format!("did:repo:topics-{}", ids[0]);
+
+    let shutdown = Shutdown::new();
+    let topics = [
+        vec![Topic::NewRepos],
+        vec![Topic::Repo(repo_id.clone())],
+        vec![],
+    ];
+    let mut services = Vec::new();
+    for ((node, transport), topics) in nodes.iter().zip(&transports).zip(topics) {
+        let service = Arc::new(
+            GossipService::new(Arc::clone(transport), node.clone(), None)
+                .with_subscriptions(topics)
+                .with_shutdown(shutdown.clone()),
+        );
+        Arc::clone(&service).start().await.unwrap();
+        services.push(service);
+    }
+    let (legacy_tx, mut legacy_rx) = mpsc::channel(64);
+    transports[3]
+        .register_channel(Channel::Gossip, legacy_tx)
+        .await;
+    for transport in &transports[1..] {
+        transport.connect(ids[0].clone(), vec![]).await.unwrap();
+    }
+    let owner = &services[0];
+    assert!(wait_peer_topics(owner, &ids[1], |t| t.is_some()).await);
+    assert!(wait_peer_topics(owner, &ids[2], |t| t.is_some()).await);
+
+    // 2. RefUpdate 只发给关注该仓库的节点和未声明订阅的旧版本节点
+    let change = |new: &str| RefChange {
+        repo_id: repo_id.clone(),
+        ref_name: "refs/heads/main".to_string(),
+        old_oid: None,
+        new_oid: Some(new.to_string()),
+    };
+    let receivers = [&services[1], &services[2]];
+    let mut legacy_count = 0;
+    let before = wait_received(receivers, &mut legacy_rx, &mut legacy_count, None).await;
+    owner.announce_ref_changes(vec![change("c1")]).await;
+    let expected = [before[0] + 1, before[1], before[2] + 1];
+    assert_eq!(
+        wait_received(receivers, &mut legacy_rx, &mut legacy_count, Some(expected)).await,
+        expected
+    );
+
+    // 3. bystander 改为关注 owner 创建的所有仓库后也会收到
+    services[2]
+        .set_subscriptions([Topic::Creator(ids[0].clone())])
+        .await;
+    assert!(
+        wait_peer_topics(owner, &ids[2], |t| {
+            t.is_some_and(|t| t.contains(&Topic::Creator(ids[0].clone())))
+        })
+        .await
+    );
+    owner.announce_ref_changes(vec![change("c2")]).await;
+    let expected = [expected[0] + 1, expected[1] + 1, expected[2] + 1];
+    assert_eq!(
+        wait_received(receivers, &mut legacy_rx, &mut legacy_count, Some(expected)).await,
+        expected
+    );
+
+    shutdown.trigger();
+    for id in &ids {
+        let _ = node_model::delete_node_from_db(&id.to_string()).await;
+    }
+}
@@ -0,0 +1,256 @@
+//! 集成测试：接收方将仓库所有者的 RefUpdate 增量应用到 refs 表，并只为受影响的仓库请求同步；
+//! 完整库存与 RefUpdate 一样逐个 ref 按序号合并；所有者广播变化后签名全部 refs
+//!
+//! 发送方不运行 gossip 服务，直接在 Gossip 通道上发送构造的 RefUpdate。
+use megaengine::gossip::{GossipService, SignedMessage};
+use megaengine::identity::keypair::KeyPair;
+use megaengine::node::node::{Node, NodeType};
+use megaengine::node::shutdown::Shutdown;
+use megaengine::repo::repo::{P2PDescription, Repo};
+use megaengine::repo::repo_sync::RefChange;
+use megaengine::storage::{ref_model, repo_model};
+use megaengine::transport::frame::Channel;
+use megaengine::transport::memory::MemoryNetwork;
+use megaengine::transport::transport::Transport;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+const MAIN: &str = "refs/heads/main";
+
+/// 以指定序号签名的 RefUpdate，封装为 Gossip 通道上的消息
+fn ref_update(node: &Node, repo_id: &str, old: &str, new: &str, sequence: u64) -> Vec<u8> {
+    let change = RefChange {
+        repo_id: repo_id.to_string(),
+        ref_name: MAIN.to_string(),
+        old_oid: Some(old.to_string()),
+        new_oid: Some(new.to_string()),
+    };
+    let signed =
+        SignedMessage::new_ref_update_sign_message(change, node.clone(), sequence).unwrap();
+    serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap()
+}
+
+/// 所有者以指定序号签名的完整库存，只包含 `repo`
+fn inventory(node: &Node, repo: &Repo, sequence: u64) -> Vec<u8> {
+    let signed =
+        SignedMessage::new_inventory_sign_message(vec![repo.clone()], None, node.clone(), sequence)
+            .unwrap();
+    serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap()
+}
+
+/// 等待 refs 表中 `repo_id` 的 main 指向 `commit`，超时返回 false
+async fn wait_ref(repo_id: &str, commit: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if ref_model::get_ref(repo_id, MAIN).await.unwrap().as_deref() == Some(commit) {
+            return true;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_ref_update_applies_delta_and_requests_sync() {
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_test_writer()
+        .try_init();
+
+    // 1. owner 直接发送构造的消息，receiver 运行 gossip 服务
+    let network = MemoryNetwork::new();
+    let owner = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "owner",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let to: Arc<dyn Transport> = network.add_node(owner.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let (sync_tx, mut sync_rx) = mpsc::unbounded_channel();
+    let shutdown = Shutdown::new();
+    let service = Arc::new(
+        GossipService::new(Arc::clone(&tr), receiver.clone(), None)
+            .with_sync_requests(sync_tx)
+            .with_shutdown(shutdown.clone()),
+    );
+    Arc::clone(&service).start().await.unwrap();
+    to.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+
+    // 2. receiver 此前经库存公告得知 owner 的仓库，并已有 bundle
+    let repo_id = format!("did:repo:ref-update-{}", owner.node_id());
+    let mut repo = Repo::new(
+        repo_id.clone(),
+        P2PDescription {
+            creator: owner.node_id().to_string(),
+            name: "ref-update".to_string(),
+            description: String::new(),
+            language: String::new(),
+            latest_commit_at: 0,
+            size: 0,
+        },
+        PathBuf::new(),
+    );
+    repo.is_external = true;
+    repo.bundle = PathBuf::from("/nonexistent/ref-update.bundle");
+    repo.add_ref(MAIN.to_string(), "c1".to_string());
+    repo_model::save_repo_to_db(&repo).await.unwrap();
+
+    let send = |data: Vec<u8>| {
+        let to = Arc::clone(&to);
+        let receiver = receiver.node_id().clone();
+        async move { to.send(receiver, Channel::Gossip, data).await.unwrap() }
+    };
+
+    // 3. RefUpdate 更新 ref、清除过期的 bundle，并请求同步该仓库
+    send(ref_update(&owner, &repo_id, "c1", "c2", 5)).await;
+    assert!(wait_ref(&repo_id, "c2").await);
+    let requested = timeout(Duration::from_secs(5), sync_rx.recv()).await;
+    assert_eq!(requested.unwrap().as_deref(), Some(repo_id.as_str()));
+    let stored = repo_model::load_repo_from_db(&repo_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored.bundle.as_os_str().is_empty());
+
+    // 4. 序号更小的更新被忽略，不会回滚 ref，也不会请求同步
+    send(ref_update(&owner, &repo_id, "c0", "c1", 4)).await;
+    send(ref_update(&owner, &repo_id, "c2", "c3", 6)).await;
+    assert!(wait_ref(&repo_id, "c3").await);
+    assert_eq!(sync_rx.recv().await.as_deref(), Some(repo_id.as_str()));
+    assert!(sync_rx.try_recv().is_err());
+
+    // 5. 非所有者发出的更新被忽略
+    let stranger = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "stranger",
+        vec![],
+        NodeType::Normal,
+    );
+    let ts: Arc<dyn Transport> = network.add_node(stranger.node_id().clone());
+    ts.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+    ts.send(
+        receiver.node_id().clone(),
+        Channel::Gossip,
+        ref_update(&stranger, &repo_id, "c3", "evil", 100),
+    )
+    .await
+    .unwrap();
+    sleep(Duration::from_millis(300)).await;
+    assert!(wait_ref(&repo_id, "c3").await);
+    assert!(sync_rx.try_recv().is_err());
+
+    // 6. 迟到的旧库存不会回滚较新的 RefUpdate
+    let mut stale = repo.clone();
+    stale.refs.clear();
+    stale.add_ref(MAIN.to_string(), "c2".to_string());
+    send(inventory(&owner, &stale, 5)).await;
+    sleep(Duration::from_millis(300)).await;
+    assert!(wait_ref(&repo_id, "c3").await);
+    assert!(sync_rx.try_recv().is_err());
+
+    // 7. 较新的库存逐个合并 ref：新增 tag，删除库存中没有的 ref
+    let mut newer = repo.clone();
+    newer.refs.clear();
+    newer.add_ref("refs/tags/v1".to_string(), "t1".to_string());
+    send(inventory(&owner, &newer, 7)).await;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while ref_model::load_refs_for_repo(&repo_id).await.unwrap() != newer.refs {
+        assert!(Instant::now() < deadline, "newer inventory was not merged");
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    // 8. 删除后迟到的旧更新不会恢复 ref
+    send(ref_update(&owner, &repo_id, "c3", "c9", 6)).await;
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(ref_model::get_ref(&repo_id, MAIN).await.unwrap(), None);
+    assert!(sync_rx.try_recv().is_err());
+
+    shutdown.trigger();
+    repo_model::delete_repo_from_db(&repo_id).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_owner_signs_refs_when_announcing_changes() {
+    let network = MemoryNetwork::new();
+    let owner = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "owner",
+        vec![],
+        NodeType::Normal,
+    );
+    let transport: Arc<dyn Transport> = network.add_node(owner.node_id().clone());
+    let service = GossipService::new(transport, owner.clone(), None);
+
+    // 1. owner 的本地仓库检测到 main 的变化后广播，并签名变化后的全部 refs
+    let repo_id = format!("did:repo:signed-refs-{}", owner.node_id());
+    let mut repo = Repo::new(
+        repo_id.clone(),
+        P2PDescription {
+            creator: owner.node_id().to_string(),
+            name: "signed-refs".to_string(),
+            description: String::new(),
+            language: String::new(),
+            latest_commit_at: 0,
+            size: 0,
+        },
+        PathBuf::new(),
+    );
+    repo.add_ref(MAIN.to_string(), "c1".to_string());
+    repo_model::save_repo_to_db(&repo).await.unwrap();
+    let change = |old: Option<&str>, new: &str| RefChange {
+        repo_id: repo_id.clone(),
+        ref_name: MAIN.to_string(),
+        old_oid: old.map(str::to_string),
+        new_oid: Some(new.to_string()),
+    };
+    service.announce_ref_changes(vec![change(None, "c1")]).await;
+    let stored = repo_model::load_repo_from_db(&repo_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let first = stored.verified_refs().unwrap().clone();
+    assert_eq!(first.refs, repo.refs);
+
+    // 2. refs 未变化时不重新签名，变化后以更大的序号签名
+    service.announce_ref_changes(vec![change(None, "c1")]).await;
+    let stored = repo_model::load_repo_from_db(&repo_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.signed_refs.as_ref(), Some(&first));
+
+    ref_model::save_ref(&repo_id, MAIN, "c2").await.unwrap();
+    service
+        .announce_ref_changes(vec![change(Some("c1"), "c2")])
+        .await;
+    let stored = repo_model::load_repo_from_db(&repo_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let second = stored.verified_refs().unwrap();
+    assert_eq!(second.refs[MAIN], "c2");
+    assert!(second.sequence > first.sequence);
+
+    repo_model::delete_repo_from_db(&repo_id).await.unwrap();
+    assert_eq!(
+        megaengine::storage::signed_refs_model::load_signed_refs(&repo_id)
+            .await
+            .unwrap(),
+        None
+    );
+}
@@ -0,0 +1,335 @@
+//! 集成测试：发送无效签名或无法验证签名的直连节点被扣分，多次违规后被断开并封禁，解除封禁后可以重新连接；
+//! 转发过时公告的节点不被扣分，重发自己旧公告的节点被扣分
+//!
+//! 攻击者不运行 gossip 服务，直接在 Gossip 通道上发送篡改过的公告。
+use megaengine::gossip::message::GossipMessage;
+use megaengine::gossip::{GossipService, SignedMessage};
+use megaengine::identity::keypair::KeyPair;
+use megaengine::node::node::{Node, NodeType};
+use megaengine::node::node_id::NodeId;
+use megaengine::node::reputation::{self, Offence};
+use megaengine::node::shutdown::Shutdown;
+use megaengine::storage::{ban_model, node_model};
+use megaengine::transport::events::ConnectionEvent;
+use megaengine::transport::frame::Channel;
+use megaengine::transport::memory::MemoryNetwork;
+use megaengine::transport::transport::Transport;
+use megaengine::util::timestamp_now;
+use std::sync::Arc;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// 签名后再修改时间戳的节点公告，签名不再有效
+fn forged_announcement(node: &Node, timestamp: i64) -> Vec<u8> {
+    let mut signed = SignedMessage::new_node_sign_message(node.clone(), 1).unwrap();
+    signed.timestamp = timestamp;
+    serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap()
+}
+
+/// 签名者和公告节点都是无法解析出公钥的 NodeId
+fn undecodable_announcement(node: &Node) -> Vec<u8> {
+    let mut signed = SignedMessage::new_node_sign_message(node.clone(), 1).unwrap();
+    let bogus = NodeId("did:key:not-a-key".to_string());
+    signed.node_id = bogus.clone();
+    if let GossipMessage::NodeAnnouncement(na) = &mut signed.message {
+        na.node_id = bogus;
+    }
+    serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap()
+}
+
+#[tokio::test]
+async fn test_undecodable_signer_is_rejected_and_penalized() {
+    let network = MemoryNetwork::new();
+    let attacker = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "attacker",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let ta: Arc<dyn Transport> = network.add_node(attacker.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let shutdown = Shutdown::new();
+    let service = Arc::new(
+        GossipService::new(Arc::clone(&tr), receiver.clone(), None).with_shutdown(shutdown.clone()),
+    );
+    Arc::clone(&service).start().await.unwrap();
+    ta.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+
+    // 先发送签名者无法解析的公告，再发送一条有效公告，有效公告被处理说明前一条已处理完
+    let valid = SignedMessage::new_node_sign_message(attacker.clone(), 2).unwrap();
+    for data in [
+        undecodable_announcement(&attacker),
+        serde_json::to_vec(&serde_json::json!({"payload": valid, "ttl": 3})).unwrap(),
+    ] {
+        ta.send(receiver.node_id().clone(), Channel::Gossip, data)
+            .await
+            .unwrap();
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while service.stats().delivered < 1 {
+        assert!(
+            Instant::now() < deadline,
+            "valid announcement was not delivered"
+        );
+        sleep(Duration::from_millis(10)).await;
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(service.stats().delivered, 1);
+    let score = tr
+        .reputation()
+        .lock()
+        .await
+        .score(attacker.node_id(), timestamp_now());
+    // 得分随时间缓慢恢复，只检查扣分已经发生
+    assert!(score <= 1.0 - Offence::InvalidSignature.penalty());
+
+    shutdown.trigger();
+    for node in [&attacker, &receiver] {
+        let _ = node_model::delete_node_from_db(&node.node_id().to_string()).await;
+    }
+}
+
+#[tokio::test]
+async fn test_relayed_stale_announcement_is_not_penalized() {
+    let network = MemoryNetwork::new();
+    let origin = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "origin",
+        vec![],
+        NodeType::Normal,
+    );
+    let relay = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "relay",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let tl: Arc<dyn Transport> = network.add_node(relay.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let shutdown = Shutdown::new();
+    let service = Arc::new(
+        GossipService::new(Arc::clone(&tr), receiver.clone(), None).with_shutdown(shutdown.clone()),
+    );
+    Arc::clone(&service).start().await.unwrap();
+    tl.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+
+    // relay 先转发 origin 的新公告，再转发延迟到达的旧公告
+    for sequence in [2, 1] {
+        let signed = SignedMessage::new_node_sign_message(origin.clone(), sequence).unwrap();
+        tl.send(
+            receiver.node_id().clone(),
+            Channel::Gossip,
+            serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while service.stats().rejected < 1 {
+        assert!(
+            Instant::now() < deadline,
+            "stale announcement was not rejected"
+        );
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    // 旧公告被丢弃，但 relay 没有被扣分
+    assert_eq!(service.stats().delivered, 1);
+    assert_eq!(
+        tr.reputation()
+            .lock()
+            .await
+            .score(relay.node_id(), timestamp_now()),
+        0.0
+    );
+
+    shutdown.trigger();
+    for node in [&origin, &receiver] {
+        let _ = node_model::delete_node_from_db(&node.node_id().to_string()).await;
+    }
+}
+
+#[tokio::test]
+async fn test_replayed_own_announcement_is_penalized() {
+    let network = MemoryNetwork::new();
+    let replayer = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "replayer",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let tp: Arc<dyn Transport> = network.add_node(replayer.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let shutdown = Shutdown::new();
+    let service = Arc::new(
+        GossipService::new(Arc::clone(&tr), receiver.clone(), None).with_shutdown(shutdown.clone()),
+    );
+    Arc::clone(&service).start().await.unwrap();
+    tp.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+
+    // replayer 直接发送自己的新公告，再重发序号不更新的旧公告
+    for sequence in [2, 1, 2] {
+        let signed = SignedMessage::new_node_sign_message(replayer.clone(), sequence).unwrap();
+        tp.send(
+            receiver.node_id().clone(),
+            Channel::Gossip,
+            serde_json::to_vec(&serde_json::json!({"payload": signed, "ttl": 3})).unwrap(),
+        )
+        .await
+        .unwrap();
+        // 同一秒内重新签名的公告与原公告相同，会被 dedup 丢弃
+        sleep(Duration::from_millis(1100)).await;
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while service.stats().rejected < 2 {
+        assert!(
+            Instant::now() < deadline,
+            "replayed announcements were not rejected"
+        );
+        sleep(Duration::from_millis(10)).await;
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(service.stats().delivered, 1);
+    let score = tr
+        .reputation()
+        .lock()
+        .await
+        .score(replayer.node_id(), timestamp_now());
+    // 得分随时间缓慢恢复，只检查两次扣分都已发生
+    assert!(score <= 1.0 - 2.0 * Offence::Replay.penalty());
+
+    shutdown.trigger();
+    for node in [&replayer, &receiver] {
+        let _ = node_model::delete_node_from_db(&node.node_id().to_string()).await;
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_signatures_lead_to_ban() {
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_test_writer()
+        .try_init();
+
+    // 1. attacker 直连运行 gossip 服务的 receiver
+    let network = MemoryNetwork::new();
+    let attacker = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "attacker",
+        vec![],
+        NodeType::Normal,
+    );
+    let mut receiver = Node::from_keypair(
+        &KeyPair::generate().unwrap(),
+        "receiver",
+        vec![],
+        NodeType::Normal,
+    );
+    let ta: Arc<dyn Transport> = network.add_node(attacker.node_id().clone());
+    let tr: Arc<dyn Transport> = network.add_node(receiver.node_id().clone());
+    receiver.transport = Some(Arc::clone(&tr));
+
+    let shutdown = Shutdown::new();
+    let service = Arc::new(
+        GossipService::new(Arc::clone(&tr), receiver.clone(), None).with_shutdown(shutdown.clone()),
+    );
+    Arc::clone(&service).start().await.unwrap();
+    ta.connect(receiver.node_id().clone(), vec![])
+        .await
+        .unwrap();
+    let mut events = ta.subscribe_events();
+
+    // 2. 无效签名的公告使 attacker 的得分降到阈值以下；两次扣分之间得分会略微恢复，
+    //    因此多发一条，避免两条恰好跨过整秒时得分停在阈值之上
+    let now = timestamp_now();
+    for timestamp in [now - 1, now - 2, now - 3] {
+        // receiver 可能在最后一条之前已经断开连接
+        let _ = ta
+            .send(
+                receiver.node_id().clone(),
+                Channel::Gossip,
+                forged_announcement(&attacker, timestamp),
+            )
+            .await;
+    }
+    let disconnected = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(ConnectionEvent::Disconnected { node_id }) = events.recv().await {
+                break node_id;
+            }
+        }
+    })
+    .await
+    .expect("attacker was not disconnected");
+    assert_eq!(&disconnected, receiver.node_id());
+    assert_eq!(service.stats().delivered, 0);
+
+    // 3. 封禁写入数据库，attacker 无法重新连接
+    let bans = ban_model::list_bans().await.unwrap();
+    let ban = bans
+        .iter()
+        .find(|b| &b.node_id == attacker.node_id())
+        .expect("ban was not persisted");
+    assert!(ban.reason.contains(&Offence::InvalidSignature.to_string()));
+    assert!(ban.is_active(timestamp_now()));
+    assert!(ta
+        .connect(receiver.node_id().clone(), vec![])
+        .await
+        .is_err());
+
+    // 4. 运营者解除封禁后，运行中的节点重新读取封禁记录，attacker 可以重新连接
+    assert!(ban_model::delete_ban(attacker.node_id()).await.unwrap());
+    reputation::start_reputation_task(Arc::clone(&tr), shutdown.clone());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while ta
+        .connect(receiver.node_id().clone(), vec![])
+        .await
+        .is_err()
+    {
+        assert!(Instant::now() < deadline, "ban was not lifted");
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        tr.reputation()
+            .lock()
+            .await
+            .score(attacker.node_id(), timestamp_now()),
+        0.0
+    );
+
+    shutdown.trigger();
+    for node in [&attacker, &receiver] {
+        let _ = node_model::delete_node_from_db(&node.node_id().to_string()).await;
+    }
+}